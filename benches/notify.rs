@@ -0,0 +1,97 @@
+//! The work done for a RandR notification that leaves the connected outputs unchanged, which
+//! is only a comparison, against the work it skips: parsing the EDIDs of the connected monitors
+//! and matching them against the layouts. The round trips to the X server to read the EDIDs,
+//! which are skipped as well, aren't included.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use kdl::parse_document;
+use monitor_layout::{
+    config::{Config, Monitor},
+    ConnectedOutputs,
+};
+use x11rb::protocol::randr::GetScreenResourcesCurrentReply;
+
+use std::{collections::HashMap, convert::TryFrom};
+
+const CONFIG: &str = r#"
+monitor "Laptop" product="Panel" serial="1"
+monitor "Left" product="Desk" serial="2"
+monitor "Right" product="Desk" serial="3"
+layout "Laptop" {
+    matches "Laptop"
+    monitor "Laptop" w=1920 h=1080 x=0 y=0
+}
+layout "Desk" {
+    matches "Laptop" "Left" "Right"
+    monitor "Laptop" "off"
+    monitor "Left" w=2560 h=1440 x=0 y=0
+    monitor "Right" w=2560 h=1440 x=2560 y=0
+}
+"#;
+
+/// A 128 byte EDID for the vendor "ABC", with a product name and a serial number.
+fn edid(product: &str, serial: &str) -> Vec<u8> {
+    let mut bytes = vec![0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+    // Vendor, product code, serial number, week and year of manufacture, and EDID version
+    bytes.extend(&[0x04, 0x43, 0, 0, 0, 0, 0, 0, 1, 30, 1, 4]);
+    // Digital input, 60x34 cm, gamma and features
+    bytes.extend(&[0x80, 60, 34, 120, 0x0a]);
+    // Chromaticity, established timings and standard timings
+    bytes.extend(&[0; 29]);
+    for &(tag, text) in &[(0xfc, product), (0xff, serial)] {
+        bytes.extend(&[0, 0, 0, tag, 0]);
+        let mut field = format!("{}\n", text).into_bytes();
+        field.resize(13, b' ');
+        bytes.extend(field);
+    }
+    for _ in 0..2 {
+        bytes.extend(&[0, 0, 0, 0x10, 0]);
+        bytes.extend(&[0; 13]);
+    }
+    // Extension count and checksum
+    bytes.extend(&[0, 0]);
+    bytes
+}
+
+fn resources() -> GetScreenResourcesCurrentReply {
+    GetScreenResourcesCurrentReply {
+        sequence: 0,
+        length: 0,
+        timestamp: 1000,
+        config_timestamp: 1000,
+        crtcs: vec![100, 101, 102],
+        outputs: vec![10, 11, 12, 13],
+        modes: Vec::new(),
+        names: Vec::new(),
+    }
+}
+
+fn notify(c: &mut Criterion) {
+    let config = Config::try_from(parse_document(CONFIG).unwrap()).unwrap();
+    let edids = vec![
+        (10, edid("Panel", "1")),
+        (11, edid("Desk", "2")),
+        (12, edid("Desk", "3")),
+    ];
+    let res = resources();
+    let connected = vec![10, 11, 12];
+    let last = ConnectedOutputs::new(&res, &connected);
+
+    c.bench_function("unchanged notify", |b| {
+        b.iter(|| ConnectedOutputs::new(black_box(&res), black_box(&connected)) == last)
+    });
+    c.bench_function("full rescan", |b| {
+        b.iter(|| {
+            let monitors: HashMap<_, _> = black_box(&edids)
+                .iter()
+                .map(|(out, bytes)| {
+                    let edid = edid::parse(bytes).to_result().unwrap();
+                    (*out, Monitor::from(edid))
+                })
+                .collect();
+            config.find_layout(&monitors).map(|(layout, _)| layout.name.len())
+        })
+    });
+}
+
+criterion_group!(benches, notify);
+criterion_main!(benches);
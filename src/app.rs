@@ -4,6 +4,16 @@ use clap::{App, Arg, SubCommand};
 
 pub const NAME: &'static str = "monitor-layout";
 
+/// The daemon's D-Bus flag, which only builds with the dbus feature have
+fn dbus_args() -> Vec<Arg<'static, 'static>> {
+    if !cfg!(feature = "dbus") {
+        return Vec::new();
+    }
+    vec![Arg::with_name("dbus")
+        .long("dbus")
+        .help("Serve org.monitorlayout.Daemon on the D-Bus session bus")]
+}
+
 pub fn args() -> App<'static, 'static> {
     App::new(NAME)
         .about("Utilities for laying out monitors in Xorg sessions")
@@ -14,15 +24,103 @@ pub fn args() -> App<'static, 'static> {
                 .multiple(true)
                 .help("Increase message verbosity"),
         )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .global(true)
+                .help("The format of log messages"),
+        )
         .subcommand(
             SubCommand::with_name("daemon")
                 .about("Watch for changes in connected monitors and apply matching layouts")
                 .arg(
                     Arg::with_name("config")
                         .value_name("CONFIG")
-                        .help("The configuration file")
-                        .required(true)
+                        .help("The configuration file, without which outputs are side by side")
                         .index(1),
+                )
+                .arg(
+                    Arg::with_name("no-initial-apply")
+                        .long("no-initial-apply")
+                        .help("Only apply layouts on changes, not when the daemon starts"),
+                )
+                .arg(
+                    Arg::with_name("status-file")
+                        .long("status-file")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .help("Write the name of the applied layout to this file"),
+                )
+                .arg(
+                    Arg::with_name("event-socket")
+                        .long("event-socket")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .help("Tell clients of a Unix socket at this path about layout changes"),
+                )
+                .arg(
+                    Arg::with_name("selector")
+                        .long("selector")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .help("Run this script to pick the layout to apply, by name"),
+                )
+                .arg(
+                    Arg::with_name("post-resize-exec")
+                        .long("post-resize-exec")
+                        .value_name("CMD")
+                        .takes_value(true)
+                        .help("Run this shell command each time the screen changes size"),
+                )
+                .args(&dbus_args())
+                .arg(
+                    Arg::with_name("foreground")
+                        .long("foreground")
+                        .help("Stay attached to the terminal that started the daemon (default)"),
+                )
+                .arg(
+                    Arg::with_name("daemonize")
+                        .long("daemonize")
+                        .conflicts_with("foreground")
+                        .help("Detach from the terminal and run in the background"),
+                )
+                .arg(
+                    Arg::with_name("pid-file")
+                        .long("pid-file")
+                        .value_name("PATH")
+                        .takes_value(true)
+                        .help("Write the PID of the daemon to this file, removing it on exit"),
+                )
+                .arg(
+                    Arg::with_name("replace")
+                        .long("replace")
+                        .requires("pid-file")
+                        .help("Stop the daemon named by the PID file, and take over from it"),
+                )
+                .arg(
+                    Arg::with_name("validate")
+                        .long("validate")
+                        .help("Check the configuration and exit, without connecting to X"),
+                )
+                .arg(
+                    Arg::with_name("poll-interval")
+                        .long("poll-interval")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .help("Also probe the outputs this often, for drivers that miss hotplugs"),
+                )
+                .arg(
+                    Arg::with_name("remember-crtcs")
+                        .long("remember-crtcs")
+                        .help("Re-enable outputs on the CRTC that last drove them"),
+                )
+                .arg(
+                    Arg::with_name("keep-previous")
+                        .long("keep-previous")
+                        .help("Restore the whole previous configuration when a layout fails"),
                 ),
         )
         .subcommand(
@@ -37,8 +135,216 @@ pub fn args() -> App<'static, 'static> {
                 ),
         )
         .subcommand(
-            SubCommand::with_name("print-edids").about(
-                "Read the edids and print them as they would appear in a configuration file",
-            ),
+            SubCommand::with_name("dump")
+                .about("Print the configuration as it's read, as JSON, to debug it")
+                .arg(
+                    Arg::with_name("config")
+                        .value_name("CONFIG")
+                        .help("The configuration file")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("render")
+                .about("Draw a diagram of a layout, without applying it")
+                .arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("The name of the layout")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .value_name("CONFIG")
+                        .help("The configuration file")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("match")
+                .about("Print the layout that a captured set of monitors matches, without X")
+                .arg(
+                    Arg::with_name("monitors")
+                        .long("monitors")
+                        .value_name("FILE")
+                        .help("A JSON or KDL list of monitors, or - for stdin")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .value_name("CONFIG")
+                        .help("The configuration file")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Convert a profile of the Python autorandr into a configuration")
+                .arg(
+                    Arg::with_name("profile")
+                        .value_name("PROFILE_DIR")
+                        .help("The autorandr profile directory, with its setup and config files")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("out")
+                        .value_name("OUT")
+                        .help("The configuration file to write, or - for stdout")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Show how the current monitor configuration differs from a layout")
+                .arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("The name of the layout")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .value_name("CONFIG")
+                        .help("The configuration file")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Apply a layout by name")
+                .arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("The name of the layout to apply")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .value_name("CONFIG")
+                        .help("The configuration file")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("confirm")
+                        .long("confirm")
+                        .help("Restore the previous configuration unless a key is pressed"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .long("timeout")
+                        .value_name("SECONDS")
+                        .takes_value(true)
+                        .requires("confirm")
+                        .help("How long --confirm waits for a key press [default: 15]"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply-group")
+                .about("Apply the layout of a group that matches the connected monitors")
+                .arg(
+                    Arg::with_name("group")
+                        .value_name("GROUP")
+                        .help("The group of layouts to choose from")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .value_name("CONFIG")
+                        .help("The configuration file")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("print-edids")
+                .about(
+                    "Read the edids and print them as they would appear in a configuration file",
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .value_name("CONFIG")
+                        .help("A configuration file to take monitor names from")
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("unmatched")
+                        .long("unmatched")
+                        .requires("config")
+                        .help("Only print monitors that the configuration doesn't declare"),
+                )
+                .arg(
+                    Arg::with_name("with-layout")
+                        .long("with-layout")
+                        .help("Also print a layout of every monitor, side by side"),
+                )
+                .arg(
+                    Arg::with_name("detailed")
+                        .long("detailed")
+                        .help("Also print the gamma, chromaticity and color depth of each monitor"),
+                ),
         )
+        .subcommand(
+            SubCommand::with_name("dump-edid")
+                .about("Print the raw EDID of each output, for bug reports")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("NAME")
+                        .takes_value(true)
+                        .help("Only print the EDID of this output"),
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .possible_values(&["hex", "base64"])
+                        .default_value("hex")
+                        .help("How to print the EDID bytes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("modes")
+                .about("Print the modes that each connected output supports")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("NAME")
+                        .takes_value(true)
+                        .help("Only print the modes of this output"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Print the state of every output and CRTC, for bug reports"),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn confirm_timeout_is_optional() {
+        let apply = |extra: &[&'static str]| {
+            let mut argv = vec!["monitor-layout", "apply", "L", "c.kdl"];
+            argv.extend_from_slice(extra);
+            args().get_matches_from_safe(argv)
+        };
+        assert!(apply(&[]).is_ok());
+        assert!(apply(&["--confirm"]).is_ok());
+        assert!(apply(&["--confirm", "--timeout", "5"]).is_ok());
+        assert!(apply(&["--timeout", "5"]).is_err());
+    }
 }
@@ -0,0 +1,1245 @@
+//! The subset of RandR requests used to apply a layout.
+//!
+//! Applying a layout is written against the [`Backend`] trait rather than directly against an
+//! x11rb connection, so that the apply logic may be exercised without an X server.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::{Mutex, PoisonError};
+
+use miette::{Diagnostic, IntoDiagnostic, Result};
+use thiserror::Error;
+use x11rb::{
+    connection::Connection,
+    cookie::Cookie,
+    errors::ReplyError,
+    protocol::randr::{
+        ConnectionExt as RandrExt, Crtc, GetCrtcInfoReply, GetOutputInfoReply,
+        GetScreenResourcesCurrentReply, GetScreenResourcesReply, ModeInfo, Output,
+        SetCrtcConfigReply, SetCrtcConfigRequest, SetPanningReply, SetPanningRequest,
+    },
+    protocol::render::Transform,
+    protocol::xinput::{
+        self, ConnectionExt as XinputExt, DeviceClassData, DeviceType, XIChangePropertyAux,
+    },
+    protocol::xproto::{AtomEnum, ConnectionExt as XprotoExt, PropMode, Timestamp, Window},
+    protocol::ErrorKind,
+};
+
+use crate::config::{Mode, Monitor};
+use crate::{edid_atom, get_edid};
+
+/// The server rejected a screen size with a Match error. Some drivers do so for a moment after
+/// CRTCs are disabled, so the resize may succeed when it's tried again.
+#[derive(Error, Debug)]
+#[error("The X server rejected the screen size {0}")]
+pub struct ScreenSizeMismatch(pub Mode);
+
+impl Diagnostic for ScreenSizeMismatch {}
+
+/// What an output property holds, and the values the server accepts for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertyInfo {
+    /// The name of the property's type, such as "ATOM" or "INTEGER"
+    pub kind: String,
+    /// The size of each of the property's items, in bits
+    pub format: u8,
+    /// The smallest and largest value of a property that takes a range of numbers
+    pub range: Option<(i32, i32)>,
+    /// The names of the values an ATOM property takes, when the server lists them
+    pub atoms: Vec<String>,
+}
+
+/// A value for an output property, converted to the property's type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyData {
+    /// The atom with this name
+    Atom(String),
+    /// A number, written as an INTEGER, or a CARDINAL when `cardinal`, of `format` bits
+    Number { value: i64, format: u8, cardinal: bool },
+    /// A STRING, of 8 bit characters
+    String(String),
+}
+
+/// Where an output sits among the outputs of a tiled monitor, such as an 8K monitor driven over
+/// DisplayPort MST as two outputs, from the output's TILE property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    /// Shared by every tile of the same monitor
+    pub group: u32,
+    /// The number of tiles across and down the monitor
+    pub columns: u32,
+    pub rows: u32,
+    /// The position of this tile, counted in tiles from the top left
+    pub column: u32,
+    pub row: u32,
+    /// The size of this tile, in pixels
+    pub w: u16,
+    pub h: u16,
+}
+
+pub trait Backend {
+    /// Complete a RandR getScreenResources request.
+    fn screen_resources(&self, root: Window) -> Result<GetScreenResourcesReply>;
+    /// Complete a RandR getScreenResourcesCurrent request, which doesn't probe the outputs.
+    fn screen_resources_current(&self, root: Window) -> Result<GetScreenResourcesCurrentReply>;
+    /// Complete a RandR getOutputInfo request.
+    fn output_info(&self, output: Output, timestamp: Timestamp) -> Result<GetOutputInfoReply>;
+    /// Complete a RandR getCrtcInfo request.
+    fn crtc_info(&self, crtc: Crtc, timestamp: Timestamp) -> Result<GetCrtcInfoReply>;
+    /// Complete a RandR getOutputInfo request for each of `outputs`. Connections send every
+    /// request before waiting for any reply, so that their round trips overlap.
+    fn output_infos(
+        &self,
+        outputs: &[Output],
+        timestamp: Timestamp,
+    ) -> Vec<Result<GetOutputInfoReply>> {
+        outputs.iter().map(|&out| self.output_info(out, timestamp)).collect()
+    }
+    /// Complete a RandR getCrtcInfo request for each of `crtcs`, as `output_infos` does.
+    fn crtc_infos(&self, crtcs: &[Crtc], timestamp: Timestamp) -> Vec<Result<GetCrtcInfoReply>> {
+        crtcs.iter().map(|&crtc| self.crtc_info(crtc, timestamp)).collect()
+    }
+    /// The time of the last change to the screen's configuration and the time the server last
+    /// noticed a change in the available hardware, from getScreenResourcesCurrent.
+    fn screen_timestamps(&self, root: Window) -> Result<(Timestamp, Timestamp)>;
+    /// The current size of the screen, in pixels.
+    fn screen_size(&self, root: Window) -> Result<Mode>;
+    /// The current physical size of the screen, in millimeters.
+    fn screen_mm(&self, root: Window) -> Result<Mode>;
+    /// The current primary output, or 0 when there is none.
+    fn output_primary(&self, root: Window) -> Result<Output>;
+    /// Send a batch of SetCrtcConfig requests, then collect all of their replies.
+    fn set_crtc_configs(
+        &self,
+        batch: Vec<SetCrtcConfigRequest<'_>>,
+    ) -> Result<Vec<SetCrtcConfigReply>>;
+    /// Send a batch of SetPanning requests, then collect all of their replies.
+    fn set_pannings(&self, batch: Vec<SetPanningRequest>) -> Result<Vec<SetPanningReply>>;
+    /// Resize the screen's frame buffer. A Match error is returned as a [`ScreenSizeMismatch`].
+    fn set_screen_size(&self, root: Window, w: u16, h: u16, mm_w: u32, mm_h: u32) -> Result<()>;
+    /// Make an output the primary output.
+    fn set_output_primary(&self, root: Window, output: Output) -> Result<()>;
+    /// Create a new mode, returning its id.
+    fn create_mode(&self, root: Window, mode: ModeInfo, name: &[u8]) -> Result<u32>;
+    /// Allow an output to use a mode.
+    fn add_output_mode(&self, output: Output, mode: u32) -> Result<()>;
+    /// Stop an output from using a mode.
+    fn delete_output_mode(&self, output: Output, mode: u32) -> Result<()>;
+    /// Destroy a mode, which no output may be using.
+    fn destroy_mode(&self, mode: u32) -> Result<()>;
+    /// The range of values accepted by an output's Backlight property, or None when the output
+    /// has no backlight.
+    fn backlight_range(&self, output: Output) -> Result<Option<(i32, i32)>>;
+    /// Set an output's Backlight property.
+    fn set_backlight(&self, output: Output, value: i32) -> Result<()>;
+    /// Whether an output has a variable refresh rate property.
+    fn has_vrr(&self, output: Output) -> Result<bool>;
+    /// Turn an output's variable refresh rate on or off.
+    fn set_vrr(&self, output: Output, enabled: bool) -> Result<()>;
+    /// Whether an output has a scaling mode property.
+    fn has_scaling_mode(&self, output: Output) -> Result<bool>;
+    /// Set an output's scaling mode property to the value named `mode`, such as "Full".
+    fn set_scaling_mode(&self, output: Output, mode: &str) -> Result<()>;
+    /// What the output property named `name` holds, or None when the output lacks it.
+    fn property_info(&self, output: Output, name: &str) -> Result<Option<PropertyInfo>>;
+    /// Set the output property named `name`.
+    fn set_property(&self, output: Output, name: &str, data: &PropertyData) -> Result<()>;
+    /// Where an output sits within a tiled monitor, or None when its monitor isn't tiled.
+    fn tile(&self, output: Output) -> Result<Option<Tile>>;
+    /// The transform a CRTC currently applies to its picture.
+    fn crtc_transform(&self, crtc: Crtc) -> Result<Transform>;
+    /// The monitor described by an output's EDID, or None when the output has no EDID.
+    fn monitor(&self, output: Output) -> Result<Option<Monitor>>;
+    /// Set the transform, and the filter named `filter`, that a CRTC applies to its picture once
+    /// it's next configured.
+    fn set_crtc_transform(&self, crtc: Crtc, transform: Transform, filter: &[u8]) -> Result<()>;
+    /// The id and name of each XInput device that reports touches on a display, rather than on
+    /// a touchpad. Empty when the server doesn't support XInput 2.2.
+    fn touch_screens(&self) -> Result<Vec<(u16, String)>>;
+    /// Set the matrix, 3x3 and row by row, that maps a touch device onto the screen.
+    fn set_touch_matrix(&self, device: u16, matrix: [f32; 9]) -> Result<()>;
+    /// The number of entries in each of a CRTC's gamma ramps.
+    fn gamma_size(&self, crtc: Crtc) -> Result<u16>;
+    /// Set a CRTC's red, green and blue gamma ramps.
+    fn set_gamma(&self, crtc: Crtc, ramps: &[Vec<u16>; 3]) -> Result<()>;
+    /// Whether the root window has a property named `name`.
+    fn has_root_property(&self, root: Window, name: &[u8]) -> Result<bool>;
+    /// Set the root window property named `name` to the bytes of `value`, or delete it when
+    /// `value` is None.
+    fn set_root_property(&self, root: Window, name: &[u8], value: Option<&[u8]>) -> Result<()>;
+    /// Set the root window property named `name` to `text`, as a UTF8_STRING.
+    fn set_root_text(&self, root: Window, name: &[u8], text: &str) -> Result<()>;
+}
+
+/// The transform of a CRTC that leaves its picture as it is
+pub const IDENTITY_TRANSFORM: Transform = Transform {
+    matrix11: 1 << 16,
+    matrix12: 0,
+    matrix13: 0,
+    matrix21: 0,
+    matrix22: 1 << 16,
+    matrix23: 0,
+    matrix31: 0,
+    matrix32: 0,
+    matrix33: 1 << 16,
+};
+
+/// The output property that turns variable refresh rate, such as FreeSync, on or off
+const VRR_PROPERTY: &[u8] = b"VRR_ENABLED";
+
+/// The output property that picks how a mode smaller than the panel is scaled to fill it
+const SCALING_MODE_PROPERTY: &[u8] = b"scaling mode";
+
+/// The output property that describes an output's place within a tiled monitor
+const TILE_PROPERTY: &[u8] = b"TILE";
+
+/// The input device property that maps a touch device onto the screen
+const TOUCH_MATRIX_PROPERTY: &[u8] = b"Coordinate Transformation Matrix";
+
+/// The physical size of each screen, by its root window, as it was last set or notified. RandR
+/// only reports it through getScreenInfo, which probes every output and answers with the size it
+/// emulates for RandR 1.0 clients, so it's tracked here instead.
+static SCREEN_MM: Mutex<Option<HashMap<Window, Mode>>> = Mutex::new(None);
+
+/// Record the physical size of a screen, such as from an RRScreenChangeNotify event, for
+/// [`Backend::screen_mm`] to return.
+pub fn note_screen_mm(root: Window, mm: Mode) {
+    let mut sizes = SCREEN_MM.lock().unwrap_or_else(PoisonError::into_inner);
+    sizes.get_or_insert_with(HashMap::new).insert(root, mm);
+}
+
+/// Get the atom that names an output property.
+fn property_atom<C: Connection>(conn: &C, name: &[u8]) -> Result<u32> {
+    Ok(conn
+        .intern_atom(false, name)
+        .into_diagnostic()?
+        .reply()
+        .into_diagnostic()?
+        .atom)
+}
+
+impl<C: Connection> Backend for C {
+    fn screen_resources(&self, root: Window) -> Result<GetScreenResourcesReply> {
+        self.randr_get_screen_resources(root)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()
+    }
+
+    fn output_info(&self, output: Output, timestamp: Timestamp) -> Result<GetOutputInfoReply> {
+        self.randr_get_output_info(output, timestamp)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()
+    }
+
+    fn crtc_info(&self, crtc: Crtc, timestamp: Timestamp) -> Result<GetCrtcInfoReply> {
+        self.randr_get_crtc_info(crtc, timestamp)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()
+    }
+
+    fn output_infos(
+        &self,
+        outputs: &[Output],
+        timestamp: Timestamp,
+    ) -> Vec<Result<GetOutputInfoReply>> {
+        let cookies: Vec<_> = outputs
+            .iter()
+            .map(|&out| self.randr_get_output_info(out, timestamp))
+            .collect();
+        cookies
+            .into_iter()
+            .map(|cookie| cookie.into_diagnostic()?.reply().into_diagnostic())
+            .collect()
+    }
+
+    fn crtc_infos(&self, crtcs: &[Crtc], timestamp: Timestamp) -> Vec<Result<GetCrtcInfoReply>> {
+        let cookies: Vec<_> = crtcs
+            .iter()
+            .map(|&crtc| self.randr_get_crtc_info(crtc, timestamp))
+            .collect();
+        cookies
+            .into_iter()
+            .map(|cookie| cookie.into_diagnostic()?.reply().into_diagnostic())
+            .collect()
+    }
+
+    fn screen_resources_current(&self, root: Window) -> Result<GetScreenResourcesCurrentReply> {
+        self.randr_get_screen_resources_current(root)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()
+    }
+
+    fn screen_timestamps(&self, root: Window) -> Result<(Timestamp, Timestamp)> {
+        let res = self.screen_resources_current(root)?;
+        Ok((res.timestamp, res.config_timestamp))
+    }
+
+    fn screen_size(&self, root: Window) -> Result<Mode> {
+        let geom = self
+            .get_geometry(root)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        Ok(Mode {
+            w: geom.width,
+            h: geom.height,
+        })
+    }
+
+    fn screen_mm(&self, root: Window) -> Result<Mode> {
+        let sizes = SCREEN_MM.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(mm) = sizes.as_ref().and_then(|sizes| sizes.get(&root)) {
+            return Ok(mm.clone());
+        }
+        // Until the size changes, it's the one the server sent when the connection was set up
+        let screen = self
+            .setup()
+            .roots
+            .iter()
+            .find(|screen| screen.root == root)
+            .ok_or_else(|| miette::miette!("Window {} is not the root of a screen", root))?;
+        Ok(Mode {
+            w: screen.width_in_millimeters,
+            h: screen.height_in_millimeters,
+        })
+    }
+
+    fn output_primary(&self, root: Window) -> Result<Output> {
+        Ok(self
+            .randr_get_output_primary(root)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?
+            .output)
+    }
+
+    fn set_crtc_configs(
+        &self,
+        batch: Vec<SetCrtcConfigRequest<'_>>,
+    ) -> Result<Vec<SetCrtcConfigReply>> {
+        // All requests are sent before waiting on any reply, so the batch costs a single
+        // round trip.
+        let cookies: Vec<Cookie<C, SetCrtcConfigReply>> = batch
+            .into_iter()
+            .map(|req| req.send(self))
+            .collect::<std::result::Result<_, _>>()
+            .into_diagnostic()?;
+        cookies
+            .into_iter()
+            .map(|cookie| cookie.reply())
+            .collect::<std::result::Result<_, _>>()
+            .into_diagnostic()
+    }
+
+    fn set_pannings(&self, batch: Vec<SetPanningRequest>) -> Result<Vec<SetPanningReply>> {
+        let cookies: Vec<Cookie<C, SetPanningReply>> = batch
+            .into_iter()
+            .map(|req| req.send(self))
+            .collect::<std::result::Result<_, _>>()
+            .into_diagnostic()?;
+        cookies
+            .into_iter()
+            .map(|cookie| cookie.reply())
+            .collect::<std::result::Result<_, _>>()
+            .into_diagnostic()
+    }
+
+    fn set_screen_size(&self, root: Window, w: u16, h: u16, mm_w: u32, mm_h: u32) -> Result<()> {
+        match self
+            .randr_set_screen_size(root, w, h, mm_w, mm_h)
+            .into_diagnostic()?
+            .check()
+        {
+            Err(ReplyError::X11Error(e)) if e.error_kind == ErrorKind::Match => {
+                return Err(ScreenSizeMismatch(Mode { w, h }).into());
+            }
+            res => res.into_diagnostic()?,
+        }
+        // The server keeps the size in millimeters as a u16
+        let mm = Mode {
+            w: u16::try_from(mm_w).unwrap_or(u16::MAX),
+            h: u16::try_from(mm_h).unwrap_or(u16::MAX),
+        };
+        note_screen_mm(root, mm);
+        Ok(())
+    }
+
+    fn set_output_primary(&self, root: Window, output: Output) -> Result<()> {
+        self.randr_set_output_primary(root, output)
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+
+    fn create_mode(&self, root: Window, mode: ModeInfo, name: &[u8]) -> Result<u32> {
+        Ok(self
+            .randr_create_mode(root, mode, name)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?
+            .mode)
+    }
+
+    fn add_output_mode(&self, output: Output, mode: u32) -> Result<()> {
+        self.randr_add_output_mode(output, mode)
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+
+    fn delete_output_mode(&self, output: Output, mode: u32) -> Result<()> {
+        self.randr_delete_output_mode(output, mode)
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+
+    fn destroy_mode(&self, mode: u32) -> Result<()> {
+        self.randr_destroy_mode(mode)
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+
+    fn backlight_range(&self, output: Output) -> Result<Option<(i32, i32)>> {
+        let atom = property_atom(self, b"Backlight")?;
+        if !has_output_property(self, output, atom)? {
+            return Ok(None);
+        }
+        let query = self
+            .randr_query_output_property(output, atom)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        match query.valid_values.as_slice() {
+            &[min, max] if query.range => Ok(Some((min, max))),
+            _ => Ok(None),
+        }
+    }
+
+    fn set_backlight(&self, output: Output, value: i32) -> Result<()> {
+        let atom = property_atom(self, b"Backlight")?;
+        set_integer_property(self, output, atom, value)
+    }
+
+    fn has_vrr(&self, output: Output) -> Result<bool> {
+        let atom = property_atom(self, VRR_PROPERTY)?;
+        has_output_property(self, output, atom)
+    }
+
+    fn set_vrr(&self, output: Output, enabled: bool) -> Result<()> {
+        let atom = property_atom(self, VRR_PROPERTY)?;
+        set_integer_property(self, output, atom, enabled.into())
+    }
+
+    fn has_scaling_mode(&self, output: Output) -> Result<bool> {
+        let atom = property_atom(self, SCALING_MODE_PROPERTY)?;
+        has_output_property(self, output, atom)
+    }
+
+    fn set_scaling_mode(&self, output: Output, mode: &str) -> Result<()> {
+        let atom = property_atom(self, SCALING_MODE_PROPERTY)?;
+        let value = property_atom(self, mode.as_bytes())?;
+        self.randr_change_output_property(
+            output,
+            atom,
+            AtomEnum::ATOM.into(),
+            32,
+            PropMode::REPLACE,
+            1,
+            &value.to_ne_bytes(),
+        )
+        .into_diagnostic()?
+        .check()
+        .into_diagnostic()
+    }
+
+    fn property_info(&self, output: Output, name: &str) -> Result<Option<PropertyInfo>> {
+        let atom = property_atom(self, name.as_bytes())?;
+        let prop = self
+            .randr_get_output_property(output, atom, AtomEnum::ANY, 0, 0, false, false)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        if prop.type_ == u32::from(AtomEnum::NONE) {
+            return Ok(None);
+        }
+        let query = self
+            .randr_query_output_property(output, atom)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        let atom_name = |atom: u32| -> Result<String> {
+            let reply = self.get_atom_name(atom).into_diagnostic()?.reply().into_diagnostic()?;
+            Ok(String::from_utf8_lossy(&reply.name).into_owned())
+        };
+        let kind = atom_name(prop.type_)?;
+        let (range, atoms) = match query.valid_values.as_slice() {
+            &[min, max] if query.range => (Some((min, max)), Vec::new()),
+            values if kind == "ATOM" => {
+                let names = values.iter().map(|&v| atom_name(v as u32));
+                (None, names.collect::<Result<_>>()?)
+            }
+            _ => (None, Vec::new()),
+        };
+        Ok(Some(PropertyInfo {
+            kind,
+            format: prop.format,
+            range,
+            atoms,
+        }))
+    }
+
+    fn set_property(&self, output: Output, name: &str, data: &PropertyData) -> Result<()> {
+        let atom = property_atom(self, name.as_bytes())?;
+        let (kind, format, bytes): (u32, u8, Vec<u8>) = match data {
+            PropertyData::Atom(value) => {
+                let value = property_atom(self, value.as_bytes())?;
+                (AtomEnum::ATOM.into(), 32, value.to_ne_bytes().to_vec())
+            }
+            PropertyData::Number {
+                value,
+                format,
+                cardinal,
+            } => {
+                let kind = if *cardinal { AtomEnum::CARDINAL } else { AtomEnum::INTEGER };
+                let bytes = match format {
+                    8 => (*value as i8).to_ne_bytes().to_vec(),
+                    16 => (*value as i16).to_ne_bytes().to_vec(),
+                    _ => (*value as i32).to_ne_bytes().to_vec(),
+                };
+                (kind.into(), *format, bytes)
+            }
+            PropertyData::String(value) => {
+                (AtomEnum::STRING.into(), 8, value.as_bytes().to_vec())
+            }
+        };
+        let items = (bytes.len() / usize::from(format / 8)) as u32;
+        self.randr_change_output_property(
+            output,
+            atom,
+            kind,
+            format,
+            PropMode::REPLACE,
+            items,
+            &bytes,
+        )
+        .into_diagnostic()?
+        .check()
+        .into_diagnostic()
+    }
+
+    fn tile(&self, output: Output) -> Result<Option<Tile>> {
+        let atom = property_atom(self, TILE_PROPERTY)?;
+        let prop = self
+            .randr_get_output_property(output, atom, AtomEnum::INTEGER, 0, 8, false, false)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        if prop.format != 32 || prop.data.len() != 32 {
+            return Ok(None);
+        }
+        let values: Vec<u32> = prop
+            .data
+            .chunks(4)
+            .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        // The flags, the second value, aren't needed to place the tiles
+        Ok(Some(Tile {
+            group: values[0],
+            columns: values[2],
+            rows: values[3],
+            column: values[4],
+            row: values[5],
+            w: values[6] as u16,
+            h: values[7] as u16,
+        }))
+    }
+
+    fn monitor(&self, output: Output) -> Result<Option<Monitor>> {
+        let edid = get_edid(self, edid_atom(self)?, output).into_diagnostic()?;
+        Ok(edid.map(Monitor::from))
+    }
+
+    fn crtc_transform(&self, crtc: Crtc) -> Result<Transform> {
+        Ok(self
+            .randr_get_crtc_transform(crtc)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?
+            .current_transform)
+    }
+
+    fn set_crtc_transform(&self, crtc: Crtc, transform: Transform, filter: &[u8]) -> Result<()> {
+        self.randr_set_crtc_transform(crtc, transform, filter, &[])
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+
+    fn touch_screens(&self) -> Result<Vec<(u16, String)>> {
+        let ext = self
+            .extension_information(xinput::X11_EXTENSION_NAME)
+            .into_diagnostic()?;
+        if ext.is_none() {
+            return Ok(Vec::new());
+        }
+        // Touch classes are only reported to clients that announce XInput 2.2
+        let version = self
+            .xinput_xi_query_version(2, 2)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        if (version.major_version, version.minor_version) < (2, 2) {
+            return Ok(Vec::new());
+        }
+        let devices = self
+            .xinput_xi_query_device(xinput::Device::ALL)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        // Master pointers copy the classes of the last device used, so only the physical
+        // devices are considered
+        let physical =
+            |kind| kind == DeviceType::SLAVE_POINTER || kind == DeviceType::FLOATING_SLAVE;
+        Ok(devices
+            .infos
+            .into_iter()
+            .filter(|info| physical(info.type_))
+            .filter(|info| {
+                info.classes.iter().any(|class| match &class.data {
+                    DeviceClassData::Touch(touch) => touch.mode == xinput::TouchMode::DIRECT,
+                    _ => false,
+                })
+            })
+            .map(|info| (info.deviceid, String::from_utf8_lossy(&info.name).into_owned()))
+            .collect())
+    }
+
+    fn set_touch_matrix(&self, device: u16, matrix: [f32; 9]) -> Result<()> {
+        let atom = property_atom(self, TOUCH_MATRIX_PROPERTY)?;
+        let float = property_atom(self, b"FLOAT")?;
+        let items = XIChangePropertyAux::Data32(matrix.iter().map(|v| v.to_bits()).collect());
+        self.xinput_xi_change_property(device, PropMode::REPLACE, atom, float, 9, &items)
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+
+    fn gamma_size(&self, crtc: Crtc) -> Result<u16> {
+        Ok(self
+            .randr_get_crtc_gamma_size(crtc)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?
+            .size)
+    }
+
+    fn set_gamma(&self, crtc: Crtc, ramps: &[Vec<u16>; 3]) -> Result<()> {
+        let [red, green, blue] = ramps;
+        self.randr_set_crtc_gamma(crtc, red, green, blue)
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+
+    fn has_root_property(&self, root: Window, name: &[u8]) -> Result<bool> {
+        let atom = property_atom(self, name)?;
+        let prop = self
+            .get_property(false, root, atom, AtomEnum::ANY, 0, 0)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?;
+        Ok(prop.type_ != u32::from(AtomEnum::NONE))
+    }
+
+    fn set_root_property(&self, root: Window, name: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let atom = property_atom(self, name)?;
+        let cookie = match value {
+            Some(value) => self.change_property(
+                PropMode::REPLACE,
+                root,
+                atom,
+                AtomEnum::CARDINAL,
+                8,
+                value.len() as u32,
+                value,
+            ),
+            None => self.delete_property(root, atom),
+        };
+        cookie.into_diagnostic()?.check().into_diagnostic()
+    }
+
+    fn set_root_text(&self, root: Window, name: &[u8], text: &str) -> Result<()> {
+        let atom = property_atom(self, name)?;
+        let utf8 = property_atom(self, b"UTF8_STRING")?;
+        let text = text.as_bytes();
+        self.change_property(PropMode::REPLACE, root, atom, utf8, 8, text.len() as u32, text)
+            .into_diagnostic()?
+            .check()
+            .into_diagnostic()
+    }
+}
+
+/// Whether an output has a property.
+fn has_output_property<C: Connection>(conn: &C, output: Output, atom: u32) -> Result<bool> {
+    let prop = conn
+        .randr_get_output_property(output, atom, AtomEnum::ANY, 0, 1, false, false)
+        .into_diagnostic()?
+        .reply()
+        .into_diagnostic()?;
+    // A property that does not exist has no type
+    Ok(prop.type_ != u32::from(AtomEnum::NONE))
+}
+
+/// Set an output property that holds a single 32 bit integer.
+fn set_integer_property<C: Connection>(
+    conn: &C,
+    output: Output,
+    atom: u32,
+    value: i32,
+) -> Result<()> {
+    conn.randr_change_output_property(
+        output,
+        atom,
+        AtomEnum::INTEGER.into(),
+        32,
+        PropMode::REPLACE,
+        1,
+        &value.to_ne_bytes(),
+    )
+    .into_diagnostic()?
+    .check()
+    .into_diagnostic()
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    //! A [`Backend`] that answers from canned replies and records every change requested of it.
+    use super::*;
+    use std::borrow::Cow;
+    use std::cell::{Cell, RefCell};
+    use std::collections::{HashMap, HashSet};
+    use x11rb::protocol::randr::{Connection as OutConnection, SetConfig};
+
+    /// A request that changes the state of the (pretend) X server.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Call {
+        CrtcConfig(SetCrtcConfigRequest<'static>),
+        Panning(SetPanningRequest),
+        ScreenSize(u16, u16, u32, u32),
+        OutputPrimary(Output),
+        CreateMode(ModeInfo, Vec<u8>),
+        AddOutputMode(Output, u32),
+        DeleteOutputMode(Output, u32),
+        DestroyMode(u32),
+        Backlight(Output, i32),
+        Vrr(Output, bool),
+        ScalingMode(Output, String),
+        CrtcTransform(Crtc, Transform, Vec<u8>),
+        TouchMatrix(u16, [f32; 9]),
+        Gamma(Crtc, [Vec<u16>; 3]),
+        RootProperty(Vec<u8>, Option<Vec<u8>>),
+        Property(Output, String, PropertyData),
+    }
+
+    /// The id given to the first mode created through the mock.
+    pub const FIRST_CREATED_MODE: u32 = 1000;
+
+    pub const TIMESTAMP: Timestamp = 1000;
+
+    #[derive(Default)]
+    pub struct MockBackend {
+        pub modes: Vec<ModeInfo>,
+        pub outputs: HashMap<Output, GetOutputInfoReply>,
+        pub crtcs: HashMap<Crtc, GetCrtcInfoReply>,
+        pub size: Option<Mode>,
+        /// The physical size of the screen, in millimeters
+        pub mm: Option<Mode>,
+        pub primary: Output,
+        pub backlights: HashMap<Output, (i32, i32)>,
+        /// The outputs with a variable refresh rate property
+        pub vrr: HashSet<Output>,
+        /// The outputs with a scaling mode property
+        pub scaling: HashSet<Output>,
+        pub tiles: HashMap<Output, Tile>,
+        /// The monitor connected to each output with an EDID
+        pub monitors: HashMap<Output, Monitor>,
+        /// The properties of each output, other than those above, by name
+        pub properties: HashMap<(Output, String), PropertyInfo>,
+        /// The touchscreens, by device id and name
+        pub touch_screens: Vec<(u16, String)>,
+        /// The size of every CRTC's gamma ramps
+        pub gamma_size: u16,
+        /// The names of the root window's properties
+        pub root_properties: HashSet<Vec<u8>>,
+        /// The CRTCs whose transform isn't the identity
+        pub transforms: HashMap<Crtc, Transform>,
+        /// Outputs whose info can't be read
+        pub broken: HashSet<Output>,
+        /// A mode that every SetCrtcConfig batch fails to set
+        pub reject_mode: Option<u32>,
+        /// CRTCs whose SetCrtcConfig replies report failure when they're given a mode
+        pub failing_crtcs: HashSet<Crtc>,
+        /// How many SetCrtcConfig batches reply that the configuration changed, and so are
+        /// ignored, before one succeeds
+        pub stale_configs: Cell<u32>,
+        /// How many screen resizes fail with a Match error before one succeeds
+        pub size_mismatches: Cell<u32>,
+        pub calls: RefCell<Vec<Call>>,
+    }
+
+    impl MockBackend {
+        /// Add a mode with id `id` to the screen resources.
+        pub fn mode(mut self, id: u32, w: u16, h: u16) -> Self {
+            self.modes.push(ModeInfo {
+                id,
+                width: w,
+                height: h,
+                dot_clock: 0,
+                hsync_start: 0,
+                hsync_end: 0,
+                htotal: 0,
+                hskew: 0,
+                vsync_start: 0,
+                vsync_end: 0,
+                vtotal: 0,
+                name_len: 0,
+                mode_flags: 0,
+            });
+            self
+        }
+
+        /// Give the mode with id `id` timings that refresh `hz` times a second.
+        pub fn refresh(mut self, id: u32, hz: u32) -> Self {
+            if let Some(mi) = self.modes.iter_mut().find(|mi| mi.id == id) {
+                mi.htotal = mi.width;
+                mi.vtotal = mi.height;
+                mi.dot_clock = u32::from(mi.width) * u32::from(mi.height) * hz;
+            }
+            self
+        }
+
+        /// Add a connected output named `name`, currently driven by `crtc` (0 for none),
+        /// that may use the CRTCs `crtcs` and the modes `modes`.
+        pub fn output(
+            mut self,
+            id: Output,
+            name: &str,
+            crtc: Crtc,
+            crtcs: &[Crtc],
+            modes: &[u32],
+        ) -> Self {
+            self.outputs.insert(
+                id,
+                GetOutputInfoReply {
+                    status: SetConfig::SUCCESS,
+                    sequence: 0,
+                    length: 0,
+                    timestamp: TIMESTAMP,
+                    crtc,
+                    mm_width: 600,
+                    mm_height: 340,
+                    connection: OutConnection::CONNECTED,
+                    subpixel_order: 0u8.into(),
+                    num_preferred: 1,
+                    crtcs: crtcs.to_vec(),
+                    modes: modes.to_vec(),
+                    clones: Vec::new(),
+                    name: name.as_bytes().to_vec(),
+                },
+            );
+            self
+        }
+
+        /// Add a CRTC, showing `mode` (0 for disabled) on `outputs` at `x`,`y`. The mode must
+        /// already have been added.
+        pub fn crtc(mut self, id: Crtc, mode: u32, x: i16, y: i16, outputs: &[Output]) -> Self {
+            let (width, height) = self
+                .modes
+                .iter()
+                .find(|mi| mi.id == mode)
+                .map(|mi| (mi.width, mi.height))
+                .unwrap_or((0, 0));
+            self.crtcs.insert(
+                id,
+                GetCrtcInfoReply {
+                    status: SetConfig::SUCCESS,
+                    sequence: 0,
+                    length: 0,
+                    timestamp: TIMESTAMP,
+                    x,
+                    y,
+                    width,
+                    height,
+                    mode,
+                    rotation: 1,
+                    rotations: 0x3f,
+                    outputs: outputs.to_vec(),
+                    possible: Vec::new(),
+                },
+            );
+            self
+        }
+
+        /// Give an output a backlight that accepts values from `min` to `max`.
+        pub fn backlight(mut self, output: Output, min: i32, max: i32) -> Self {
+            self.backlights.insert(output, (min, max));
+            self
+        }
+
+        /// Give an output a variable refresh rate property.
+        pub fn vrr(mut self, output: Output) -> Self {
+            self.vrr.insert(output);
+            self
+        }
+
+        /// Give an output a scaling mode property.
+        pub fn scaling(mut self, output: Output) -> Self {
+            self.scaling.insert(output);
+            self
+        }
+
+        /// Add a touchscreen input device.
+        pub fn touch_screen(mut self, device: u16, name: &str) -> Self {
+            self.touch_screens.push((device, name.to_string()));
+            self
+        }
+
+        /// Give an output a property.
+        pub fn property(mut self, output: Output, name: &str, info: PropertyInfo) -> Self {
+            self.properties.insert((output, name.to_string()), info);
+            self
+        }
+
+        /// Make outputs clones of each other, which may share a CRTC.
+        pub fn clones(mut self, outputs: &[Output]) -> Self {
+            for out in outputs {
+                if let Some(info) = self.outputs.get_mut(out) {
+                    info.clones = outputs.iter().copied().filter(|o| o != out).collect();
+                }
+            }
+            self
+        }
+
+        /// Fail every read of an output's info.
+        pub fn broken(mut self, output: Output) -> Self {
+            self.broken.insert(output);
+            self
+        }
+
+        /// Connect a monitor, with an EDID, to an output.
+        pub fn monitor(mut self, output: Output, monitor: Monitor) -> Self {
+            self.monitors.insert(output, monitor);
+            self
+        }
+
+        /// Make an output a tile of a tiled monitor.
+        pub fn tile(mut self, output: Output, tile: Tile) -> Self {
+            self.tiles.insert(output, tile);
+            self
+        }
+
+        /// Fail the next `count` screen resizes with a Match error.
+        pub fn size_mismatches(self, count: u32) -> Self {
+            self.size_mismatches.set(count);
+            self
+        }
+
+        /// Reply to the next `count` SetCrtcConfig batches that the configuration changed.
+        pub fn stale_configs(self, count: u32) -> Self {
+            self.stale_configs.set(count);
+            self
+        }
+
+        /// Set the current frame buffer size.
+        pub fn size(mut self, w: u16, h: u16) -> Self {
+            self.size = Some(Mode { w, h });
+            self
+        }
+
+        /// The screen resources, as returned by getScreenResourcesCurrent.
+        pub fn resources(&self) -> GetScreenResourcesCurrentReply {
+            let mut outputs: Vec<_> = self.outputs.keys().cloned().collect();
+            let mut crtcs: Vec<_> = self.crtcs.keys().cloned().collect();
+            outputs.sort_unstable();
+            crtcs.sort_unstable();
+            GetScreenResourcesCurrentReply {
+                sequence: 0,
+                length: 0,
+                timestamp: TIMESTAMP,
+                config_timestamp: TIMESTAMP,
+                crtcs,
+                outputs,
+                modes: self.modes.clone(),
+                names: Vec::new(),
+            }
+        }
+
+        pub fn calls(&self) -> Vec<Call> {
+            self.calls.borrow().clone()
+        }
+    }
+
+    impl Backend for MockBackend {
+        fn screen_resources(&self, _: Window) -> Result<GetScreenResourcesReply> {
+            let res = self.resources();
+            Ok(GetScreenResourcesReply {
+                sequence: 0,
+                length: 0,
+                timestamp: res.timestamp,
+                config_timestamp: res.config_timestamp,
+                crtcs: res.crtcs,
+                outputs: res.outputs,
+                modes: res.modes,
+                names: res.names,
+            })
+        }
+
+        fn screen_resources_current(&self, _: Window) -> Result<GetScreenResourcesCurrentReply> {
+            Ok(self.resources())
+        }
+
+        fn output_info(&self, output: Output, _: Timestamp) -> Result<GetOutputInfoReply> {
+            if self.broken.contains(&output) {
+                return Err(miette::miette!("Output {} is broken", output));
+            }
+            self.outputs
+                .get(&output)
+                .cloned()
+                .ok_or_else(|| miette::miette!("No output {}", output))
+        }
+
+        fn crtc_info(&self, crtc: Crtc, _: Timestamp) -> Result<GetCrtcInfoReply> {
+            self.crtcs
+                .get(&crtc)
+                .cloned()
+                .ok_or_else(|| miette::miette!("No crtc {}", crtc))
+        }
+
+        fn screen_timestamps(&self, _: Window) -> Result<(Timestamp, Timestamp)> {
+            // Like the replies to set requests, any change moves the time of the last change
+            if self.calls.borrow().is_empty() {
+                Ok((TIMESTAMP, TIMESTAMP))
+            } else {
+                Ok((TIMESTAMP + 1, TIMESTAMP))
+            }
+        }
+
+        fn screen_size(&self, _: Window) -> Result<Mode> {
+            Ok(self.size.clone().unwrap_or(Mode { w: 0, h: 0 }))
+        }
+
+        fn screen_mm(&self, _: Window) -> Result<Mode> {
+            Ok(self.mm.clone().unwrap_or(Mode { w: 0, h: 0 }))
+        }
+
+        fn output_primary(&self, _: Window) -> Result<Output> {
+            Ok(self.primary)
+        }
+
+        fn set_crtc_configs(
+            &self,
+            batch: Vec<SetCrtcConfigRequest<'_>>,
+        ) -> Result<Vec<SetCrtcConfigReply>> {
+            if let Some(mode) = self.reject_mode {
+                if batch.iter().any(|req| req.mode == mode) {
+                    return Err(miette::miette!("Mode {} rejected", mode));
+                }
+            }
+            if self.stale_configs.get() > 0 {
+                self.stale_configs.set(self.stale_configs.get() - 1);
+                let stale = |_| SetCrtcConfigReply {
+                    status: SetConfig::INVALID_CONFIG_TIME,
+                    sequence: 0,
+                    length: 0,
+                    timestamp: TIMESTAMP,
+                };
+                return Ok(batch.iter().map(stale).collect());
+            }
+            let mut calls = self.calls.borrow_mut();
+            Ok(batch
+                .into_iter()
+                .map(|req| {
+                    let status = if req.mode != 0 && self.failing_crtcs.contains(&req.crtc) {
+                        SetConfig::FAILED
+                    } else {
+                        SetConfig::SUCCESS
+                    };
+                    calls.push(Call::CrtcConfig(SetCrtcConfigRequest {
+                        crtc: req.crtc,
+                        timestamp: req.timestamp,
+                        config_timestamp: req.config_timestamp,
+                        x: req.x,
+                        y: req.y,
+                        mode: req.mode,
+                        rotation: req.rotation,
+                        outputs: Cow::Owned(req.outputs.into_owned()),
+                    }));
+                    SetCrtcConfigReply {
+                        status,
+                        sequence: 0,
+                        length: 0,
+                        timestamp: TIMESTAMP + 1,
+                    }
+                })
+                .collect())
+        }
+
+        fn set_pannings(&self, batch: Vec<SetPanningRequest>) -> Result<Vec<SetPanningReply>> {
+            let mut calls = self.calls.borrow_mut();
+            Ok(batch
+                .into_iter()
+                .map(|req| {
+                    calls.push(Call::Panning(req));
+                    SetPanningReply {
+                        status: SetConfig::SUCCESS,
+                        sequence: 0,
+                        length: 0,
+                        timestamp: TIMESTAMP + 1,
+                    }
+                })
+                .collect())
+        }
+
+        fn set_screen_size(&self, _: Window, w: u16, h: u16, mm_w: u32, mm_h: u32) -> Result<()> {
+            self.calls
+                .borrow_mut()
+                .push(Call::ScreenSize(w, h, mm_w, mm_h));
+            match self.size_mismatches.get() {
+                0 => Ok(()),
+                left => {
+                    self.size_mismatches.set(left - 1);
+                    Err(ScreenSizeMismatch(Mode { w, h }).into())
+                }
+            }
+        }
+
+        fn set_output_primary(&self, _: Window, output: Output) -> Result<()> {
+            self.calls.borrow_mut().push(Call::OutputPrimary(output));
+            Ok(())
+        }
+
+        fn create_mode(&self, _: Window, mode: ModeInfo, name: &[u8]) -> Result<u32> {
+            let mut calls = self.calls.borrow_mut();
+            let created = calls
+                .iter()
+                .filter(|c| matches!(c, Call::CreateMode(..)))
+                .count();
+            calls.push(Call::CreateMode(mode, name.to_vec()));
+            Ok(FIRST_CREATED_MODE + created as u32)
+        }
+
+        fn add_output_mode(&self, output: Output, mode: u32) -> Result<()> {
+            self.calls.borrow_mut().push(Call::AddOutputMode(output, mode));
+            Ok(())
+        }
+
+        fn delete_output_mode(&self, output: Output, mode: u32) -> Result<()> {
+            self.calls.borrow_mut().push(Call::DeleteOutputMode(output, mode));
+            Ok(())
+        }
+
+        fn destroy_mode(&self, mode: u32) -> Result<()> {
+            self.calls.borrow_mut().push(Call::DestroyMode(mode));
+            Ok(())
+        }
+
+        fn backlight_range(&self, output: Output) -> Result<Option<(i32, i32)>> {
+            Ok(self.backlights.get(&output).cloned())
+        }
+
+        fn set_backlight(&self, output: Output, value: i32) -> Result<()> {
+            self.calls.borrow_mut().push(Call::Backlight(output, value));
+            Ok(())
+        }
+
+        fn has_vrr(&self, output: Output) -> Result<bool> {
+            Ok(self.vrr.contains(&output))
+        }
+
+        fn set_vrr(&self, output: Output, enabled: bool) -> Result<()> {
+            self.calls.borrow_mut().push(Call::Vrr(output, enabled));
+            Ok(())
+        }
+
+        fn has_scaling_mode(&self, output: Output) -> Result<bool> {
+            Ok(self.scaling.contains(&output))
+        }
+
+        fn set_scaling_mode(&self, output: Output, mode: &str) -> Result<()> {
+            let call = Call::ScalingMode(output, mode.to_string());
+            self.calls.borrow_mut().push(call);
+            Ok(())
+        }
+
+        fn property_info(&self, output: Output, name: &str) -> Result<Option<PropertyInfo>> {
+            Ok(self.properties.get(&(output, name.to_string())).cloned())
+        }
+
+        fn set_property(&self, output: Output, name: &str, data: &PropertyData) -> Result<()> {
+            let call = Call::Property(output, name.to_string(), data.clone());
+            self.calls.borrow_mut().push(call);
+            Ok(())
+        }
+
+        fn tile(&self, output: Output) -> Result<Option<Tile>> {
+            Ok(self.tiles.get(&output).copied())
+        }
+
+        fn monitor(&self, output: Output) -> Result<Option<Monitor>> {
+            Ok(self.monitors.get(&output).cloned())
+        }
+
+        fn crtc_transform(&self, crtc: Crtc) -> Result<Transform> {
+            Ok(self.transforms.get(&crtc).copied().unwrap_or(IDENTITY_TRANSFORM))
+        }
+
+        fn set_crtc_transform(
+            &self,
+            crtc: Crtc,
+            transform: Transform,
+            filter: &[u8],
+        ) -> Result<()> {
+            let call = Call::CrtcTransform(crtc, transform, filter.to_vec());
+            self.calls.borrow_mut().push(call);
+            Ok(())
+        }
+
+        fn touch_screens(&self) -> Result<Vec<(u16, String)>> {
+            Ok(self.touch_screens.clone())
+        }
+
+        fn set_touch_matrix(&self, device: u16, matrix: [f32; 9]) -> Result<()> {
+            self.calls.borrow_mut().push(Call::TouchMatrix(device, matrix));
+            Ok(())
+        }
+
+        fn gamma_size(&self, _: Crtc) -> Result<u16> {
+            Ok(self.gamma_size)
+        }
+
+        fn set_gamma(&self, crtc: Crtc, ramps: &[Vec<u16>; 3]) -> Result<()> {
+            self.calls.borrow_mut().push(Call::Gamma(crtc, ramps.clone()));
+            Ok(())
+        }
+
+        fn has_root_property(&self, _: Window, name: &[u8]) -> Result<bool> {
+            Ok(self.root_properties.contains(name))
+        }
+
+        fn set_root_property(&self, _: Window, name: &[u8], value: Option<&[u8]>) -> Result<()> {
+            let call = Call::RootProperty(name.to_vec(), value.map(<[u8]>::to_vec));
+            self.calls.borrow_mut().push(call);
+            Ok(())
+        }
+
+        fn set_root_text(&self, root: Window, name: &[u8], text: &str) -> Result<()> {
+            self.set_root_property(root, name, Some(text.as_bytes()))
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Read the color characteristics of a monitor from the base block of its EDID: its gamma, the
+//! chromaticity of its primaries and white point, and its color depth.
+
+/// The offset of the video input definition, which for a digital input holds the color depth
+const VIDEO_INPUT: usize = 20;
+
+/// The offset of the display transfer characteristic, the gamma
+const GAMMA: usize = 23;
+
+/// The offset of the chromaticity coordinates: two bytes of low bits, then the high 8 bits of
+/// red, green, blue and white x and y
+const CHROMATICITY: usize = 25;
+
+/// The color characteristics declared by an EDID
+#[derive(Debug, Clone, PartialEq)]
+pub struct Colorimetry {
+    /// The gamma, which is None when it's defined in an extension block
+    pub gamma: Option<f64>,
+    /// The CIE 1931 x and y coordinates of the red, green and blue primaries and the white point
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white: (f64, f64),
+    /// The bits of each primary color, which only EDID 1.4 declares for digital inputs
+    pub bit_depth: Option<u8>,
+}
+
+/// Read the color characteristics from the base block of `edid`. Returns None when the block is
+/// too short to hold them.
+pub fn colorimetry(edid: &[u8]) -> Option<Colorimetry> {
+    let block = edid.get(..CHROMATICITY + 10)?;
+    let gamma = match block[GAMMA] {
+        0xff => None,
+        value => Some((f64::from(value) + 100.0) / 100.0),
+    };
+    // Each coordinate is a 10 bit fraction, with its 2 low bits packed into the first two bytes
+    let low = u16::from_be_bytes([block[CHROMATICITY], block[CHROMATICITY + 1]]);
+    let coordinate = |i: usize| {
+        let low_bits = (low >> (14 - 2 * i)) & 0b11;
+        let high_bits = u16::from(block[CHROMATICITY + 2 + i]) << 2;
+        f64::from(high_bits | low_bits) / 1024.0
+    };
+    let point = |i: usize| (coordinate(2 * i), coordinate(2 * i + 1));
+    let (version, revision) = (block[18], block[19]);
+    let input = block[VIDEO_INPUT];
+    let digital = input & 0x80 != 0;
+    let bit_depth = match (input >> 4) & 0b111 {
+        depth @ 1..=6 if digital && (version, revision) >= (1, 4) => Some(4 + 2 * depth),
+        _ => None,
+    };
+    Some(Colorimetry {
+        gamma,
+        red: point(0),
+        green: point(1),
+        blue: point(2),
+        white: point(3),
+        bit_depth,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_gamma_chromaticity_and_depth() {
+        let mut edid = vec![0; 128];
+        edid[18..20].copy_from_slice(&[1, 4]);
+        // A digital input of 10 bits per color, and a gamma of 2.2
+        edid[VIDEO_INPUT] = 0xb5;
+        edid[GAMMA] = 120;
+        // The sRGB primaries and D65 white point
+        edid[CHROMATICITY..CHROMATICITY + 10]
+            .copy_from_slice(&[0xee, 0x91, 0xa3, 0x54, 0x4c, 0x99, 0x26, 0x0f, 0x50, 0x54]);
+        let colors = colorimetry(&edid).unwrap();
+        assert_eq!(colors.gamma, Some(2.2));
+        assert_eq!(colors.bit_depth, Some(10));
+        let rounded = |(x, y): (f64, f64)| ((x * 1000.0).round(), (y * 1000.0).round());
+        assert_eq!(rounded(colors.red), (640.0, 330.0));
+        assert_eq!(rounded(colors.green), (300.0, 600.0));
+        assert_eq!(rounded(colors.blue), (150.0, 60.0));
+        assert_eq!(rounded(colors.white), (313.0, 329.0));
+        // EDID 1.3 doesn't declare a color depth, and a gamma of 0xff is in an extension
+        edid[19] = 3;
+        edid[GAMMA] = 0xff;
+        let colors = colorimetry(&edid).unwrap();
+        assert_eq!((colors.gamma, colors.bit_depth), (None, None));
+        assert_eq!(colorimetry(&edid[..30]), None);
+    }
+}
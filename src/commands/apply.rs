@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use clap::{value_t, ArgMatches};
+use miette::{IntoDiagnostic, Result};
+use tracing::{error, info, warn};
+use x11rb::{
+    connection::Connection,
+    protocol::randr::Output,
+    protocol::xproto::{ConnectionExt as _, GrabMode, GrabStatus, Window},
+    protocol::Event,
+    rust_connection::RustConnection,
+    CURRENT_TIME,
+};
+
+use super::daemon::{
+    apply_config, group_tiles, identify_outputs, layout_outputs, load_config, monitor_execs,
+    run_hook, tile_setup, wait_readable, ApplyError, Error, Snapshot,
+};
+use crate::config::{pair_monitors, Config, Monitor, SingleConfig};
+use crate::xresources::set_resource;
+use crate::{edid_atom, get_connected_outputs, get_monitors, get_outputs, randr_version};
+
+/// How long `--confirm` waits for a key press without `--timeout`, in seconds
+const CONFIRM_TIMEOUT: u64 = 15;
+
+/// Wait up to `timeout` for a key to be pressed on the screen at `root`, or for Enter on stdin.
+/// The keyboard is grabbed meanwhile, so that a key pressed in any window confirms the layout,
+/// which may have left the terminal off screen.
+fn confirmed(conn: &RustConnection, root: Window, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    let grab = conn
+        .grab_keyboard(false, root, CURRENT_TIME, GrabMode::ASYNC, GrabMode::ASYNC)
+        .into_diagnostic()?
+        .reply()
+        .into_diagnostic()?;
+    if grab.status != GrabStatus::SUCCESS {
+        warn!("Could not grab the keyboard, so only Enter on stdin keeps the layout");
+    }
+    let pressed = wait_for_key(conn, deadline);
+    conn.ungrab_keyboard(CURRENT_TIME).into_diagnostic()?;
+    conn.flush().into_diagnostic()?;
+    pressed
+}
+
+/// Wait until `deadline` for a key press on the grabbed keyboard, or for a line on stdin. A stdin
+/// that isn't a terminal, or that's closed, confirms nothing, so then only a key press is waited
+/// for.
+fn wait_for_key(conn: &RustConnection, deadline: Instant) -> Result<bool> {
+    let mut stdin = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    loop {
+        // Events read along with an earlier reply don't leave the connection readable
+        while let Some(event) = conn.poll_for_event().into_diagnostic()? {
+            if let Event::KeyPress(_) = event {
+                return Ok(true);
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        let mut fds = vec![conn.stream().as_raw_fd()];
+        if stdin {
+            fds.push(libc::STDIN_FILENO);
+        }
+        let readable = wait_readable(&fds, Some(deadline - now)).into_diagnostic()?;
+        if readable.get(1) == Some(&true) {
+            // A closed stdin is readable too, at once and forever after
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line).into_diagnostic()? > 0 {
+                return Ok(true);
+            }
+            stdin = false;
+        }
+    }
+}
+
+/// A layout that `apply_layout` applied
+pub(super) struct Applied<'c> {
+    pub(super) layout: &'c SingleConfig,
+    /// Whether applying the layout changed the configuration
+    pub(super) changed: bool,
+    /// The configuration from before the layout was applied, when it was asked for
+    pub(super) snapshot: Option<Snapshot>,
+    /// The commands of the layout's monitors, to run once it's kept
+    pub(super) execs: Vec<String>,
+}
+
+/// Apply a layout to the monitors connected to the screen at `root`. `pick` chooses the layout
+/// from the connected monitors, and pairs them with the layout's monitors. With `snapshot`, the
+/// configuration is saved first, so that it can be restored.
+pub(super) fn apply_layout<'c>(
+    conn: &RustConnection,
+    root: Window,
+    config: &'c Config,
+    pick: impl FnOnce(
+        &HashMap<Output, Monitor>,
+    ) -> std::result::Result<(&'c SingleConfig, HashMap<Output, Monitor>), ApplyError>,
+    snapshot: bool,
+) -> Result<Applied<'c>> {
+    let atom_edid = edid_atom(conn)?;
+    let res = get_outputs(conn, root)?;
+    let outputs = get_connected_outputs(conn, &res)?;
+    let mut connected = get_monitors(conn, &outputs, atom_edid).collect();
+    identify_outputs(config, conn, &outputs, res.config_timestamp, &mut connected);
+    let tiles = group_tiles(conn, &mut connected);
+    let (layout, out_to_mon) = pick(&connected).into_diagnostic()?;
+    let setup = layout_outputs(conn, &layout.setup, out_to_mon, res.config_timestamp);
+    let tiled = tile_setup(&setup, &tiles);
+    let setup = tiled.iter().map(|(&out, conf)| (out, conf)).collect();
+    let execs = monitor_execs(conn, &setup, res.config_timestamp);
+    let snapshot = if snapshot {
+        Some(Snapshot::take(conn, &res, root)?)
+    } else {
+        None
+    };
+    let size = layout.into();
+    let changed =
+        apply_config(conn, &res, size, setup, root, &config.settings, None).into_diagnostic()?;
+    conn.flush().into_diagnostic()?;
+    Ok(Applied {
+        layout,
+        changed,
+        snapshot,
+        execs,
+    })
+}
+
+/// Set the Xft.dpi resource to the DPI of `layout`, when it has one.
+pub(super) fn set_dpi(conn: &RustConnection, root: Window, layout: &SingleConfig) -> Result<()> {
+    if let Some(dpi) = layout.dpi {
+        info!("Setting Xft.dpi to {}", dpi);
+        set_resource(conn, root, "Xft.dpi", &dpi.to_string())?;
+    }
+    conn.flush().into_diagnostic()
+}
+
+/// Apply the layout called `name`, and print its name. With `--confirm`, the previous
+/// configuration is restored unless a key is pressed before the timeout.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let config = load_config(args)?;
+    // Unwrap below is safe, because the program exits from `get_matches` when a name is not
+    // provided.
+    let name = args.value_of("name").unwrap();
+    let timeout = match args.value_of("timeout") {
+        Some(_) => value_t!(args, "timeout", u64).unwrap_or_else(|e| e.exit()),
+        None => CONFIRM_TIMEOUT,
+    };
+    let (wanted, layout) = config
+        .named_layout(name)
+        .ok_or_else(|| Error::UnknownLayout(name.to_string()))
+        .into_diagnostic()?;
+    let (conn, screen_num) = RustConnection::connect(None).into_diagnostic()?;
+    randr_version(&conn)?;
+    // A layout for another screen is applied to that screen, rather than to the one of DISPLAY
+    let number = layout.screen.map_or(screen_num, usize::from);
+    let screen = conn
+        .setup()
+        .roots
+        .get(number)
+        .ok_or(Error::UnknownScreen(number))
+        .into_diagnostic()?;
+    let root = screen.root;
+    let pick = |connected: &_| {
+        let out_to_mon = pair_monitors(wanted, connected)
+            .ok_or_else(|| ApplyError::LayoutUnmatched(name.to_string()))?;
+        Ok((layout, out_to_mon))
+    };
+    let Applied {
+        changed,
+        snapshot,
+        execs,
+        ..
+    } = apply_layout(&conn, root, &config, pick, args.is_present("confirm"))?;
+    if let Some(snapshot) = snapshot.filter(|_| changed) {
+        eprintln!("Press a key within {} seconds to keep layout {}", timeout, name);
+        // Whatever keeps the key press from being seen, the layout isn't kept without it
+        let kept = confirmed(&conn, root, Duration::from_secs(timeout)).unwrap_or_else(|e| {
+            error!("Could not wait for a key press: {:?}", e);
+            false
+        });
+        if !kept {
+            warn!("Layout {} was not confirmed, restoring the previous configuration", name);
+            snapshot.restore(&conn, root)?;
+            conn.flush().into_diagnostic()?;
+            return Err(Error::NotConfirmed(name.to_string())).into_diagnostic();
+        }
+    }
+    set_dpi(&conn, root, layout)?;
+    for command in execs.iter() {
+        info!("Running {}", command);
+        run_hook(command, &[]);
+    }
+    println!("{}", layout.name);
+    Ok(())
+}
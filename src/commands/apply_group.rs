@@ -0,0 +1,31 @@
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+use x11rb::{connection::Connection, rust_connection::RustConnection};
+
+use super::apply::{apply_layout, set_dpi, Applied};
+use super::daemon::{load_config, ApplyError, Error};
+use crate::randr_version;
+
+/// Apply the layout of a group that matches the connected monitors, and print its name.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let config = load_config(args)?;
+    // Unwrap below is safe, because the program exits from `get_matches` when a group is not
+    // provided.
+    let group = args.value_of("group").unwrap();
+    if !config.groups.contains_key(group) {
+        return Err(Error::UnknownGroup(group.to_string())).into_diagnostic();
+    }
+    let (conn, screen_num) = RustConnection::connect(None).into_diagnostic()?;
+    randr_version(&conn)?;
+    let root = conn.setup().roots[screen_num].root;
+    let config = config.for_screen(screen_num);
+    let pick = |connected: &_| {
+        config
+            .find_group_layout(group, connected)
+            .ok_or_else(|| ApplyError::NoMatchingLayout(connected.len()))
+    };
+    let Applied { layout, .. } = apply_layout(&conn, root, &config, pick, false)?;
+    set_dpi(&conn, root, layout)?;
+    println!("{}", layout.name);
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use clap::ArgMatches;
+use miette::Result;
+use tracing::warn;
+
+use super::daemon::load_config;
+
+/// Check the configuration, and warn about each layout whose monitors don't form one connected
+/// desktop, as the cursor can't reach a monitor that touches none of the others.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let config = load_config(args)?;
+    let mut layouts: Vec<_> = config
+        .layouts
+        .values()
+        .chain(config.groups.values().flat_map(|g| g.values()))
+        .chain(config.lid_layouts.values().flat_map(|l| l.values()))
+        .collect();
+    layouts.sort_by(|a, b| a.name.cmp(&b.name));
+    for layout in layouts {
+        let islands = layout.islands();
+        for island in islands.iter().skip(1) {
+            warn!(
+                "In layout {}, the cursor can't reach {} from {}",
+                layout.name,
+                island.join(" and "),
+                islands[0].join(", ")
+            );
+        }
+    }
+    Ok(())
+}
@@ -1,72 +1,652 @@
-use tracing::{error, info};
+use tracing::{debug, error, info, trace, warn};
 use x11rb::{
-    connect,
     connection::Connection,
-    cookie::Cookie,
     protocol::randr::{
         ConnectionExt as RandrExt, Crtc, GetCrtcInfoReply, GetOutputInfoReply,
-        GetScreenResourcesCurrentReply, NotifyMask, Output, SetConfig, SetCrtcConfigReply,
-        SetCrtcConfigRequest, SetPanningReply, SetPanningRequest, Rotation as Rot,
+        GetScreenResourcesCurrentReply, ModeFlag, ModeInfo, NotifyMask, Output, SetConfig,
+        SetCrtcConfigRequest, SetPanningRequest, Rotation as Rot,
     },
-    protocol::xproto::{Atom, ConnectionExt as XprotoExt, Timestamp, Window},
+    protocol::render::Transform,
+    protocol::xproto::{Atom, Timestamp, Window},
     protocol::Event,
+    rust_connection::RustConnection,
+    CURRENT_TIME,
 };
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{self, Write};
+#[cfg(feature = "dbus")]
+use std::iter;
+use std::os::unix::{
+    io::{AsRawFd, RawFd},
+    net::UnixStream,
+};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
-use clap::ArgMatches;
-use miette::{IntoDiagnostic, Result};
+use clap::{value_t, ArgMatches};
+use miette::{Diagnostic, IntoDiagnostic, Result};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    low_level::pipe,
+};
 use thiserror::Error;
 
-use crate::config::{Config, Mode, MonConfig, Position, SingleConfig, Rotation};
-use crate::{edid_atom, get_monitors, get_outputs};
+use crate::backend::{
+    note_screen_mm, Backend, PropertyData, PropertyInfo, ScreenSizeMismatch, Tile,
+    IDENTITY_TRANSFORM,
+};
+use crate::config::{
+    pair_monitors, Config, MatchRule, Mode, ModeLine, MonConfig, Monitor, Position,
+    PropertyValue, Reflection, Rotation, Settings, SingleConfig, SAFE_LAYOUT,
+};
+use crate::daemonize::{daemonize, running_pid, stop, PidFile};
+#[cfg(feature = "dbus")]
+use crate::dbus::{DbusService, Request};
+use crate::event_socket::EventSocket;
+use crate::icc;
+use crate::lid::lid_state;
+use crate::selector::select;
+use crate::xresources::set_resource;
+use crate::{
+    edid_atom, get_connected_outputs, get_monitors, get_output_name, get_outputs, randr_version,
+    ConnectedOutputs, TILE_RANDR_VERSION,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
+    #[error("No layout named {0}")]
+    UnknownLayout(String),
+    #[error("No layouts are in group {0}")]
+    UnknownGroup(String),
+    #[error("The connected outputs have no mode in common")]
+    NoCommonMode,
+    #[error("Layout {0} was not confirmed, so the previous configuration was restored")]
+    NotConfirmed(String),
+    #[error("No connected output is named {0}")]
+    UnknownOutput(String),
+    #[error("The poll interval must be at least one second")]
+    ZeroPollInterval,
+    #[error("The monitors file is not a list of monitors")]
+    BadMonitors,
+    #[error("The connected outputs are too wide to place side by side")]
+    TooWide,
+    #[error("Another daemon is running with PID {0}; pass --replace to take over from it")]
+    AlreadyRunning(i32),
+    #[error("The daemon running with PID {0} did not exit")]
+    DidNotExit(i32),
+    #[error("The display has no screen {0}")]
+    UnknownScreen(usize),
+    #[error("No connected output has a mode to show the safe layout in")]
+    NoSafeOutput,
+    #[error("Line {0} of the autorandr config can't be read: {1}")]
+    BadAutorandrConfig(usize, String),
+}
+
+/// The reasons that a layout can't be found or applied.
+#[derive(Error, Debug)]
+pub enum ApplyError {
+    #[error("No layout matches the {0} connected monitors")]
+    NoMatchingLayout(usize),
+    #[error("No layout named {0}")]
+    UnknownLayout(String),
+    #[error("Layout {0} does not match the connected monitors")]
+    LayoutUnmatched(String),
+    #[error("No CRTC available for output {0}")]
+    NoCrtcAvailable(Output),
     #[error("Mode {0} not found")]
     ModeNotFound(Mode),
-    #[error("Mode {0} not supported")]
-    ModeNotSupported(Mode),
-    #[error("No Crtc available for monitor {0}")]
-    NoCrtc(String),
+    #[error("Output {0} does not support mode {1}")]
+    ModeUnsupported(Output, Mode),
+    #[error("Output {0} can't use CRTC {1}")]
+    CrtcUnsupported(Output, u8),
+    #[error("Another client changed the output configuration while it was applied")]
+    ConfigChanged,
+    #[error("Configuring CRTC {0} failed{}", status_reason(*.1))]
+    CrtcConfigFailed(Crtc, SetConfig),
+    #[error("Reading the output configuration failed: {0}")]
+    ReadFailed(miette::Report),
+    #[error("Creating the custom mode of output {0} failed: {1}")]
+    CustomModeFailed(Output, miette::Report),
+    #[error("Sending the output configuration failed: {0}")]
+    SendFailed(miette::Report),
+    #[error("Resizing the screen to {0} failed: {1}")]
+    ResizeFailed(Mode, miette::Report),
+    #[error("Making output {0} primary failed: {1}")]
+    PrimaryFailed(Output, miette::Report),
+    #[error("Configuring the other settings of the outputs failed: {0}")]
+    OutputSettingsFailed(miette::Report),
+}
+
+impl Diagnostic for ApplyError {}
+
+/// Describe why a request failed with `status`, to follow "failed".
+fn status_reason(status: SetConfig) -> &'static str {
+    match status {
+        SetConfig::INVALID_CONFIG_TIME => " with invalid config time",
+        SetConfig::INVALID_TIME => " with invalid time",
+        _ => "",
+    }
+}
+
+/// A layout that matches the connected monitors.
+struct Matched<'a> {
+    name: &'a String,
+    size: ScreenSize<'a>,
+    /// The monitor config of each output
+    setup: HashMap<Output, &'a MonConfig>,
+    /// The serial numbers of the connected monitors
+    serials: Vec<String>,
+    dpi: Option<u32>,
+    tiles: Tiles,
+}
+
+/// The CRTC that last drove each output.
+type CrtcMemory = HashMap<Output, Crtc>;
+
+/// The custom modes that the daemon created, by id, with the timings they were created with.
+/// Only these are ever destroyed, never the modes of the driver.
+type CreatedModes = HashMap<u32, ModeInfo>;
+
+/// The state the daemon keeps for each X screen of the display, which it lays out on its own.
+struct Screen {
+    /// The number of the screen, as in ":0.1"
+    number: usize,
+    root: Window,
+    /// The configuration, without the layouts of other screens
+    config: Config,
+    /// Present when outputs should reuse the CRTC that last drove them
+    crtc_memory: Option<CrtcMemory>,
+    created_modes: CreatedModes,
+    /// The connected outputs when a layout was last tried, whether or not it applied
+    last_outputs: Option<ConnectedOutputs>,
+}
+
+impl Screen {
+    /// The screens of the display that `conn` is connected to, each with the layouts of
+    /// `config` that may be applied to it.
+    fn all(conn: &RustConnection, config: &Config, remember_crtcs: bool) -> Vec<Self> {
+        let roots = conn.setup().roots.iter().map(|screen| screen.root);
+        roots
+            .enumerate()
+            .map(|(number, root)| Screen {
+                number,
+                root,
+                config: config.for_screen(number),
+                crtc_memory: if remember_crtcs {
+                    Some(CrtcMemory::new())
+                } else {
+                    None
+                },
+                created_modes: CreatedModes::new(),
+                last_outputs: None,
+            })
+            .collect()
+    }
+}
+
+/// The settings and state of the daemon that last across layout changes, along with its
+/// connection to the X server.
+pub struct DaemonState<'a> {
+    status_file: Option<&'a Path>,
+    json: bool,
+    /// Whether the whole configuration is restored when a layout fails to apply
+    keep_previous: bool,
+    /// Whether the safe layout is applied in place of the layouts that match
+    safe: bool,
+    events: Option<EventSocket>,
+    #[cfg(feature = "dbus")]
+    dbus: Option<DbusService>,
+    /// A script that picks the layout to apply, in place of matching
+    selector: Option<&'a Path>,
+    /// A shell command to run each time the screen changes size
+    post_resize_exec: Option<&'a str>,
+    conn: RustConnection,
+    atom_edid: Atom,
+    screens: Vec<Screen>,
+}
+
+impl DaemonState<'static> {
+    /// Connect to the X server, to apply the layouts of `config` to each of its screens as the
+    /// monitors change, without any of the daemon's optional features.
+    pub fn new(config: Config) -> Result<Self> {
+        let (conn, atom_edid) = connect_notified()?;
+        let screens = Screen::all(&conn, &config, false);
+        Ok(DaemonState {
+            status_file: None,
+            json: false,
+            keep_previous: false,
+            safe: false,
+            events: None,
+            #[cfg(feature = "dbus")]
+            dbus: None,
+            selector: None,
+            post_resize_exec: None,
+            conn,
+            atom_edid,
+            screens,
+        })
+    }
+}
+
+impl DaemonState<'_> {
+    /// Apply the layout that matches the connected monitors of every screen, as the daemon does
+    /// when it starts.
+    pub fn apply_matching(&mut self) -> Result<()> {
+        for screen in 0..self.screens.len() {
+            switch_setup(self, screen, true, None);
+        }
+        self.conn.flush().into_diagnostic()
+    }
+}
+
+impl AsRawFd for DaemonState<'_> {
+    /// The X connection, which is readable when [`poll_and_handle`] has events to process.
+    fn as_raw_fd(&self) -> RawFd {
+        self.conn.stream().as_raw_fd()
+    }
+}
+
+/// Process the RandR events that have arrived, applying a layout when the monitors changed,
+/// and return without waiting for more. This lets the daemon run within another event loop,
+/// which calls it whenever the state's file descriptor is readable.
+pub fn poll_and_handle(state: &mut DaemonState) -> Result<()> {
+    while let Some(event) = state.conn.poll_for_event().into_diagnostic()? {
+        if let Event::RandrScreenChangeNotify(change) = event {
+            let mm = Mode {
+                w: change.mwidth,
+                h: change.mheight,
+            };
+            note_screen_mm(change.root, mm);
+            if let Some(screen) = state.screens.iter().position(|s| s.root == change.root) {
+                switch_setup(state, screen, false, None)
+            }
+        }
+    }
+    state.conn.flush().into_diagnostic()
+}
+
+/// Wait until the X connection is readable, without blocking the executor, then process the
+/// RandR events that have arrived as [`poll_and_handle`] does. Only the wait is asynchronous:
+/// applying a layout blocks the executor's thread until the X server has replied, so an
+/// executor that must stay responsive should run this on a thread of its own.
+#[cfg(feature = "async")]
+pub async fn handle_when_readable(state: &mut DaemonState<'_>) -> Result<()> {
+    use std::os::unix::io::BorrowedFd;
+    // Events already read from the connection, such as while applying the first layout, would
+    // not make it readable
+    poll_and_handle(state)?;
+    // Safe, as the connection outlives the borrow, which ends before the state is used again
+    let fd = unsafe { BorrowedFd::borrow_raw(state.as_raw_fd()) };
+    // The connection's socket is already non-blocking
+    let readable = async_io::Async::new_nonblocking(fd).into_diagnostic()?;
+    readable.readable().await.into_diagnostic()?;
+    drop(readable);
+    poll_and_handle(state)
 }
 
-/// Find the config that matches the attached monitors. On a match, this returns a tuple of
-/// (name, frame buffer size, map from output to output config).
+/// Find the config that matches the attached monitors, or the layout called `named` when the
+/// attached monitors match it. Without `named`, a layout picked by the `selector` script is
+/// tried before matching.
 fn get_config<'a, C: Connection>(
     config: &'a Config,
     conn: &'a C,
     outputs: &'a Vec<Output>,
     atom_edid: Atom,
-) -> Option<(&'a String, &'a Mode, HashMap<Output, &'a MonConfig>)> {
-    let out_to_mon: HashMap<_, _> = get_monitors(conn, outputs, atom_edid).collect();
-    let mut monitors: Vec<_> = out_to_mon.values().cloned().collect();
-    monitors.sort();
-    let SingleConfig {
+    timestamp: Timestamp,
+    named: Option<&str>,
+    selector: Option<&Path>,
+) -> std::result::Result<Matched<'a>, ApplyError> {
+    let mut connected: HashMap<_, _> = get_monitors(conn, outputs, atom_edid).collect();
+    identify_outputs(config, conn, outputs, timestamp, &mut connected);
+    let tiles = group_tiles(conn, &mut connected);
+    let mut serials: Vec<_> = connected.values().filter_map(|m| m.serial.clone()).collect();
+    serials.sort();
+    for (output, mon) in connected.iter() {
+        debug!("Output {} has monitor {:?}", output, mon);
+    }
+    let (found, reason) = match named {
+        Some(named) => {
+            let (wanted, layout) = config
+                .named_layout(named)
+                .ok_or_else(|| ApplyError::UnknownLayout(named.to_string()))?;
+            let out_to_mon = pair_monitors(wanted, &connected)
+                .ok_or_else(|| ApplyError::LayoutUnmatched(named.to_string()))?;
+            ((layout, out_to_mon), "as it was asked for by name".to_string())
+        }
+        None => {
+            let selected = selector
+                .and_then(|path| select_layout(conn, path, &connected, timestamp))
+                .and_then(|name| selected_layout(config, &name, &connected));
+            // The lid is only read when a layout depends on it
+            let lid = if config.lid_layouts.is_empty() { None } else { lid_state() };
+            let for_lid = lid.and_then(|lid| Some((config.find_lid_layout(lid, &connected)?, lid)));
+            match (selected, for_lid) {
+                (Some(found), _) => (found, "as the selector picked it".to_string()),
+                (None, Some((found, lid))) => {
+                    let rule = MatchRule::of(found.1.values());
+                    let reason = format!(
+                        "by the {} rule, with priority {}, while the lid is {}",
+                        rule.name(),
+                        found.0.priority,
+                        lid.name()
+                    );
+                    (found, reason)
+                }
+                (None, None) => {
+                    let found = config
+                        .find_layout(&connected)
+                        .ok_or_else(|| ApplyError::NoMatchingLayout(connected.len()))?;
+                    let rule = MatchRule::of(found.1.values());
+                    let reason =
+                        format!("by the {} rule, with priority {}", rule.name(), found.0.priority);
+                    (found, reason)
+                }
+            }
+        }
+    };
+    let (
+        layout @ SingleConfig {
+            name,
+            setup,
+            dpi,
+            ..
+        },
+        out_to_mon,
+    ) = found;
+    info!("Layout {} matches the connected monitors {}", name, reason);
+    Ok(Matched {
         name,
-        setup,
-        fb_size,
-    } = config.0.get(&monitors)?;
-    let mut out = HashMap::with_capacity(setup.len());
+        size: layout.into(),
+        setup: layout_outputs(conn, setup, out_to_mon, timestamp),
+        serials,
+        dpi: *dpi,
+        tiles,
+    })
+}
+
+/// Run the `selector` script on the connected monitors, returning the name of the layout it
+/// picks.
+fn select_layout<C: Connection>(
+    conn: &C,
+    selector: &Path,
+    connected: &HashMap<Output, Monitor>,
+    timestamp: Timestamp,
+) -> Option<String> {
+    let mut monitors = Vec::with_capacity(connected.len());
+    for (&output, mon) in connected.iter() {
+        match get_output_name(conn, output, timestamp) {
+            Ok(name) => monitors.push((name, mon)),
+            Err(e) => error!("{:?}", e),
+        }
+    }
+    monitors.sort_by(|a, b| a.0.cmp(&b.0));
+    match select(selector, &monitors) {
+        Ok(name) => name,
+        Err(e) => {
+            error!("Could not run layout selector {}: {}", selector.display(), e);
+            None
+        }
+    }
+}
+
+/// The layout called `name`, when it exists and matches the connected monitors, paired with
+/// the monitor on each output.
+fn selected_layout<'a>(
+    config: &'a Config,
+    name: &str,
+    connected: &HashMap<Output, Monitor>,
+) -> Option<(&'a SingleConfig, HashMap<Output, Monitor>)> {
+    let found = config
+        .named_layout(name)
+        .and_then(|(wanted, layout)| Some((layout, pair_monitors(wanted, connected)?)));
+    if found.is_none() {
+        warn!("The selected layout {} does not match the connected monitors", name);
+    }
+    found
+}
+
+/// Fill in the details of the connected monitors that come from the names of their outputs:
+/// the connector type of each monitor, and the monitor on each output named by a monitor's
+/// `force-output`, which replaces whatever monitor its EDID describes.
+pub(crate) fn identify_outputs<B: Backend>(
+    config: &Config,
+    conn: &B,
+    outputs: &[Output],
+    timestamp: Timestamp,
+    connected: &mut HashMap<Output, Monitor>,
+) {
+    let forced: HashMap<&str, &Monitor> = config
+        .monitors
+        .values()
+        .filter_map(|mon| mon.force_output.as_deref().map(|name| (name, mon)))
+        .collect();
+    // Output names cost a round trip each, so they're only read when they're used
+    if forced.is_empty() && config.monitors.values().all(|m| m.connector.is_none()) {
+        return;
+    }
+    for &output in outputs {
+        let name = match conn.output_info(output, timestamp) {
+            Ok(info) => String::from_utf8_lossy(&info.name).into_owned(),
+            Err(e) => {
+                error!("{:?}", e);
+                continue;
+            }
+        };
+        if let Some(&mon) = forced.get(name.as_str()) {
+            debug!("Output {} is forced to monitor {:?}", name, mon);
+            connected.insert(output, mon.clone());
+        } else if let Some(mon) = connected.get_mut(&output) {
+            mon.connector = Some(connector_type(&name).to_string());
+        }
+    }
+}
+
+/// The tiles of each tiled monitor, keyed by the output of its top left tile, in rows from the
+/// top left.
+pub(crate) type Tiles = HashMap<Output, Vec<(Output, Tile)>>;
+
+/// Merge the outputs of each tiled monitor into the output of its top left tile, so that the
+/// monitor matches layouts as a single monitor. A monitor with a tile that isn't connected is
+/// left as separate outputs.
+pub(crate) fn group_tiles<B: Backend>(
+    conn: &B,
+    connected: &mut HashMap<Output, Monitor>,
+) -> Tiles {
+    let mut groups: HashMap<u32, Vec<(Output, Tile)>> = HashMap::new();
+    for &output in connected.keys() {
+        match conn.tile(output) {
+            Ok(Some(tile)) => groups.entry(tile.group).or_default().push((output, tile)),
+            Ok(None) => (),
+            Err(e) => error!("{:?}", e),
+        }
+    }
+    let mut tiles = HashMap::new();
+    for (group, mut members) in groups {
+        let expected = u64::from(members[0].1.columns) * u64::from(members[0].1.rows);
+        let first = members.iter().find(|(_, t)| t.column == 0 && t.row == 0);
+        match first.map(|&(output, _)| output) {
+            Some(first) if members.len() as u64 == expected => {
+                members.sort_by_key(|(_, t)| (t.row, t.column));
+                for (output, _) in members.iter() {
+                    if *output != first {
+                        connected.remove(output);
+                    }
+                }
+                debug!("Output {} has a monitor tiled over {:?}", first, members);
+                tiles.insert(first, members);
+            }
+            _ => warn!(
+                "Only {} of the {} tiles of tiled monitor {} are connected",
+                members.len(),
+                expected,
+                group
+            ),
+        }
+    }
+    tiles
+}
+
+/// Split the config of each tiled monitor into a config for each of its tiles, offset by the
+/// tiles above and left of it. A monitor that's rotated, reflected or transformed, or doesn't use
+/// the size of all of its tiles together, is shown on its top left tile alone.
+pub(crate) fn tile_setup(
+    setup: &HashMap<Output, &MonConfig>,
+    tiles: &Tiles,
+) -> HashMap<Output, MonConfig> {
+    let mut tiled = HashMap::with_capacity(setup.len());
+    for (&output, &conf) in setup.iter() {
+        let members = match tiles.get(&output) {
+            Some(members) => members,
+            None => {
+                tiled.insert(output, conf.clone());
+                continue;
+            }
+        };
+        let offset = |tile: &Tile| {
+            let x: u32 = members
+                .iter()
+                .filter(|(_, t)| t.row == tile.row && t.column < tile.column)
+                .map(|(_, t)| u32::from(t.w))
+                .sum();
+            let y: u32 = members
+                .iter()
+                .filter(|(_, t)| t.column == tile.column && t.row < tile.row)
+                .map(|(_, t)| u32::from(t.h))
+                .sum();
+            (x, y)
+        };
+        let full = members.iter().fold((0, 0), |(w, h), (_, t)| {
+            let (x, y) = offset(t);
+            (w.max(x + u32::from(t.w)), h.max(y + u32::from(t.h)))
+        });
+        let mode = (u32::from(conf.mode.w), u32::from(conf.mode.h));
+        let transformed = conf.rot.is_some() || conf.reflect.is_some() || conf.transform.is_some();
+        if transformed || conf.modeline.is_some() || mode != full {
+            debug!("Monitor {} is shown on the first of its tiles", conf.name);
+            tiled.insert(output, conf.clone());
+            continue;
+        }
+        for &(tile_output, tile) in members.iter() {
+            let (x, y) = offset(&tile);
+            let first = tile_output == output;
+            let tile_conf = MonConfig {
+                mode: Mode {
+                    w: tile.w,
+                    h: tile.h,
+                },
+                position: Position {
+                    x: (i32::from(conf.position.x) + x as i32) as i16,
+                    y: (i32::from(conf.position.y) + y as i32) as i16,
+                },
+                primary: conf.primary && first,
+                crtc: conf.crtc.filter(|_| first),
+                ..conf.clone()
+            };
+            tiled.insert(tile_output, tile_conf);
+        }
+    }
+    tiled
+}
+
+/// The type of connector of an output, which prefixes its name, such as "DP" for "DP-1".
+pub(crate) fn connector_type(output_name: &str) -> &str {
+    output_name.split('-').next().unwrap_or(output_name)
+}
+
+/// Pair the connected outputs with the monitor configs of a layout.
+pub(crate) fn layout_outputs<'a, C: Connection>(
+    conn: &C,
+    setup: &'a HashMap<Monitor, Vec<MonConfig>>,
+    out_to_mon: HashMap<Output, Monitor>,
+    timestamp: Timestamp,
+) -> HashMap<Output, &'a MonConfig> {
+    // Output names are only needed to tell apart monitors with identical EDIDs
+    let mut names = HashMap::new();
+    for (&output, mon) in out_to_mon.iter() {
+        if out_to_mon.values().filter(|&m| m == mon).count() > 1 {
+            match get_output_name(conn, output, timestamp) {
+                Ok(name) => {
+                    names.insert(output, name);
+                }
+                Err(e) => error!("{:?}", e),
+            }
+        }
+    }
+    assign_outputs(setup, out_to_mon, &names)
+}
+
+/// Pair each output with the config of its monitor. When more than one output has the same
+/// monitor, configs with an output hint are paired with the output of that name first, and the
+/// rest are paired in the order they appear in the layout with the outputs sorted by name.
+fn assign_outputs<'a>(
+    setup: &'a HashMap<Monitor, Vec<MonConfig>>,
+    out_to_mon: HashMap<Output, Monitor>,
+    names: &HashMap<Output, String>,
+) -> HashMap<Output, &'a MonConfig> {
+    let mut mon_to_outs: HashMap<Monitor, Vec<Output>> = HashMap::new();
     for (output, mon) in out_to_mon.into_iter() {
-        if let Some(moncfg) = setup.get(&mon) {
-            out.insert(output, moncfg);
+        mon_to_outs.entry(mon).or_default().push(output);
+    }
+    let mut out = HashMap::with_capacity(setup.len());
+    for (mon, mut outputs) in mon_to_outs.into_iter() {
+        // Outputs come from a HashMap, so without sorting the pairing would change between runs
+        outputs.sort_by_key(|output| (names.get(output), *output));
+        let mut confs: Vec<&MonConfig> = match setup.get(&mon) {
+            Some(confs) => confs.iter().collect(),
+            None => {
+                debug!("No monitor config for {:?} on outputs {:?}", mon, outputs);
+                continue;
+            }
+        };
+        confs.retain(|conf| {
+            let hinted = conf
+                .output
+                .as_ref()
+                .and_then(|want| outputs.iter().position(|o| names.get(o) == Some(want)));
+            match hinted {
+                Some(idx) => {
+                    let output = outputs.remove(idx);
+                    debug!("Output {} receives {} by its output hint", output, conf.name);
+                    out.insert(output, *conf);
+                    false
+                }
+                None => true,
+            }
+        });
+        for (output, conf) in outputs.into_iter().zip(confs) {
+            debug!("Output {} receives {}", output, conf.name);
+            out.insert(output, conf);
         }
     }
-    Some((name, fb_size, out))
+    out
+}
+
+/// The Xorg mode identifiers of each mode size, with the refresh rate of each mode in mHz
+pub(crate) type ModeMap = HashMap<Mode, HashMap<u32, u64>>;
+
+/// The refresh rate of a mode, in mHz.
+pub(crate) fn refresh_rate(mi: &ModeInfo) -> u64 {
+    let mut lines = u64::from(mi.vtotal);
+    if mi.mode_flags & u32::from(ModeFlag::DOUBLE_SCAN) != 0 {
+        lines *= 2;
+    }
+    if mi.mode_flags & u32::from(ModeFlag::INTERLACE) != 0 {
+        lines /= 2;
+    }
+    match u64::from(mi.htotal) * lines {
+        0 => 0,
+        pixels => u64::from(mi.dot_clock) * 1000 / pixels,
+    }
 }
 
 /// Create a map from human mode descriptions, in width and height, to Xorg mode identifiers
-fn mode_map<C: Connection>(
-    conn: &C,
-    root: Window,
-) -> Result<(HashMap<Mode, HashSet<u32>>, Timestamp)> {
-    let resources = conn
-        .randr_get_screen_resources(root)
-        .into_diagnostic()?
-        .reply()
-        .into_diagnostic()?;
-    let mut modes: HashMap<_, HashSet<u32>> = HashMap::with_capacity(resources.modes.len());
+pub(crate) fn mode_map<B: Backend>(conn: &B, root: Window) -> Result<(ModeMap, Timestamp)> {
+    let resources = conn.screen_resources(root)?;
+    let mut modes: ModeMap = HashMap::with_capacity(resources.modes.len());
     for mi in resources.modes.iter() {
         modes
             .entry(Mode {
@@ -74,7 +654,7 @@ fn mode_map<C: Connection>(
                 h: mi.height,
             })
             .or_default()
-            .insert(mi.id);
+            .insert(mi.id, refresh_rate(mi));
     }
     Ok((modes, resources.timestamp))
 }
@@ -93,271 +673,1491 @@ fn disable_crtc<'a, 'b>(crtc: u32, from: &'a GetCrtcInfoReply) -> SetCrtcConfigR
     }
 }
 
-/// Allocate a CRTC for use by an output.
-fn allocate_crtc(info: &GetOutputInfoReply, free: &mut HashSet<&Crtc>) -> Option<Crtc> {
-    let dest = if info.crtc != 0 {
-        Some(info.crtc)
-    } else {
-        info.crtcs.iter().find_map(|c| free.get(&c).map(|&&a| a))
-    };
+/// Whether a CRTC's current configuration lies within a screen of size `size`.
+fn fits_within(info: &GetCrtcInfoReply, size: &Mode) -> bool {
+    i32::from(info.x) + i32::from(info.width) <= i32::from(size.w)
+        && i32::from(info.y) + i32::from(info.height) <= i32::from(size.h)
+}
+
+/// How many times a screen resize rejected with a Match error is retried
+const RESIZE_RETRIES: u32 = 3;
+
+/// How long to wait before retrying a rejected screen resize, about a frame
+const RESIZE_RETRY_DELAY: Duration = Duration::from_millis(20);
+
+/// Resize the screen. Some drivers reject a resize with a Match error for a moment after CRTCs
+/// are disabled, so a rejected resize is retried after a short wait.
+fn resize_screen<B: Backend>(
+    conn: &B,
+    root: Window,
+    fb_size: &Mode,
+    mm_w: u32,
+    mm_h: u32,
+) -> Result<()> {
+    let mut retries = 0;
+    loop {
+        match conn.set_screen_size(root, fb_size.w, fb_size.h, mm_w, mm_h) {
+            Err(e)
+                if retries < RESIZE_RETRIES && e.downcast_ref::<ScreenSizeMismatch>().is_some() =>
+            {
+                retries += 1;
+                warn!("{}, retrying ({}/{})", e, retries, RESIZE_RETRIES);
+                sleep(RESIZE_RETRY_DELAY);
+            }
+            resized => return resized,
+        }
+    }
+}
+
+/// Create a request that returns a CRTC to its current configuration.
+fn restore_crtc(
+    crtc: Crtc,
+    from: &GetCrtcInfoReply,
+    config_timestamp: Timestamp,
+) -> SetCrtcConfigRequest<'static> {
+    SetCrtcConfigRequest {
+        crtc,
+        // The CRTC's own timestamp will be stale by the time it's restored
+        timestamp: CURRENT_TIME,
+        config_timestamp,
+        x: from.x,
+        y: from.y,
+        mode: from.mode,
+        rotation: from.rotation,
+        outputs: from.outputs.clone().into(),
+    }
+}
+
+/// Allocate a CRTC for use by a group of outputs that show the same picture, which must be
+/// able to drive every one of them.
+/// An output keeps its current CRTC, unless another output has already been given it, as when
+/// two outputs showed the same picture. Otherwise, the outputs prefer the CRTC that last drove
+/// one of them, `last`, when it's free.
+fn allocate_crtc(
+    infos: &[&GetOutputInfoReply],
+    free: &mut HashSet<&Crtc>,
+    last: Option<Crtc>,
+) -> Option<Crtc> {
+    let drives_all = |crtc: Crtc| infos.iter().all(|i| i.crtc == crtc || i.crtcs.contains(&crtc));
+    let dest = infos
+        .iter()
+        .map(|info| info.crtc)
+        .find(|&c| c != 0 && free.contains(&c) && drives_all(c))
+        .or_else(|| last.filter(|&c| free.contains(&c) && drives_all(c)))
+        .or_else(|| {
+            let first = infos.first()?;
+            first.crtcs.iter().copied().find(|&c| free.contains(&c) && drives_all(c))
+        });
     if let Some(dest) = &dest {
         free.remove(dest);
     }
     dest
 }
 
-/// Find a matching mode id for the output within the mode map.
-///
-/// Since this is a helper function that's part of a command line utility,
-/// errors are returned as strings
-fn find_mode_id(
+/// Take the CRTC at `index` among the screen's CRTCs, for a group of outputs pinned to it.
+fn pinned_crtc(
+    res: &GetScreenResourcesCurrentReply,
+    infos: &[&GetOutputInfoReply],
+    free: &mut HashSet<&Crtc>,
+    out: Output,
+    index: u8,
+) -> std::result::Result<Crtc, ApplyError> {
+    let crtc = res
+        .crtcs
+        .get(usize::from(index))
+        .filter(|crtc| infos.iter().all(|info| info.crtcs.contains(crtc)))
+        .ok_or(ApplyError::CrtcUnsupported(out, index))?;
+    if !free.remove(crtc) {
+        return Err(ApplyError::NoCrtcAvailable(out));
+    }
+    Ok(*crtc)
+}
+
+/// Find a matching mode id for the output `out` within the mode map. The output's first mode of
+/// the right size is used, or with `prefer_refresh`, the one with the highest refresh rate.
+pub(crate) fn find_mode_id(
+    out: Output,
     info: &GetOutputInfoReply,
-    mode_map: &HashMap<Mode, HashSet<u32>>,
+    mode_map: &ModeMap,
     mode: &Mode,
-) -> Result<u32> {
+    prefer_refresh: bool,
+) -> std::result::Result<u32, ApplyError> {
     let mode_ids = mode_map
         .get(&mode)
-        .ok_or_else(|| Error::ModeNotFound(mode.clone()))
-        .into_diagnostic()?;
-    info.modes
+        .ok_or_else(|| ApplyError::ModeNotFound(mode.clone()))?;
+    trace!(
+        "Mode {} has ids {:?}, of which the output supports {:?}",
+        mode, mode_ids, info.modes
+    );
+    let mut supported = info
+        .modes
         .iter()
-        .find_map(|m| mode_ids.get(m).map(|&m| m))
-        .ok_or_else(|| Error::ModeNotSupported(mode.clone()))
-        .into_diagnostic()
+        .filter_map(|m| mode_ids.get(m).map(|&refresh| (*m, refresh)));
+    let found = if prefer_refresh {
+        // The first of the fastest modes is kept, as max_by_key would keep the last
+        supported.fold(None, |best: Option<(u32, u64)>, (m, refresh)| match best {
+            Some((_, best_refresh)) if best_refresh >= refresh => best,
+            _ => Some((m, refresh)),
+        })
+    } else {
+        supported.next()
+    };
+    found
+        .map(|(m, _)| m)
+        .ok_or_else(|| ApplyError::ModeUnsupported(out, mode.clone()))
 }
 
-/// Apply a batch of SetCrtcConfig commands.
-fn batch_config<C: Connection + RandrExt>(
-    conn: &C,
-    batch: Vec<SetCrtcConfigRequest>,
-    disable_panning: Option<Vec<SetPanningRequest>>
+/// The RandR description of a custom mode.
+fn custom_mode_info(line: &ModeLine) -> ModeInfo {
+    ModeInfo {
+        id: 0,
+        width: line.hdisplay,
+        height: line.vdisplay,
+        dot_clock: (line.clock * 1_000_000.0).round() as u32,
+        hsync_start: line.hsync_start,
+        hsync_end: line.hsync_end,
+        htotal: line.htotal,
+        hskew: 0,
+        vsync_start: line.vsync_start,
+        vsync_end: line.vsync_end,
+        vtotal: line.vtotal,
+        name_len: 0,
+        mode_flags: line.flags,
+    }
+}
+
+/// Find the id of the custom mode described by `line`, creating it when it does not exist, and
+/// make sure that `out` may use it.
+fn custom_mode<B: Backend>(
+    conn: &B,
+    root: Window,
+    res: &GetScreenResourcesCurrentReply,
+    out: Output,
+    out_info: &GetOutputInfoReply,
+    line: &ModeLine,
+) -> Result<u32> {
+    let wanted = custom_mode_info(line);
+    let same_timings = |mi: &&ModeInfo| {
+        ModeInfo {
+            id: 0,
+            name_len: 0,
+            ..**mi
+        } == wanted
+    };
+    let mode = match res.modes.iter().find(same_timings) {
+        Some(mi) => mi.id,
+        None => {
+            let name = format!("{}x{}_{}", line.hdisplay, line.vdisplay, wanted.dot_clock / 1000);
+            info!("Creating mode {}", name);
+            let mode_info = ModeInfo {
+                name_len: name.len() as u16,
+                ..wanted
+            };
+            conn.create_mode(root, mode_info, name.as_bytes())?
+        }
+    };
+    if !out_info.modes.contains(&mode) {
+        info!("Adding mode {} to output {}", mode, out);
+        conn.add_output_mode(out, mode)?;
+    }
+    Ok(mode)
+}
+
+/// Remember the custom modes of `setup` that have appeared since `before`, as applying `setup`
+/// created them. Then delete the created modes that `setup` doesn't use from the outputs that
+/// have them, and destroy them, so that they don't collide with the modes of later monitors.
+fn clean_up_modes<B: Backend>(
+    conn: &B,
+    root: Window,
+    before: &GetScreenResourcesCurrentReply,
+    setup: &HashMap<Output, &MonConfig>,
+    created: &mut CreatedModes,
 ) -> Result<()> {
-    for req in &batch {
-        if req.mode != 0 {
-            info!(
-                "Configuring CRTC {} to mode {} at {},{}",
-                req.crtc, req.mode, req.x, req.y,
-            );
-        } else {
-            info!("Disabling CRTC {}", req.crtc);
+    let wanted: Vec<ModeInfo> = setup
+        .values()
+        .filter(|conf| !conf.off)
+        .filter_map(|conf| conf.modeline.as_ref().map(custom_mode_info))
+        .collect();
+    if wanted.is_empty() && created.is_empty() {
+        return Ok(());
+    }
+    let timings = |mi: &ModeInfo| ModeInfo {
+        id: 0,
+        name_len: 0,
+        ..*mi
+    };
+    let res = conn.screen_resources(root)?;
+    for mi in res.modes.iter() {
+        if wanted.contains(&timings(mi)) && !before.modes.iter().any(|b| b.id == mi.id) {
+            created.insert(mi.id, timings(mi));
         }
     }
-    info!("Batch pre-sent");
-    let cookies: Vec<Cookie<C, SetCrtcConfigReply>> = batch
-        .into_iter()
-        .map(|req| req.send(conn))
-        .collect::<std::result::Result<_, _>>()
-        .into_diagnostic()?;
-    info!("Batch sent");
-    let responses: Vec<SetCrtcConfigReply> = cookies
-        .into_iter()
-        .map(|cookie| cookie.reply())
-        .collect::<std::result::Result<_, _>>()
-        .into_diagnostic()?;
-    info!("Batch recieved");
-    for (num, res) in responses.iter().enumerate() {
-        match res.status {
-            SetConfig::INVALID_CONFIG_TIME => {
-                error!("Request #{} failed with invalid config time", num)
-            }
-            SetConfig::INVALID_TIME => error!("Request #{} failed with invalid time", num),
-            SetConfig::FAILED => error!("Request #{} failed", num),
-            _ => (),
+    let mut unused: Vec<u32> = created
+        .iter()
+        .filter(|(_, mi)| !wanted.contains(mi))
+        .map(|(&id, _)| id)
+        .collect();
+    if unused.is_empty() {
+        return Ok(());
+    }
+    unused.sort_unstable();
+    let infos = conn.output_infos(&res.outputs, res.config_timestamp);
+    for (&out, info) in res.outputs.iter().zip(infos) {
+        for mode in info?.modes.into_iter().filter(|mode| unused.contains(mode)) {
+            info!("Deleting mode {} from output {}", mode, out);
+            conn.delete_output_mode(out, mode)?;
         }
     }
-    if let Some(batch) = disable_panning {
-        info!("Batch pre-sent");
-        let timestamp = responses.first().map(|r| r.timestamp);
-        let cookies: Vec<Cookie<C, SetPanningReply>> = batch
-            .into_iter()
-            .map(|mut req|{
-                if let Some(ts) = timestamp {
-                    req.timestamp = ts;
-                }
-                req.send(conn)
-            })
-            .collect::<std::result::Result<_, _>>()
-            .into_diagnostic()?;
-        info!("Batch sent");
-        let responses: Vec<SetPanningReply> = cookies
-            .into_iter()
-            .map(|cookie| cookie.reply())
-            .collect::<std::result::Result<_, _>>()
-            .into_diagnostic()?;
-        info!("Batch recieved");
-        for (num, res) in responses.iter().enumerate() {
-            match res.status {
-                SetConfig::INVALID_CONFIG_TIME => {
-                    error!("Request #{} failed with invalid config time", num)
-                }
-                SetConfig::INVALID_TIME => error!("Request #{} failed with invalid time", num),
-                SetConfig::FAILED => error!("Request #{} failed", num),
-                _ => (),
+    for mode in unused {
+        // Forgotten first, so that a mode that can't be destroyed isn't tried again
+        created.remove(&mode);
+        info!("Destroying mode {}", mode);
+        conn.destroy_mode(mode)?;
+    }
+    Ok(())
+}
+
+/// Set the backlight of every output with a configured backlight, skipping outputs that don't
+/// have one.
+fn set_backlights<B: Backend>(conn: &B, setup: &HashMap<Output, &MonConfig>) -> Result<()> {
+    for (&out, conf) in setup.iter() {
+        let percent = match conf.backlight {
+            Some(percent) => i64::from(percent),
+            None => continue,
+        };
+        match conn.backlight_range(out)? {
+            Some((min, max)) => {
+                let value = i64::from(min) + (i64::from(max) - i64::from(min)) * percent / 100;
+                info!("Setting backlight of {} to {}%", conf.name, percent);
+                conn.set_backlight(out, value as i32)?;
             }
+            None => info!("Monitor {} has no backlight, skipping it", conf.name),
         }
     }
     Ok(())
 }
 
-/// Make the current Xorg server match the specified configuration.
-fn apply_config<C: Connection>(
-    conn: &C,
-    res: &GetScreenResourcesCurrentReply,
-    fb_size: &Mode,
-    setup: HashMap<Output, &MonConfig>,
-    root: Window,
-) -> Result<bool> {
-    let primary: Option<Output> = setup
-        .iter()
-        .find(|(_, c)| c.primary)
-        .map(|(o, _)| *o);
-    let (modes, timestamp) = mode_map(conn, root)?;
-    let mut free_crtcs: HashSet<_> = res.crtcs.iter().collect();
-    let mut enables = Vec::with_capacity(res.crtcs.len());
-    let mut panning = Vec::with_capacity(res.crtcs.len());
-    let mut mm_w = 0;
-    let mut mm_h = 0;
-    let outs_in_conf = res
-        .outputs
-        .iter()
-        .filter_map(|o| setup.get(&o).map(|c| (c, o)));
-    // This loop can't easily be a map, as it needs to be able to use '?'
-    for (&conf, &out) in outs_in_conf {
-        let out_info = conn
-            .randr_get_output_info(out, timestamp)
-            .into_diagnostic()?
-            .reply()
-            .into_diagnostic()?;
-        let mode = find_mode_id(&out_info, &modes, &conf.mode)?;
-        let dest_crtc = allocate_crtc(&out_info, &mut free_crtcs)
-            .ok_or_else(|| Error::NoCrtc(conf.name.clone()))
-            .into_diagnostic()?;
-        //TODO: This is not a correct computation of the screen size
-        mm_w += out_info.mm_width;
-        mm_h += out_info.mm_height;
-        let Position { x, y } = conf.position;
-        let crtc_info = conn
-            .randr_get_crtc_info(dest_crtc, timestamp)
-            .into_diagnostic()?
-            .reply()
-            .into_diagnostic()?;
-        let rotation: u16 = match conf.rot {
-            None => Rot::ROTATE0,
-            Some(Rotation::Right) => Rot::ROTATE270,
-            Some(Rotation::Left) => Rot::ROTATE90,
-        }.into();
-        if x != crtc_info.x
-            || y != crtc_info.y
-            || mode != crtc_info.mode
-            || rotation != crtc_info.rotation
-        {
-            enables.push(SetCrtcConfigRequest {
-                x,
-                y,
-                rotation,
-                mode,
-                outputs: vec![out].into(),
-                ..disable_crtc(dest_crtc, &crtc_info)
-            });
-            panning.push(SetPanningRequest {
-                crtc: dest_crtc,
-                timestamp: crtc_info.timestamp,
-                left: x as u16, top: y as u16, width: conf.mode.w, height: conf.mode.h,
-                track_left: x as u16, track_top: y as u16, track_width: conf.mode.w, track_height: conf.mode.h,
-                border_left: 0, border_top: 0, border_right: 0, border_bottom: 0,
-            })
+/// Turn variable refresh rate on or off for every output with it configured, warning about
+/// outputs that don't support it.
+fn set_vrrs<B: Backend>(conn: &B, setup: &HashMap<Output, &MonConfig>) -> Result<()> {
+    for (&out, conf) in setup.iter() {
+        let enabled = match conf.vrr {
+            Some(enabled) => enabled,
+            None => continue,
+        };
+        if conn.has_vrr(out)? {
+            info!("Setting variable refresh rate of {} to {}", conf.name, enabled);
+            conn.set_vrr(out, enabled)?;
+        } else {
+            warn!("Monitor {} does not support variable refresh rate, skipping it", conf.name);
         }
     }
-    // If there were CRTCs left over after allocating the next setup, ensure that they are
-    // disabled
-    let mut disables = Vec::with_capacity(free_crtcs.len());
-    for &crtc in free_crtcs.into_iter() {
-        let info = conn
-            .randr_get_crtc_info(crtc, timestamp)
-            .into_diagnostic()?
-            .reply()
-            .into_diagnostic()?;
-        if !info.outputs.is_empty() || info.mode != 0 {
-            disables.push(disable_crtc(crtc, &info));
+    Ok(())
+}
+
+/// Set the scaling mode of every output with one configured, warning about outputs that don't
+/// have one.
+fn set_scaling_modes<B: Backend>(conn: &B, setup: &HashMap<Output, &MonConfig>) -> Result<()> {
+    for (&out, conf) in setup.iter() {
+        let mode = match conf.scaling_mode {
+            Some(mode) => mode,
+            None => continue,
+        };
+        if conn.has_scaling_mode(out)? {
+            info!("Setting scaling mode of {} to {}", conf.name, mode.name());
+            conn.set_scaling_mode(out, mode.property_value())?;
+        } else {
+            warn!("Monitor {} has no scaling mode, skipping it", conf.name);
         }
     }
+    Ok(())
+}
 
-    let geom = conn
-        .get_geometry(root)
-        .into_diagnostic()?
-        .reply()
-        .into_diagnostic()?;
-    let mut current = Mode {
-        w: geom.width,
-        h: geom.height,
-    };
-    let cur_primary = if primary.is_some() {
-        Some(
-            conn.randr_get_output_primary(root)
-                .into_diagnostic()?
-                .reply()
-                .into_diagnostic()?.output
-        )
-    } else {
-        None
-    };
+/// Convert the value a monitor sets an output property to into the property's type. Returns
+/// None when the property can't hold it: a number out of its range, or an atom it doesn't list.
+pub(crate) fn property_data(info: &PropertyInfo, value: &PropertyValue) -> Option<PropertyData> {
+    match (info.kind.as_str(), value) {
+        ("ATOM", PropertyValue::String(name)) => {
+            let known = info.atoms.is_empty() || info.atoms.contains(name);
+            Some(PropertyData::Atom(name.clone())).filter(|_| known)
+        }
+        (kind @ ("INTEGER" | "CARDINAL"), &PropertyValue::Int(value)) => {
+            let (min, max) = info.range.unwrap_or((i32::MIN, i32::MAX));
+            let format = match info.format {
+                8 | 16 => info.format,
+                _ => 32,
+            };
+            let cardinal = kind == "CARDINAL";
+            let fits = (i64::from(min)..=i64::from(max)).contains(&value);
+            Some(PropertyData::Number { value, format, cardinal }).filter(|_| fits)
+        }
+        ("STRING", PropertyValue::String(text)) => Some(PropertyData::String(text.clone())),
+        _ => None,
+    }
+}
+
+/// Set the output properties that each monitor names in its `properties` node, warning about
+/// properties its output lacks, and values they can't hold.
+fn set_output_properties<B: Backend>(conn: &B, setup: &HashMap<Output, &MonConfig>) -> Result<()> {
+    for (&out, conf) in setup.iter() {
+        for (name, value) in conf.properties.iter() {
+            let info = match conn.property_info(out, name)? {
+                Some(info) => info,
+                None => {
+                    warn!("Monitor {} has no property {}, skipping it", conf.name, name);
+                    continue;
+                }
+            };
+            match property_data(&info, value) {
+                Some(data) => {
+                    info!("Setting property {} of {} to {:?}", name, conf.name, value);
+                    conn.set_property(out, name, &data)?;
+                }
+                None => warn!(
+                    "Property {} of monitor {}, of type {}, can't be set to {:?}",
+                    name, conf.name, info.kind, value
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The environment variable that makes the daemon apply the safe layout, whatever monitors are
+/// connected
+const SAFE_ENV: &str = "MONITOR_LAYOUT_SAFE";
+
+/// The connector types of the panels built into laptops, which touchscreens that no monitor
+/// names are mapped onto
+const BUILT_IN_CONNECTORS: &[&str] = &["eDP", "LVDS", "DSI"];
+
+/// The coordinate transformation matrix that maps a touchscreen onto a lit monitor of a screen
+/// of size `screen`: the monitor's rotation is applied to the touches, which are then scaled
+/// and moved onto the monitor's part of the screen.
+pub(crate) fn touch_matrix(conf: &MonConfig, screen: &Mode) -> [f32; 9] {
+    let (w, h) = conf.span();
+    let (screen_w, screen_h) = (f32::from(screen.w.max(1)), f32::from(screen.h.max(1)));
+    let (scale_x, scale_y) = (f32::from(w) / screen_w, f32::from(h) / screen_h);
+    let (x, y) = (f32::from(conf.position.x) / screen_w, f32::from(conf.position.y) / screen_h);
+    // The top two rows of the rotation, of touches within a unit square
+    let r = match conf.rot {
+        None => [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+        Some(Rotation::Left) => [0.0, -1.0, 1.0, 1.0, 0.0, 0.0],
+        Some(Rotation::Right) => [0.0, 1.0, 0.0, -1.0, 0.0, 1.0],
+    };
+    [
+        scale_x * r[0],
+        scale_x * r[1],
+        scale_x * r[2] + x,
+        scale_y * r[3],
+        scale_y * r[4],
+        scale_y * r[5] + y,
+        0.0,
+        0.0,
+        1.0,
+    ]
+}
+
+/// Map each touchscreen onto the lit monitor that names it with `touch-device`. Touchscreens
+/// that no monitor names are mapped onto the laptop's built-in panel, when it's lit.
+fn map_touch_screens<B: Backend>(
+    conn: &B,
+    setup: &HashMap<Output, &MonConfig>,
+    screen: &Mode,
+    timestamp: Timestamp,
+) -> Result<()> {
+    let devices = conn.touch_screens()?;
+    if devices.is_empty() {
+        return Ok(());
+    }
+    let mut lit: Vec<(Output, &MonConfig)> = setup
+        .iter()
+        .filter(|(_, conf)| !conf.off)
+        .map(|(&out, &conf)| (out, conf))
+        .collect();
+    lit.sort_by_key(|&(out, _)| out);
+    let named: HashSet<&str> = setup.values().filter_map(|c| c.touch_device.as_deref()).collect();
+    for &(_, conf) in lit.iter() {
+        let name = match &conf.touch_device {
+            Some(name) => name,
+            None => continue,
+        };
+        match devices.iter().find(|(_, device)| device == name) {
+            Some(&(id, _)) => {
+                info!("Mapping touchscreen {} onto {}", name, conf.name);
+                conn.set_touch_matrix(id, touch_matrix(conf, screen))?;
+            }
+            None => warn!("Touchscreen {} of monitor {} was not found", name, conf.name),
+        }
+    }
+    let unnamed: Vec<_> = devices
+        .iter()
+        .filter(|(_, device)| !named.contains(device.as_str()))
+        .collect();
+    if unnamed.is_empty() {
+        return Ok(());
+    }
+    let mut panel = None;
+    for &(out, conf) in lit.iter() {
+        let info = conn.output_info(out, timestamp)?;
+        if BUILT_IN_CONNECTORS.contains(&connector_type(&String::from_utf8_lossy(&info.name))) {
+            panel = Some(conf);
+            break;
+        }
+    }
+    if let Some(conf) = panel {
+        for (id, device) in unnamed {
+            info!("Mapping touchscreen {} onto the built-in panel {}", device, conf.name);
+            conn.set_touch_matrix(*id, touch_matrix(conf, screen))?;
+        }
+    }
+    Ok(())
+}
+
+/// The root window property that holds the ICC profile of the first Xinerama screen. The
+/// profiles of the others are held by the same name, suffixed with `_` and their index.
+const ICC_PROFILE: &str = "_ICC_PROFILE";
+
+/// The root window property that names the layout the daemon last applied, for status bars and
+/// other tools that read X properties
+const ACTIVE_LAYOUT: &str = "_MONITOR_LAYOUT_ACTIVE";
+
+/// Load the ICC profile of each lit monitor that declares one into the root window property of
+/// its Xinerama screen, and its VCGT into the gamma ramps of the CRTC that drives it. Xinerama
+/// numbers the primary monitor first, then the rest in the order of their CRTCs. The properties
+/// of screens without a profile are deleted, so the profiles of a previous layout don't linger.
+fn load_icc_profiles<B: Backend>(
+    conn: &B,
+    root: Window,
+    res: &GetScreenResourcesCurrentReply,
+    setup: &HashMap<Output, &MonConfig>,
+    assigned: &HashMap<Output, Crtc>,
+) -> Result<()> {
+    let mut lit = Vec::new();
+    for (&out, &conf) in setup.iter().filter(|(_, conf)| !conf.off) {
+        // Outputs that weren't moved keep their CRTC, and those that can't be read were skipped
+        let crtc = match assigned.get(&out) {
+            Some(&crtc) => crtc,
+            None => match conn.output_info(out, res.config_timestamp) {
+                Ok(info) => info.crtc,
+                Err(_) => continue,
+            },
+        };
+        let index = res.crtcs.iter().position(|&c| c == crtc).unwrap_or(res.crtcs.len());
+        lit.push((!conf.primary, index, crtc, conf));
+    }
+    lit.sort_by_key(|&(not_primary, index, _, _)| (not_primary, index));
+    for i in 0..res.crtcs.len().max(lit.len()) {
+        let name = match i {
+            0 => ICC_PROFILE.to_string(),
+            i => format!("{}_{}", ICC_PROFILE, i),
+        };
+        let profile = match lit.get(i) {
+            Some(&(_, _, crtc, conf)) => match &conf.icc {
+                Some(path) => match std::fs::read(path) {
+                    Ok(profile) => Some((crtc, conf, profile)),
+                    Err(e) => {
+                        warn!("Could not read ICC profile {} of {}: {}", path, conf.name, e);
+                        None
+                    }
+                },
+                None => None,
+            },
+            None => None,
+        };
+        let (crtc, conf, profile) = match profile {
+            Some(found) => found,
+            None => {
+                if conn.has_root_property(root, name.as_bytes())? {
+                    conn.set_root_property(root, name.as_bytes(), None)?;
+                }
+                continue;
+            }
+        };
+        info!("Loading ICC profile of {} into {}", conf.name, name);
+        conn.set_root_property(root, name.as_bytes(), Some(&profile))?;
+        let size = usize::from(conn.gamma_size(crtc)?);
+        let ramps = match icc::vcgt(&profile) {
+            Some(vcgt) => vcgt.ramps(size),
+            None => icc::linear_ramps(size),
+        };
+        conn.set_gamma(crtc, &ramps)?;
+    }
+    Ok(())
+}
+
+/// Name the active layout in the root window's `_MONITOR_LAYOUT_ACTIVE` property, or delete
+/// the property when no layout is active.
+fn publish_layout<B: Backend>(conn: &B, root: Window, name: Option<&str>) -> Result<()> {
+    let property = ACTIVE_LAYOUT.as_bytes();
+    match name {
+        Some(name) => conn.set_root_text(root, property, name),
+        None if conn.has_root_property(root, property)? => {
+            conn.set_root_property(root, property, None)
+        }
+        None => Ok(()),
+    }
+}
+
+/// The value of a CRTC's rotation field that displays a monitor config: one rotation bit, and a
+/// bit for each axis it's reflected along.
+pub(crate) fn rotation(conf: &MonConfig) -> u16 {
+    let rotation = u16::from(match conf.rot {
+        None => Rot::ROTATE0,
+        Some(Rotation::Right) => Rot::ROTATE270,
+        Some(Rotation::Left) => Rot::ROTATE90,
+    });
+    let (x, y) = (u16::from(Rot::REFLECT_X), u16::from(Rot::REFLECT_Y));
+    rotation
+        | match conf.reflect {
+            None => 0,
+            Some(Reflection::X) => x,
+            Some(Reflection::Y) => y,
+            Some(Reflection::XY) => x | y,
+        }
+}
+
+/// The transform of a CRTC that displays a monitor config, in the 16.16 fixed point numbers
+/// RandR expects.
+pub(crate) fn transform(conf: &MonConfig) -> Transform {
+    let m = match &conf.transform {
+        Some(m) => m,
+        None => return IDENTITY_TRANSFORM,
+    };
+    let fixed = |v: f64| (v * 65536.0).round() as i32;
+    Transform {
+        matrix11: fixed(m[0]),
+        matrix12: fixed(m[1]),
+        matrix13: fixed(m[2]),
+        matrix21: fixed(m[3]),
+        matrix22: fixed(m[4]),
+        matrix23: fixed(m[5]),
+        matrix31: fixed(m[6]),
+        matrix32: fixed(m[7]),
+        matrix33: fixed(m[8]),
+    }
+}
+
+/// Give CRTCs their transforms, which take effect when each is next configured. Any transform
+/// but the identity is filtered bilinearly, as xrandr does.
+fn set_transforms<B: Backend>(conn: &B, transforms: &[(Crtc, Transform)]) -> Result<()> {
+    for &(crtc, transform) in transforms {
+        let filter: &[u8] = if transform == IDENTITY_TRANSFORM { b"" } else { b"bilinear" };
+        info!("Setting transform of CRTC {}", crtc);
+        conn.set_crtc_transform(crtc, transform, filter)?;
+    }
+    Ok(())
+}
+
+/// Check if a CRTC must be reconfigured to display a monitor config using the mode id `mode`.
+pub(crate) fn crtc_differs(crtc_info: &GetCrtcInfoReply, conf: &MonConfig, mode: u32) -> bool {
+    let Position { x, y } = conf.position;
+    x != crtc_info.x
+        || y != crtc_info.y
+        || mode != crtc_info.mode
+        || rotation(conf) != crtc_info.rotation
+}
+
+/// Apply a batch of SetCrtcConfig commands. Fails with `CrtcConfigFailed` when the server
+/// doesn't configure a CRTC, or `ConfigChanged` when it's because another client changed the
+/// configuration, in which case no panning is set.
+fn batch_config<B: Backend>(
+    conn: &B,
+    batch: Vec<SetCrtcConfigRequest>,
+    disable_panning: Option<Vec<SetPanningRequest>>
+) -> std::result::Result<(), ApplyError> {
+    for req in &batch {
+        if req.mode != 0 {
+            info!(
+                "Configuring CRTC {} to mode {} at {},{}",
+                req.crtc, req.mode, req.x, req.y,
+            );
+        } else {
+            info!("Disabling CRTC {}", req.crtc);
+        }
+    }
+    let crtcs: Vec<Crtc> = batch.iter().map(|req| req.crtc).collect();
+    info!("Batch pre-sent");
+    let responses = conn.set_crtc_configs(batch).map_err(ApplyError::SendFailed)?;
+    info!("Batch recieved");
+    let mut failed = None;
+    for (&crtc, res) in crtcs.iter().zip(responses.iter()) {
+        if res.status != SetConfig::SUCCESS {
+            error!("{}", ApplyError::CrtcConfigFailed(crtc, res.status));
+            failed = failed.or(Some((crtc, res.status)));
+        }
+    }
+    if let Some((crtc, status)) = failed {
+        check_fresh(status)?;
+        return Err(ApplyError::CrtcConfigFailed(crtc, status));
+    }
+    if let Some(batch) = disable_panning {
+        info!("Batch pre-sent");
+        let timestamp = responses.first().map(|r| r.timestamp);
+        let batch = batch
+            .into_iter()
+            .map(|mut req| {
+                if let Some(ts) = timestamp {
+                    req.timestamp = ts;
+                }
+                req
+            })
+            .collect();
+        let responses = conn.set_pannings(batch).map_err(ApplyError::SendFailed)?;
+        info!("Batch recieved");
+        for (num, res) in responses.iter().enumerate() {
+            match res.status {
+                SetConfig::INVALID_CONFIG_TIME => {
+                    error!("Request #{} failed with invalid config time", num)
+                }
+                SetConfig::INVALID_TIME => error!("Request #{} failed with invalid time", num),
+                SetConfig::FAILED => error!("Request #{} failed", num),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many times a layout is planned and applied again, when another client changes the
+/// configuration of the outputs and CRTCs first
+const READ_RETRIES: u32 = 2;
+
+/// The changes that apply a layout, and the configuration of the CRTCs they change.
+struct Plan<'a> {
+    enables: Vec<SetCrtcConfigRequest<'a>>,
+    panning: Vec<SetPanningRequest>,
+    disables: Vec<SetCrtcConfigRequest<'a>>,
+    /// The transforms of the CRTCs being enabled whose transform changes
+    transforms: Vec<(Crtc, Transform)>,
+    /// The configuration of every CRTC this layout changes, in case it must be rolled back
+    previous: Vec<SetCrtcConfigRequest<'static>>,
+    /// The transforms that `transforms` replace
+    previous_transforms: Vec<(Crtc, Transform)>,
+    mm_w: u32,
+    mm_h: u32,
+}
+
+/// Outputs that show the same picture, in the same mode, and so are driven by a single CRTC
+struct CloneGroup<'c> {
+    mode: u32,
+    outputs: Vec<(&'c MonConfig, Output, GetOutputInfoReply)>,
+}
+
+impl CloneGroup<'_> {
+    /// Whether the output `out`, showing `conf` in `mode`, may join the group. Its picture must be
+    /// the same, it and every output of the group must list each other as clones, and there must
+    /// be a CRTC that can drive them all.
+    fn accepts(&self, conf: &MonConfig, mode: u32, out: Output, info: &GetOutputInfoReply) -> bool {
+        let (first, _, _) = self.outputs[0];
+        mode == self.mode
+            && conf.position == first.position
+            && rotation(conf) == rotation(first)
+            && transform(conf) == transform(first)
+            && conf.crtc == first.crtc
+            && self.outputs.iter().all(|(_, other, other_info)| {
+                info.clones.contains(other) && other_info.clones.contains(&out)
+            })
+            && info.crtcs.iter().any(|crtc| {
+                self.outputs.iter().all(|(_, _, other_info)| other_info.crtcs.contains(crtc))
+            })
+    }
+}
+
+/// Fail with `ConfigChanged` when a reply says the configuration changed since the timestamp
+/// of its request.
+fn check_fresh(status: SetConfig) -> std::result::Result<(), ApplyError> {
+    if status == SetConfig::INVALID_TIME || status == SetConfig::INVALID_CONFIG_TIME {
+        Err(ApplyError::ConfigChanged)
+    } else {
+        Ok(())
+    }
+}
+
+/// Read the state of the outputs and CRTCs, and work out the changes that apply a layout.
+fn plan_layout<'a, B: Backend>(
+    conn: &B,
+    res: &GetScreenResourcesCurrentReply,
+    fb_size: &Mode,
+    setup: &HashMap<Output, &MonConfig>,
+    root: Window,
+    settings: &Settings,
+    mut crtc_memory: Option<&mut CrtcMemory>,
+) -> std::result::Result<Plan<'a>, ApplyError> {
+    let (modes, timestamp) = mode_map(conn, root).map_err(ApplyError::ReadFailed)?;
+    let mut free_crtcs: HashSet<_> = res.crtcs.iter().collect();
+    let mut enables = Vec::with_capacity(res.crtcs.len());
+    let mut panning = Vec::with_capacity(res.crtcs.len());
+    let mut early_disables = Vec::new();
+    let mut previous = Vec::with_capacity(res.crtcs.len());
+    let mut transforms = Vec::new();
+    let mut previous_transforms = Vec::new();
+    let mut mm_w = 0;
+    let mut mm_h = 0;
+    let mut outs_in_conf = Vec::with_capacity(setup.len());
+    // The CRTCs of outputs that are turned off are left free, and so are disabled below
+    let lit: Vec<_> = res
+        .outputs
+        .iter()
+        .filter_map(|out| setup.get(out).filter(|conf| !conf.off).map(|&conf| (conf, *out)))
+        .collect();
+    let outputs: Vec<_> = lit.iter().map(|&(_, out)| out).collect();
+    let mut skipped = Vec::new();
+    for ((conf, out), info) in lit.into_iter().zip(conn.output_infos(&outputs, timestamp)) {
+        // One output that can't be read shouldn't keep the others from being configured
+        let info = match info {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("Skipping output {}, which could not be read: {:?}", out, e);
+                skipped.push(out);
+                continue;
+            }
+        };
+        outs_in_conf.push((conf, out, info));
+    }
+    // Every CRTC is read at once, as most are either given an output or disabled below
+    let mut crtc_infos: HashMap<Crtc, _> = res
+        .crtcs
+        .iter()
+        .copied()
+        .zip(conn.crtc_infos(&res.crtcs, timestamp))
+        .collect();
+    // A skipped output keeps its CRTC, rather than having it disabled or given to another
+    for (crtc, info) in crtc_infos.iter() {
+        if matches!(info, Ok(info) if info.outputs.iter().any(|out| skipped.contains(out))) {
+            free_crtcs.remove(crtc);
+        }
+    }
+    let mut crtc_info = |crtc| {
+        crtc_infos
+            .remove(&crtc)
+            .unwrap_or_else(|| conn.crtc_info(crtc, timestamp))
+    };
+    // Outputs pinned to a CRTC take it first. Then, outputs that are already lit keep their
+    // CRTCs before the other outputs are given one, so that the CRTCs of outputs being
+    // disabled, and no others, are left for the outputs being enabled
+    outs_in_conf.sort_by_key(|(conf, _, info)| (conf.crtc.is_none(), info.crtc == 0));
+    // Outputs that show the same picture share a CRTC, when they're able to
+    let mut groups: Vec<CloneGroup> = Vec::with_capacity(outs_in_conf.len());
+    for (conf, out, out_info) in outs_in_conf {
+        let mode = match &conf.modeline {
+            Some(line) => custom_mode(conn, root, res, out, &out_info, line)
+                .map_err(|e| ApplyError::CustomModeFailed(out, e))?,
+            None => {
+                let prefer_refresh = conf.prefer_refresh.unwrap_or(settings.prefer_refresh);
+                find_mode_id(out, &out_info, &modes, &conf.mode, prefer_refresh)?
+            }
+        };
+        match groups.iter_mut().find(|g| g.accepts(conf, mode, out, &out_info)) {
+            Some(group) => group.outputs.push((conf, out, out_info)),
+            None => groups.push(CloneGroup {
+                mode,
+                outputs: vec![(conf, out, out_info)],
+            }),
+        }
+    }
+    groups.reverse();
+    // This loop can't easily be a map, as it needs to be able to use '?', skip outputs, and
+    // split groups
+    while let Some(CloneGroup { mode, outputs }) = groups.pop() {
+        let (conf, out, _) = outputs[0];
+        let infos: Vec<_> = outputs.iter().map(|(_, _, info)| info).collect();
+        let last = crtc_memory
+            .as_ref()
+            .and_then(|m| outputs.iter().find_map(|(_, out, _)| m.get(out).copied()));
+        let dest_crtc = match conf.crtc {
+            Some(index) => pinned_crtc(res, &infos, &mut free_crtcs, out, index)?,
+            None => match allocate_crtc(&infos, &mut free_crtcs, last) {
+                Some(crtc) => crtc,
+                // Clones that can't share a free CRTC may still each be given one
+                None if outputs.len() > 1 => {
+                    let single = |output| CloneGroup {
+                        mode,
+                        outputs: vec![output],
+                    };
+                    groups.extend(outputs.into_iter().rev().map(single));
+                    continue;
+                }
+                None => return Err(ApplyError::NoCrtcAvailable(out)),
+            },
+        };
+        let outs: Vec<Output> = outputs.iter().map(|&(_, out, _)| out).collect();
+        // The CRTC stays out of the free CRTCs, so that it's left alone rather than disabled
+        let crtc_info = match crtc_info(dest_crtc) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!(
+                    "Skipping outputs {:?}, as CRTC {} could not be read: {:?}",
+                    outs, dest_crtc, e
+                );
+                continue;
+            }
+        };
+        for (conf, out, out_info) in outputs.iter() {
+            if let Some(memory) = crtc_memory.as_deref_mut() {
+                memory.insert(*out, dest_crtc);
+            }
+            debug!(
+                "Monitor {} on output {} uses CRTC {} with mode {} ({})",
+                conf.name, out, dest_crtc, mode, conf.mode
+            );
+            //TODO: This is not a correct computation of the screen size
+            mm_w += out_info.mm_width;
+            mm_h += out_info.mm_height;
+        }
+        let Position { x, y } = conf.position;
+        trace!("CRTC {} is currently {:?}", dest_crtc, crtc_info);
+        let wanted = transform(conf);
+        let current = conn.crtc_transform(dest_crtc).map_err(ApplyError::ReadFailed)?;
+        if current != wanted {
+            transforms.push((dest_crtc, wanted));
+            previous_transforms.push((dest_crtc, current));
+        }
+        let shown: HashSet<_> = crtc_info.outputs.iter().collect();
+        let moved = shown != outs.iter().collect();
+        if current != wanted || moved || crtc_differs(&crtc_info, conf, mode) {
+            previous.push(restore_crtc(dest_crtc, &crtc_info, res.config_timestamp));
+            // The screen is resized straight to its final size, so a CRTC that would not fit
+            // within it is turned off until it's reconfigured
+            if crtc_info.mode != 0 && !fits_within(&crtc_info, fb_size) {
+                early_disables.push(disable_crtc(dest_crtc, &crtc_info));
+            }
+            enables.push(SetCrtcConfigRequest {
+                x,
+                y,
+                rotation: rotation(conf),
+                mode,
+                outputs: outs.into(),
+                ..disable_crtc(dest_crtc, &crtc_info)
+            });
+            // The panning area is the mode's, which a transformed picture doesn't cover
+            if wanted != IDENTITY_TRANSFORM {
+                continue;
+            }
+            panning.push(SetPanningRequest {
+                crtc: dest_crtc,
+                timestamp: crtc_info.timestamp,
+                left: x as u16, top: y as u16, width: conf.mode.w, height: conf.mode.h,
+                track_left: x as u16, track_top: y as u16, track_width: conf.mode.w, track_height: conf.mode.h,
+                border_left: 0, border_top: 0, border_right: 0, border_bottom: 0,
+            })
+        }
+    }
+    // If there were CRTCs left over after allocating the next setup, ensure that they are
+    // disabled
+    let mut disables = early_disables;
+    for &crtc in free_crtcs.into_iter() {
+        let info = match crtc_info(crtc) {
+            Ok(info) => info,
+            Err(e) => {
+                warn!("Leaving CRTC {} alone, as it could not be read: {:?}", crtc, e);
+                continue;
+            }
+        };
+        if !info.outputs.is_empty() || info.mode != 0 {
+            previous.push(restore_crtc(crtc, &info, res.config_timestamp));
+            if let Some(memory) = crtc_memory.as_deref_mut() {
+                for &out in info.outputs.iter() {
+                    memory.insert(out, crtc);
+                }
+            }
+            disables.push(disable_crtc(crtc, &info));
+        }
+    }
+    Ok(Plan {
+        enables,
+        panning,
+        transforms,
+        previous,
+        previous_transforms,
+        disables,
+        mm_w,
+        mm_h,
+    })
+}
+
+/// The size of the screen a layout is applied to, in pixels, and in millimeters when the layout
+/// gives it, rather than leaving it to be summed from the monitors' sizes.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScreenSize<'a> {
+    pub(crate) fb: &'a Mode,
+    pub(crate) mm: Option<&'a Mode>,
+}
+
+impl<'a> From<&'a Mode> for ScreenSize<'a> {
+    fn from(fb: &'a Mode) -> Self {
+        ScreenSize { fb, mm: None }
+    }
+}
+
+impl<'a> From<&'a SingleConfig> for ScreenSize<'a> {
+    fn from(layout: &'a SingleConfig) -> Self {
+        ScreenSize {
+            fb: &layout.fb_size,
+            mm: layout.mm.as_ref(),
+        }
+    }
+}
+
+/// Make the current Xorg server match the specified configuration. When another client changes
+/// the configuration first, the layout is rolled back, then planned and applied again from the
+/// new screen resources.
+pub(crate) fn apply_config<B: Backend>(
+    conn: &B,
+    res: &GetScreenResourcesCurrentReply,
+    size: ScreenSize<'_>,
+    setup: HashMap<Output, &MonConfig>,
+    root: Window,
+    settings: &Settings,
+    mut crtc_memory: Option<&mut CrtcMemory>,
+) -> std::result::Result<bool, ApplyError> {
+    let mut attempts = 0;
+    let mut refreshed = None;
+    loop {
+        let res = refreshed.as_ref().unwrap_or(res);
+        let memory = crtc_memory.as_deref_mut();
+        match apply_once(conn, res, size, &setup, root, settings, memory) {
+            Err(e @ ApplyError::ConfigChanged) if attempts < READ_RETRIES => {
+                attempts += 1;
+                warn!("{}, applying the layout again ({}/{})", e, attempts, READ_RETRIES);
+                let current = conn.screen_resources_current(root).map_err(ApplyError::ReadFailed)?;
+                refreshed = Some(current);
+            }
+            applied => return applied,
+        }
+    }
+}
+
+/// Plan a layout from the state of the outputs and CRTCs of `res`, and apply it, rolling it back
+/// when it fails part way through.
+fn apply_once<B: Backend>(
+    conn: &B,
+    res: &GetScreenResourcesCurrentReply,
+    size: ScreenSize<'_>,
+    setup: &HashMap<Output, &MonConfig>,
+    root: Window,
+    settings: &Settings,
+    crtc_memory: Option<&mut CrtcMemory>,
+) -> std::result::Result<bool, ApplyError> {
+    let primary: Option<Output> = setup
+        .iter()
+        .find(|(_, c)| c.primary)
+        .map(|(o, _)| *o);
+    let fb_size = size.fb;
+    let Plan {
+        mut enables,
+        panning,
+        transforms,
+        previous,
+        previous_transforms,
+        disables,
+        mm_w,
+        mm_h,
+    } = plan_layout(conn, res, fb_size, setup, root, settings, crtc_memory)?;
+    let (mm_w, mm_h) = size.mm.map_or((mm_w, mm_h), |mm| (mm.w.into(), mm.h.into()));
+    let previous_size = conn.screen_size(root).map_err(ApplyError::ReadFailed)?;
+    let previous_mm = conn.screen_mm(root).map_err(ApplyError::ReadFailed)?;
+    // A layout that gives the screen's physical size changes it, even at the same pixel size
+    let mm_differs = size.mm.is_some_and(|mm| &previous_mm != mm);
+    // A layout that turns every output off keeps the current screen size
+    let fb_size = if fb_size.w == 0 || fb_size.h == 0 {
+        &previous_size
+    } else {
+        fb_size
+    };
+    let previous_primary = conn.output_primary(root).map_err(ApplyError::ReadFailed)?;
+    // The CRTC each enabled output is moved onto, which its ICC profile's gamma is loaded into
+    let assigned: HashMap<Output, Crtc> = enables
+        .iter()
+        .flat_map(|req| req.outputs.iter().map(move |&out| (out, req.crtc)))
+        .collect();
     if disables.is_empty()
         && enables.is_empty()
-        && &current == fb_size
-        && primary == cur_primary
+        && &previous_size == fb_size
+        && !mm_differs
+        && (primary.is_none() || primary == Some(previous_primary))
     {
-        Ok(false)
-    } else {
+        // The monitors' other settings may still differ, such as after the config is edited
+        configure_outputs(conn, root, res, setup, fb_size, &assigned)
+            .map_err(ApplyError::OutputSettingsFailed)?;
+        return Ok(false);
+    }
+    // Should the layout fail, the CRTCs it lights are turned off again, as their outputs may
+    // have come from the CRTCs that the previous configuration gives back to them
+    let lit: Vec<SetCrtcConfigRequest<'static>> = enables
+        .iter()
+        .map(|req| SetCrtcConfigRequest {
+            crtc: req.crtc,
+            timestamp: CURRENT_TIME,
+            config_timestamp: res.config_timestamp,
+            x: req.x,
+            y: req.y,
+            mode: 0,
+            rotation: req.rotation,
+            outputs: Vec::new().into(),
+        })
+        .collect();
+    let applied = (|| -> std::result::Result<(), ApplyError> {
+        if &previous_size == fb_size {
+            refresh_timestamps(conn, root, &mut enables).map_err(ApplyError::ReadFailed)?;
+            set_transforms(conn, &transforms).map_err(ApplyError::SendFailed)?;
+            // Without a resize, disabling and enabling CRTCs is a single batch
+            let mut batch = disables;
+            batch.extend(enables);
+            if !batch.is_empty() {
+                batch_config(conn, batch, Some(panning))?;
+            }
+            if mm_differs {
+                info!("Setting Screen {} Size to {}mmx{}mm", root, mm_w, mm_h);
+                conn.set_screen_size(root, fb_size.w, fb_size.h, mm_w, mm_h)
+                    .map_err(|e| ApplyError::ResizeFailed(fb_size.clone(), e))?;
+            }
+            return Ok(());
+        }
         // First, we disable any CTRCs that must be disabled
         if !disables.is_empty() {
             info!("Disabling CRTCs {:?}", disables);
             batch_config(conn, disables, None)?;
         }
-        // Then we change the screen size to be large enough for both configuration
-        if current != current.union(fb_size) {
-            current = current.union(fb_size);
-            info!(
-                "Before Config - Setting Screen {} Size to {}x{} {}mmx{}mm",
-                root, current.w, current.h, mm_w, mm_h
-            );
-            conn.randr_set_screen_size(root, current.w, current.h, mm_w, mm_h)
-                .into_diagnostic()?
-                .check()
-                .into_diagnostic()?;
-        }
+        // Then, as every CRTC that's still lit fits within both the current and the final
+        // screen, the screen changes size once
+        info!(
+            "Setting Screen {} Size to {}x{} {}mmx{}mm",
+            root, fb_size.w, fb_size.h, mm_w, mm_h
+        );
+        resize_screen(conn, root, fb_size, mm_w, mm_h)
+            .map_err(|e| ApplyError::ResizeFailed(fb_size.clone(), e))?;
         // Finally we enable and change modes of CRTCs
-        batch_config(conn, enables, Some(panning))?;
-        // Lastly we change the screen size to be the correct size for the final config
-        if &current != fb_size {
-            conn.randr_set_screen_size(root, fb_size.w, fb_size.h, mm_w, mm_h)
-                .into_diagnostic()?
-                .check()
-                .into_diagnostic()?;
-            info!(
-                "After Config - Setting Screen Size to {}x{}",
-                fb_size.w, fb_size.h
-            );
+        refresh_timestamps(conn, root, &mut enables).map_err(ApplyError::ReadFailed)?;
+        set_transforms(conn, &transforms).map_err(ApplyError::SendFailed)?;
+        batch_config(conn, enables, Some(panning))
+    })();
+    if let Err(e) = applied {
+        error!("Applying layout failed, restoring the previous configuration");
+        let snapshot = Snapshot {
+            crtcs: previous,
+            transforms: previous_transforms,
+            size: previous_size.clone(),
+            mm: previous_mm,
+            primary: previous_primary,
+        };
+        if let Err(rollback_err) = snapshot.roll_back(conn, root, lit, fb_size) {
+            error!("Restoring the previous configuration failed: {:?}", rollback_err);
+        }
+        return Err(e);
+    }
+    // Set the primary when we have to
+    if let Some(out) = primary {
+        conn.set_output_primary(root, out)
+            .map_err(|e| ApplyError::PrimaryFailed(out, e))?;
+    }
+    configure_outputs(conn, root, res, setup, fb_size, &assigned)
+        .map_err(ApplyError::OutputSettingsFailed)?;
+    Ok(true)
+}
+
+/// Apply the settings of each monitor other than its CRTC's: its backlight, variable refresh
+/// rate, scaling mode, output properties, touchscreen and ICC profile.
+fn configure_outputs<B: Backend>(
+    conn: &B,
+    root: Window,
+    res: &GetScreenResourcesCurrentReply,
+    setup: &HashMap<Output, &MonConfig>,
+    fb_size: &Mode,
+    assigned: &HashMap<Output, Crtc>,
+) -> Result<()> {
+    set_backlights(conn, setup)?;
+    set_vrrs(conn, setup)?;
+    set_scaling_modes(conn, setup)?;
+    set_output_properties(conn, setup)?;
+    map_touch_screens(conn, setup, fb_size, res.config_timestamp)?;
+    load_icc_profiles(conn, root, res, setup, assigned)
+}
+
+/// The `exec` commands of the lit monitors in `setup`, with each `{output}` replaced by the name
+/// of the monitor's output, sorted so that they run in a stable order.
+pub(crate) fn monitor_execs<B: Backend>(
+    conn: &B,
+    setup: &HashMap<Output, &MonConfig>,
+    timestamp: Timestamp,
+) -> Vec<String> {
+    let mut execs = Vec::new();
+    for (&out, conf) in setup.iter() {
+        let command = match &conf.exec {
+            Some(command) if !conf.off => command,
+            _ => continue,
+        };
+        match conn.output_info(out, timestamp) {
+            Ok(info) => {
+                let name = String::from_utf8_lossy(&info.name);
+                execs.push(command.replace("{output}", &name));
+            }
+            Err(e) => error!("Not running {} for output {}: {:?}", command, out, e),
+        }
+    }
+    execs.sort();
+    execs
+}
+
+/// Run a hook command, such as the `--post-resize-exec` command, through the shell with the
+/// environment variables `env`, without waiting for it to finish.
+pub(crate) fn run_hook(command: &str, env: &[(&str, &str)]) {
+    let mut sh = Command::new("sh");
+    sh.arg("-c").arg(command).stdin(Stdio::null());
+    sh.envs(env.iter().copied());
+    match sh.spawn() {
+        Ok(mut child) => {
+            // Reap the command when it exits, so that it isn't left a zombie
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => error!("Could not run {}: {}", command, e),
+    }
+}
+
+/// Bring the timestamps of a batch of requests up to date, as the requests before them may have
+/// changed the configuration, and the server rejects requests with stale timestamps.
+fn refresh_timestamps<B: Backend>(
+    conn: &B,
+    root: Window,
+    batch: &mut [SetCrtcConfigRequest],
+) -> Result<()> {
+    let (timestamp, config_timestamp) = conn.screen_timestamps(root)?;
+    for req in batch.iter_mut() {
+        req.timestamp = timestamp;
+        req.config_timestamp = config_timestamp;
+    }
+    Ok(())
+}
+
+/// The configuration of every CRTC, the screen size and the primary output, taken before a
+/// layout is applied so that they can be restored.
+pub(crate) struct Snapshot {
+    crtcs: Vec<SetCrtcConfigRequest<'static>>,
+    transforms: Vec<(Crtc, Transform)>,
+    size: Mode,
+    /// The physical size of the screen, in millimeters
+    mm: Mode,
+    primary: Output,
+}
+
+impl Snapshot {
+    pub(crate) fn take<B: Backend>(
+        conn: &B,
+        res: &GetScreenResourcesCurrentReply,
+        root: Window,
+    ) -> Result<Self> {
+        let mut crtcs = Vec::with_capacity(res.crtcs.len());
+        let mut transforms = Vec::with_capacity(res.crtcs.len());
+        for &crtc in res.crtcs.iter() {
+            let info = conn.crtc_info(crtc, res.config_timestamp)?;
+            crtcs.push(restore_crtc(crtc, &info, res.config_timestamp));
+            transforms.push((crtc, conn.crtc_transform(crtc)?));
+        }
+        Ok(Self {
+            crtcs,
+            transforms,
+            size: conn.screen_size(root)?,
+            mm: conn.screen_mm(root)?,
+            primary: conn.output_primary(root)?,
+        })
+    }
+
+    /// Put the CRTCs, their transforms, the screen size and primary output back as they were.
+    /// Every lit CRTC is turned off first, so that outputs can return to CRTCs that others have
+    /// taken since.
+    pub(crate) fn restore<B: Backend>(mut self, conn: &B, root: Window) -> Result<()> {
+        let current = conn.screen_size(root)?;
+        let mut lit = Vec::new();
+        for req in self.crtcs.iter() {
+            let info = conn.crtc_info(req.crtc, req.config_timestamp)?;
+            if info.mode != 0 {
+                lit.push(disable_crtc(req.crtc, &info));
+            }
+        }
+        let mut transforms = Vec::new();
+        for &(crtc, transform) in self.transforms.iter() {
+            if conn.crtc_transform(crtc)? != transform {
+                transforms.push((crtc, transform));
+            }
+        }
+        self.transforms = transforms;
+        self.roll_back(conn, root, lit, &current)
+    }
+
+    /// Return to the snapshot from a screen of size `attempted`, turning off the CRTCs of `lit`
+    /// before the snapshot's CRTCs are lit again.
+    fn roll_back<B: Backend>(
+        self,
+        conn: &B,
+        root: Window,
+        mut lit: Vec<SetCrtcConfigRequest>,
+        attempted: &Mode,
+    ) -> Result<()> {
+        if !lit.is_empty() {
+            refresh_timestamps(conn, root, &mut lit)?;
+            batch_config(conn, lit, None)?;
+        }
+        set_transforms(conn, &self.transforms)?;
+        // Some CRTCs may be configured for either size at this point, so the screen must be
+        // large enough for both while they are restored
+        let both = self.size.union(attempted);
+        let (mm_w, mm_h) = (self.mm.w.into(), self.mm.h.into());
+        conn.set_screen_size(root, both.w, both.h, mm_w, mm_h)?;
+        let crtcs: Vec<_> = self.crtcs.into_iter().filter(|req| req.mode != 0).collect();
+        if !crtcs.is_empty() {
+            batch_config(conn, crtcs, None)?;
+        }
+        if both != self.size {
+            conn.set_screen_size(root, self.size.w, self.size.h, mm_w, mm_h)?;
+        }
+        conn.set_output_primary(root, self.primary)
+    }
+}
+
+/// Replace the contents of the status file with the layout name. The name is written to a
+/// temporary file that's renamed over the status file, so readers never see a partial write.
+fn write_status(path: &Path, name: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, format!("{}\n", name))?;
+    std::fs::rename(&tmp, path)
+}
+
+/// A layout made up from the modes of the connected outputs, such as one that shows the same
+/// picture on every connected output.
+struct Generated {
+    fb_size: Mode,
+    setup: HashMap<Output, MonConfig>,
+}
+
+impl Generated {
+    /// Apply this layout as though it matched the connected monitors, under the name `name`.
+    fn matched<'a>(&'a self, name: &'a String) -> Matched<'a> {
+        Matched {
+            name,
+            size: (&self.fb_size).into(),
+            setup: self.setup.iter().map(|(&out, conf)| (out, conf)).collect(),
+            serials: Vec::new(),
+            dpi: None,
+            tiles: Tiles::new(),
         }
-        // Set the primary when we have to
-        if let Some(out) = primary {
-            conn.randr_set_output_primary(root, out)
-                .into_diagnostic()?
-                .check()
-                .into_diagnostic()?;
+    }
+}
+
+/// The configuration of an output in a generated layout, lit in `mode` at `position`.
+fn generated_monitor(name: String, mode: Mode, position: Position) -> MonConfig {
+    MonConfig {
+        name,
+        mode,
+        position,
+        primary: false,
+        rot: None,
+        reflect: None,
+        output: None,
+        modeline: None,
+        transform: None,
+        backlight: None,
+        vrr: None,
+        scaling_mode: None,
+        prefer_refresh: None,
+        crtc: None,
+        touch_device: None,
+        properties: BTreeMap::new(),
+        icc: None,
+        exec: None,
+        off: false,
+    }
+}
+
+/// Mirror every output in `outputs` at 0,0, in the largest mode that all of them support.
+fn mirror_layout<B: Backend>(
+    conn: &B,
+    res: &GetScreenResourcesCurrentReply,
+    outputs: &[Output],
+) -> Result<Generated> {
+    let sizes: HashMap<u32, Mode> = res
+        .modes
+        .iter()
+        .map(|mi| {
+            (
+                mi.id,
+                Mode {
+                    w: mi.width,
+                    h: mi.height,
+                },
+            )
+        })
+        .collect();
+    let mut common: Option<HashSet<Mode>> = None;
+    for &out in outputs {
+        let out_info = conn.output_info(out, res.config_timestamp)?;
+        let supported: HashSet<Mode> = out_info
+            .modes
+            .iter()
+            .filter_map(|id| sizes.get(id).cloned())
+            .collect();
+        common = Some(match common {
+            Some(common) => common.intersection(&supported).cloned().collect(),
+            None => supported,
+        });
+    }
+    let fb_size = common
+        .unwrap_or_default()
+        .into_iter()
+        .max_by_key(|m| (u32::from(m.w) * u32::from(m.h), m.w))
+        .ok_or(Error::NoCommonMode)
+        .into_diagnostic()?;
+    let setup = outputs
+        .iter()
+        .map(|&out| {
+            let name = format!("mirror of output {}", out);
+            (out, generated_monitor(name, fb_size.clone(), Position { x: 0, y: 0 }))
+        })
+        .collect();
+    Ok(Generated { fb_size, setup })
+}
+
+/// Place every output in `outputs` side by side, from left to right in the order of their
+/// names, each in its preferred mode, or its first mode when it prefers none. Outputs without
+/// modes are left out.
+fn side_by_side_layout<B: Backend>(
+    conn: &B,
+    res: &GetScreenResourcesCurrentReply,
+    outputs: &[Output],
+) -> Result<Generated> {
+    let mut named = Vec::with_capacity(outputs.len());
+    for &out in outputs {
+        let info = conn.output_info(out, res.config_timestamp)?;
+        if let Some(mode) = preferred_mode(res, &info) {
+            named.push((String::from_utf8_lossy(&info.name).into_owned(), out, mode));
         }
-        Ok(true)
     }
+    named.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut fb_size = Mode { w: 0, h: 0 };
+    let mut setup = HashMap::new();
+    for (name, out, mode) in named {
+        let x = i16::try_from(fb_size.w)
+            .map_err(|_| Error::TooWide)
+            .into_diagnostic()?;
+        fb_size.w = fb_size.w.checked_add(mode.w).ok_or(Error::TooWide).into_diagnostic()?;
+        fb_size.h = fb_size.h.max(mode.h);
+        setup.insert(out, generated_monitor(name, mode, Position { x, y: 0 }));
+    }
+    Ok(Generated { fb_size, setup })
 }
 
-/// Called for each screen change notificaiton. Detects connected monitors and switches
-/// to the appropriate config.
-fn switch_setup<C: Connection>(
-    config: &Config,
-    conn: &C,
-    edid: Atom,
+/// The preferred mode of an output, which it lists first, or its first mode when it prefers
+/// none. None when it has no modes.
+fn preferred_mode(res: &GetScreenResourcesCurrentReply, info: &GetOutputInfoReply) -> Option<Mode> {
+    let id = *info.modes.first()?;
+    let mi = res.modes.iter().find(|mi| mi.id == id)?;
+    Some(Mode {
+        w: mi.width,
+        h: mi.height,
+    })
+}
+
+/// Light only one of `outputs`, as the primary output at 0,0 in its preferred mode, and turn
+/// the others off. The lit output is the current primary output, or else the panel built into
+/// a laptop, or else the first output by name, among those with a mode.
+fn safe_layout<B: Backend>(
+    conn: &B,
     root: Window,
-    force_print: bool,
-) -> () {
+    res: &GetScreenResourcesCurrentReply,
+    outputs: &[Output],
+) -> Result<Generated> {
+    let primary = conn.output_primary(root)?;
+    let mut named = Vec::with_capacity(outputs.len());
+    for &out in outputs {
+        let info = conn.output_info(out, res.config_timestamp)?;
+        let name = String::from_utf8_lossy(&info.name).into_owned();
+        named.push((name, out, preferred_mode(res, &info)));
+    }
+    let (lit_name, lit, fb_size) = named
+        .iter()
+        .filter_map(|(name, out, mode)| Some((name, *out, mode.clone()?)))
+        .min_by_key(|&(name, out, _)| {
+            let built_in = BUILT_IN_CONNECTORS.contains(&connector_type(name));
+            (out != primary, !built_in, name.clone())
+        })
+        .ok_or(Error::NoSafeOutput)
+        .into_diagnostic()?;
+    info!("The safe layout lights only output {}", lit_name);
+    let setup = named
+        .iter()
+        .map(|(name, out, _)| {
+            let origin = Position { x: 0, y: 0 };
+            let mut conf = generated_monitor(name.clone(), fb_size.clone(), origin);
+            if *out == lit {
+                conf.primary = true;
+            } else {
+                conf.mode = Mode { w: 0, h: 0 };
+                conf.off = true;
+            }
+            (*out, conf)
+        })
+        .collect();
+    Ok(Generated { fb_size, setup })
+}
+
+/// Put back the configuration saved in `snapshot` after the layout `name` failed to apply, so
+/// that the screen is left as it was, rather than partly changed.
+fn restore_previous<B: Backend>(conn: &B, root: Window, snapshot: Snapshot, name: &str) {
+    match snapshot.restore(conn, root) {
+        Ok(()) => warn!(
+            "Layout {} could not be applied, so the previous configuration was retained",
+            name
+        ),
+        Err(e) => error!("Restoring the previous configuration failed: {:?}", e),
+    }
+}
+
+/// Called for each screen change notificaiton. Detects the monitors connected to the screen
+/// numbered `screen` and switches to the appropriate config, or to the layout called `named`
+/// when it's given.
+/// Record the connected outputs of a screen as those a layout is tried for, returning false when
+/// a layout was already tried for them. A layout that fails isn't tried again until the outputs
+/// change, as the RRScreenChangeNotify events of its partial apply and of its rollback would
+/// otherwise apply it, and fail, again and again.
+fn try_outputs(last_outputs: &mut Option<ConnectedOutputs>, outputs: ConnectedOutputs) -> bool {
+    if last_outputs.as_ref() == Some(&outputs) {
+        return false;
+    }
+    *last_outputs = Some(outputs);
+    true
+}
+
+fn switch_setup(state: &mut DaemonState, screen: usize, force_print: bool, named: Option<&str>) {
+    let DaemonState {
+        status_file,
+        json,
+        keep_previous,
+        safe,
+        events,
+        #[cfg(feature = "dbus")]
+        dbus,
+        selector,
+        post_resize_exec,
+        conn,
+        atom_edid,
+        screens,
+    } = state;
+    let several = screens.len() > 1;
+    let Screen {
+        number,
+        root,
+        config,
+        crtc_memory,
+        created_modes,
+        last_outputs,
+    } = &mut screens[screen];
+    let (conn, edid, root) = (&*conn, *atom_edid, *root);
+    if several {
+        debug!("Laying out screen {}", number);
+    }
     let res = match get_outputs(conn, root) {
         Ok(o) => o,
         Err(e) => {
@@ -365,18 +2165,187 @@ fn switch_setup<C: Connection>(
             return;
         }
     };
-    match get_config(&config, conn, &res.outputs, edid) {
-        Some((name, fb_size, setup)) => match apply_config(conn, &res, fb_size, setup, root) {
-            Ok(changed) => {
-                if changed || force_print {
-                    println!("{}", name)
+    let connected = match get_connected_outputs(conn, &res) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("{:?}", e);
+            return;
+        }
+    };
+    let outputs = ConnectedOutputs::new(&res, &connected);
+    if !try_outputs(last_outputs, outputs) && named.is_none() {
+        debug!("The connected outputs are unchanged");
+        return;
+    }
+    let generated;
+    let safe_name = SAFE_LAYOUT.to_string();
+    let timestamp = res.config_timestamp;
+    let selector = *selector;
+    let matched = if *safe || named == Some(SAFE_LAYOUT) {
+        match safe_layout(conn, root, &res, &connected) {
+            Ok(layout) => {
+                generated = layout;
+                Some(generated.matched(&safe_name))
+            }
+            Err(e) => {
+                error!(layout = SAFE_LAYOUT, status = "failed", "{:?}", e);
+                return;
+            }
+        }
+    } else {
+        match get_config(config, conn, &connected, edid, timestamp, named, selector) {
+            Ok(matched) => Some(matched),
+            // No monitors are connected for a moment while docking, and there's nothing to
+            // configure
+            Err(e) if connected.is_empty() && named.is_none() => {
+                debug!("{}, but no monitors are connected", e);
+                return;
+            }
+            Err(e) => match (config.mirror_all.as_ref().or(config.side_by_side.as_ref()), named) {
+                (Some(name), None) => {
+                    let made = match config.mirror_all {
+                        Some(_) => mirror_layout(conn, &res, &connected),
+                        None => side_by_side_layout(conn, &res, &connected),
+                    };
+                    match made {
+                        Ok(layout) => {
+                            generated = layout;
+                            info!("{}, so applying {} to {} outputs", e, name, connected.len());
+                            Some(generated.matched(name))
+                        }
+                        Err(e) => {
+                            error!(layout = %name, status = "failed", "{:?}", e);
+                            return;
+                        }
+                    }
+                }
+                (_, Some(_)) => {
+                    error!(status = "failed", "{}", e);
+                    return;
+                }
+                (None, None) => {
+                    debug!("{}", e);
+                    None
+                }
+            },
+        }
+    };
+    match matched {
+        Some(Matched {
+            name,
+            size,
+            setup,
+            serials,
+            dpi,
+            tiles,
+        }) => {
+            let tiled;
+            let setup = if tiles.is_empty() {
+                setup
+            } else {
+                tiled = tile_setup(&setup, &tiles);
+                tiled.iter().map(|(&out, conf)| (out, conf)).collect()
+            };
+            let execs = monitor_execs(conn, &setup, timestamp);
+            let applied = setup.clone();
+            let before = post_resize_exec.and_then(|_| conn.screen_size(root).ok());
+            let snapshot = if *keep_previous {
+                Snapshot::take(conn, &res, root)
+                    .map_err(|e| error!("Could not save the configuration: {:?}", e))
+                    .ok()
+            } else {
+                None
+            };
+            let memory = crtc_memory.as_mut();
+            match apply_config(conn, &res, size, setup, root, &config.settings, memory) {
+                Ok(changed) => {
+                    if let Err(e) = clean_up_modes(conn, root, &res, &applied, created_modes) {
+                        error!("Could not clean up custom modes: {:?}", e);
+                    }
+                    if let (Some(command), Some(before)) = (*post_resize_exec, before) {
+                        match conn.screen_size(root) {
+                            Ok(after) if after != before => {
+                                info!("The screen changed size to {}, running {}", after, command);
+                                run_hook(command, &[]);
+                            }
+                            Ok(_) => (),
+                            Err(e) => error!("{:?}", e),
+                        }
+                    }
+                    let status = if changed { "applied" } else { "unchanged" };
+                    info!(
+                        layout = %name,
+                        monitors = ?serials,
+                        status,
+                        "Monitor configuration: {}",
+                        name
+                    );
+                    #[cfg(feature = "dbus")]
+                    if let Some(dbus) = dbus {
+                        if let Err(e) = dbus.set_current_layout(name) {
+                            error!("Could not update the D-Bus CurrentLayout property: {}", e);
+                        }
+                    }
+                    if let Err(e) = publish_layout(conn, root, Some(name)) {
+                        error!("Could not set {}: {:?}", ACTIVE_LAYOUT, e);
+                    }
+                    if changed || force_print {
+                        // With JSON logs, the event above is the status line
+                        if !*json {
+                            println!("{}", name);
+                        }
+                        if let Some(path) = status_file {
+                            if let Err(e) = write_status(path, name) {
+                                error!("Could not write status file {}: {}", path.display(), e);
+                            }
+                        }
+                        if let Some(events) = events {
+                            events.broadcast(name);
+                        }
+                        for command in execs.iter() {
+                            info!("Running {}", command);
+                            run_hook(command, &[]);
+                        }
+                        if let Some(dpi) = dpi {
+                            info!("Setting Xft.dpi to {}", dpi);
+                            if let Err(e) = set_resource(conn, root, "Xft.dpi", &dpi.to_string())
+                            {
+                                error!("{:?}", e);
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        layout = %name,
+                        monitors = ?serials,
+                        status = "failed",
+                        "{:?}",
+                        e
+                    );
+                    if let Some(snapshot) = snapshot {
+                        restore_previous(conn, root, snapshot, name);
+                    }
+                    if let Some(command) = &config.on_error_exec {
+                        let message = e.to_string();
+                        let env = [
+                            ("MONITOR_LAYOUT_NAME", name.as_str()),
+                            ("MONITOR_LAYOUT_ERROR", message.as_str()),
+                        ];
+                        run_hook(command, &env);
+                    }
                 }
             }
-            Err(e) => error!("{:?}", e),
-        },
-        None => error!(
-            "Error: Monitor change indicated, and the connected monitors did not match a config"
-        ),
+        }
+        None => {
+            if let Err(e) = publish_layout(conn, root, None) {
+                error!("Could not delete {}: {:?}", ACTIVE_LAYOUT, e);
+            }
+            error!(
+                status = "unmatched",
+                "Error: Monitor change indicated, and the connected monitors did not match a config"
+            )
+        }
     }
 }
 
@@ -388,32 +2357,1749 @@ fn setup_notify<C: Connection>(conn: &C, root: Window, mask: NotifyMask) -> Resu
     Ok(())
 }
 
+/// Connect to the X server, and ask it to report changes to the monitors of each of its screens.
+fn connect_notified() -> Result<(RustConnection, Atom)> {
+    let (conn, _) = RustConnection::connect(None).into_diagnostic()?;
+    let version = randr_version(&conn)?;
+    if version < TILE_RANDR_VERSION {
+        warn!(
+            "RandR {}.{} does not describe tiled monitors, so each tile is placed on its own",
+            version.0, version.1
+        );
+    }
+    let atom_edid = edid_atom(&conn)?;
+    let notify_mask =
+        NotifyMask::SCREEN_CHANGE | NotifyMask::OUTPUT_CHANGE | NotifyMask::CRTC_CHANGE;
+    for screen in conn.setup().roots.iter() {
+        setup_notify(&conn, screen.root, notify_mask)?;
+    }
+    Ok((conn, atom_edid))
+}
+
+/// Block until at least one of the file descriptors is readable, or the timeout passes,
+/// returning which ones are.
+pub(crate) fn wait_readable(fds: &[RawFd], timeout: Option<Duration>) -> io::Result<Vec<bool>> {
+    let mut pollfds: Vec<_> = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    // Rounded up, so that the timeout has passed when poll returns
+    let millis = match timeout {
+        Some(t) => t.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+    loop {
+        let nfds = pollfds.len() as libc::nfds_t;
+        let res = unsafe { libc::poll(pollfds.as_mut_ptr(), nfds, millis) };
+        if res >= 0 {
+            return Ok(pollfds.iter().map(|p| p.revents != 0).collect());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Ask the X server to probe its outputs, for drivers that don't report hotplugs, and match the
+/// monitors when the connected outputs changed.
+fn rescan(state: &mut DaemonState) {
+    debug!("Probing the outputs");
+    for screen in 0..state.screens.len() {
+        // Unlike the current resources, reading the resources makes the server probe the
+        // outputs
+        if let Err(e) = state.conn.screen_resources(state.screens[screen].root) {
+            error!("{:?}", e);
+            continue;
+        }
+        switch_setup(state, screen, false, None);
+    }
+}
+
+/// Whether the environment asks for the safe layout, with MONITOR_LAYOUT_SAFE set to anything
+/// but "" or "0".
+fn safe_requested() -> bool {
+    std::env::var_os(SAFE_ENV).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+/// The screens that a request to apply the layout called `named` applies to: those that have
+/// it, or every screen when it's not named or it's the safe layout. When no screen has it, the
+/// first screen reports that it's unknown.
+#[cfg(feature = "dbus")]
+fn requested_screens(state: &DaemonState, named: Option<&str>) -> Vec<usize> {
+    let screens = state.screens.iter().enumerate();
+    let with_layout: Vec<_> = match named {
+        Some(name) if name != SAFE_LAYOUT => screens
+            .filter(|(_, screen)| screen.config.named_layout(name).is_some())
+            .map(|(i, _)| i)
+            .collect(),
+        _ => screens.map(|(i, _)| i).collect(),
+    };
+    if with_layout.is_empty() {
+        vec![0]
+    } else {
+        with_layout
+    }
+}
+
+/// How often the daemon checks for a configuration that doesn't exist yet
+const CONFIG_POLL: Duration = Duration::from_secs(1);
+
+/// Wait for the configuration at `path` to appear, and read it. Returns None when a signal to
+/// shut down arrives on `signals` first. A configuration that appears but can't be read is
+/// reported, and read again once it's modified.
+fn wait_for_config(path: &str, signals: RawFd) -> Result<Option<Config>> {
+    warn!("Configuration {} does not exist yet, waiting for it to appear", path);
+    let mut tried = None;
+    loop {
+        if wait_readable(&[signals], Some(CONFIG_POLL)).into_diagnostic()?[0] {
+            return Ok(None);
+        }
+        let modified = match std::fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if tried == Some(modified) {
+            continue;
+        }
+        tried = Some(modified);
+        match Config::from_path(path) {
+            Ok(config) => {
+                info!("Configuration {} appeared", path);
+                return Ok(Some(config));
+            }
+            Err(e) => error!("Configuration {} can't be read: {}", path, e),
+        }
+    }
+}
+
 pub fn daemon(args: &ArgMatches<'_>) -> Result<()> {
-    let config = check(args)?;
-    if !args.is_present("check") {
-        let (conn, screen_num) = connect(None).into_diagnostic()?;
-        let setup = conn.setup();
-        let atom_edid = edid_atom(&conn)?;
-        let root = setup.roots[screen_num].root;
-        let notify_mask =
-            NotifyMask::SCREEN_CHANGE | NotifyMask::OUTPUT_CHANGE | NotifyMask::CRTC_CHANGE;
-        setup_notify(&conn, root, notify_mask)?;
-        switch_setup(&config, &conn, atom_edid, root, true);
+    // A configuration that doesn't exist yet, such as one written after login by a declarative
+    // setup, is waited for, unless it's only validated. Its path is kept in place of it.
+    let config = match args.value_of("config") {
+        Some(path) if !args.is_present("validate") && !Path::new(path).exists() => Err(path),
+        Some(_) => Ok(load_config(args)?),
+        None => {
+            warn!(
+                "No configuration given, so every output is placed side by side. Run \
+                 `monitor-layout print-edids --with-layout` to write a configuration"
+            );
+            Ok(Config::built_in())
+        }
+    };
+    let poll_interval = match args.value_of("poll-interval") {
+        Some(_) => match value_t!(args, "poll-interval", u64) {
+            Ok(0) => return Err(Error::ZeroPollInterval).into_diagnostic(),
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(e) => e.exit(),
+        },
+        None => None,
+    };
+    if let (true, Ok(config)) = (args.is_present("validate"), &config) {
+        println!(
+            "{} is valid, with {} monitors and {} layouts",
+            args.value_of("config").unwrap_or_default(),
+            config.monitors.len(),
+            config.layouts.len()
+        );
+    } else {
+        // Another daemon is checked for before detaching, so that the error is printed on the
+        // terminal.
+        if let Some(path) = args.value_of_os("pid-file") {
+            if let Some(pid) = running_pid(Path::new(path)).into_diagnostic()? {
+                if !args.is_present("replace") {
+                    return Err(Error::AlreadyRunning(pid)).into_diagnostic();
+                }
+                info!("Replacing the daemon running with PID {}", pid);
+                if !stop(pid, Duration::from_secs(5)).into_diagnostic()? {
+                    return Err(Error::DidNotExit(pid)).into_diagnostic();
+                }
+            }
+        }
+        let mut detached = if args.is_present("daemonize") {
+            Some(daemonize().into_diagnostic()?)
+        } else {
+            None
+        };
+        let _pid_file = args
+            .value_of_os("pid-file")
+            .map(|path| PidFile::create(Path::new(path)))
+            .transpose()
+            .into_diagnostic()?;
+        // Signals are delivered as writes to this socket, so that they wake the event loop
+        // without interrupting a layout change.
+        let (signals, sig_write) = UnixStream::pair().into_diagnostic()?;
+        for &sig in &[SIGTERM, SIGINT] {
+            pipe::register(sig, sig_write.try_clone().into_diagnostic()?).into_diagnostic()?;
+        }
+        let config = match config {
+            Ok(config) => config,
+            Err(path) => {
+                // The daemon has started, though it has nothing to apply yet
+                if let Some(detached) = detached.take() {
+                    detached.ready().into_diagnostic()?;
+                }
+                match wait_for_config(path, signals.as_raw_fd())? {
+                    Some(config) => config,
+                    None => {
+                        info!("Shutting down");
+                        return Ok(());
+                    }
+                }
+            }
+        };
+        let (conn, atom_edid) = connect_notified()?;
+        let screens = Screen::all(&conn, &config, args.is_present("remember-crtcs"));
+        let mut state = DaemonState {
+            status_file: args.value_of_os("status-file").map(Path::new),
+            json: args.value_of("log-format") == Some("json"),
+            keep_previous: args.is_present("keep-previous"),
+            safe: safe_requested(),
+            events: args
+                .value_of_os("event-socket")
+                .map(|path| EventSocket::bind(Path::new(path)))
+                .transpose()
+                .into_diagnostic()?,
+            #[cfg(feature = "dbus")]
+            dbus: None,
+            selector: args.value_of_os("selector").map(Path::new),
+            post_resize_exec: args.value_of("post-resize-exec"),
+            conn,
+            atom_edid,
+            screens,
+        };
+        #[cfg(feature = "dbus")]
+        if args.is_present("dbus") {
+            let grouped = config.groups.values().chain(config.lid_layouts.values());
+            let grouped = grouped.flat_map(|g| g.values());
+            let layouts = config.layouts.values().chain(grouped).map(|l| l.name.clone());
+            let layouts = layouts.chain(iter::once(SAFE_LAYOUT.to_string())).collect();
+            state.dbus = Some(DbusService::start(layouts).into_diagnostic()?);
+        }
+        if let Some(detached) = detached {
+            detached.ready().into_diagnostic()?;
+        }
+        if !args.is_present("no-initial-apply") {
+            state.apply_matching()?;
+        }
+        let mut fds = vec![state.as_raw_fd(), signals.as_raw_fd()];
+        let mut add_fd = |fd: Option<RawFd>| {
+            fds.extend(fd);
+            fd.map(|_| fds.len() - 1)
+        };
+        let events_fd = add_fd(state.events.as_ref().map(AsRawFd::as_raw_fd));
+        #[cfg(feature = "dbus")]
+        let dbus_fd = add_fd(state.dbus.as_ref().map(AsRawFd::as_raw_fd));
+        let mut next_scan = poll_interval.map(|interval| Instant::now() + interval);
         loop {
-            match conn.wait_for_event() {
-                Ok(Event::RandrScreenChangeNotify(_)) => {
-                    switch_setup(&config, &conn, atom_edid, root, false)
+            poll_and_handle(&mut state)?;
+            let timeout = next_scan.map(|at| at.saturating_duration_since(Instant::now()));
+            let readable = wait_readable(&fds, timeout).into_diagnostic()?;
+            if readable[1] {
+                info!("Shutting down");
+                // The layout stays applied, but nothing keeps it up to date
+                for screen in state.screens.iter() {
+                    if let Err(e) = publish_layout(&state.conn, screen.root, None) {
+                        error!("Could not delete {}: {:?}", ACTIVE_LAYOUT, e);
+                    }
+                }
+                break;
+            }
+            if let (Some(events), Some(i)) = (state.events.as_mut(), events_fd) {
+                // A client that fails to connect shouldn't stop the daemon
+                if readable[i] {
+                    if let Err(e) = events.accept() {
+                        error!("Could not accept an event client: {:?}", e);
+                    }
+                }
+            }
+            #[cfg(feature = "dbus")]
+            if let (Some(dbus), Some(i)) = (state.dbus.as_mut(), dbus_fd) {
+                if readable[i] {
+                    match dbus.requests() {
+                        Ok(requests) => handle_requests(&mut state, requests),
+                        Err(e) => error!("Could not read D-Bus requests: {:?}", e),
+                    }
+                }
+            }
+            if let (Some(at), Some(interval)) = (next_scan, poll_interval) {
+                if Instant::now() >= at {
+                    rescan(&mut state);
+                    next_scan = Some(Instant::now() + interval);
                 }
-                _ => (),
             }
         }
+        io::stdout().flush().into_diagnostic()?;
     }
     Ok(())
 }
 
-pub fn check(args: &ArgMatches<'_>) -> Result<Config> {
+/// Apply the layouts that D-Bus clients requested.
+#[cfg(feature = "dbus")]
+fn handle_requests(state: &mut DaemonState, requests: Vec<Request>) {
+    for request in requests {
+        let named = match &request {
+            Request::Reapply => None,
+            Request::ApplyNamed(name) => Some(name.as_str()),
+        };
+        for screen in requested_screens(state, named) {
+            // A request applies a layout even when the connected outputs haven't changed
+            state.screens[screen].last_outputs = None;
+            switch_setup(state, screen, false, named);
+        }
+    }
+}
+
+/// Read the configuration from the file or directory given as the CONFIG argument.
+pub fn load_config(args: &ArgMatches<'_>) -> Result<Config> {
     // Unwrap below is safe, because the program exits from `get_matches` above when a config
     // is not provided.
     let config_name = args.value_of("config").unwrap();
-    Config::from_fname(&config_name).into_diagnostic()
+    Config::from_path(&config_name).into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, convert::TryFrom};
+
+    use super::*;
+    use crate::backend::mock::{Call, MockBackend, FIRST_CREATED_MODE, TIMESTAMP};
+    use crate::backend::Tile;
+    use crate::config::ScalingMode;
+
+    fn monitor(name: &str, w: u16, h: u16, x: i16, y: i16) -> MonConfig {
+        MonConfig {
+            name: name.to_string(),
+            mode: Mode { w, h },
+            position: Position { x, y },
+            primary: false,
+            rot: None,
+            reflect: None,
+            output: None,
+            modeline: None,
+            transform: None,
+            backlight: None,
+            vrr: None,
+            scaling_mode: None,
+            prefer_refresh: None,
+            crtc: None,
+            touch_device: None,
+            properties: BTreeMap::new(),
+            icc: None,
+            exec: None,
+            off: false,
+        }
+    }
+
+    /// An enable sent at `timestamp`, which is later than TIMESTAMP when anything changed
+    /// before it.
+    fn enable(
+        timestamp: Timestamp,
+        crtc: Crtc,
+        mode: u32,
+        x: i16,
+        y: i16,
+        rotation: u16,
+        out: Output,
+    ) -> Call {
+        Call::CrtcConfig(SetCrtcConfigRequest {
+            crtc,
+            timestamp,
+            config_timestamp: TIMESTAMP,
+            x,
+            y,
+            mode,
+            rotation,
+            outputs: Cow::Owned(vec![out]),
+        })
+    }
+
+    fn disable(crtc: Crtc, x: i16, y: i16) -> Call {
+        Call::CrtcConfig(SetCrtcConfigRequest {
+            crtc,
+            timestamp: TIMESTAMP,
+            config_timestamp: TIMESTAMP,
+            x,
+            y,
+            mode: 0,
+            rotation: Rot::ROTATE0.into(),
+            outputs: Cow::Owned(vec![]),
+        })
+    }
+
+    /// Apply a layout with the default settings, and without remembering CRTCs.
+    fn apply(
+        mock: &MockBackend,
+        fb: &Mode,
+        setup: HashMap<Output, &MonConfig>,
+    ) -> std::result::Result<bool, ApplyError> {
+        apply_config(mock, &mock.resources(), fb.into(), setup, 1, &Settings::default(), None)
+    }
+
+    fn crtc_calls(calls: Vec<Call>) -> Vec<Call> {
+        calls
+            .into_iter()
+            .filter(|c| !matches!(c, Call::Panning(_)))
+            .collect()
+    }
+
+    #[test]
+    fn enables_single_monitor() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1024, 768);
+        let mut edp = monitor("laptop", 1920, 1080, 0, 0);
+        edp.primary = true;
+        let setup = vec![(10, &edp)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        let changed = apply(&mock, &fb, setup).unwrap();
+        assert!(changed);
+        assert_eq!(
+            mock.calls(),
+            vec![
+                Call::ScreenSize(1920, 1080, 600, 340),
+                enable(TIMESTAMP + 1, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+                Call::Panning(SetPanningRequest {
+                    crtc: 100,
+                    timestamp: TIMESTAMP + 1,
+                    left: 0,
+                    top: 0,
+                    width: 1920,
+                    height: 1080,
+                    track_left: 0,
+                    track_top: 0,
+                    track_width: 1920,
+                    track_height: 1080,
+                    border_left: 0,
+                    border_top: 0,
+                    border_right: 0,
+                    border_bottom: 0,
+                }),
+                Call::OutputPrimary(10),
+            ]
+        );
+    }
+
+    #[test]
+    fn grows_screen_before_enabling_rotated_monitor() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let mut right = monitor("right", 1920, 1080, 1920, 0);
+        right.rot = Some(Rotation::Left);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 3000, h: 1920 };
+        let changed = apply(&mock, &fb, setup).unwrap();
+        assert!(changed);
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                Call::ScreenSize(3000, 1920, 1200, 680),
+                enable(TIMESTAMP + 1, 101, 1, 1920, 0, Rot::ROTATE90.into(), 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn layouts_may_give_the_physical_screen_size() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        let mm = Mode { w: 1000, h: 280 };
+        let size = ScreenSize {
+            fb: &fb,
+            mm: Some(&mm),
+        };
+        apply_config(&mock, &mock.resources(), size, setup, 1, &Settings::default(), None)
+            .unwrap();
+        assert_eq!(crtc_calls(mock.calls())[0], Call::ScreenSize(3840, 1080, 1000, 280));
+
+        // Only the physical size changes when the frame buffer already has the layout's size
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .size(1920, 1080);
+        mock.mm = Some(Mode { w: 600, h: 340 });
+        let only = monitor("only", 1920, 1080, 0, 0);
+        let fb = Mode { w: 1920, h: 1080 };
+        let mm = Mode { w: 500, h: 280 };
+        let size = ScreenSize {
+            fb: &fb,
+            mm: Some(&mm),
+        };
+        let apply_mm = |mock: &MockBackend| {
+            let setup = vec![(10, &only)].into_iter().collect();
+            apply_config(mock, &mock.resources(), size, setup, 1, &Settings::default(), None)
+        };
+        assert!(apply_mm(&mock).unwrap());
+        assert_eq!(mock.calls(), vec![Call::ScreenSize(1920, 1080, 500, 280)]);
+        mock.calls.borrow_mut().clear();
+        mock.mm = Some(mm.clone());
+        assert!(!apply_mm(&mock).unwrap());
+        assert!(mock.calls().is_empty());
+    }
+
+    #[test]
+    fn reflections_combine_with_rotation() {
+        let mut mon = monitor("mirror", 1920, 1080, 0, 0);
+        mon.reflect = Some(Reflection::X);
+        assert_eq!(rotation(&mon), u16::from(Rot::ROTATE0 | Rot::REFLECT_X));
+        mon.rot = Some(Rotation::Left);
+        assert_eq!(rotation(&mon), u16::from(Rot::ROTATE90 | Rot::REFLECT_X));
+        mon.reflect = Some(Reflection::XY);
+        let flipped = Rot::ROTATE90 | Rot::REFLECT_X | Rot::REFLECT_Y;
+        assert_eq!(rotation(&mon), u16::from(flipped));
+    }
+
+    #[test]
+    fn disables_unused_crtc_and_shrinks_screen() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .output(10, "DP-1", 100, &[100, 101], &[1, 2])
+            .output(11, "DP-2", 101, &[100, 101], &[2])
+            .crtc(100, 2, 0, 0, &[10])
+            .crtc(101, 2, 2560, 0, &[11])
+            .size(5120, 1440);
+        let only = monitor("only", 1920, 1080, 0, 0);
+        let setup = vec![(10, &only)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        let changed = apply(&mock, &fb, setup).unwrap();
+        assert!(changed);
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                // CRTC 100 is too large for the smaller screen, so it's disabled until it's
+                // reconfigured
+                disable(100, 0, 0),
+                disable(101, 2560, 0),
+                Call::ScreenSize(1920, 1080, 600, 340),
+                enable(TIMESTAMP + 1, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn off_monitor_is_disabled() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-1", 101, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 1, 1920, 0, &[11])
+            .size(3840, 1080);
+        let mut lid = monitor("lid", 0, 0, 0, 0);
+        lid.off = true;
+        let dock = monitor("dock", 1920, 1080, 1920, 0);
+        let setup = vec![(10, &lid), (11, &dock)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(crtc_calls(mock.calls()), vec![disable(100, 0, 0)]);
+    }
+
+    #[test]
+    fn retries_rejected_screen_size() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .size(1024, 768)
+            .size_mismatches(2);
+        let only = monitor("only", 1920, 1080, 0, 0);
+        let setup = vec![(10, &only)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                Call::ScreenSize(1920, 1080, 600, 340),
+                Call::ScreenSize(1920, 1080, 600, 340),
+                Call::ScreenSize(1920, 1080, 600, 340),
+                enable(TIMESTAMP + 1, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+            ]
+        );
+
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .size(1024, 768)
+            .size_mismatches(RESIZE_RETRIES + 1);
+        let setup = vec![(10, &only)].into_iter().collect();
+        assert!(apply(&mock, &fb, setup).is_err());
+    }
+
+    #[test]
+    fn gives_up_on_a_configuration_that_keeps_changing() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .size(1920, 1080)
+            .stale_configs(READ_RETRIES);
+        let only = monitor("only", 1920, 1080, 0, 0);
+        let setup = vec![(10, &only)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+
+        // The rollbacks are ignored too, so nothing is changed
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .size(1920, 1080)
+            .stale_configs(2 * (READ_RETRIES + 1));
+        let setup = vec![(10, &only)].into_iter().collect();
+        assert!(matches!(apply(&mock, &fb, setup), Err(ApplyError::ConfigChanged)));
+        assert!(!mock.calls().iter().any(|c| matches!(c, Call::CrtcConfig(_))));
+    }
+
+    #[test]
+    fn enabled_outputs_take_crtcs_of_removed_outputs() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-2", 100, &[100, 101], &[1])
+            .output(12, "HDMI-1", 101, &[101], &[1])
+            .crtc(100, 1, 0, 0, &[11])
+            .crtc(101, 1, 0, 0, &[12])
+            .size(3840, 1080);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(10, &right), (11, &left)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![enable(TIMESTAMP, 101, 1, 1920, 0, Rot::ROTATE0.into(), 10)]
+        );
+    }
+
+    #[test]
+    fn pinned_outputs_move_to_their_crtc() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .size(3840, 1080);
+        let mut left = monitor("left", 1920, 1080, 0, 0);
+        left.crtc = Some(1);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                enable(TIMESTAMP, 101, 1, 0, 0, Rot::ROTATE0.into(), 10),
+                enable(TIMESTAMP, 100, 1, 1920, 0, Rot::ROTATE0.into(), 11),
+            ]
+        );
+
+        let mut pinned = monitor("pinned", 1920, 1080, 0, 0);
+        pinned.crtc = Some(2);
+        let setup = vec![(10, &pinned)].into_iter().collect();
+        let applied = apply(&mock, &fb, setup);
+        assert!(matches!(applied, Err(ApplyError::CrtcUnsupported(10, 2))));
+    }
+
+    #[test]
+    fn transforms_are_set_before_their_crtc() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .size(3840, 2160);
+        let mut scaled = monitor("scaled", 1920, 1080, 0, 0);
+        scaled.transform = Some([2.0, 0.0, 0.0, 0.0, 2.0, 0.0, 0.0, 0.0, 1.0]);
+        let setup = vec![(10, &scaled)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 2160 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        let doubled = Transform {
+            matrix11: 2 << 16,
+            matrix22: 2 << 16,
+            ..IDENTITY_TRANSFORM
+        };
+        assert_eq!(
+            mock.calls(),
+            vec![
+                Call::CrtcTransform(100, doubled, b"bilinear".to_vec()),
+                enable(TIMESTAMP, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn tiled_monitors_are_placed_as_one() {
+        let tile = |column| Tile {
+            group: 1,
+            columns: 2,
+            rows: 1,
+            column,
+            row: 0,
+            w: 3840,
+            h: 4320,
+        };
+        let mock = MockBackend::default()
+            .mode(1, 3840, 4320)
+            .output(10, "DP-1-1", 0, &[100, 101], &[1])
+            .output(11, "DP-1-2", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .tile(11, tile(1))
+            .tile(10, tile(0))
+            .size(7680, 4320);
+        let eight_k = Monitor {
+            product: Some("8K".to_string()),
+            ..Default::default()
+        };
+        let mut connected: HashMap<_, _> =
+            vec![(10, eight_k.clone()), (11, eight_k)].into_iter().collect();
+        let tiles = group_tiles(&mock, &mut connected);
+        assert_eq!(connected.keys().collect::<Vec<_>>(), vec![&10]);
+
+        let conf = monitor("8K", 7680, 4320, 0, 0);
+        let tiled = tile_setup(&vec![(10, &conf)].into_iter().collect(), &tiles);
+        assert_eq!(tiled[&11].position, Position { x: 3840, y: 0 });
+        let setup = tiled.iter().map(|(&out, conf)| (out, conf)).collect();
+        let fb = Mode { w: 7680, h: 4320 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                enable(TIMESTAMP, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+                enable(TIMESTAMP, 101, 1, 3840, 0, Rot::ROTATE0.into(), 11),
+            ]
+        );
+
+        let smaller = monitor("8K", 3840, 2160, 0, 0);
+        let tiled = tile_setup(&vec![(10, &smaller)].into_iter().collect(), &tiles);
+        assert_eq!(tiled.keys().collect::<Vec<_>>(), vec![&10]);
+    }
+
+    #[test]
+    fn unreadable_outputs_are_skipped() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .broken(10)
+            .size(3840, 1080);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![enable(TIMESTAMP, 100, 1, 1920, 0, Rot::ROTATE0.into(), 11)]
+        );
+
+        // An unreadable output that's lit keeps its CRTC, which isn't disabled
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .broken(10)
+            .size(1920, 1080);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                Call::ScreenSize(3840, 1080, 600, 340),
+                enable(TIMESTAMP + 1, 101, 1, 1920, 0, Rot::ROTATE0.into(), 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn snapshots_restore_every_crtc() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        mock.mm = Some(Mode { w: 508, h: 285 });
+        let snapshot = Snapshot::take(&mock, &mock.resources(), 1).unwrap();
+        snapshot.restore(&mock, 1).unwrap();
+        let calls = crtc_calls(mock.calls());
+        assert_eq!(calls.len(), 4);
+        assert!(matches!(&calls[0], Call::CrtcConfig(req) if req.crtc == 100 && req.mode == 0));
+        assert_eq!(calls[1], Call::ScreenSize(1920, 1080, 508, 285));
+        assert!(matches!(&calls[2], Call::CrtcConfig(req) if req.crtc == 100 && req.mode == 1));
+        assert_eq!(calls[3], Call::OutputPrimary(0));
+    }
+
+    #[test]
+    fn failed_layouts_restore_the_physical_size_of_the_snapshot() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .size(1920, 1080);
+        mock.mm = Some(Mode { w: 508, h: 285 });
+        let snapshot = Snapshot::take(&mock, &mock.resources(), 1).unwrap();
+        // The layout that failed had already given the screen another physical size
+        mock.mm = Some(Mode { w: 600, h: 340 });
+        restore_previous(&mock, 1, snapshot, "Failed");
+        let sizes: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::ScreenSize(..)))
+            .collect();
+        assert_eq!(sizes, vec![Call::ScreenSize(1920, 1080, 508, 285)]);
+    }
+
+    #[test]
+    fn mirrored_outputs_split_their_crtc() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 100, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10, 11])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        // The CRTC that's kept stops driving the output that moves away
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                Call::ScreenSize(3840, 1080, 1200, 680),
+                enable(TIMESTAMP + 1, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+                enable(TIMESTAMP + 1, 101, 1, 1920, 0, Rot::ROTATE0.into(), 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn clones_share_a_crtc() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[101], &[1])
+            .clones(&[10, 11])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let first = monitor("first", 1920, 1080, 0, 0);
+        let second = monitor("second", 1920, 1080, 0, 0);
+        let setup = vec![(10, &first), (11, &second)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        // The only CRTC that can drive both is shared, and the other is left free
+        let outputs: Vec<_> = crtc_calls(mock.calls())
+            .into_iter()
+            .filter_map(|c| match c {
+                Call::CrtcConfig(req) => Some((req.crtc, req.outputs.to_vec())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(outputs, vec![(101, vec![10, 11])]);
+
+        // Outputs that aren't clones of each other each get a CRTC of their own
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100, 101], &[1])
+            .output(12, "HDMI-1", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let setup = vec![(10, &first), (12, &second)].into_iter().collect();
+        assert!(apply(&mock, &fb, setup).unwrap());
+        let crtcs: Vec<_> = crtc_calls(mock.calls())
+            .into_iter()
+            .filter_map(|c| match c {
+                Call::CrtcConfig(req) => Some(req.crtc),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(crtcs, vec![100, 101]);
+    }
+
+    #[test]
+    fn prefers_fastest_mode_when_asked() {
+        let mock = || {
+            MockBackend::default()
+                .mode(1, 1920, 1080)
+                .mode(2, 1920, 1080)
+                .mode(3, 1920, 1080)
+                .refresh(1, 60)
+                .refresh(2, 144)
+                .refresh(3, 120)
+                .output(10, "DP-1", 0, &[100], &[1, 2, 3])
+                .crtc(100, 0, 0, 0, &[])
+                .size(1920, 1080)
+        };
+        let mut only = monitor("only", 1920, 1080, 0, 0);
+        let fb = Mode { w: 1920, h: 1080 };
+        let enabled_mode = |mock: &MockBackend| {
+            mock.calls().into_iter().find_map(|c| match c {
+                Call::CrtcConfig(req) => Some(req.mode),
+                _ => None,
+            })
+        };
+
+        let first = mock();
+        apply(&first, &fb, vec![(10, &only)].into_iter().collect()).unwrap();
+        assert_eq!(enabled_mode(&first), Some(1));
+
+        let fastest = mock();
+        let settings = Settings {
+            prefer_refresh: true,
+        };
+        let setup = vec![(10, &only)].into_iter().collect();
+        let res = fastest.resources();
+        apply_config(&fastest, &res, (&fb).into(), setup, 1, &settings, None).unwrap();
+        assert_eq!(enabled_mode(&fastest), Some(2));
+
+        // A monitor's own preference wins over the setting
+        only.prefer_refresh = Some(false);
+        let overridden = mock();
+        let setup = vec![(10, &only)].into_iter().collect();
+        let res = overridden.resources();
+        apply_config(&overridden, &res, (&fb).into(), setup, 1, &settings, None).unwrap();
+        assert_eq!(enabled_mode(&overridden), Some(1));
+    }
+
+    #[test]
+    fn same_size_layout_never_resizes_screen() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 101, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 1, 1920, 0, &[11])
+            .size(3840, 1080);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(11, &left), (10, &right)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                enable(TIMESTAMP, 100, 1, 1920, 0, Rot::ROTATE0.into(), 10),
+                enable(TIMESTAMP, 101, 1, 0, 0, Rot::ROTATE0.into(), 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn applied_layout_is_unchanged() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .size(1920, 1080);
+        let only = monitor("only", 1920, 1080, 0, 0);
+        let setup = vec![(10, &only)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        let changed = apply(&mock, &fb, setup).unwrap();
+        assert!(!changed);
+        assert!(mock.calls().is_empty());
+    }
+
+    #[test]
+    fn unsupported_mode_is_an_error() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .output(10, "DP-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let only = monitor("only", 2560, 1440, 0, 0);
+        let setup = vec![(10, &only)].into_iter().collect();
+        let fb = Mode { w: 2560, h: 1440 };
+        let applied = apply(&mock, &fb, setup);
+        assert!(matches!(applied, Err(ApplyError::ModeUnsupported(10, _))));
+        assert!(mock.calls().is_empty());
+    }
+
+    #[test]
+    fn output_hint_breaks_edid_tie() {
+        let twin = Monitor {
+            product: Some("Twin".to_string()),
+            ..Default::default()
+        };
+        let mut left = monitor("left", 1920, 1080, 0, 0);
+        left.output = Some("DP-2".to_string());
+        let mut right = monitor("right", 1920, 1080, 1920, 0);
+        right.output = Some("DP-1".to_string());
+        let setup = vec![(twin.clone(), vec![left, right])].into_iter().collect();
+        let out_to_mon = vec![(10, twin.clone()), (11, twin)].into_iter().collect();
+        let names = vec![(10, "DP-1".to_string()), (11, "DP-2".to_string())]
+            .into_iter()
+            .collect();
+        let assigned = assign_outputs(&setup, out_to_mon, &names);
+        assert_eq!(assigned[&10].name, "right");
+        assert_eq!(assigned[&11].name, "left");
+    }
+
+    #[test]
+    fn identical_monitors_follow_layout_order() {
+        let twin = Monitor {
+            product: Some("Twin".to_string()),
+            ..Default::default()
+        };
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(twin.clone(), vec![left, right])].into_iter().collect();
+        let names: HashMap<_, _> = vec![(10, "DP-2".to_string()), (11, "DP-1".to_string())]
+            .into_iter()
+            .collect();
+        for _ in 0..8 {
+            let out_to_mon = vec![(10, twin.clone()), (11, twin.clone())].into_iter().collect();
+            let assigned = assign_outputs(&setup, out_to_mon, &names);
+            assert_eq!(assigned[&11].name, "left");
+            assert_eq!(assigned[&10].name, "right");
+        }
+    }
+
+    #[test]
+    fn status_file_is_replaced() {
+        let dir = std::env::temp_dir().join(format!("monitor-layout-test-{}", std::process::id()));
+        let path = dir.join("current");
+        write_status(&path, "Work-Desktop").unwrap();
+        write_status(&path, "Work-Lower-Only").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Work-Lower-Only\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn waiting_times_out() {
+        let (quiet, mut loud) = UnixStream::pair().unwrap();
+        let fds = [quiet.as_raw_fd()];
+        let timeout = Some(Duration::from_millis(10));
+        assert_eq!(wait_readable(&fds, timeout).unwrap(), vec![false]);
+        loud.write_all(b"x").unwrap();
+        assert_eq!(wait_readable(&fds, timeout).unwrap(), vec![true]);
+    }
+
+    #[test]
+    fn creates_custom_mode_once() {
+        let line = ModeLine {
+            clock: 185.58,
+            hdisplay: 2560,
+            hsync_start: 2608,
+            hsync_end: 2640,
+            htotal: 2720,
+            vdisplay: 1080,
+            vsync_start: 1083,
+            vsync_end: 1093,
+            vtotal: 1137,
+            flags: 0,
+        };
+        let mut wide = monitor("wide", 2560, 1080, 0, 0);
+        wide.modeline = Some(line.clone());
+        let fb = Mode { w: 2560, h: 1080 };
+
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .size(1920, 1080);
+        let setup = vec![(10, &wide)].into_iter().collect();
+        apply(&mock, &fb, setup).unwrap();
+        let calls = mock.calls();
+        match &calls[0] {
+            Call::CreateMode(mi, name) => {
+                assert_eq!(mi.dot_clock, 185_580_000);
+                assert_eq!(name, b"2560x1080_185580");
+            }
+            call => panic!("expected the mode to be created, not {:?}", call),
+        }
+        assert_eq!(calls[1], Call::AddOutputMode(10, FIRST_CREATED_MODE));
+        let enabled = enable(TIMESTAMP + 1, 100, FIRST_CREATED_MODE, 0, 0, 1, 10);
+        assert!(calls.contains(&enabled));
+
+        // Once the mode exists and belongs to the output, it's reused
+        let mut mock = MockBackend::default()
+            .output(10, "DP-1", 100, &[100], &[1, 7])
+            .crtc(100, 1, 0, 0, &[10])
+            .size(1920, 1080);
+        mock.modes.push(ModeInfo {
+            id: 7,
+            name_len: 16,
+            ..custom_mode_info(&line)
+        });
+        let setup = vec![(10, &wide)].into_iter().collect();
+        apply(&mock, &fb, setup).unwrap();
+        let calls = mock.calls();
+        assert!(!calls
+            .iter()
+            .any(|c| matches!(c, Call::CreateMode(..) | Call::AddOutputMode(..))));
+        assert!(calls.contains(&enable(TIMESTAMP + 1, 100, 7, 0, 0, 1, 10)));
+    }
+
+    #[test]
+    fn cleans_up_only_created_modes() {
+        let line = |clock: f64, hdisplay| ModeLine {
+            clock,
+            hdisplay,
+            hsync_start: hdisplay + 48,
+            hsync_end: hdisplay + 80,
+            htotal: hdisplay + 160,
+            vdisplay: 1080,
+            vsync_start: 1083,
+            vsync_end: 1093,
+            vtotal: 1137,
+            flags: 0,
+        };
+        let mut wide = monitor("wide", 2560, 1080, 0, 0);
+        wide.modeline = Some(line(185.58, 2560));
+        let mut narrow = monitor("narrow", 1600, 1080, 0, 0);
+        narrow.modeline = Some(line(120.0, 1600));
+        let mode = |id, line: &ModeLine| ModeInfo {
+            id,
+            ..custom_mode_info(line)
+        };
+        // The driver's mode 1 and the user's mode 5 are never destroyed
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100], &[1, 5, 7])
+            .output(11, "DP-2", 0, &[100], &[1, 7])
+            .crtc(100, 7, 0, 0, &[10]);
+        mock.modes.push(mode(5, &line(185.58, 2560)));
+        mock.modes.push(mode(7, &line(185.58, 2560)));
+        let mut before = MockBackend::default().mode(1, 1920, 1080).resources();
+        before.modes.push(mode(5, &line(185.58, 2560)));
+        let mut created = CreatedModes::new();
+        let setup = vec![(10, &wide)].into_iter().collect();
+        clean_up_modes(&mock, 1, &before, &setup, &mut created).unwrap();
+        assert_eq!(created.keys().collect::<Vec<_>>(), vec![&7]);
+        assert!(mock.calls().is_empty());
+
+        let setup = vec![(10, &narrow)].into_iter().collect();
+        let before = mock.resources();
+        clean_up_modes(&mock, 1, &before, &setup, &mut created).unwrap();
+        assert!(created.is_empty());
+        assert_eq!(
+            mock.calls(),
+            vec![
+                Call::DeleteOutputMode(10, 7),
+                Call::DeleteOutputMode(11, 7),
+                Call::DestroyMode(7)
+            ]
+        );
+    }
+
+    #[test]
+    fn scales_backlight_and_skips_outputs_without_one() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-1", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .backlight(10, 0, 1200)
+            .size(3840, 1080);
+        let mut edp = monitor("laptop", 1920, 1080, 0, 0);
+        edp.backlight = Some(25);
+        let mut dp = monitor("external", 1920, 1080, 1920, 0);
+        dp.backlight = Some(100);
+        let setup = vec![(10, &edp), (11, &dp)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        apply(&mock, &fb, setup).unwrap();
+        let backlights: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::Backlight(..)))
+            .collect();
+        assert_eq!(backlights, vec![Call::Backlight(10, 300)]);
+    }
+
+    #[test]
+    fn unchanged_layouts_still_configure_outputs() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 100, &[100], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .backlight(10, 0, 1200)
+            .vrr(10)
+            .scaling(10)
+            .size(1920, 1080);
+        let mut edp = monitor("laptop", 1920, 1080, 0, 0);
+        edp.backlight = Some(50);
+        edp.vrr = Some(false);
+        edp.scaling_mode = Some(ScalingMode::Center);
+        let setup = vec![(10, &edp)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        assert!(!apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            mock.calls(),
+            vec![
+                Call::Backlight(10, 600),
+                Call::Vrr(10, false),
+                Call::ScalingMode(10, "Center".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn execs_name_the_outputs_of_lit_monitors() {
+        let mock = MockBackend::default()
+            .output(10, "eDP-1", 0, &[100], &[])
+            .output(11, "DP-1", 0, &[100], &[])
+            .output(12, "HDMI-1", 0, &[100], &[]);
+        let mut edp = monitor("laptop", 1920, 1080, 0, 0);
+        edp.exec = Some("xrandr --output {output} --set \"Broadcast RGB\" Full".to_string());
+        let dp = monitor("external", 1920, 1080, 1920, 0);
+        let mut hdmi = monitor("tv", 0, 0, 0, 0);
+        hdmi.exec = Some("echo {output}".to_string());
+        hdmi.off = true;
+        let setup = vec![(10, &edp), (11, &dp), (12, &hdmi)].into_iter().collect();
+        assert_eq!(
+            monitor_execs(&mock, &setup, TIMESTAMP),
+            vec!["xrandr --output eDP-1 --set \"Broadcast RGB\" Full".to_string()]
+        );
+    }
+
+    #[test]
+    fn sets_vrr_and_skips_outputs_without_it() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-2", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .vrr(10)
+            .size(3840, 1080);
+        let mut gaming = monitor("gaming", 1920, 1080, 0, 0);
+        gaming.vrr = Some(true);
+        let mut office = monitor("office", 1920, 1080, 1920, 0);
+        office.vrr = Some(true);
+        let setup = vec![(10, &gaming), (11, &office)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        apply(&mock, &fb, setup).unwrap();
+        let vrrs: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::Vrr(..)))
+            .collect();
+        assert_eq!(vrrs, vec![Call::Vrr(10, true)]);
+    }
+
+    #[test]
+    fn sets_scaling_mode_by_property_value() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-1", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .scaling(10)
+            .size(3840, 1080);
+        let mut edp = monitor("laptop", 1920, 1080, 0, 0);
+        edp.scaling_mode = Some(ScalingMode::Aspect);
+        let mut dp = monitor("external", 1920, 1080, 1920, 0);
+        dp.scaling_mode = Some(ScalingMode::Center);
+        let setup = vec![(10, &edp), (11, &dp)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        apply(&mock, &fb, setup).unwrap();
+        let scaling: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::ScalingMode(..)))
+            .collect();
+        assert_eq!(scaling, vec![Call::ScalingMode(10, "Full aspect".to_string())]);
+    }
+
+    #[test]
+    fn maps_touch_screens_onto_their_monitors() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-1", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .touch_screen(5, "ELAN Touchscreen")
+            .touch_screen(6, "Pen Display")
+            .size(3000, 1920);
+        let mut edp = monitor("laptop", 1920, 1080, 0, 0);
+        edp.rot = Some(Rotation::Left);
+        let mut dp = monitor("pen", 1920, 1080, 1080, 0);
+        dp.touch_device = Some("Pen Display".to_string());
+        let setup = vec![(10, &edp), (11, &dp)].into_iter().collect();
+        let fb = Mode { w: 3000, h: 1920 };
+        apply(&mock, &fb, setup).unwrap();
+        let (w, h) = (1080.0 / 3000.0, 1080.0 / 1920.0);
+        let matrices: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::TouchMatrix(..)))
+            .collect();
+        assert_eq!(
+            matrices,
+            vec![
+                Call::TouchMatrix(6, [1920.0 / 3000.0, 0.0, w, 0.0, h, 0.0, 0.0, 0.0, 1.0]),
+                Call::TouchMatrix(5, [0.0, -w, w, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_for_a_missing_config() {
+        let path = std::env::temp_dir().join(format!("monitor-layout-{}.kdl", std::process::id()));
+        let name = path.to_string_lossy().into_owned();
+        let (signals, mut sig_write) = UnixStream::pair().unwrap();
+        let written = path.clone();
+        let writer = std::thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            std::fs::write(&written, "side-by-side \"Wide\"\n").unwrap();
+        });
+        let config = wait_for_config(&name, signals.as_raw_fd()).unwrap().unwrap();
+        writer.join().unwrap();
+        assert_eq!(config.side_by_side.as_deref(), Some("Wide"));
+        std::fs::remove_file(&path).unwrap();
+        // A signal to shut down ends the wait
+        sig_write.write_all(&[1]).unwrap();
+        assert!(wait_for_config(&name, signals.as_raw_fd()).unwrap().is_none());
+    }
+
+    #[test]
+    fn sets_output_properties_by_their_type() {
+        let atoms = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "HDMI-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .property(
+                10,
+                "Broadcast RGB",
+                PropertyInfo {
+                    kind: "ATOM".to_string(),
+                    format: 32,
+                    range: None,
+                    atoms: atoms(&["Automatic", "Full", "Limited 16:235"]),
+                },
+            )
+            .property(
+                10,
+                "underscan hborder",
+                PropertyInfo {
+                    kind: "INTEGER".to_string(),
+                    format: 32,
+                    range: Some((0, 128)),
+                    atoms: Vec::new(),
+                },
+            )
+            .size(1920, 1080);
+        let mut tv = monitor("tv", 1920, 1080, 0, 0);
+        let properties = vec![
+            ("Broadcast RGB", PropertyValue::String("Full".to_string())),
+            ("underscan hborder", PropertyValue::Int(200)),
+            ("non-existent", PropertyValue::Int(1)),
+        ];
+        tv.properties = properties.into_iter().map(|(k, v)| (k.to_string(), v)).collect();
+        let setup = vec![(10, &tv)].into_iter().collect();
+        apply(&mock, &Mode { w: 1920, h: 1080 }, setup).unwrap();
+        let set: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::Property(..)))
+            .collect();
+        // The border is out of range, and the output lacks the last property
+        let full = PropertyData::Atom("Full".to_string());
+        assert_eq!(set, vec![Call::Property(10, "Broadcast RGB".to_string(), full)]);
+        let border = mock.properties[&(10, "underscan hborder".to_string())].clone();
+        assert_eq!(
+            property_data(&border, &PropertyValue::Int(16)),
+            Some(PropertyData::Number {
+                value: 16,
+                format: 32,
+                cardinal: false
+            })
+        );
+        assert_eq!(property_data(&border, &PropertyValue::String("16".to_string())), None);
+    }
+
+    #[test]
+    fn loads_icc_profiles_of_lit_monitors() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 0, &[100, 101], &[1])
+            .output(11, "DP-1", 0, &[100, 101], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[])
+            .size(3840, 1080);
+        mock.gamma_size = 3;
+        mock.root_properties.insert(b"_ICC_PROFILE_1".to_vec());
+        // A profile without a VCGT, which loads linear gamma ramps
+        let path = std::env::temp_dir().join(format!("monitor-layout-{}.icc", std::process::id()));
+        let profile = vec![0; 132];
+        std::fs::write(&path, &profile).unwrap();
+        let mut edp = monitor("laptop", 1920, 1080, 0, 0);
+        edp.icc = Some("/nonexistent/laptop.icc".to_string());
+        let mut dp = monitor("desk", 1920, 1080, 1920, 0);
+        dp.primary = true;
+        dp.icc = Some(path.to_string_lossy().into_owned());
+        let setup = vec![(10, &edp), (11, &dp)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        apply(&mock, &fb, setup).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let loaded: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| matches!(c, Call::Gamma(..) | Call::RootProperty(..)))
+            .collect();
+        // The primary is the first Xinerama screen, and the unreadable profile is cleared
+        let linear = vec![0, 32768, 65535];
+        assert_eq!(
+            loaded,
+            vec![
+                Call::RootProperty(b"_ICC_PROFILE".to_vec(), Some(profile)),
+                Call::Gamma(101, [linear.clone(), linear.clone(), linear]),
+                Call::RootProperty(b"_ICC_PROFILE_1".to_vec(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn publishes_the_active_layout() {
+        let mut mock = MockBackend::default();
+        publish_layout(&mock, 1, None).unwrap();
+        publish_layout(&mock, 1, Some("Docked")).unwrap();
+        mock.root_properties.insert(ACTIVE_LAYOUT.as_bytes().to_vec());
+        publish_layout(&mock, 1, None).unwrap();
+        // The property is only deleted once it exists
+        assert_eq!(
+            mock.calls(),
+            vec![
+                Call::RootProperty(ACTIVE_LAYOUT.as_bytes().to_vec(), Some(b"Docked".to_vec())),
+                Call::RootProperty(ACTIVE_LAYOUT.as_bytes().to_vec(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejected_mode_rolls_back() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .output(10, "DP-1", 100, &[100, 101], &[1, 2])
+            .output(11, "DP-2", 0, &[100, 101], &[1, 2])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        mock.mm = Some(Mode { w: 508, h: 285 });
+        mock.reject_mode = Some(2);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 2560, 1440, 1920, 0);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 4480, h: 1440 };
+        assert!(matches!(apply(&mock, &fb, setup), Err(ApplyError::SendFailed(_))));
+        assert_eq!(
+            mock.calls(),
+            vec![
+                // The screen grew, then the enables were rejected
+                Call::ScreenSize(4480, 1440, 1200, 680),
+                // Rolling back, to the physical size the screen had before
+                Call::CrtcConfig(SetCrtcConfigRequest {
+                    crtc: 101,
+                    timestamp: TIMESTAMP + 1,
+                    config_timestamp: TIMESTAMP,
+                    x: 1920,
+                    y: 0,
+                    mode: 0,
+                    rotation: 1,
+                    outputs: Cow::Owned(vec![]),
+                }),
+                Call::ScreenSize(4480, 1440, 508, 285),
+                Call::ScreenSize(1920, 1080, 508, 285),
+                Call::OutputPrimary(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_crtc_config_rolls_back() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .output(10, "DP-1", 100, &[100, 101], &[1, 2])
+            .output(11, "DP-2", 0, &[100, 101], &[1, 2])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .size(4480, 1440);
+        mock.failing_crtcs.insert(101);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 2560, 1440, 1920, 0);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 4480, h: 1440 };
+        match apply(&mock, &fb, setup) {
+            Err(e @ ApplyError::CrtcConfigFailed(101, SetConfig::FAILED)) => {
+                assert_eq!(e.to_string(), "Configuring CRTC 101 failed")
+            }
+            applied => panic!("Expected the failure to be reported, not {:?}", applied),
+        }
+        let calls = mock.calls();
+        // The CRTC is turned back off, and no panning is set on it
+        assert!(!calls.iter().any(|c| matches!(c, Call::Panning(_))));
+        let last_config = calls.iter().rev().find_map(|c| match c {
+            Call::CrtcConfig(req) => Some(req),
+            _ => None,
+        });
+        assert!(matches!(last_config, Some(req) if req.crtc == 101 && req.mode == 0));
+    }
+
+    #[test]
+    fn failed_layouts_are_not_tried_again_for_the_same_outputs() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .size(1920, 1080);
+        mock.failing_crtcs.insert(100);
+        let only = monitor("only", 1920, 1080, 0, 0);
+        let fb = Mode { w: 1920, h: 1080 };
+        let mut last_outputs = None;
+        let notify = |mock: &MockBackend| ConnectedOutputs::new(&mock.resources(), &[10]);
+        assert!(try_outputs(&mut last_outputs, notify(&mock)));
+        let setup = vec![(10, &only)].into_iter().collect();
+        assert!(apply(&mock, &fb, setup).is_err());
+        // The failed apply and its rollback leave the same outputs connected
+        assert!(!try_outputs(&mut last_outputs, notify(&mock)));
+        // Once another monitor is connected, or one is unplugged, a layout is tried again
+        let other = ConnectedOutputs::new(&mock.resources(), &[]);
+        assert!(try_outputs(&mut last_outputs, other));
+    }
+
+    #[test]
+    fn outputs_moved_onto_other_crtcs_are_rolled_back() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-2", 100, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10, 11])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        mock.mm = Some(Mode { w: 508, h: 285 });
+        mock.failing_crtcs.insert(101);
+        let left = monitor("left", 1920, 1080, 0, 0);
+        let right = monitor("right", 1920, 1080, 1920, 0);
+        let setup = vec![(10, &left), (11, &right)].into_iter().collect();
+        let fb = Mode { w: 3840, h: 1080 };
+        assert!(matches!(
+            apply(&mock, &fb, setup),
+            Err(ApplyError::CrtcConfigFailed(101, _))
+        ));
+        let off = |crtc, x| {
+            Call::CrtcConfig(SetCrtcConfigRequest {
+                crtc,
+                timestamp: TIMESTAMP + 1,
+                config_timestamp: TIMESTAMP,
+                x,
+                y: 0,
+                mode: 0,
+                rotation: Rot::ROTATE0.into(),
+                outputs: Cow::Owned(vec![]),
+            })
+        };
+        // DP-2 left CRTC 100 for 101, so both are turned off before 100 drives both outputs
+        // again, at the screen's previous physical size
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                Call::ScreenSize(3840, 1080, 1200, 680),
+                enable(TIMESTAMP + 1, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+                enable(TIMESTAMP + 1, 101, 1, 1920, 0, Rot::ROTATE0.into(), 11),
+                off(100, 0),
+                off(101, 1920),
+                Call::ScreenSize(3840, 1080, 508, 285),
+                Call::CrtcConfig(restore_crtc(100, &mock.crtcs[&100], TIMESTAMP)),
+                Call::ScreenSize(1920, 1080, 508, 285),
+                Call::OutputPrimary(0),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_configs_are_rolled_back_and_applied_again() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "DP-1", 0, &[100], &[1])
+            .crtc(100, 0, 0, 0, &[])
+            .size(1920, 1080)
+            .stale_configs(1);
+        mock.mm = Some(Mode { w: 508, h: 285 });
+        let only = monitor("only", 1920, 1080, 0, 0);
+        let setup = vec![(10, &only)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        assert!(apply(&mock, &fb, setup).unwrap());
+        assert_eq!(
+            crtc_calls(mock.calls()),
+            vec![
+                // The ignored batch is rolled back
+                disable(100, 0, 0),
+                Call::ScreenSize(1920, 1080, 508, 285),
+                Call::OutputPrimary(0),
+                enable(TIMESTAMP + 1, 100, 1, 0, 0, Rot::ROTATE0.into(), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn reenabled_output_reuses_its_crtc() {
+        let settings = Settings::default();
+        let mut memory = CrtcMemory::new();
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 100, &[100, 101, 102], &[1])
+            .output(11, "DP-1", 0, &[100, 101, 102], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .crtc(102, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let laptop = monitor("laptop", 1920, 1080, 0, 0);
+        let external = monitor("external", 1920, 1080, 1920, 0);
+        let both = vec![(10, &laptop), (11, &external)].into_iter().collect();
+        // The remembered CRTC is chosen over the first free one
+        let fb = Mode { w: 3840, h: 1080 };
+        memory.insert(11, 102);
+        let res = mock.resources();
+        apply_config(&mock, &res, (&fb).into(), both, 1, &settings, Some(&mut memory)).unwrap();
+        assert!(mock
+            .calls()
+            .contains(&enable(TIMESTAMP + 1, 102, 1, 1920, 0, Rot::ROTATE0.into(), 11)));
+
+        // Turning the output off remembers the CRTC it was on
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 100, &[100, 101, 102], &[1])
+            .output(11, "DP-1", 101, &[100, 101, 102], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 1, 1920, 0, &[11])
+            .crtc(102, 0, 0, 0, &[])
+            .size(3840, 1080);
+        let only = vec![(10, &laptop)].into_iter().collect();
+        let fb = Mode { w: 1920, h: 1080 };
+        let res = mock.resources();
+        apply_config(&mock, &res, (&fb).into(), only, 1, &settings, Some(&mut memory)).unwrap();
+        assert_eq!(memory[&11], 101);
+    }
+
+    #[test]
+    fn forced_output_replaces_edid_and_connector_is_named() {
+        let config = Config::try_from(
+            kdl::parse_document(
+                r#"
+                monitor "Broken" force-output="HDMI-1"
+                monitor "Fine" product="Fine"
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let mock = MockBackend::default()
+            .output(10, "HDMI-1", 0, &[], &[])
+            .output(11, "DP-1", 0, &[], &[]);
+        let fine = Monitor {
+            product: Some("Fine".to_string()),
+            ..Default::default()
+        };
+        // HDMI-1 has no readable EDID
+        let mut connected = vec![(11, fine.clone())].into_iter().collect();
+        identify_outputs(&config, &mock, &[10, 11], TIMESTAMP, &mut connected);
+        assert_eq!(connected[&10], config.monitors["Broken"]);
+        assert_eq!(connected[&11].connector.as_deref(), Some("DP"));
+        assert!(config.monitors["Broken"].matches(&connected[&10]));
+        assert!(!config.monitors["Fine"].matches(&connected[&10]));
+    }
+
+    #[test]
+    fn mirrors_in_largest_common_mode() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .mode(3, 1280, 720)
+            .output(10, "eDP-1", 0, &[100], &[1, 2, 3])
+            .output(11, "HDMI-1", 0, &[101], &[1, 3])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[]);
+        let mirror = mirror_layout(&mock, &mock.resources(), &[10, 11]).unwrap();
+        assert_eq!(mirror.fb_size, Mode { w: 1920, h: 1080 });
+        assert_eq!(mirror.setup[&11].mode, Mode { w: 1920, h: 1080 });
+
+        let mock = mock.output(12, "DP-1", 0, &[101], &[2]);
+        assert!(mirror_layout(&mock, &mock.resources(), &[10, 11, 12]).is_err());
+    }
+
+    #[test]
+    fn safe_layout_lights_only_the_primary_or_built_in_output() {
+        let mut mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .output(10, "eDP-1", 0, &[100], &[1, 2])
+            .output(11, "DP-1", 0, &[101], &[2, 1])
+            .output(12, "HDMI-1", 0, &[101], &[])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[]);
+        let layout = safe_layout(&mock, 1, &mock.resources(), &[10, 11, 12]).unwrap();
+        assert_eq!(layout.fb_size, Mode { w: 1920, h: 1080 });
+        assert!(layout.setup[&10].primary);
+        assert_eq!(layout.setup[&10].position, Position { x: 0, y: 0 });
+        assert!(layout.setup[&11].off && layout.setup[&12].off);
+        mock.primary = 11;
+        let layout = safe_layout(&mock, 1, &mock.resources(), &[10, 11, 12]).unwrap();
+        assert_eq!(layout.fb_size, Mode { w: 2560, h: 1440 });
+        assert!(layout.setup[&11].primary && layout.setup[&10].off);
+        // An output without modes can't be lit, even as the primary
+        mock.primary = 12;
+        let layout = safe_layout(&mock, 1, &mock.resources(), &[11, 12]).unwrap();
+        assert!(layout.setup[&11].primary && layout.setup[&12].off);
+        assert!(safe_layout(&mock, 1, &mock.resources(), &[12]).is_err());
+    }
+
+    #[test]
+    fn places_outputs_side_by_side_by_name() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .output(10, "eDP-1", 0, &[100], &[1, 2])
+            .output(11, "DP-1", 0, &[101], &[2, 1])
+            .output(12, "HDMI-1", 0, &[101], &[])
+            .crtc(100, 0, 0, 0, &[])
+            .crtc(101, 0, 0, 0, &[]);
+        let layout = side_by_side_layout(&mock, &mock.resources(), &[10, 11, 12]).unwrap();
+        assert_eq!(layout.fb_size, Mode { w: 4480, h: 1440 });
+        assert_eq!(layout.setup[&11].position, Position { x: 0, y: 0 });
+        assert_eq!(layout.setup[&11].mode, Mode { w: 2560, h: 1440 });
+        assert_eq!(layout.setup[&10].position, Position { x: 2560, y: 0 });
+        assert_eq!(layout.setup[&10].mode, Mode { w: 1920, h: 1080 });
+        assert!(!layout.setup.contains_key(&12));
+    }
 }
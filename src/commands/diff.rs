@@ -0,0 +1,241 @@
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+use x11rb::{
+    connection::Connection,
+    protocol::randr::{GetScreenResourcesCurrentReply, Output, Rotation as Rot},
+    protocol::xproto::Window,
+    rust_connection::RustConnection,
+};
+
+use std::collections::HashMap;
+
+use super::daemon::{
+    crtc_differs, find_mode_id, identify_outputs, layout_outputs, load_config, mode_map,
+    rotation, transform, Error,
+};
+use crate::backend::{Backend, IDENTITY_TRANSFORM};
+use crate::config::{pair_monitors, Mode, MonConfig, Position, Settings};
+use crate::{edid_atom, get_connected_outputs, get_monitors, get_outputs, randr_version};
+
+/// Describe a CRTC configuration like "2560x1440+0+1440 left", followed by "reflect x" when it's
+/// reflected, and "transformed" when it has a transform.
+fn describe(mode: &Mode, x: i16, y: i16, rotation: u16, transformed: bool) -> String {
+    let rot = rotation & 0xf;
+    let rot = if rot == u16::from(Rot::ROTATE90) {
+        "left"
+    } else if rot == u16::from(Rot::ROTATE180) {
+        "inverted"
+    } else if rot == u16::from(Rot::ROTATE270) {
+        "right"
+    } else {
+        "normal"
+    };
+    let reflect_x = rotation & u16::from(Rot::REFLECT_X) != 0;
+    let reflect_y = rotation & u16::from(Rot::REFLECT_Y) != 0;
+    let reflect = match (reflect_x, reflect_y) {
+        (false, false) => "",
+        (true, false) => " reflect x",
+        (false, true) => " reflect y",
+        (true, true) => " reflect xy",
+    };
+    let transformed = if transformed { " transformed" } else { "" };
+    format!("{}+{}+{} {}{}{}", mode, x, y, rot, reflect, transformed)
+}
+
+/// The difference between an output's current configuration and its configuration in a layout.
+#[derive(Debug, PartialEq)]
+struct OutputDiff {
+    output: String,
+    current: String,
+    wanted: String,
+    differs: bool,
+}
+
+/// Compare the current configuration of every output against the layout `setup`. Outputs that
+/// are disabled both now and in the layout are omitted.
+fn diff_layout<B: Backend>(
+    conn: &B,
+    res: &GetScreenResourcesCurrentReply,
+    setup: &HashMap<Output, &MonConfig>,
+    root: Window,
+    settings: &Settings,
+) -> Result<Vec<OutputDiff>> {
+    let (modes, timestamp) = mode_map(conn, root)?;
+    let sizes: HashMap<u32, Mode> = res
+        .modes
+        .iter()
+        .map(|mi| {
+            (
+                mi.id,
+                Mode {
+                    w: mi.width,
+                    h: mi.height,
+                },
+            )
+        })
+        .collect();
+    let mut diffs = Vec::new();
+    for &out in res.outputs.iter() {
+        let out_info = conn.output_info(out, timestamp)?;
+        let crtc_info = if out_info.crtc != 0 {
+            Some(conn.crtc_info(out_info.crtc, timestamp)?).filter(|info| info.mode != 0)
+        } else {
+            None
+        };
+        let current_transform = match &crtc_info {
+            Some(_) => conn.crtc_transform(out_info.crtc)?,
+            None => IDENTITY_TRANSFORM,
+        };
+        let current = match (&crtc_info, crtc_info.as_ref().and_then(|i| sizes.get(&i.mode))) {
+            (Some(info), Some(mode)) => {
+                let transformed = current_transform != IDENTITY_TRANSFORM;
+                describe(mode, info.x, info.y, info.rotation, transformed)
+            }
+            _ => "off".to_string(),
+        };
+        let (wanted, differs) = match setup.get(&out) {
+            Some(conf) if conf.off => ("off".to_string(), crtc_info.is_some()),
+            Some(conf) => {
+                let Position { x, y } = conf.position;
+                let wanted = describe(&conf.mode, x, y, rotation(conf), conf.transform.is_some());
+                let prefer_refresh = conf.prefer_refresh.unwrap_or(settings.prefer_refresh);
+                match find_mode_id(out, &out_info, &modes, &conf.mode, prefer_refresh) {
+                    Ok(mode) => {
+                        let differs = match &crtc_info {
+                            Some(info) => {
+                                crtc_differs(info, conf, mode)
+                                    || current_transform != transform(conf)
+                            }
+                            None => true,
+                        };
+                        (wanted, differs)
+                    }
+                    Err(e) => (format!("{} ({})", wanted, e), true),
+                }
+            }
+            None if crtc_info.is_some() => ("off".to_string(), true),
+            None => continue,
+        };
+        diffs.push(OutputDiff {
+            output: String::from_utf8_lossy(&out_info.name).into_owned(),
+            current,
+            wanted,
+            differs,
+        });
+    }
+    Ok(diffs)
+}
+
+/// Print the differences between the current state and a layout. Returns true when they differ.
+pub fn main(args: &ArgMatches<'_>) -> Result<bool> {
+    let config = load_config(args)?;
+    // Unwrap below is safe, because the program exits from `get_matches` when a name is not
+    // provided.
+    let name = args.value_of("name").unwrap();
+    let (wanted, layout) = config
+        .named_layout(name)
+        .ok_or_else(|| Error::UnknownLayout(name.to_string()))
+        .into_diagnostic()?;
+    let (conn, screen_num) = RustConnection::connect(None).into_diagnostic()?;
+    randr_version(&conn)?;
+    let root = conn.setup().roots[screen_num].root;
+    let atom_edid = edid_atom(&conn)?;
+    let res = get_outputs(&conn, root)?;
+    let outputs = get_connected_outputs(&conn, &res)?;
+    let mut connected = get_monitors(&conn, &outputs, atom_edid).collect();
+    identify_outputs(&config, &conn, &outputs, res.config_timestamp, &mut connected);
+    // When the connected monitors don't match the layout, compare what matches exactly
+    let out_to_mon = pair_monitors(wanted, &connected).unwrap_or(connected);
+    let setup = layout_outputs(&conn, &layout.setup, out_to_mon, res.config_timestamp);
+    let diffs = diff_layout(&conn, &res, &setup, root, &config.settings)?;
+    for diff in diffs.iter() {
+        if diff.differs {
+            println!("- {}: {}", diff.output, diff.current);
+            println!("+ {}: {}", diff.output, diff.wanted);
+        } else {
+            println!("  {}: {}", diff.output, diff.current);
+        }
+    }
+    Ok(diffs.iter().any(|d| d.differs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::config::Rotation;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn reports_each_output() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .mode(2, 2560, 1440)
+            .output(10, "DP-1", 100, &[100, 101, 102], &[1, 2])
+            .output(11, "DP-2", 101, &[100, 101, 102], &[1, 2])
+            .output(12, "HDMI-1", 102, &[100, 101, 102], &[1])
+            .output(13, "HDMI-2", 0, &[100, 101, 102], &[1])
+            .crtc(100, 2, 0, 0, &[10])
+            .crtc(101, 1, 2560, 0, &[11])
+            .crtc(102, 1, 0, 1440, &[12])
+            .size(4480, 2520);
+        let same = MonConfig {
+            name: "same".to_string(),
+            mode: Mode { w: 2560, h: 1440 },
+            position: Position { x: 0, y: 0 },
+            primary: false,
+            rot: None,
+            reflect: None,
+            output: None,
+            modeline: None,
+            transform: None,
+            backlight: None,
+            vrr: None,
+            scaling_mode: None,
+            prefer_refresh: None,
+            crtc: None,
+            touch_device: None,
+            properties: BTreeMap::new(),
+            icc: None,
+            exec: None,
+            off: false,
+        };
+        let rotated = MonConfig {
+            name: "rotated".to_string(),
+            mode: Mode { w: 1920, h: 1080 },
+            position: Position { x: 2560, y: 0 },
+            primary: false,
+            rot: Some(Rotation::Left),
+            reflect: None,
+            output: None,
+            modeline: None,
+            transform: None,
+            backlight: None,
+            vrr: None,
+            scaling_mode: None,
+            prefer_refresh: None,
+            crtc: None,
+            touch_device: None,
+            properties: BTreeMap::new(),
+            icc: None,
+            exec: None,
+            off: false,
+        };
+        let setup = vec![(10, &same), (11, &rotated)].into_iter().collect();
+        let diffs = diff_layout(&mock, &mock.resources(), &setup, 1, &Settings::default()).unwrap();
+        let line = |output: &str, current: &str, wanted: &str, differs| OutputDiff {
+            output: output.to_string(),
+            current: current.to_string(),
+            wanted: wanted.to_string(),
+            differs,
+        };
+        assert_eq!(
+            diffs,
+            vec![
+                line("DP-1", "2560x1440+0+0 normal", "2560x1440+0+0 normal", false),
+                line("DP-2", "1920x1080+2560+0 normal", "1920x1080+2560+0 left", true),
+                line("HDMI-1", "1920x1080+0+1440 normal", "off", true),
+            ]
+        );
+    }
+}
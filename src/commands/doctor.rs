@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use miette::{IntoDiagnostic, Result};
+use x11rb::{
+    connect,
+    connection::Connection,
+    protocol::randr::{Connection as OutConnection, ModeInfo, Output},
+    protocol::xproto::{Atom, Window},
+};
+
+use super::daemon::refresh_rate;
+use crate::backend::Backend;
+use crate::config::Monitor;
+use crate::{edid_atom, get_connected_outputs, get_monitors, get_outputs, randr_version};
+
+/// Describe a monitor by the fields of its EDID that layouts match, such as
+/// `product="S27D850" serial="1234"`.
+fn describe_monitor(mon: &Monitor) -> String {
+    let fields = [
+        ("product", mon.product.clone()),
+        ("serial", mon.serial.clone()),
+        ("text", mon.text.clone()),
+        ("manufacturer", mon.manufacturer.clone()),
+        ("diagonal", mon.diagonal.map(|d| d.to_string())),
+    ];
+    fields
+        .iter()
+        .filter_map(|(name, value)| value.as_ref().map(|v| format!("{}={:?}", name, v)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Describe a mode like "1920x1080 60.00Hz", leaving out the refresh rate of a mode without
+/// timings.
+pub(crate) fn describe_mode(mi: &ModeInfo) -> String {
+    match refresh_rate(mi) {
+        0 => format!("{}x{}", mi.width, mi.height),
+        mhz => format!("{}x{} {}.{:02}Hz", mi.width, mi.height, mhz / 1000, mhz % 1000 / 10),
+    }
+}
+
+/// Write out the state of the screen, each output and each CRTC. `monitors` holds the monitor
+/// read from the EDID of each connected output that has one.
+fn report<B: Backend>(
+    conn: &B,
+    root: Window,
+    atom_edid: Atom,
+    monitors: &HashMap<Output, Monitor>,
+) -> Result<String> {
+    let res = conn.screen_resources(root)?;
+    let timestamp = res.config_timestamp;
+    let modes: HashMap<u32, &ModeInfo> = res.modes.iter().map(|mi| (mi.id, mi)).collect();
+    let mode_name = |id: u32| match modes.get(&id) {
+        Some(mi) => format!("{} ({})", describe_mode(mi), id),
+        None => format!("unknown mode {}", id),
+    };
+    let mut names = HashMap::with_capacity(res.outputs.len());
+    let mut out = String::new();
+    let size = conn.screen_size(root)?;
+    // Writing to a String can't fail
+    writeln!(out, "EDID atom: {}", atom_edid).unwrap();
+    writeln!(out, "Screen size: {}", size).unwrap();
+    let mut outputs = Vec::with_capacity(res.outputs.len());
+    for &output in res.outputs.iter() {
+        let info = conn.output_info(output, timestamp)?;
+        names.insert(output, String::from_utf8_lossy(&info.name).into_owned());
+        outputs.push((output, info));
+    }
+    let primary = conn.output_primary(root)?;
+    match names.get(&primary) {
+        Some(name) => writeln!(out, "Primary output: {}", name).unwrap(),
+        None => writeln!(out, "Primary output: none").unwrap(),
+    }
+    for (output, info) in outputs.iter() {
+        let state = if info.connection == OutConnection::CONNECTED {
+            "connected"
+        } else if info.connection == OutConnection::DISCONNECTED {
+            "disconnected"
+        } else {
+            "unknown"
+        };
+        writeln!(out, "Output {} ({}): {}", names[output], output, state).unwrap();
+        if info.connection == OutConnection::CONNECTED {
+            match monitors.get(output) {
+                Some(mon) => writeln!(out, "  Monitor: {}", describe_monitor(mon)).unwrap(),
+                None => writeln!(out, "  Monitor: no EDID").unwrap(),
+            }
+        }
+        match info.crtc {
+            0 => writeln!(out, "  CRTC: none").unwrap(),
+            crtc => writeln!(out, "  CRTC: {}", crtc).unwrap(),
+        }
+        writeln!(out, "  Modes:").unwrap();
+        for (i, &mode) in info.modes.iter().enumerate() {
+            let preferred = if i < usize::from(info.num_preferred) { " preferred" } else { "" };
+            writeln!(out, "    {}{}", mode_name(mode), preferred).unwrap();
+        }
+    }
+    for &crtc in res.crtcs.iter() {
+        let info = conn.crtc_info(crtc, timestamp)?;
+        if info.mode == 0 {
+            writeln!(out, "CRTC {}: disabled", crtc).unwrap();
+            continue;
+        }
+        let driven: Vec<&str> = info
+            .outputs
+            .iter()
+            .map(|o| names.get(o).map_or("unknown", String::as_str))
+            .collect();
+        writeln!(
+            out,
+            "CRTC {}: {} at {},{} rotation {} on {}",
+            crtc,
+            mode_name(info.mode),
+            info.x,
+            info.y,
+            info.rotation,
+            driven.join(", ")
+        )
+        .unwrap();
+    }
+    Ok(out)
+}
+
+/// Print the state of every output and CRTC, for bug reports.
+pub fn main() -> Result<()> {
+    let (conn, screen_num) = connect(None).into_diagnostic()?;
+    randr_version(&conn)?;
+    let root = conn.setup().roots[screen_num].root;
+    let atom_edid = edid_atom(&conn)?;
+    let res = get_outputs(&conn, root)?;
+    let connected = get_connected_outputs(&conn, &res)?;
+    let monitors = get_monitors(&conn, &connected, atom_edid).collect();
+    print!("{}", report(&conn, root, atom_edid, &monitors)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+
+    #[test]
+    fn reports_outputs_and_crtcs() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .refresh(1, 60)
+            .mode(2, 1280, 720)
+            .output(10, "DP-1", 100, &[100, 101], &[1, 2])
+            .output(11, "HDMI-1", 0, &[100, 101], &[2])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .size(1920, 1080);
+        let dock = Monitor {
+            product: Some("S27D850".to_string()),
+            serial: Some("1234".to_string()),
+            ..Default::default()
+        };
+        let monitors = vec![(10, dock)].into_iter().collect();
+        let report = report(&mock, 1, 90, &monitors).unwrap();
+        assert_eq!(
+            report,
+            "EDID atom: 90
+Screen size: 1920x1080
+Primary output: none
+Output DP-1 (10): connected
+  Monitor: product=\"S27D850\" serial=\"1234\"
+  CRTC: 100
+  Modes:
+    1920x1080 60.00Hz (1) preferred
+    1280x720 (2)
+Output HDMI-1 (11): connected
+  Monitor: no EDID
+  CRTC: none
+  Modes:
+    1280x720 (2) preferred
+CRTC 100: 1920x1080 60.00Hz (1) at 0,0 rotation 1 on DP-1
+CRTC 101: disabled
+"
+        );
+    }
+}
@@ -0,0 +1,12 @@
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+
+use super::daemon::load_config;
+
+/// Print the configuration as the daemon reads it, as pretty JSON, to see how its layouts are
+/// keyed by their monitors. Maps keyed by monitors are lists of their entries, ordered by key.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let config = load_config(args)?;
+    println!("{}", serde_json::to_string_pretty(&config).into_diagnostic()?);
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+use x11rb::{connect, connection::Connection};
+
+use crate::{edid_atom, get_output_name, get_outputs, get_raw_edids, randr_version};
+
+/// Format bytes as lines of 32 hex digits, like `xxd -p`.
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .chunks(16)
+        .map(|line| line.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Format bytes as standard, padded base64, in lines of 76 characters.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for group in bytes.chunks(3) {
+        let b = [
+            group[0],
+            group.get(1).copied().unwrap_or(0),
+            group.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= group.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out.as_bytes()
+        .chunks(76)
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Print the raw EDID of each output, without parsing it.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let format: fn(&[u8]) -> String = match args.value_of("format") {
+        Some("base64") => base64,
+        _ => hex,
+    };
+    let (conn, screen_num) = connect(None).into_diagnostic()?;
+    randr_version(&conn)?;
+    let root = conn.setup().roots[screen_num].root;
+    let atom_edid = edid_atom(&conn)?;
+    let outs = get_outputs(&conn, root)?;
+    for (out, bytes) in get_raw_edids(&conn, &outs.outputs, atom_edid) {
+        let name = get_output_name(&conn, out, outs.timestamp)?;
+        match args.value_of("output") {
+            Some(want) if want != name => continue,
+            _ => (),
+        }
+        if bytes.is_empty() {
+            println!("{}: no EDID", name);
+        } else {
+            println!("{}:\n{}", name, format(&bytes));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodings() {
+        let header = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+        assert_eq!(hex(&header), "00ffffffffffff00");
+        assert_eq!(hex(&[0xab; 17]), format!("{}\nab", "ab".repeat(16)));
+        assert_eq!(base64(&header), "AP///////wA=");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"abc"), "YWJj");
+        assert_eq!(base64(&[0; 60]).lines().map(str::len).collect::<Vec<_>>(), [76, 4]);
+    }
+}
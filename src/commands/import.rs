@@ -0,0 +1,320 @@
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+use tracing::{debug, warn};
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::path::Path;
+
+use super::daemon::{connector_type, Error};
+use crate::config::{
+    Config, Mode, MonConfig, Monitor, Position, Reflection, Rotation, Settings, SingleConfig,
+};
+use crate::parse_edid_bytes;
+
+/// Decode a string of hex digits, as autorandr records EDIDs. Returns None when it's not hex.
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Declare the monitor with the EDID recorded for `output` by autorandr, as `print-edids`
+/// would. A monitor without a product name or serial is matched by its connector type.
+fn declare(output: &str, edid: &str) -> Monitor {
+    let parsed = from_hex(edid).and_then(|bytes| parse_edid_bytes(output, &bytes));
+    let mon = match parsed {
+        Some(parsed) => Monitor::from(parsed),
+        None => {
+            warn!("The EDID of output {} can't be read, matching it by connector", output);
+            Monitor::default()
+        }
+    };
+    // Without a serial descriptor, the serial number may still tell monitors apart
+    let (serial, any_serial) = match (mon.serial, mon.serial_number) {
+        (Some(serial), _) => (Some(serial), false),
+        (None, Some(number)) => (Some(number.to_string()), true),
+        (None, None) => (None, false),
+    };
+    let connector = match (&mon.product, &serial) {
+        (None, None) => Some(connector_type(output).to_string()),
+        _ => None,
+    };
+    Monitor {
+        product: mon.product,
+        serial,
+        any_serial,
+        text: mon.text,
+        connector,
+        ..Default::default()
+    }
+}
+
+/// Read an autorandr `setup` file: the name of each connected output, followed by its EDID.
+fn parse_setup(text: &str) -> Vec<(String, Monitor)> {
+    text.lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let output = words.next()?;
+            Some((output.to_string(), declare(output, words.next().unwrap_or(""))))
+        })
+        .collect()
+}
+
+/// The monitor config of an output that's turned off, or not yet configured
+fn off(output: &str) -> MonConfig {
+    MonConfig {
+        name: output.to_string(),
+        mode: Mode { w: 0, h: 0 },
+        position: Position { x: 0, y: 0 },
+        primary: false,
+        rot: None,
+        reflect: None,
+        output: None,
+        modeline: None,
+        transform: None,
+        backlight: None,
+        vrr: None,
+        scaling_mode: None,
+        prefer_refresh: None,
+        crtc: None,
+        touch_device: None,
+        properties: BTreeMap::new(),
+        icc: None,
+        exec: None,
+        off: true,
+    }
+}
+
+/// Read a list of numbers written like "1920x1080" or "1,0,0".
+fn numbers<T: std::str::FromStr>(value: &str, sep: char) -> Option<Vec<T>> {
+    value.split(sep).map(|n| n.trim().parse().ok()).collect()
+}
+
+/// Read an autorandr `config` file, which describes each output in a block of xrandr options
+/// that starts with `output <name>`, into a monitor config named by the output. Options that
+/// layouts have no equivalent for, such as `rate` and `panning`, are skipped.
+fn parse_config(text: &str) -> std::result::Result<HashMap<String, MonConfig>, Error> {
+    let mut configs = HashMap::new();
+    let mut current: Option<MonConfig> = None;
+    // Whether the picture is reflected along x and y, which xrandr's "inverted" rotation flips
+    let mut flipped = (false, false);
+    let finish = |conf: Option<MonConfig>, (x, y), configs: &mut HashMap<_, _>| {
+        if let Some(mut conf) = conf {
+            conf.reflect = match (x, y) {
+                (false, false) => None,
+                (true, false) => Some(Reflection::X),
+                (false, true) => Some(Reflection::Y),
+                (true, true) => Some(Reflection::XY),
+            };
+            configs.insert(conf.name.clone(), conf);
+        }
+    };
+    for (i, line) in text.lines().enumerate() {
+        let bad = |what: &str| Error::BadAutorandrConfig(i + 1, what.to_string());
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let value = value.trim();
+        if key == "output" {
+            finish(current.take(), flipped, &mut configs);
+            flipped = (false, false);
+            current = Some(MonConfig { off: false, ..off(value) });
+            continue;
+        }
+        let conf = current.as_mut().ok_or_else(|| bad("an option before the first output"))?;
+        match key {
+            "off" => conf.off = true,
+            "primary" => conf.primary = true,
+            "mode" => {
+                // Interlaced modes are suffixed with "i"
+                let size = value.trim_end_matches(|c: char| !c.is_ascii_digit());
+                conf.mode = size.parse().map_err(|_| bad("a mode"))?;
+            }
+            "pos" => match numbers(value, 'x').as_deref() {
+                Some(&[x, y]) => conf.position = Position { x, y },
+                _ => return Err(bad("a position")),
+            },
+            "rotate" => match value {
+                "normal" => (),
+                "left" => conf.rot = Some(Rotation::Left),
+                "right" => conf.rot = Some(Rotation::Right),
+                "inverted" => flipped = (!flipped.0, !flipped.1),
+                _ => return Err(bad("a rotation")),
+            },
+            "reflect" => match value {
+                "normal" => (),
+                "x" => flipped.0 = !flipped.0,
+                "y" => flipped.1 = !flipped.1,
+                "xy" => flipped = (!flipped.0, !flipped.1),
+                _ => return Err(bad("a reflection")),
+            },
+            "transform" => {
+                let m: Vec<f64> = numbers(value, ',').ok_or_else(|| bad("a transform"))?;
+                let identity = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+                match <[f64; 9]>::try_from(m) {
+                    Ok(m) if m == identity => (),
+                    Ok(m) => conf.transform = Some(m),
+                    Err(_) => return Err(bad("a transform")),
+                }
+            }
+            "scale" => match numbers::<f64>(value, 'x').as_deref() {
+                Some(&[x, y]) if (x, y) == (1.0, 1.0) => (),
+                Some(&[x, y]) if conf.transform.is_none() => {
+                    conf.transform = Some([x, 0.0, 0.0, 0.0, y, 0.0, 0.0, 0.0, 1.0])
+                }
+                Some(&[_, _]) => (),
+                _ => return Err(bad("a scale")),
+            },
+            _ => debug!("Skipping option {} of output {}", key, conf.name),
+        }
+    }
+    finish(current, flipped, &mut configs);
+    Ok(configs)
+}
+
+/// Build a configuration with a monitor declaration for each output of an autorandr profile's
+/// `setup`, named by the output, and a layout named `name` that places them as the profile's
+/// `config` does. Outputs that the config leaves out are turned off.
+fn import(
+    name: &str,
+    setup: Vec<(String, Monitor)>,
+    mut configs: HashMap<String, MonConfig>,
+) -> Config {
+    let mut monitors = HashMap::new();
+    let mut layout: HashMap<Monitor, Vec<MonConfig>> = HashMap::new();
+    let mut fb = (0u16, 0u16);
+    for (output, mon) in setup {
+        let conf = configs.remove(&output).unwrap_or_else(|| {
+            warn!("The autorandr config does not describe output {}, turning it off", output);
+            off(&output)
+        });
+        if !conf.off {
+            let (w, h) = conf.span();
+            fb.0 = fb.0.max((i32::from(conf.position.x) + i32::from(w)) as u16);
+            fb.1 = fb.1.max((i32::from(conf.position.y) + i32::from(h)) as u16);
+        }
+        layout.entry(mon.clone()).or_default().push(conf);
+        monitors.insert(output, mon);
+    }
+    for output in configs.keys() {
+        debug!("Skipping output {}, which was not connected", output);
+    }
+    let mut matches: Vec<Monitor> = monitors.values().cloned().collect();
+    matches.sort();
+    let single = SingleConfig {
+        name: name.to_string(),
+        fb_size: Mode { w: fb.0, h: fb.1 },
+        setup: layout,
+        dpi: None,
+        mm: None,
+        priority: 0,
+        screen: None,
+    };
+    Config {
+        layouts: vec![(matches, single)].into_iter().collect(),
+        groups: HashMap::new(),
+        lid_layouts: HashMap::new(),
+        monitors,
+        mirror_all: None,
+        side_by_side: None,
+        settings: Settings::default(),
+        on_error_exec: None,
+    }
+}
+
+/// Convert a profile of the Python autorandr into a configuration, with a layout named after
+/// the profile's directory.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    // Unwraps below are safe, because the program exits from `get_matches` when the arguments
+    // are not provided.
+    let dir = Path::new(args.value_of("profile").unwrap());
+    let out = args.value_of("out").unwrap();
+    let setup = std::fs::read_to_string(dir.join("setup")).into_diagnostic()?;
+    let config = std::fs::read_to_string(dir.join("config")).into_diagnostic()?;
+    let name = dir
+        .canonicalize()
+        .into_diagnostic()?
+        .file_name()
+        .map_or_else(|| "autorandr".to_string(), |n| n.to_string_lossy().into_owned());
+    let configs = parse_config(&config).into_diagnostic()?;
+    let text = import(&name, parse_setup(&setup), configs).to_kdl();
+    // The layout is read back, so that a profile this can't express isn't written
+    Config::from_reader(text.as_bytes()).into_diagnostic()?;
+    if out == "-" {
+        print!("{}", text);
+    } else {
+        std::fs::write(out, text).into_diagnostic()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an EDID, as hex, with a product name and serial number descriptor.
+    fn edid_hex(product: &str, serial: &str) -> String {
+        let mut edid = vec![0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+        // Vendor "DEL", product code, serial number, week, year, and EDID 1.3
+        edid.extend_from_slice(&[0x10, 0xac, 0x01, 0x00, 0, 0, 0, 0, 1, 30, 1, 3]);
+        edid.extend_from_slice(&[0x80, 60, 34, 0x78, 0x0a]);
+        edid.extend_from_slice(&[0; 10 + 3 + 16]);
+        for (tag, text) in [(0xfc, product), (0xff, serial)].iter() {
+            let mut field = format!("{}\n", text).into_bytes();
+            field.resize(13, b' ');
+            edid.extend_from_slice(&[0, 0, 0, *tag, 0]);
+            edid.extend_from_slice(&field);
+        }
+        for _ in 0..2 {
+            edid.extend_from_slice(&[0, 0, 0, 0x10, 0]);
+            edid.extend_from_slice(&[0; 13]);
+        }
+        edid.push(0);
+        let sum = edid.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        edid.push(sum.wrapping_neg());
+        edid.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn imports_an_autorandr_profile() {
+        let setup = format!(
+            "eDP-1 {}\nDP-1 {}\nHDMI-1 *\n",
+            edid_hex("Panel", "P1"),
+            edid_hex("Dock", "D1")
+        );
+        let config = "output DP-2\noff\n\
+            output DP-1\ncrtc 0\nmode 2560x1440\npos 0x0\nprimary\nrate 59.95\n\
+            x-prop-non_desktop 0\n\
+            output eDP-1\nmode 1920x1080\npos 2560x360\nrotate inverted\nreflect x\n\
+            scale 1.5x1.5\n\
+            output HDMI-1\noff\n";
+        let setup = parse_setup(&setup);
+        let imported = import("docked", setup, parse_config(config).unwrap());
+        let dock = &imported.monitors["DP-1"];
+        assert_eq!(dock.product.as_deref(), Some("Dock"));
+        assert_eq!(dock.serial.as_deref(), Some("D1"));
+        let hdmi = &imported.monitors["HDMI-1"];
+        assert_eq!(hdmi.connector.as_deref(), Some("HDMI"));
+        let (_, layout) = imported.layouts.iter().next().unwrap();
+        assert_eq!(layout.name, "docked");
+        assert_eq!(layout.fb_size, Mode { w: 5440, h: 1980 });
+        let dp = &layout.setup[dock][0];
+        assert!(dp.primary && !dp.off);
+        assert_eq!(dp.mode, Mode { w: 2560, h: 1440 });
+        let edp = &layout.setup[&imported.monitors["eDP-1"]][0];
+        assert_eq!(edp.position, Position { x: 2560, y: 360 });
+        // Inverted, then reflected along x, is reflected along y alone
+        assert_eq!(edp.reflect, Some(Reflection::Y));
+        assert_eq!(edp.transform, Some([1.5, 0.0, 0.0, 0.0, 1.5, 0.0, 0.0, 0.0, 1.0]));
+        assert!(layout.setup[hdmi][0].off);
+        assert!(parse_config("mode 1920x1080\n").is_err());
+        assert!(parse_config("output DP-1\npos 0\n").is_err());
+    }
+}
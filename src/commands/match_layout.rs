@@ -0,0 +1,166 @@
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+use serde_json::Value;
+use tracing::debug;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Read;
+
+use super::daemon::{connector_type, load_config, ApplyError, Error};
+use crate::config::{Config, Monitor};
+
+/// Read a string field of a monitor described in JSON, which is null or absent when unknown.
+fn json_str(mon: &Value, field: &str) -> Option<String> {
+    mon.get(field).and_then(Value::as_str).map(str::to_string)
+}
+
+/// Read the monitors described by JSON, either as a list of objects or as the input of a
+/// `--selector` script, with its list in the "monitors" member. Each has the name of its
+/// "output", and any of the "product", "serial", "serial_number", "text", "manufacturer" and
+/// "diagonal" of its EDID. Monitors without an output are named by their place in the list.
+fn from_json(value: &Value) -> std::result::Result<Vec<(String, Monitor)>, Error> {
+    let list = match value {
+        Value::Object(obj) => obj.get("monitors").and_then(Value::as_array),
+        Value::Array(list) => Some(list),
+        _ => None,
+    };
+    let list = list.ok_or(Error::BadMonitors)?;
+    list.iter()
+        .enumerate()
+        .map(|(i, mon)| {
+            if !mon.is_object() {
+                return Err(Error::BadMonitors);
+            }
+            let output = json_str(mon, "output").unwrap_or_else(|| format!("monitor-{}", i));
+            let number = |field| mon.get(field).and_then(Value::as_u64);
+            let monitor = Monitor {
+                product: json_str(mon, "product"),
+                serial: json_str(mon, "serial"),
+                serial_number: number("serial_number").and_then(|n| u32::try_from(n).ok()),
+                text: json_str(mon, "text"),
+                manufacturer: json_str(mon, "manufacturer"),
+                diagonal: number("diagonal").and_then(|n| u8::try_from(n).ok()),
+                ..Default::default()
+            };
+            Ok((output, monitor))
+        })
+        .collect()
+}
+
+/// Read the monitors described by the `monitor` nodes of a KDL document, as `print-edids`
+/// prints them, each named by its output. A serial with `any-serial` is the serial number from
+/// the EDID's header.
+fn from_kdl(text: &str) -> Result<Vec<(String, Monitor)>> {
+    let captured = Config::from_reader(text.as_bytes()).into_diagnostic()?;
+    let monitors = captured.monitors.into_iter().map(|(output, mut mon)| {
+        if mon.any_serial {
+            mon.serial_number = mon.serial.take().and_then(|s| s.parse().ok());
+            mon.any_serial = false;
+        }
+        (output, mon)
+    });
+    Ok(monitors.collect())
+}
+
+/// Find the layout of `config` that matches `monitors`, each connected to the output it's
+/// named by, as the daemon would. Monitors forced onto an output are replaced as they are when
+/// the daemon reads their EDIDs.
+fn find_layout<'c>(config: &'c Config, monitors: &[(String, Monitor)]) -> Option<&'c str> {
+    let forced: HashMap<&str, &Monitor> = config
+        .monitors
+        .values()
+        .filter_map(|mon| mon.force_output.as_deref().map(|name| (name, mon)))
+        .collect();
+    let connected: HashMap<usize, Monitor> = monitors
+        .iter()
+        .enumerate()
+        .map(|(i, (output, mon))| match forced.get(output.as_str()) {
+            Some(&mon) => (i, mon.clone()),
+            None => {
+                let connector = Some(connector_type(output).to_string());
+                (i, Monitor { connector, ..mon.clone() })
+            }
+        })
+        .collect();
+    let (layout, _) = config.find_layout(&connected)?;
+    Some(&layout.name)
+}
+
+/// Print the name of the layout that matches the monitors described in a file, without an X
+/// server.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let config = load_config(args)?;
+    // Unwrap below is safe, because the program exits from `get_matches` when the monitors are
+    // not provided.
+    let path = args.value_of("monitors").unwrap();
+    let mut text = String::new();
+    if path == "-" {
+        std::io::stdin().read_to_string(&mut text).into_diagnostic()?;
+    } else {
+        text = std::fs::read_to_string(path).into_diagnostic()?;
+    }
+    let mut monitors = match serde_json::from_str(&text) {
+        Ok(value) => from_json(&value).into_diagnostic()?,
+        Err(_) => from_kdl(&text)?,
+    };
+    monitors.sort_by(|a, b| a.0.cmp(&b.0));
+    for (output, mon) in monitors.iter() {
+        debug!("Output {} has monitor {:?}", output, mon);
+    }
+    let name = find_layout(&config, &monitors)
+        .ok_or(ApplyError::NoMatchingLayout(monitors.len()))
+        .into_diagnostic()?;
+    println!("{}", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn matches_captured_monitors() {
+        let config = Config::try_from(
+            kdl::parse_document(
+                r#"
+                monitor "Laptop" product="Panel" serial="1" any-serial=true
+                monitor "Dock" product="Dock" serial="D1"
+                monitor "Tv" connector="HDMI"
+                layout "Docked" {
+                    matches "Laptop" "Dock"
+                    monitor "Laptop" w=1920 h=1080 x=0 y=0
+                    monitor "Dock" w=1920 h=1080 x=1920 y=0
+                }
+                layout "Tv" {
+                    matches "Laptop" "Tv"
+                    monitor "Laptop" w=1920 h=1080 x=0 y=0
+                    monitor "Tv" w=1920 h=1080 x=1920 y=0
+                }
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let captured = json!({
+            "monitors": [
+                { "output": "eDP-1", "product": "Panel", "serial_number": 1 },
+                { "output": "DP-1", "product": "Dock", "serial": "D1", "text": null },
+            ]
+        });
+        let monitors = from_json(&captured).unwrap();
+        assert_eq!(find_layout(&config, &monitors), Some("Docked"));
+        let monitors = from_kdl(
+            r#"
+            monitor "eDP-1" product="Panel" serial="1" any-serial=true
+            monitor "HDMI-1" product="Projector"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(find_layout(&config, &monitors), Some("Tv"));
+        let monitors = from_json(&json!([{ "output": "DP-1", "product": "Dock" }])).unwrap();
+        assert_eq!(find_layout(&config, &monitors), None);
+        assert!(from_json(&json!({ "monitor": [] })).is_err());
+    }
+}
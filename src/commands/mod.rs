@@ -1,4 +1,28 @@
+mod apply;
+mod apply_group;
+mod check;
 mod daemon;
+mod diff;
+mod doctor;
+mod dump;
+mod dump_edid;
+mod import;
+mod match_layout;
+mod modes;
 mod print_edids;
-pub use daemon::{check, daemon};
+mod render;
+pub use apply::main as apply;
+pub use apply_group::main as apply_group;
+pub use check::main as check_config;
+pub use daemon::{daemon, load_config, poll_and_handle, ApplyError, DaemonState};
+#[cfg(feature = "async")]
+pub use daemon::handle_when_readable;
+pub use diff::main as diff;
+pub use doctor::main as doctor;
+pub use dump::main as dump;
+pub use dump_edid::main as dump_edid;
+pub use import::main as import;
+pub use match_layout::main as match_layout;
+pub use modes::main as modes;
 pub use print_edids::main as print_edids;
+pub use render::main as render;
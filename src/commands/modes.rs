@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+use x11rb::{
+    connect,
+    connection::Connection,
+    protocol::randr::{Connection as OutConnection, ModeInfo},
+    protocol::xproto::Window,
+};
+
+use super::daemon::Error;
+use super::doctor::describe_mode;
+use crate::backend::Backend;
+use crate::randr_version;
+
+/// List the modes of each connected output, or only of the output named `only`, by output
+/// name. Each output's preferred modes are listed first, as the output lists them.
+fn output_modes<B: Backend>(
+    conn: &B,
+    root: Window,
+    only: Option<&str>,
+) -> Result<Vec<(String, Vec<String>)>> {
+    let res = conn.screen_resources(root)?;
+    let modes: HashMap<u32, &ModeInfo> = res.modes.iter().map(|mi| (mi.id, mi)).collect();
+    let mut listed = Vec::new();
+    for &output in res.outputs.iter() {
+        let info = conn.output_info(output, res.config_timestamp)?;
+        let name = String::from_utf8_lossy(&info.name).into_owned();
+        if info.connection != OutConnection::CONNECTED || only.is_some_and(|o| o != name) {
+            continue;
+        }
+        let described = info
+            .modes
+            .iter()
+            .enumerate()
+            .filter_map(|(i, id)| {
+                let mode = describe_mode(modes.get(id)?);
+                Some(if i < usize::from(info.num_preferred) {
+                    format!("{} preferred", mode)
+                } else {
+                    mode
+                })
+            })
+            .collect();
+        listed.push((name, described));
+    }
+    match only {
+        Some(name) if listed.is_empty() => {
+            Err(Error::UnknownOutput(name.to_string())).into_diagnostic()
+        }
+        _ => Ok(listed),
+    }
+}
+
+/// Print the modes each connected output supports, for picking the size of a monitor.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let (conn, screen_num) = connect(None).into_diagnostic()?;
+    randr_version(&conn)?;
+    let root = conn.setup().roots[screen_num].root;
+    for (name, modes) in output_modes(&conn, root, args.value_of("output"))? {
+        println!("{}:", name);
+        for mode in modes {
+            println!("  {}", mode);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+
+    #[test]
+    fn lists_the_modes_of_connected_outputs() {
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .refresh(1, 60)
+            .mode(2, 1920, 1080)
+            .refresh(2, 144)
+            .output(10, "DP-1", 0, &[100], &[1, 2])
+            .output(11, "HDMI-1", 0, &[100], &[1]);
+        let listed = output_modes(&mock, 1, Some("DP-1")).unwrap();
+        let modes = vec![
+            "1920x1080 60.00Hz preferred".to_string(),
+            "1920x1080 144.00Hz".to_string(),
+        ];
+        assert_eq!(listed, vec![("DP-1".to_string(), modes)]);
+        assert_eq!(output_modes(&mock, 1, None).unwrap().len(), 2);
+        assert!(output_modes(&mock, 1, Some("VGA-1")).is_err());
+    }
+}
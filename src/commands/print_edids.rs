@@ -1,54 +1,242 @@
 use clap::ArgMatches;
+use edid::Header;
 use miette::{IntoDiagnostic, Result};
 use tracing::debug;
 use x11rb::{
     connect,
     connection::Connection,
-    protocol::randr::{ConnectionExt as RandrExt, Output},
-    protocol::xproto::Timestamp,
+    protocol::randr::{ConnectionExt, GetScreenResourcesCurrentReply, Output},
 };
 
-use crate::{config::Monitor, edid_atom, get_monitors, get_outputs};
+use crate::{
+    colorimetry::Colorimetry,
+    config::{Config, Mode, Monitor},
+    edid_atom, get_connected_outputs, get_edids, get_outputs, randr_version,
+};
+
+/// Describe the manufacturer and date of manufacture recorded in an EDID header, such as
+/// "SAM 27/2017".
+fn manufactured(header: &Header) -> String {
+    let vendor: String = header.vendor.iter().collect();
+    let year = 1990 + header.year as u16;
+    match header.week {
+        // Week 0 means no week was specified, and week 255 marks the year as a model year
+        0 | 255 => format!("{} {}", vendor, year),
+        week => format!("{} {}/{}", vendor, week, year),
+    }
+}
+
+/// Describe the color characteristics of a monitor as comment lines, so that the output is still
+/// a valid configuration.
+fn describe_colors(colors: &Colorimetry) -> String {
+    let gamma = match colors.gamma {
+        Some(gamma) => format!("gamma {:.2}", gamma),
+        None => "gamma undefined".to_string(),
+    };
+    let depth = match colors.bit_depth {
+        Some(bits) => format!(", {} bits per color", bits),
+        None => String::new(),
+    };
+    let point = |(x, y): (f64, f64)| format!("{:.4},{:.4}", x, y);
+    format!(
+        "// {}{}\n// red {} green {} blue {} white {}\n",
+        gamma,
+        depth,
+        point(colors.red),
+        point(colors.green),
+        point(colors.blue),
+        point(colors.white),
+    )
+}
 
-fn mon_name<C: Connection>(conn: &C, out: Output, ts: Timestamp) -> Result<String> {
-    Ok(String::from_utf8(
-        conn.randr_get_output_info(out, ts)
-            .into_diagnostic()?
-            .reply()
-            .into_diagnostic()?
-            .name,
-    ).into_diagnostic()?)
+/// Read the name of an output, and the mode it shows, or its preferred mode when it is off.
+fn name_and_mode<C: Connection>(
+    conn: &C,
+    res: &GetScreenResourcesCurrentReply,
+    output: Output,
+) -> Result<(String, Option<Mode>)> {
+    let ts = res.config_timestamp;
+    let info = conn.randr_get_output_info(output, ts).into_diagnostic()?.reply().into_diagnostic()?;
+    let mode = if info.crtc != 0 {
+        conn.randr_get_crtc_info(info.crtc, ts).into_diagnostic()?.reply().into_diagnostic()?.mode
+    } else if info.num_preferred > 0 {
+        info.modes[0]
+    } else {
+        0
+    };
+    let mode = res.modes.iter().find(|mi| mi.id == mode).map(|mi| Mode {
+        w: mi.width,
+        h: mi.height,
+    });
+    Ok((String::from_utf8_lossy(&info.name).into_owned(), mode))
+}
+
+/// Write a layout that matches every one of `monitors`, placing them side by side, left to
+/// right, in their modes. Monitors without a mode are turned off.
+fn starter_layout(monitors: &[(String, Option<Mode>)]) -> String {
+    let names: Vec<_> = monitors.iter().map(|(name, _)| format!(r#""{}""#, name)).collect();
+    let mut text = format!("layout \"Detected\" {{\n    matches {}\n", names.join(" "));
+    let mut x = 0u32;
+    for (name, mode) in monitors.iter() {
+        match mode {
+            Some(mode) => {
+                text.push_str(&format!(
+                    "    monitor \"{}\" w={} h={} x={} y=0\n",
+                    name, mode.w, mode.h, x
+                ));
+                x += u32::from(mode.w);
+            }
+            None => text.push_str(&format!("    monitor \"{}\" \"off\"\n", name)),
+        }
+    }
+    text.push_str("}\n");
+    text
 }
 
 /// You know.
-pub fn main(_: &ArgMatches<'_>) -> Result<()> {
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let config = match args.value_of("config") {
+        Some(fname) => Some(Config::from_path(fname).into_diagnostic()?),
+        None => None,
+    };
+    let unmatched = args.is_present("unmatched");
+    let with_layout = args.is_present("with-layout");
+    let detailed = args.is_present("detailed");
+    let labels: Vec<(&String, &Monitor)> = config
+        .iter()
+        .flat_map(|c| c.monitors.iter())
+        .collect();
     let (conn, screen_num) = connect(None).into_diagnostic()?;
+    randr_version(&conn)?;
     let setup = conn.setup();
     let atom_edid = edid_atom(&conn)?;
     let root = setup.roots[screen_num].root;
     let outs = get_outputs(&conn, root)?;
-    let monitors = get_monitors(&conn, &outs.outputs, atom_edid)
-        .map(|(k, v)| {
-            let new_k = mon_name(&conn, k, outs.timestamp)?;
-            Ok((new_k, v))
+    let connected = get_connected_outputs(&conn, &outs)?;
+    let mut monitors = get_edids(&conn, &connected, atom_edid)
+        .map(|(k, edid)| {
+            let (new_k, mode) = name_and_mode(&conn, &outs, k)?;
+            let made = manufactured(&edid.edid.header);
+            let colors = edid.colorimetry.clone();
+            Ok((new_k, mode, made, colors, Monitor::from(edid)))
         })
-        .collect::<Result<Vec<(String, Monitor)>>>()?;
-    for (name, m) in monitors.into_iter() {
+        .collect::<Result<Vec<(String, Option<Mode>, String, Option<Colorimetry>, Monitor)>>>()?;
+    // Printed in order of their outputs, so that the output is the same from run to run
+    monitors.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut placed = Vec::new();
+    for (port, mode, made, colors, m) in monitors.into_iter() {
         debug!("{:?}", m);
         let product = m
             .product
+            .as_ref()
             .map(|p| format!(r#"product="{}""#, p))
             .unwrap_or_default();
-        let serial = m
-            .serial
-            .map(|s| format!(r#"serial="{}""#, s))
+        // Without a serial descriptor, the serial number may still tell monitors apart
+        let serial = match (&m.serial, m.serial_number) {
+            (Some(s), _) => format!(r#"serial="{}""#, s),
+            (None, Some(n)) => format!(r#"serial="{}" any-serial=true"#, n),
+            (None, None) => String::new(),
+        };
+        let text = m
+            .text
+            .as_ref()
+            .map(|t| format!(r#" text="{}""#, t))
             .unwrap_or_default();
+        // An exact alias is a better label than a fuzzy one
+        let label = labels
+            .iter()
+            .filter(|(_, mon)| mon.matches(&m))
+            .min_by_key(|(_, mon)| mon.is_fuzzy());
+        let (name, comment) = match label {
+            Some((label, _)) if unmatched => {
+                placed.push((label.to_string(), mode));
+                continue;
+            }
+            Some((label, _)) => (label.to_string(), format!("{}, {}", port, made)),
+            None => (port, made),
+        };
+        placed.push((name.clone(), mode));
         println!(
-            r#"monitor "{name}" {product} {serial}"#,
+            r#"monitor "{name}" {product} {serial}{text} // {comment}"#,
             name = name,
             serial = serial,
-            product = product
+            text = text,
+            product = product,
+            comment = comment,
         );
+        if let Some(colors) = colors.filter(|_| detailed) {
+            print!("{}", describe_colors(&colors));
+        }
+    }
+    if with_layout && !placed.is_empty() {
+        print!("{}", starter_layout(&placed));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(week: u8, year: u8) -> Header {
+        Header {
+            vendor: ['S', 'A', 'M'],
+            product: 596,
+            serial: 0,
+            week,
+            year,
+            version: 1,
+            revision: 3,
+        }
+    }
+
+    #[test]
+    fn manufacture_date() {
+        assert_eq!(manufactured(&header(27, 17)), "SAM 27/2007");
+        assert_eq!(manufactured(&header(0, 30)), "SAM 2020");
+        assert_eq!(manufactured(&header(255, 30)), "SAM 2020");
+    }
+
+    #[test]
+    fn colors_are_described_in_comments() {
+        let colors = Colorimetry {
+            gamma: Some(2.2),
+            red: (0.64, 0.33),
+            green: (0.3, 0.6),
+            blue: (0.15, 0.06),
+            white: (0.3125, 0.3291),
+            bit_depth: Some(8),
+        };
+        assert_eq!(
+            describe_colors(&colors),
+            "// gamma 2.20, 8 bits per color\n\
+             // red 0.6400,0.3300 green 0.3000,0.6000 blue 0.1500,0.0600 white 0.3125,0.3291\n"
+        );
+        let colors = Colorimetry {
+            gamma: None,
+            bit_depth: None,
+            ..colors
+        };
+        assert!(describe_colors(&colors).starts_with("// gamma undefined\n// red"));
+    }
+
+    #[test]
+    fn starter_layout_places_monitors_side_by_side() {
+        let mode = |w, h| Some(Mode { w, h });
+        let monitors = [
+            ("eDP-1".to_string(), mode(1920, 1200)),
+            ("DP-1".to_string(), mode(2560, 1440)),
+            ("HDMI-1".to_string(), None),
+            ("DP-2".to_string(), mode(1920, 1080)),
+        ];
+        let expected = r#"layout "Detected" {
+    matches "eDP-1" "DP-1" "HDMI-1" "DP-2"
+    monitor "eDP-1" w=1920 h=1200 x=0 y=0
+    monitor "DP-1" w=2560 h=1440 x=1920 y=0
+    monitor "HDMI-1" "off"
+    monitor "DP-2" w=1920 h=1080 x=4480 y=0
+}
+"#;
+        assert_eq!(starter_layout(&monitors), expected);
+    }
+}
@@ -0,0 +1,147 @@
+use clap::ArgMatches;
+use miette::{IntoDiagnostic, Result};
+
+use super::daemon::{load_config, Error};
+use crate::config::{MonConfig, SingleConfig};
+
+/// The width of the terminal on stdout, from `COLUMNS` when it isn't a terminal, or 80.
+fn terminal_width() -> usize {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if ret == 0 && size.ws_col > 0 {
+        return size.ws_col.into();
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(80)
+}
+
+/// Draw the lit monitors of `layout` as boxes within its frame buffer, which is `columns` wide.
+/// Rows are scaled by half as much as columns, as a character is about twice as tall as it is
+/// wide. The frame buffer is filled with '.', so that gaps stand out, and places covered by more
+/// than one monitor with '#'. Each box is labeled with its monitor's name and mode, which are
+/// written over any '#'.
+fn render(layout: &SingleConfig, columns: usize) -> String {
+    let (fb_w, fb_h) = (usize::from(layout.fb_size.w), usize::from(layout.fb_size.h));
+    let columns = columns.max(2);
+    let rows = ((columns * fb_h) / (fb_w * 2).max(1)).max(2);
+    let col = |x: usize| (x * columns / fb_w.max(1)).min(columns);
+    let row = |y: usize| (y * rows / fb_h.max(1)).min(rows);
+    let mut monitors: Vec<&MonConfig> = layout.setup.values().flatten().collect();
+    monitors.sort_by(|a, b| a.name.cmp(&b.name));
+    let (off, lit): (Vec<_>, Vec<_>) = monitors.into_iter().partition(|conf| conf.off);
+    let boxes: Vec<_> = lit
+        .iter()
+        .map(|conf| {
+            let (w, h) = conf.span();
+            let (x, y) = (conf.position.x.max(0) as usize, conf.position.y.max(0) as usize);
+            let (left, top) = (col(x).min(columns - 1), row(y).min(rows - 1));
+            let right = col(x + usize::from(w)).saturating_sub(1).max(left);
+            let bottom = row(y + usize::from(h)).saturating_sub(1).max(top);
+            (conf, left, top, right, bottom)
+        })
+        .collect();
+    let mut grid = vec![vec!['.'; columns]; rows];
+    let mut covered = vec![vec![0u8; columns]; rows];
+    for &(_, left, top, right, bottom) in boxes.iter() {
+        for r in top..=bottom {
+            for c in left..=right {
+                covered[r][c] = covered[r][c].saturating_add(1);
+                grid[r][c] = match (r == top || r == bottom, c == left || c == right) {
+                    (true, true) => '+',
+                    (true, false) => '-',
+                    (false, true) => '|',
+                    (false, false) => ' ',
+                };
+            }
+        }
+    }
+    for (r, line) in covered.iter().enumerate() {
+        for (c, &count) in line.iter().enumerate() {
+            if count > 1 {
+                grid[r][c] = '#';
+            }
+        }
+    }
+    for &(conf, left, top, right, bottom) in boxes.iter() {
+        let labels = [conf.name.clone(), conf.mode.to_string()];
+        let inner = (right - left).saturating_sub(1);
+        for (r, label) in (top + 1..bottom).zip(labels.iter()) {
+            for (c, ch) in (left + 1..).zip(label.chars().take(inner)) {
+                grid[r][c] = ch;
+            }
+        }
+    }
+    let mut text = format!("{} ({})\n", layout.name, layout.fb_size);
+    for line in grid {
+        text.extend(line);
+        text.push('\n');
+    }
+    if !off.is_empty() {
+        let names: Vec<_> = off.iter().map(|conf| conf.name.as_str()).collect();
+        text.push_str(&format!("off: {}\n", names.join(", ")));
+    }
+    text
+}
+
+/// Print a diagram of the layout `name`, scaled to fit the terminal, without applying it.
+pub fn main(args: &ArgMatches<'_>) -> Result<()> {
+    let config = load_config(args)?;
+    // Unwrap below is safe, because the program exits from `get_matches` when a name is not
+    // provided.
+    let name = args.value_of("name").unwrap();
+    let (_, layout) = config
+        .named_layout(name)
+        .ok_or_else(|| Error::UnknownLayout(name.to_string()))
+        .into_diagnostic()?;
+    print!("{}", render(layout, terminal_width()));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn draws_monitors_and_their_overlaps() {
+        let config = Config::try_from(
+            kdl::parse_document(
+                r#"
+                monitor "Laptop" product="Panel"
+                monitor "Dock" product="Dock"
+                monitor "Tv" product="Tv"
+                layout "Desk" fb="4000x1600" {
+                    matches "Laptop" "Dock" "Tv"
+                    monitor "Dock" w=2000 h=1200 x=0 y=0
+                    monitor "Laptop" w=1600 h=800 x=1700 y=800
+                    monitor "Tv" "off"
+                }
+                "#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let (_, layout) = config.named_layout("Desk").unwrap();
+        let expected = "\
+Desk (4000x1600)
++------------------+....................
+|Dock              |....................
+|2000x1200         |....................
+|                  |....................
+|                ###------------+.......
++----------------#Laptop        |.......
+.................|1600x800      |.......
+.................+--------------+.......
+off: Tv
+";
+        assert_eq!(render(layout, 40), expected);
+    }
+}
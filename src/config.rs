@@ -1,15 +1,24 @@
 //! Parser for the monitor-layout(5) configuration file
 use edid::{Descriptor, EDID};
 use kdl::{parse_document, KdlError, KdlNode as Node, KdlValue};
+use regex::Regex;
+use serde::{Serialize, Serializer};
 use thiserror::Error;
+use x11rb::protocol::randr::ModeFlag;
+
+use crate::ParsedEdid;
 
 use std::{
-    cmp::max,
-    collections::HashMap,
+    cmp::{max, Ordering, Reverse},
+    collections::{BTreeMap, HashMap},
     convert::TryFrom,
+    ffi::OsStr,
     fmt::{Display, Formatter},
+    hash::{Hash, Hasher},
     io::{Error as IoError, Read},
     num::ParseIntError,
+    path::Path,
+    str::FromStr,
 };
 
 #[derive(Error, Debug)]
@@ -32,30 +41,158 @@ pub enum Error {
     Unexpected(String),
     #[error("Io Error")]
     Io(#[from] IoError),
+    #[error("The size of monitor {0} does not match its modeline")]
+    ModeLineMismatch(String),
+    #[error("The backlight of monitor {0} must be a percentage, between 0 and 100")]
+    BacklightRange(String),
+    #[error("The frame buffer of layout {0} is {1}, which can't hold its monitors, needing {2}")]
+    FbTooSmall(String, Mode, Mode),
+    #[error("Layouts {0} and {1} match the same monitors")]
+    DuplicateLayout(String, String),
+    #[error("The layout name {0} is reserved for the daemon's safe layout")]
+    ReservedName(String),
+    #[error("Monitor {0} in {1} differs from its earlier declaration")]
+    ConflictingMonitor(String, String),
+    #[error("Monitor {1} in layout {0} extends past the largest possible frame buffer")]
+    OffScreen(String, String),
+    #[error("Monitor {0} sets both {1} and {2}")]
+    PositionConflict(String, &'static str, &'static str),
+    #[error("Monitor {0} sets both {1} and {2}, which are exclusive")]
+    FieldConflict(String, &'static str, &'static str),
+    #[error("The product-pattern of monitor {0} is not a valid regular expression")]
+    BadPattern(String, #[source] regex::Error),
+    #[error("Monitor {1} in layout {0} is centered on {2}, which is not lit in that layout")]
+    BadCenter(String, String, String),
+    #[error("Monitors in layout {0}, including {1}, are centered on each other in a cycle")]
+    CenterCycle(String, String),
+    #[error("Monitors {1} and {2} in layout {0} are both pinned to CRTC {3}")]
+    DuplicateCrtc(String, String, String, u8),
+    #[error("Monitors {1} and {2} in layout {0} are both expected on output {3}")]
+    DuplicateOutput(String, String, String, String),
+    #[error("Monitors {1} and {2} in layout {0} are both primary")]
+    DuplicatePrimary(String, String, String),
+    #[error("Monitor {1} in layout {0} is both turned off and lit")]
+    OffAndLit(String, String),
+    #[error("Layout {0} sets both {1} and {2}")]
+    LayoutConflict(String, &'static str, &'static str),
+    #[error("Layout {0} matches any {1} monitors, but configures {2}")]
+    CountMismatch(String, usize, usize),
+    #[error("Layout {0} requires monitor {1}, which must name one of its monitors each time")]
+    UnplacedRequirement(String, String),
+    #[error("The transform of monitor {0} can't be inverted")]
+    SingularTransform(String),
+    #[error("The {1} mode of monitor {0} must be 8 to 8192 by 1 to 8192 pixels, at up to 1000 Hz")]
+    BadTimingFormula(String, &'static str),
+    #[cfg(feature = "kdl2")]
+    #[error("Parse Error")]
+    Kdl2ParseError(#[from] kdl2::KdlError),
+    #[cfg(feature = "kdl2")]
+    #[error("Integer {0} is out of range")]
+    IntRange(i128),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// The rotation to apply to a monitor
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Rotation {
     Left,
     Right,
 }
 
+/// The axes along which to reflect, or flip, a monitor's picture
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Reflection {
+    X,
+    Y,
+    XY,
+}
+
+impl Reflection {
+    /// The name of this reflection in the configuration file
+    pub fn name(self) -> &'static str {
+        match self {
+            Reflection::X => "x",
+            Reflection::Y => "y",
+            Reflection::XY => "xy",
+        }
+    }
+}
+
+/// The state of a laptop's lid, which a layout may require
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lid {
+    Open,
+    Closed,
+}
+
+impl Lid {
+    /// The name of this state in the configuration file
+    pub fn name(self) -> &'static str {
+        match self {
+            Lid::Open => "open",
+            Lid::Closed => "closed",
+        }
+    }
+}
+
+/// How the GPU scales a mode smaller than the panel to fill it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScalingMode {
+    /// Stretch the mode over the whole panel
+    Full,
+    /// Show the mode unscaled, in the center of the panel
+    Center,
+    /// Stretch the mode as far as it goes while keeping its aspect ratio
+    Aspect,
+}
+
+impl ScalingMode {
+    /// The name of this mode in the configuration file
+    pub fn name(self) -> &'static str {
+        match self {
+            ScalingMode::Full => "full",
+            ScalingMode::Center => "center",
+            ScalingMode::Aspect => "aspect",
+        }
+    }
+
+    /// The value of the output's "scaling mode" property that selects this mode
+    pub fn property_value(self) -> &'static str {
+        match self {
+            ScalingMode::Full => "Full",
+            ScalingMode::Center => "Center",
+            ScalingMode::Aspect => "Full aspect",
+        }
+    }
+}
+
+/// The value an output property is set to, from a monitor's `properties` node. It's converted to
+/// the property's type when the layout is applied.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum PropertyValue {
+    Int(i64),
+    String(String),
+}
+
 trait FromNode: Sized {
     fn from_node(f: &Node) -> Result<Self>;
 }
 
 /// A position, expressed an <x>x<y>
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct Position {
     pub x: i16,
     pub y: i16,
 }
 
 /// A monitor mode, expressed an <w>x<h>
-#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Serialize)]
 pub struct Mode {
     pub w: u16,
     pub h: u16,
@@ -71,40 +208,586 @@ impl Mode {
     }
 }
 
+impl FromStr for Mode {
+    type Err = Error;
+    /// Parse a mode written like "1920x1080"
+    fn from_str(s: &str) -> Result<Self> {
+        let (w, h) = s
+            .split_once('x')
+            .ok_or(Error::FieldTypeMisMatch("mode", "<width>x<height>"))?;
+        Ok(Self {
+            w: w.parse()?,
+            h: h.parse()?,
+        })
+    }
+}
+
 impl Display for Mode {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         write!(f, "{}x{}", self.w, self.h)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// A custom mode, expressed as an xrandr style modeline
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ModeLine {
+    /// The pixel clock, in MHz
+    pub clock: f64,
+    pub hdisplay: u16,
+    pub hsync_start: u16,
+    pub hsync_end: u16,
+    pub htotal: u16,
+    pub vdisplay: u16,
+    pub vsync_start: u16,
+    pub vsync_end: u16,
+    pub vtotal: u16,
+    /// RandR mode flags
+    pub flags: u32,
+}
+
+/// The names of the mode flags, as written after the timings of a modeline
+const MODE_FLAGS: [(&str, ModeFlag); 9] = [
+    ("+hsync", ModeFlag::HSYNC_POSITIVE),
+    ("-hsync", ModeFlag::HSYNC_NEGATIVE),
+    ("+vsync", ModeFlag::VSYNC_POSITIVE),
+    ("-vsync", ModeFlag::VSYNC_NEGATIVE),
+    ("interlace", ModeFlag::INTERLACE),
+    ("doublescan", ModeFlag::DOUBLE_SCAN),
+    ("csync", ModeFlag::CSYNC),
+    ("+csync", ModeFlag::CSYNC_POSITIVE),
+    ("-csync", ModeFlag::CSYNC_NEGATIVE),
+];
+
+impl FromNode for ModeLine {
+    fn from_node(n: &Node) -> Result<Self> {
+        if n.name != "modeline" {
+            return Err(Error::NodeTypeMismatch("modeline", n.name.clone()));
+        }
+        let name = "layout.monitor.modeline";
+        let clock = match n.values.first() {
+            None => return Err(Error::MissingField(name, "clock")),
+            Some(KdlValue::Float(f)) => *f,
+            Some(KdlValue::Int(i)) => *i as f64,
+            Some(_) => return Err(Error::FieldTypeMisMatch(name, "number")),
+        };
+        let mut timings = [0u16; 8];
+        for (i, timing) in timings.iter_mut().enumerate() {
+            *timing = match n.values.get(i + 1) {
+                None => return Err(Error::MissingField(name, "timings")),
+                Some(KdlValue::Int(v)) => *v as u16,
+                Some(_) => return Err(Error::FieldTypeMisMatch(name, "int")),
+            };
+        }
+        let mut flags = 0;
+        for v in n.values.iter().skip(9) {
+            let flag = match v {
+                KdlValue::String(f) => {
+                    let f = f.to_lowercase();
+                    match MODE_FLAGS.iter().find(|(flag_name, _)| *flag_name == f) {
+                        Some(&(_, flag)) => flag,
+                        None => return Err(Error::FieldTypeMisMatch(name, "mode flag")),
+                    }
+                }
+                _ => return Err(Error::FieldTypeMisMatch(name, "String")),
+            };
+            flags |= u32::from(flag);
+        }
+        let [hdisplay, hsync_start, hsync_end, htotal, vdisplay, vsync_start, vsync_end, vtotal] =
+            timings;
+        Ok(Self {
+            clock,
+            hdisplay,
+            hsync_start,
+            hsync_end,
+            htotal,
+            vdisplay,
+            vsync_start,
+            vsync_end,
+            vtotal,
+            flags,
+        })
+    }
+}
+
+/// A regular expression for product names, compiled once when the config is read. Patterns
+/// compare by their text, so that a monitor with one may still be a key.
+#[derive(Clone, Debug)]
+pub struct ProductPattern(pub Regex);
+
+impl FromStr for ProductPattern {
+    type Err = regex::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Regex::new(s).map(Self)
+    }
+}
+
+impl PartialEq for ProductPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for ProductPattern {}
+
+impl Hash for ProductPattern {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state)
+    }
+}
+
+impl PartialOrd for ProductPattern {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ProductPattern {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.as_str().cmp(other.0.as_str())
+    }
+}
+
+impl Serialize for ProductPattern {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.serialize_str(self.0.as_str())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
 pub struct Monitor {
     pub product: Option<String>,
+    /// A regular expression that matches any part of the product name, in place of `product`
+    pub product_pattern: Option<ProductPattern>,
     pub serial: Option<String>,
+    /// The serial number from the EDID's header, which some monitors report in place of a serial
+    /// descriptor. Only set for connected monitors.
+    pub serial_number: Option<u32>,
+    /// Whether `serial` also matches the `serial_number` of a connected monitor
+    pub any_serial: bool,
+    /// The first unspecified text descriptor, which some vendors use for a model code
+    pub text: Option<String>,
+    /// The three letter manufacturer id, such as "DEL"
+    pub manufacturer: Option<String>,
+    /// The diagonal of the display area, rounded to the nearest inch
+    pub diagonal: Option<u8>,
+    /// The name of an output that this monitor is always connected to, regardless of its EDID
+    pub force_output: Option<String>,
+    /// The type of connector the monitor is plugged into, such as "DP" or "HDMI"
+    pub connector: Option<String>,
+    /// Matches any connected monitor. A layout with a `match-count` matches this many of them.
+    pub wildcard: bool,
+}
+
+impl Monitor {
+    /// A monitor that matches any connected monitor
+    pub fn wildcard() -> Self {
+        Monitor {
+            wildcard: true,
+            ..Default::default()
+        }
+    }
+
+    /// A monitor with a manufacturer, diagonal or connector matches any connected monitor that
+    /// shares the fields it specifies, rather than only an identical monitor.
+    pub fn is_fuzzy(&self) -> bool {
+        self.wildcard
+            || self.product_pattern.is_some()
+            || self.manufacturer.is_some()
+            || self.diagonal.is_some()
+            || self.connector.is_some()
+    }
+
+    /// Whether the serial of this monitor, from a config, is the serial of the `connected` one.
+    /// With `any_serial`, it may instead be the serial number from its EDID's header.
+    fn serial_matches(&self, connected: &Monitor) -> bool {
+        self.serial == connected.serial
+            || self.any_serial
+                && self.serial.is_some()
+                && self.serial == connected.serial_number.map(|n| n.to_string())
+    }
+
+    /// Whether this monitor, from a config, describes the `connected` monitor.
+    pub fn matches(&self, connected: &Monitor) -> bool {
+        if self.wildcard {
+            return true;
+        }
+        fn optional<T: PartialEq>(want: &Option<T>, got: &Option<T>) -> bool {
+            want.is_none() || want == got
+        }
+        // The monitor on a forced output is replaced by the config's monitor
+        if self.force_output.is_some() || connected.force_output.is_some() {
+            self == connected
+        } else if self.is_fuzzy() {
+            let pattern = match (&self.product_pattern, &connected.product) {
+                (None, _) => true,
+                (Some(pattern), Some(product)) => pattern.0.is_match(product),
+                (Some(_), None) => false,
+            };
+            pattern
+                && optional(&self.product, &connected.product)
+                && (self.serial.is_none() || self.serial_matches(connected))
+                && optional(&self.text, &connected.text)
+                && optional(&self.manufacturer, &connected.manufacturer)
+                && optional(&self.diagonal, &connected.diagonal)
+                && optional(&self.connector, &connected.connector)
+        } else {
+            // A text descriptor tells apart monitors that are otherwise identical
+            self.product == connected.product
+                && self.serial_matches(connected)
+                && optional(&self.text, &connected.text)
+        }
+    }
 }
 
 impl From<EDID> for Monitor {
     fn from(edid: EDID) -> Self {
         let mut product = None;
         let mut serial = None;
+        let mut text = None;
+        // The size of the display area in mm. The basic display parameters only record it in
+        // cm, so the size from a detailed timing is preferred.
+        let mut size = (
+            u32::from(edid.display.width) * 10,
+            u32::from(edid.display.height) * 10,
+        );
+        let mut timing_size = None;
         for desc in edid.descriptors {
             match desc {
                 Descriptor::ProductName(pn) => product = Some(pn),
                 Descriptor::SerialNumber(sn) => serial = Some(sn),
+                Descriptor::UnspecifiedText(t) if text.is_none() => text = Some(t),
+                Descriptor::DetailedTiming(dt) if timing_size.is_none() => {
+                    timing_size = Some((
+                        u32::from(dt.horizontal_size),
+                        u32::from(dt.vertical_size),
+                    ))
+                }
                 _ => (),
             }
         }
-        Self { product, serial }
+        if let Some((w, h)) = timing_size.filter(|&(w, h)| w != 0 && h != 0) {
+            size = (w, h);
+        }
+        // A serial number of 0 is unused
+        let serial_number = Some(edid.header.serial).filter(|&n| n != 0);
+        let manufacturer = Some(edid.header.vendor.iter().collect());
+        let diagonal = match size {
+            (0, _) | (_, 0) => None,
+            (w, h) => Some(((w as f64).hypot(h as f64) / 25.4).round() as u8),
+        };
+        Self {
+            product,
+            product_pattern: None,
+            serial,
+            serial_number,
+            any_serial: false,
+            text,
+            manufacturer,
+            diagonal,
+            force_output: None,
+            connector: None,
+            wildcard: false,
+        }
     }
 }
 
-#[derive(Debug)]
+impl From<ParsedEdid> for Monitor {
+    /// Monitors that leave the product name or serial number out of the base EDID block may
+    /// report them in a DisplayID extension block instead.
+    fn from(parsed: ParsedEdid) -> Self {
+        let mut monitor = Monitor::from(parsed.edid);
+        if let Some(id) = parsed.display_id {
+            if monitor.product.is_none() {
+                monitor.product = id.product;
+            }
+            if monitor.serial.is_none() && monitor.serial_number.is_none() {
+                monitor.serial_number = id.serial;
+            }
+        }
+        monitor
+    }
+}
+
+/// Pair each connected monitor with a distinct monitor from `wanted`, which must all be used.
+/// Exact monitors are preferred over fuzzy ones. Returns the connected monitors, replaced by the
+/// monitor from `wanted` they're paired with.
+pub fn pair_monitors<K: Copy + Eq + Hash>(
+    wanted: &[Monitor],
+    connected: &HashMap<K, Monitor>,
+) -> Option<HashMap<K, Monitor>> {
+    fn search<K: Copy>(
+        wanted: &[&Monitor],
+        used: &mut [bool],
+        connected: &[(K, &Monitor)],
+        paired: &mut Vec<(K, Monitor)>,
+    ) -> bool {
+        let (&(key, mon), rest) = match connected.split_first() {
+            Some(split) => split,
+            None => return true,
+        };
+        for (i, want) in wanted.iter().enumerate() {
+            if !used[i] && want.matches(mon) {
+                used[i] = true;
+                paired.push((key, (*want).clone()));
+                if search(wanted, used, rest, paired) {
+                    return true;
+                }
+                used[i] = false;
+                paired.pop();
+            }
+        }
+        false
+    }
+    if wanted.len() != connected.len() {
+        return None;
+    }
+    let mut wanted: Vec<&Monitor> = wanted.iter().collect();
+    wanted.sort_by_key(|m| m.is_fuzzy());
+    let connected: Vec<_> = connected.iter().map(|(k, m)| (*k, m)).collect();
+    let mut paired = Vec::with_capacity(connected.len());
+    if search(&wanted, &mut vec![false; wanted.len()], &connected, &mut paired) {
+        Some(paired.into_iter().collect())
+    } else {
+        None
+    }
+}
+
+/// The rules that a layout may match the connected monitors by, from the most specific to the
+/// least. A layout matches by the least specific rule of any of its monitors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MatchRule {
+    /// Every monitor by its product and serial
+    Exact,
+    /// Some monitors by their manufacturer, diagonal or a pattern of their product
+    Fuzzy,
+    /// Some monitors by the type of connector they're plugged into
+    Connector,
+    /// Any monitors, by how many are connected
+    Count,
+}
+
+impl MatchRule {
+    /// The rule that a layout matches `wanted`, its monitors, by.
+    pub fn of<'a>(wanted: impl IntoIterator<Item = &'a Monitor>) -> Self {
+        let rule = |mon: &Monitor| {
+            if mon.wildcard {
+                MatchRule::Count
+            } else if mon.connector.is_some() {
+                MatchRule::Connector
+            } else if mon.is_fuzzy() {
+                MatchRule::Fuzzy
+            } else {
+                MatchRule::Exact
+            }
+        };
+        wanted.into_iter().map(rule).max().unwrap_or(MatchRule::Exact)
+    }
+
+    /// The name of this rule, for messages
+    pub fn name(self) -> &'static str {
+        match self {
+            MatchRule::Exact => "exact",
+            MatchRule::Fuzzy => "fuzzy",
+            MatchRule::Connector => "connector",
+            MatchRule::Count => "count",
+        }
+    }
+}
+
+/// Find the layout that matches the connected monitors, preferring those that match by a more
+/// specific rule, then those with a higher priority, then those with fewer wildcards and fuzzy
+/// monitors, and then the first by name.
+fn best_layout<'a, K: Copy + Eq + Hash>(
+    layouts: &'a HashMap<Vec<Monitor>, SingleConfig>,
+    connected: &HashMap<K, Monitor>,
+) -> Option<(&'a SingleConfig, HashMap<K, Monitor>)> {
+    layouts
+        .iter()
+        .filter_map(|(wanted, layout)| {
+            let wildcards = wanted.iter().filter(|m| m.wildcard).count();
+            let fuzzy = wanted.iter().filter(|m| m.is_fuzzy()).count();
+            let rank = (MatchRule::of(wanted), Reverse(layout.priority), wildcards, fuzzy);
+            pair_monitors(wanted, connected).map(|paired| (rank, layout, paired))
+        })
+        .min_by(|(a, a_layout, _), (b, b_layout, _)| {
+            a.cmp(b).then_with(|| a_layout.name.cmp(&b_layout.name))
+        })
+        .map(|(_, layout, paired)| (layout, paired))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct MonConfig {
     pub name: String,
     pub mode: Mode,
     pub position: Position,
     pub primary: bool,
     pub rot: Option<Rotation>,
+    /// Flips the picture, after it's rotated
+    pub reflect: Option<Reflection>,
+    /// The name of the output this monitor is expected on, used to tell identical monitors apart
+    pub output: Option<String>,
+    pub modeline: Option<ModeLine>,
+    /// A 3x3 matrix, row by row, that maps the monitor's pixels onto the screen, such as for
+    /// keystone correction
+    pub transform: Option<[f64; 9]>,
+    /// The brightness of the monitor's backlight, in percent
+    pub backlight: Option<u8>,
+    /// Whether the monitor's variable refresh rate, such as FreeSync, is turned on
+    pub vrr: Option<bool>,
+    /// The GPU scaling of a mode smaller than the panel
+    pub scaling_mode: Option<ScalingMode>,
+    /// Overrides the `prefer-refresh` setting for this monitor
+    pub prefer_refresh: Option<bool>,
+    /// The index, among the screen's CRTCs, of the CRTC that must drive this monitor
+    pub crtc: Option<u8>,
+    /// The name of the XInput touchscreen built into this monitor, which is mapped onto it
+    pub touch_device: Option<String>,
+    /// Output properties set by name, as `xrandr --set` sets them
+    pub properties: BTreeMap<String, PropertyValue>,
+    /// The path of the ICC profile that calibrates the monitor
+    pub icc: Option<String>,
+    /// A command run through the shell after the layout is applied, with each `{output}`
+    /// replaced by the name of the monitor's output
+    pub exec: Option<String>,
+    /// The output is turned off, though its monitor is still part of the layout's matches
+    pub off: bool,
+}
+
+impl MonConfig {
+    /// The width and height the monitor covers on the screen, after rotation and transform,
+    /// which a scaling transform may make a fraction of a pixel.
+    fn extent(&self) -> (f64, f64) {
+        let (w, h) = match self.rot {
+            Some(_) => (self.mode.h, self.mode.w),
+            None => (self.mode.w, self.mode.h),
+        };
+        let (w, h) = (f64::from(w), f64::from(h));
+        let m = match &self.transform {
+            Some(m) => m,
+            None => return (w, h),
+        };
+        // The transform may send any corner of the picture furthest from the origin
+        let (mut right, mut bottom) = (0f64, 0f64);
+        for &(x, y) in [(0.0, 0.0), (w, 0.0), (0.0, h), (w, h)].iter() {
+            let scale = m[6] * x + m[7] * y + m[8];
+            right = right.max((m[0] * x + m[1] * y + m[2]) / scale);
+            bottom = bottom.max((m[3] * x + m[4] * y + m[5]) / scale);
+        }
+        (right, bottom)
+    }
+
+    /// The width and height the monitor covers on the screen, after rotation and transform,
+    /// rounded up to whole pixels
+    pub fn span(&self) -> (u16, u16) {
+        let (w, h) = self.extent();
+        let clamp = |v: f64| v.ceil().min(f64::from(u16::MAX)) as u16;
+        (clamp(w), clamp(h))
+    }
+}
+
+/// Parse the text of a configuration as KDL v1. A document that starts with the KDL v2 version
+/// marker, or that isn't valid KDL v1, is parsed as KDL v2 instead.
+#[cfg(feature = "kdl2")]
+fn parse_kdl(text: &str) -> Result<Vec<Node>> {
+    if text.trim_start().starts_with(crate::kdl_v2::VERSION_MARKER) {
+        return crate::kdl_v2::parse_document(text);
+    }
+    // When neither parses, the error is reported for the default syntax
+    parse_document(text).or_else(|e| crate::kdl_v2::parse_document(text).map_err(|_| e.into()))
+}
+
+/// Parse the text of a configuration as KDL v1.
+#[cfg(not(feature = "kdl2"))]
+fn parse_kdl(text: &str) -> Result<Vec<Node>> {
+    Ok(parse_document(text)?)
+}
+
+/// Read the `properties` node of a layout's monitor, whose properties name the output properties
+/// to set. Booleans are set as 1 or 0.
+fn extract_properties(n: &Node) -> Result<BTreeMap<String, PropertyValue>> {
+    let name = "layout.monitor.properties";
+    if !n.values.is_empty() || !n.children.is_empty() {
+        return Err(Error::Unexpected(format!("in {}", name)));
+    }
+    n.properties
+        .iter()
+        .map(|(prop, value)| {
+            let value = match value {
+                KdlValue::Int(i) => PropertyValue::Int(*i),
+                KdlValue::Boolean(b) => PropertyValue::Int((*b).into()),
+                KdlValue::String(s) => PropertyValue::String(s.clone()),
+                _ => return Err(Error::FieldTypeMisMatch(name, "string, integer or boolean")),
+            };
+            Ok((prop.clone(), value))
+        })
+        .collect()
+}
+
+/// Read the nine values of a `transform` node, a 3x3 matrix written row by row.
+fn extract_transform(n: &Node) -> Result<[f64; 9]> {
+    let name = "layout.monitor.transform";
+    if n.values.len() != 9 {
+        return Err(Error::FieldTypeMisMatch(name, "nine numbers"));
+    }
+    let mut matrix = [0.0; 9];
+    for (cell, value) in matrix.iter_mut().zip(&n.values) {
+        *cell = match value {
+            KdlValue::Float(f) => *f,
+            KdlValue::Int(i) => *i as f64,
+            _ => return Err(Error::FieldTypeMisMatch(name, "number")),
+        };
+    }
+    Ok(matrix)
+}
+
+/// Compute the modeline of a `cvt` or `gtf` node, whose size defaults to that of its monitor,
+/// `w` by `h`, and whose refresh rate, `r`, defaults to 60 Hz.
+fn extract_timing_formula(
+    n: &Node,
+    monitor: &str,
+    w: Option<i64>,
+    h: Option<i64>,
+) -> Result<ModeLine> {
+    let (name, formula) = match n.name.as_str() {
+        "cvt" => ("layout.monitor.cvt", "cvt"),
+        _ => ("layout.monitor.gtf", "gtf"),
+    };
+    if !n.values.is_empty() || !n.children.is_empty() {
+        return Err(Error::Unexpected(format!("in {}", name)));
+    }
+    let w = extract_optional_int(n, "w", name)?.or(w);
+    let h = extract_optional_int(n, "h", name)?.or(h);
+    let refresh = match n.properties.get("r") {
+        None => 60.0,
+        Some(KdlValue::Float(f)) => *f,
+        Some(KdlValue::Int(i)) => *i as f64,
+        Some(_) => return Err(Error::FieldTypeMisMatch(name, "number")),
+    };
+    let reduced = extract_bool_value(n, "reduced", name)?;
+    if formula == "gtf" && n.properties.contains_key("reduced") {
+        return Err(Error::Unexpected(format!("reduced in {}", name)));
+    }
+    let (w, h) = match (w, h) {
+        (Some(w), Some(h)) => (w, h),
+        (None, _) => return Err(Error::MissingField(name, "w")),
+        (_, None) => return Err(Error::MissingField(name, "h")),
+    };
+    let in_range = (8..=8192).contains(&w) && (1..=8192).contains(&h);
+    if !in_range || refresh <= 0.0 || refresh > 1000.0 {
+        return Err(Error::BadTimingFormula(monitor.to_string(), formula));
+    }
+    let (w, h) = (w as u16, h as u16);
+    Ok(match formula {
+        "cvt" => crate::timing::cvt(w, h, refresh, reduced),
+        _ => crate::timing::gtf(w, h, refresh),
+    })
+}
+
+/// The determinant of a 3x3 matrix, written row by row. RandR only accepts a transform that
+/// can be inverted, which is one whose determinant isn't 0.
+fn determinant(m: &[f64; 9]) -> f64 {
+    m[0] * (m[4] * m[8] - m[5] * m[7]) - m[1] * (m[3] * m[8] - m[5] * m[6])
+        + m[2] * (m[3] * m[7] - m[4] * m[6])
 }
 
 fn extract_int_value(n: &Node, field: &'static str, name: &'static str) -> Result<i64> {
@@ -115,6 +798,14 @@ fn extract_int_value(n: &Node, field: &'static str, name: &'static str) -> Resul
     }
 }
 
+fn extract_optional_int(n: &Node, field: &'static str, name: &'static str) -> Result<Option<i64>> {
+    match n.properties.get(field) {
+        None => Ok(None),
+        Some(KdlValue::Int(i)) => Ok(Some(*i)),
+        Some(_) => Err(Error::FieldTypeMisMatch(name, "int")),
+    }
+}
+
 fn extract_bool_value(n: &Node, field: &'static str, name: &'static str) -> Result<bool> {
     match n.properties.get(field) {
         None => Ok(false),
@@ -123,6 +814,18 @@ fn extract_bool_value(n: &Node, field: &'static str, name: &'static str) -> Resu
     }
 }
 
+fn extract_optional_bool(
+    n: &Node,
+    field: &'static str,
+    name: &'static str,
+) -> Result<Option<bool>> {
+    match n.properties.get(field) {
+        None => Ok(None),
+        Some(KdlValue::Boolean(v)) => Ok(Some(*v)),
+        Some(_) => Err(Error::FieldTypeMisMatch(name, "boolean")),
+    }
+}
+
 fn extract_rot_value(n: &Node, field: &'static str, name: &'static str) -> Result<Option<Rotation>> {
     let rot_str = extract_optional_str(n, field, name)?;
     if let Some(s) = rot_str {
@@ -136,6 +839,21 @@ fn extract_rot_value(n: &Node, field: &'static str, name: &'static str) -> Resul
     }
 }
 
+/// Extract a coordinate, which is left out when the monitor is centered on another along its
+/// axis, as it's filled in later by `center_monitors`.
+fn extract_coordinate(
+    n: &Node,
+    monitor: &str,
+    field: &'static str,
+    center: &'static str,
+) -> Result<i16> {
+    match (n.properties.contains_key(field), n.properties.contains_key(center)) {
+        (true, true) => Err(Error::PositionConflict(monitor.to_string(), field, center)),
+        (false, true) => Ok(0),
+        _ => Ok(extract_int_value(n, field, "layout.monitor")? as i16),
+    }
+}
+
 fn get_name(n: &Node, name: &'static str) -> Result<String> {
     match n.values.get(0) {
         None => Err(Error::MissingField(name, "name")),
@@ -150,13 +868,140 @@ impl FromNode for MonConfig {
             return Err(Error::NodeTypeMismatch("monitor", n.name.clone()));
         }
         let name = get_name(n, "layout.monitor")?;
-        let x = extract_int_value(n, "x", "layout.monitor")? as i16;
-        let y = extract_int_value(n, "y", "layout.monitor")? as i16;
-        let w = extract_int_value(n, "w", "layout.monitor")? as u16;
-        let h = extract_int_value(n, "h", "layout.monitor")? as u16;
+        let output = extract_optional_str(n, "output", "layout.monitor")?;
+        // A monitor is turned off with either `monitor "alias" "off"` or `x="off"`
+        let off_value = match n.values.get(1) {
+            None => false,
+            Some(KdlValue::String(off)) if off == "off" => true,
+            Some(_) => return Err(Error::FieldTypeMisMatch("layout.monitor", "off")),
+        };
+        let off_x = matches!(n.properties.get("x"), Some(KdlValue::String(off)) if off == "off");
+        if off_value || off_x {
+            return Ok(Self {
+                name,
+                mode: Mode { w: 0, h: 0 },
+                position: Position { x: 0, y: 0 },
+                primary: false,
+                rot: None,
+                reflect: None,
+                output,
+                modeline: None,
+                transform: None,
+                backlight: None,
+                vrr: None,
+                scaling_mode: None,
+                prefer_refresh: None,
+                crtc: None,
+                touch_device: None,
+                properties: BTreeMap::new(),
+                icc: None,
+                exec: None,
+                off: true,
+            });
+        }
+        let x = extract_coordinate(n, &name, "x", "hcenter-on")?;
+        let y = extract_coordinate(n, &name, "y", "vcenter-on")?;
         let primary = extract_bool_value(n, "primary", "layout.monitor")?;
         let rot = extract_rot_value(n, "rotate", "layout.monitor")?;
-        let mode = Mode { w, h };
+        let reflect = match extract_optional_str(n, "reflect", "layout.monitor")? {
+            None => None,
+            Some(axes) => Some(
+                [Reflection::X, Reflection::Y, Reflection::XY]
+                    .iter()
+                    .copied()
+                    .find(|r| r.name() == axes)
+                    .ok_or(Error::FieldTypeMisMatch("layout.monitor", "x, y or xy"))?,
+            ),
+        };
+        let backlight = match extract_optional_int(n, "backlight", "layout.monitor")? {
+            Some(b) if (0..=100).contains(&b) => Some(b as u8),
+            Some(_) => return Err(Error::BacklightRange(name)),
+            None => None,
+        };
+        let vrr = extract_optional_bool(n, "vrr", "layout.monitor")?;
+        let scaling_mode = match extract_optional_str(n, "scaling-mode", "layout.monitor")? {
+            None => None,
+            Some(mode) => Some(
+                [ScalingMode::Full, ScalingMode::Center, ScalingMode::Aspect]
+                    .iter()
+                    .copied()
+                    .find(|m| m.name() == mode)
+                    .ok_or(Error::FieldTypeMisMatch(
+                        "layout.monitor",
+                        "full, center or aspect",
+                    ))?,
+            ),
+        };
+        let prefer_refresh = extract_optional_bool(n, "prefer-refresh", "layout.monitor")?;
+        let crtc = match extract_optional_int(n, "crtc", "layout.monitor")? {
+            Some(crtc) => Some(
+                u8::try_from(crtc)
+                    .map_err(|_| Error::FieldTypeMisMatch("layout.monitor", "CRTC index"))?,
+            ),
+            None => None,
+        };
+        let touch_device = extract_optional_str(n, "touch-device", "layout.monitor")?;
+        let icc = extract_optional_str(n, "icc", "layout.monitor")?;
+        let exec = extract_optional_str(n, "exec", "layout.monitor")?;
+        let mut modeline = None;
+        let mut formula = None;
+        let mut transform = None;
+        let mut properties = None;
+        for node in &n.children {
+            match node.name.as_str() {
+                "cvt" | "gtf" if formula.is_none() => formula = Some(node),
+                "cvt" | "gtf" => {
+                    return Err(match (formula.map(|f| f.name.as_str()), node.name.as_str()) {
+                        (Some("cvt"), "cvt") => Error::DuplicateSingleton("layout.monitor.cvt"),
+                        (Some("gtf"), "gtf") => Error::DuplicateSingleton("layout.monitor.gtf"),
+                        _ => Error::FieldConflict(name, "cvt", "gtf"),
+                    })
+                }
+                "properties" if properties.is_none() => {
+                    properties = Some(extract_properties(node)?)
+                }
+                "properties" => return Err(Error::DuplicateSingleton("layout.monitor.properties")),
+                "modeline" if modeline.is_none() => modeline = Some(ModeLine::from_node(node)?),
+                "modeline" => return Err(Error::DuplicateSingleton("layout.monitor.modeline")),
+                "transform" if transform.is_none() => transform = Some(extract_transform(node)?),
+                "transform" => return Err(Error::DuplicateSingleton("layout.monitor.transform")),
+                _ => return Err(Error::Unexpected(node.name.clone())),
+            }
+        }
+        if matches!(transform, Some(m) if determinant(&m) == 0.0) {
+            return Err(Error::SingularTransform(name));
+        }
+        // A mode computed by formula is created just as a modeline is
+        if let Some(node) = formula {
+            if modeline.is_some() {
+                let formula = if node.name == "cvt" { "cvt" } else { "gtf" };
+                return Err(Error::FieldConflict(name, "modeline", formula));
+            }
+            let w = extract_optional_int(n, "w", "layout.monitor")?;
+            let h = extract_optional_int(n, "h", "layout.monitor")?;
+            modeline = Some(extract_timing_formula(node, &name, w, h)?);
+        }
+        // The size of a monitor with a modeline defaults to the size of the modeline
+        let mode = match &modeline {
+            Some(line) => {
+                let w = extract_optional_int(n, "w", "layout.monitor")?;
+                let h = extract_optional_int(n, "h", "layout.monitor")?;
+                if w.unwrap_or(line.hdisplay as i64) != line.hdisplay as i64
+                    || h.unwrap_or(line.vdisplay as i64) != line.vdisplay as i64
+                {
+                    return Err(Error::ModeLineMismatch(name));
+                }
+                Mode {
+                    w: line.hdisplay,
+                    h: line.vdisplay,
+                }
+            }
+            None => {
+                let w = extract_int_value(n, "w", "layout.monitor")? as u16;
+                let h = extract_int_value(n, "h", "layout.monitor")? as u16;
+                Mode { w, h }
+            }
+        };
         let position = Position { x, y };
         Ok(Self {
             name,
@@ -164,15 +1009,122 @@ impl FromNode for MonConfig {
             position,
             primary,
             rot,
+            reflect,
+            output,
+            modeline,
+            transform,
+            backlight,
+            vrr,
+            scaling_mode,
+            prefer_refresh,
+            crtc,
+            touch_device,
+            properties: properties.unwrap_or_default(),
+            icc,
+            exec,
+            off: false,
         })
     }
 }
 
+/// The aliases of the monitors that a monitor is centered on, horizontally and vertically
+#[derive(Default)]
+struct Centering {
+    horizontal: Option<String>,
+    vertical: Option<String>,
+}
+
+/// Position the monitors that are centered on others, returning the position of every monitor.
+/// A monitor may be centered on one that is itself centered, so positions are resolved in the
+/// order they depend on each other. Positions are kept in fractions of a pixel, as the size of
+/// a scaled monitor may be, so that centering a chain of them doesn't add up rounding errors.
+fn center_monitors(
+    layout: &str,
+    monitors: &[MonConfig],
+    centering: &[Centering],
+) -> Result<Vec<(f64, f64)>> {
+    // Coordinates that are given are unsigned, as the frame buffer checks them
+    let mut positions: Vec<_> = monitors
+        .iter()
+        .map(|m| (f64::from(m.position.x as u16), f64::from(m.position.y as u16)))
+        .collect();
+    // Each remaining centering, as the index of the monitor, whether it's horizontal, and the
+    // index of the monitor it's centered on
+    let mut pending = Vec::new();
+    for (i, center) in centering.iter().enumerate() {
+        for &(horizontal, on) in &[(true, &center.horizontal), (false, &center.vertical)] {
+            if let Some(on) = on {
+                let j = monitors
+                    .iter()
+                    .position(|m| !m.off && &m.name == on)
+                    .ok_or_else(|| {
+                        Error::BadCenter(layout.to_string(), monitors[i].name.clone(), on.clone())
+                    })?;
+                pending.push((i, horizontal, j));
+            }
+        }
+    }
+    let resolved = |pending: &[(usize, bool, usize)], j: usize, horizontal: bool| {
+        !pending.iter().any(|&(i, h, _)| i == j && h == horizontal)
+    };
+    while let Some(k) = pending.iter().position(|&(_, h, j)| resolved(&pending, j, h)) {
+        let (i, horizontal, j) = pending.remove(k);
+        let (on_w, on_h) = monitors[j].extent();
+        let (w, h) = monitors[i].extent();
+        if horizontal {
+            positions[i].0 = positions[j].0 + (on_w - w) / 2.0;
+        } else {
+            positions[i].1 = positions[j].1 + (on_h - h) / 2.0;
+        }
+    }
+    match pending.first() {
+        Some(&(i, _, _)) => Err(Error::CenterCycle(layout.to_string(), monitors[i].name.clone())),
+        None => Ok(positions),
+    }
+}
+
+/// Shift the lit monitors of a layout right and down until none are left of or above the
+/// origin, where the screen starts, and round their `positions` to the nearest pixel.
+fn normalize_origin(
+    layout: &str,
+    monitors: &mut [MonConfig],
+    positions: &[(f64, f64)],
+) -> Result<()> {
+    let lit = || monitors.iter().zip(positions).filter(|(m, _)| !m.off).map(|(_, &p)| p);
+    let left = lit().map(|(x, _)| x).fold(0f64, f64::min);
+    let top = lit().map(|(_, y)| y).fold(0f64, f64::min);
+    let pixel = |v: f64| {
+        let v = v.round();
+        Some(v as u16 as i16).filter(|_| v >= 0.0 && v <= f64::from(u16::MAX))
+    };
+    for (mon, &(x, y)) in monitors.iter_mut().zip(positions).filter(|(m, _)| !m.off) {
+        match (pixel(x - left), pixel(y - top)) {
+            (Some(x), Some(y)) => mon.position = Position { x, y },
+            _ => return Err(Error::OffScreen(layout.to_string(), mon.name.clone())),
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 struct LayoutIn {
     name: String,
+    /// The aliases of the monitors to match, or of those that must be among them when the
+    /// layout matches a count
     matches: Vec<String>,
+    /// The number of monitors of any kind the layout matches
+    match_count: Option<usize>,
     layout: Vec<MonConfig>,
+    fb: Option<Mode>,
+    dpi: Option<u32>,
+    mm: Option<Mode>,
+    priority: i32,
+    screen: Option<u8>,
+    group: Option<String>,
+    /// The state the lid must be in for the layout to match
+    lid: Option<Lid>,
+    /// The index of the file that declared the layout, among the files of a directory
+    file: usize,
 }
 
 impl FromNode for LayoutIn {
@@ -181,11 +1133,80 @@ impl FromNode for LayoutIn {
             return Err(Error::NodeTypeMismatch("layout", n.name.clone()));
         }
         let name = get_name(n, "layout")?;
+        let fb = extract_optional_str(n, "fb", "layout")?
+            .map(|fb| fb.parse())
+            .transpose()?;
+        let dpi = match extract_optional_int(n, "dpi", "layout")? {
+            Some(dpi) if dpi > 0 => Some(dpi as u32),
+            Some(_) => return Err(Error::FieldTypeMisMatch("layout", "positive dpi")),
+            None => None,
+        };
+        let priority = match extract_optional_int(n, "priority", "layout")? {
+            Some(priority) => i32::try_from(priority)
+                .map_err(|_| Error::FieldTypeMisMatch("layout", "priority"))?,
+            None => 0,
+        };
+        let screen = match extract_optional_int(n, "screen", "layout")? {
+            Some(screen) => Some(
+                u8::try_from(screen)
+                    .map_err(|_| Error::FieldTypeMisMatch("layout", "screen number"))?,
+            ),
+            None => None,
+        };
+        let mm = match extract_optional_str(n, "mm", "layout")? {
+            Some(mm) => match mm.parse()? {
+                Mode { w: 0, .. } | Mode { h: 0, .. } => {
+                    return Err(Error::FieldTypeMisMatch("layout", "positive mm"))
+                }
+                mm => Some(mm),
+            },
+            None => None,
+        };
+        let group = extract_optional_str(n, "group", "layout")?;
+        let lid = match extract_optional_str(n, "lid", "layout")? {
+            None => None,
+            Some(state) => Some(
+                [Lid::Open, Lid::Closed]
+                    .iter()
+                    .copied()
+                    .find(|l| l.name() == state)
+                    .ok_or(Error::FieldTypeMisMatch("layout", "open or closed"))?,
+            ),
+        };
+        if group.is_some() && lid.is_some() {
+            return Err(Error::LayoutConflict(name, "group", "lid"));
+        }
+        let match_count = match extract_optional_int(n, "match-count", "layout")? {
+            Some(count) if count > 0 => Some(count as usize),
+            Some(_) => return Err(Error::FieldTypeMisMatch("layout", "positive match-count")),
+            None => None,
+        };
+        let require: Vec<String> = match extract_optional_str(n, "require", "layout")? {
+            Some(aliases) => aliases
+                .split(',')
+                .map(str::trim)
+                .filter(|alias| !alias.is_empty())
+                .map(str::to_string)
+                .collect(),
+            None => Vec::new(),
+        };
         let mut layout = Vec::new();
+        let mut centering = Vec::new();
         let mut matches = None;
         for node in &n.children {
             match node.name.as_str() {
-                "monitor" => layout.push(MonConfig::from_node(node)?),
+                "monitor" => {
+                    let conf = MonConfig::from_node(node)?;
+                    centering.push(if conf.off {
+                        Centering::default()
+                    } else {
+                        Centering {
+                            horizontal: extract_optional_str(node, "hcenter-on", "layout.monitor")?,
+                            vertical: extract_optional_str(node, "vcenter-on", "layout.monitor")?,
+                        }
+                    });
+                    layout.push(conf);
+                }
                 "matches" => {
                     if matches.is_none() {
                         let m: Result<Vec<_>> = node
@@ -204,22 +1225,192 @@ impl FromNode for LayoutIn {
                 _ => return Err(Error::Unexpected(node.name.clone())),
             }
         }
-        if let Some(matches) = matches {
-            Ok(Self {
-                name,
-                matches,
-                layout,
-            })
-        } else {
-            Err(Error::MissingField("layout", "matches"))
+        let positions = center_monitors(&name, &layout, &centering)?;
+        normalize_origin(&name, &mut layout, &positions)?;
+        let matches = match (matches, match_count) {
+            (Some(_), Some(_)) => return Err(Error::LayoutConflict(name, "matches", "match-count")),
+            (None, None) => return Err(Error::MissingField("layout", "matches")),
+            (Some(_), None) if n.properties.contains_key("require") => {
+                return Err(Error::LayoutConflict(name, "matches", "require"))
+            }
+            (Some(matches), None) => matches,
+            // The monitors a count requires are matched as they would be by `matches`
+            (None, Some(_)) => require,
+        };
+        check_pinning(&name, &layout, &matches)?;
+        Ok(Self {
+            name,
+            matches,
+            match_count,
+            layout,
+            fb,
+            dpi,
+            mm,
+            priority,
+            screen,
+            group,
+            lid,
+            file: 0,
+        })
+    }
+}
+
+/// Check that the monitors of a layout don't contradict each other: no two may be pinned to the
+/// same CRTC or output, or both be primary, and a monitor matched once can't be both turned off
+/// and lit.
+fn check_pinning(layout: &str, monitors: &[MonConfig], matches: &[String]) -> Result<()> {
+    let conflict = |a: &str, b: &str| (layout.to_string(), a.to_string(), b.to_string());
+    let mut crtcs: HashMap<u8, &str> = HashMap::new();
+    let mut outputs: HashMap<&str, &str> = HashMap::new();
+    let mut primary: Option<&str> = None;
+    for mon in monitors.iter() {
+        if let Some(output) = &mon.output {
+            if let Some(other) = outputs.insert(output, &mon.name) {
+                let (layout, other, mon) = conflict(other, &mon.name);
+                return Err(Error::DuplicateOutput(layout, other, mon, output.clone()));
+            }
+        }
+        if mon.off {
+            continue;
+        }
+        if let Some(crtc) = mon.crtc {
+            if let Some(other) = crtcs.insert(crtc, &mon.name) {
+                let (layout, other, mon) = conflict(other, &mon.name);
+                return Err(Error::DuplicateCrtc(layout, other, mon, crtc));
+            }
+        }
+        if mon.primary {
+            if let Some(other) = primary.replace(&mon.name) {
+                let (layout, other, mon) = conflict(other, &mon.name);
+                return Err(Error::DuplicatePrimary(layout, other, mon));
+            }
+        }
+    }
+    // Identical monitors share an alias, so an alias matched as often as it's configured may be
+    // turned off for one monitor and lit for another
+    for mon in monitors.iter().filter(|m| m.off) {
+        let configured = monitors.iter().filter(|m| m.name == mon.name).count();
+        let matched = matches.iter().filter(|m| **m == mon.name).count();
+        if configured > 1 && matched < configured {
+            return Err(Error::OffAndLit(layout.to_string(), mon.name.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// An entry of a map whose keys JSON can't hold, as it only has string keys
+#[derive(Serialize)]
+struct Entry<'a, K, V> {
+    key: &'a K,
+    value: &'a V,
+}
+
+/// Serialize a map keyed by monitors as a list of its entries, ordered by key.
+fn entries<K, V, S>(map: &HashMap<K, V>, s: S) -> std::result::Result<S::Ok, S::Error>
+where
+    K: Serialize + Ord,
+    V: Serialize,
+    S: Serializer,
+{
+    let mut entries: Vec<_> = map.iter().map(|(key, value)| Entry { key, value }).collect();
+    entries.sort_by(|a, b| a.key.cmp(b.key));
+    s.collect_seq(entries)
+}
+
+/// Serialize maps of layouts keyed by monitors, which are themselves keyed by a group or lid
+/// state, ordered by that key.
+fn nested_entries<G, K, V, S>(
+    map: &HashMap<G, HashMap<K, V>>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    G: Serialize + Ord,
+    K: Serialize + Ord,
+    V: Serialize,
+    S: Serializer,
+{
+    struct Entries<'a, K, V>(&'a HashMap<K, V>);
+    impl<K: Serialize + Ord, V: Serialize> Serialize for Entries<'_, K, V> {
+        fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+            entries(self.0, s)
         }
     }
+    s.collect_map(map.iter().map(|(g, m)| (g, Entries(m))).collect::<BTreeMap<_, _>>())
 }
 
+/// Serialize a map ordered by key.
+fn sorted<K, V, S>(map: &HashMap<K, V>, s: S) -> std::result::Result<S::Ok, S::Error>
+where
+    K: Serialize + Ord,
+    V: Serialize,
+    S: Serializer,
+{
+    s.collect_map(map.iter().collect::<BTreeMap<_, _>>())
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct SingleConfig {
     pub name: String,
     pub fb_size: Mode,
-    pub setup: HashMap<Monitor, MonConfig>,
+    #[serde(serialize_with = "entries")]
+    pub setup: HashMap<Monitor, Vec<MonConfig>>,
+    /// The value of the Xft.dpi X resource while this layout is applied
+    pub dpi: Option<u32>,
+    /// The physical size of the screen, in millimeters, in place of the sum of the monitors'
+    pub mm: Option<Mode>,
+    /// Among layouts that match by the same rule, the one with the highest priority is applied
+    pub priority: i32,
+    /// The number of the X screen this layout is applied to, or None for any screen
+    pub screen: Option<u8>,
+}
+
+impl SingleConfig {
+    /// Group the lit monitors of this layout into islands: monitors that share an edge, or
+    /// overlap, are in the same island, as the cursor can move between them. Monitors that only
+    /// meet at a corner are not. Each island lists the names of its monitors, and the largest
+    /// island is first, so a layout that forms one connected desktop has one island.
+    pub fn islands(&self) -> Vec<Vec<&str>> {
+        let mut lit: Vec<&MonConfig> = self.setup.values().flatten().filter(|m| !m.off).collect();
+        lit.sort_by(|a, b| a.name.cmp(&b.name));
+        let rects: Vec<_> = lit
+            .iter()
+            .map(|m| {
+                let (w, h) = m.span();
+                let (x, y) = (i32::from(m.position.x), i32::from(m.position.y));
+                (x, y, x + i32::from(w), y + i32::from(h))
+            })
+            .collect();
+        let touches = |a: (i32, i32, i32, i32), b: (i32, i32, i32, i32)| {
+            let overlap_x = a.0 < b.2 && b.0 < a.2;
+            let overlap_y = a.1 < b.3 && b.1 < a.3;
+            let meet_x = a.0 <= b.2 && b.0 <= a.2;
+            let meet_y = a.1 <= b.3 && b.1 <= a.3;
+            (overlap_x && meet_y) || (overlap_y && meet_x)
+        };
+        let mut island = vec![None; rects.len()];
+        let mut islands = Vec::new();
+        for start in 0..rects.len() {
+            if island[start].is_some() {
+                continue;
+            }
+            let mut members = Vec::new();
+            let mut queue = vec![start];
+            island[start] = Some(islands.len());
+            while let Some(i) = queue.pop() {
+                members.push(lit[i].name.as_str());
+                for j in 0..rects.len() {
+                    if island[j].is_none() && touches(rects[i], rects[j]) {
+                        island[j] = Some(islands.len());
+                        queue.push(j);
+                    }
+                }
+            }
+            members.sort_unstable();
+            islands.push(members);
+        }
+        islands.sort_by_key(|island| std::cmp::Reverse(island.len()));
+        islands
+    }
 }
 
 fn extract_optional_str(
@@ -234,77 +1425,1652 @@ fn extract_optional_str(
     }
 }
 
-pub struct Config(pub HashMap<Vec<Monitor>, SingleConfig>);
+/// Options that apply to every layout, from the top level `settings` node
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Settings {
+    /// Among the modes of a monitor's size, pick the one with the highest refresh rate, rather
+    /// than the first one its output lists
+    pub prefer_refresh: bool,
+}
 
-impl TryFrom<Vec<Node>> for Config {
+fn get_bool(n: &Node, name: &'static str) -> Result<bool> {
+    match n.values.first() {
+        None => Err(Error::MissingField(name, "value")),
+        Some(KdlValue::Boolean(v)) => Ok(*v),
+        Some(_) => Err(Error::FieldTypeMisMatch(name, "boolean")),
+    }
+}
+
+impl FromNode for Settings {
+    fn from_node(n: &Node) -> Result<Self> {
+        if n.name != "settings" {
+            return Err(Error::NodeTypeMismatch("settings", n.name.clone()));
+        }
+        let mut settings = Settings::default();
+        for node in &n.children {
+            match node.name.as_str() {
+                "prefer-refresh" => {
+                    settings.prefer_refresh = get_bool(node, "settings.prefer-refresh")?
+                }
+                _ => return Err(Error::Unexpected(node.name.clone())),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Config {
+    /// Layouts, keyed by the sorted list of monitors they match
+    #[serde(serialize_with = "entries")]
+    pub layouts: HashMap<Vec<Monitor>, SingleConfig>,
+    /// Layouts tagged with a group, which are only applied by `apply-group`, keyed by their
+    /// group and then by the monitors they match
+    #[serde(serialize_with = "nested_entries")]
+    pub groups: HashMap<String, HashMap<Vec<Monitor>, SingleConfig>>,
+    /// Layouts that only match while the laptop lid is open or closed, keyed by that state and
+    /// then by the monitors they match
+    #[serde(serialize_with = "nested_entries")]
+    pub lid_layouts: HashMap<Lid, HashMap<Vec<Monitor>, SingleConfig>>,
+    /// Monitors, keyed by their alias
+    #[serde(serialize_with = "sorted")]
+    pub monitors: HashMap<String, Monitor>,
+    /// The name of the layout that mirrors every output, used when no other layout matches
+    pub mirror_all: Option<String>,
+    /// The name of the layout that places every output side by side, in its preferred mode,
+    /// used when no other layout matches
+    pub side_by_side: Option<String>,
+    pub settings: Settings,
+    /// A shell command the daemon runs when a layout fails to apply
+    pub on_error_exec: Option<String>,
+}
+
+/// The monitors and layouts declared by one or more documents, before the monitor aliases used
+/// by the layouts are resolved
+#[derive(Default)]
+struct Declarations {
+    layouts: Vec<LayoutIn>,
+    monitors: HashMap<String, Monitor>,
+    mirror_all: Option<String>,
+    side_by_side: Option<String>,
+    settings: Option<Settings>,
+    on_error_exec: Option<String>,
+}
+
+/// The name of the layout that lights only one output, which the daemon applies in place of any
+/// configured layout when asked to
+pub const SAFE_LAYOUT: &str = "safe";
+
+/// The configuration used when none is given
+const BUILT_IN: &str = "side-by-side \"side by side\"\n";
+
+impl TryFrom<Vec<Node>> for Declarations {
     type Error = Error;
     fn try_from(document: Vec<Node>) -> Result<Self> {
         let mut layouts = Vec::new();
         let mut mon_names = HashMap::new();
+        let mut mirror_all = None;
+        let mut side_by_side = None;
+        let mut settings = None;
+        let mut on_error_exec = None;
         for cld in &document {
             match cld.name.as_str() {
                 "layout" => layouts.push(LayoutIn::from_node(cld)?),
+                "mirror-all" if mirror_all.is_none() => {
+                    mirror_all = Some(get_name(cld, "mirror-all")?);
+                }
+                "mirror-all" => return Err(Error::DuplicateSingleton("mirror-all")),
+                "side-by-side" if side_by_side.is_none() => {
+                    side_by_side = Some(get_name(cld, "side-by-side")?);
+                }
+                "side-by-side" => return Err(Error::DuplicateSingleton("side-by-side")),
+                "settings" if settings.is_none() => settings = Some(Settings::from_node(cld)?),
+                "settings" => return Err(Error::DuplicateSingleton("settings")),
+                "on-error-exec" if on_error_exec.is_none() => {
+                    on_error_exec = match cld.values.first() {
+                        None => return Err(Error::MissingField("on-error-exec", "command")),
+                        Some(KdlValue::String(command)) => Some(command.clone()),
+                        Some(_) => return Err(Error::FieldTypeMisMatch("on-error-exec", "String")),
+                    };
+                }
+                "on-error-exec" => return Err(Error::DuplicateSingleton("on-error-exec")),
                 "monitor" => {
                     let name = get_name(cld, "monitor")?;
                     if !cld.children.is_empty() {
                         Err(Error::Unexpected(format!("in monitor {}", name)))?
                     }
                     let product = extract_optional_str(cld, "product", "monitor")?;
+                    let product_pattern =
+                        match extract_optional_str(cld, "product-pattern", "monitor")? {
+                            Some(_) if product.is_some() => {
+                                let (product, pattern) = ("product", "product-pattern");
+                                return Err(Error::FieldConflict(name, product, pattern));
+                            }
+                            Some(pattern) => match pattern.parse() {
+                                Ok(pattern) => Some(pattern),
+                                Err(e) => return Err(Error::BadPattern(name, e)),
+                            },
+                            None => None,
+                        };
                     let serial = extract_optional_str(cld, "serial", "monitor")?;
-                    mon_names.insert(name, Monitor { product, serial });
+                    let any_serial = extract_bool_value(cld, "any-serial", "monitor")?;
+                    if any_serial && serial.is_none() {
+                        return Err(Error::MissingField("monitor", "serial"));
+                    }
+                    let text = extract_optional_str(cld, "text", "monitor")?;
+                    let manufacturer = extract_optional_str(cld, "manufacturer", "monitor")?;
+                    let diagonal = match extract_optional_int(cld, "diagonal", "monitor")? {
+                        Some(d) if (1..=255).contains(&d) => Some(d as u8),
+                        Some(_) => Err(Error::FieldTypeMisMatch("monitor", "diagonal in inches"))?,
+                        None => None,
+                    };
+                    let force_output = extract_optional_str(cld, "force-output", "monitor")?;
+                    let connector = extract_optional_str(cld, "connector", "monitor")?;
+                    let monitor = Monitor {
+                        product,
+                        product_pattern,
+                        serial,
+                        serial_number: None,
+                        any_serial,
+                        text,
+                        manufacturer,
+                        diagonal,
+                        force_output,
+                        connector,
+                        wildcard: false,
+                    };
+                    mon_names.insert(name, monitor);
                 }
                 _ => Err(Error::Unexpected(cld.name.clone()))?,
             }
         }
-        let mut out = HashMap::new();
+        Ok(Self {
+            layouts,
+            monitors: mon_names,
+            mirror_all,
+            side_by_side,
+            settings,
+            on_error_exec,
+        })
+    }
+}
+
+impl TryFrom<Declarations> for Config {
+    type Error = Error;
+    fn try_from(declarations: Declarations) -> Result<Self> {
+        let Declarations {
+            layouts,
+            monitors: mon_names,
+            mirror_all,
+            side_by_side,
+            settings,
+            on_error_exec,
+        } = declarations;
+        let mut out: HashMap<_, SingleConfig> = HashMap::new();
+        let mut groups: HashMap<_, HashMap<_, _>> = HashMap::new();
+        let mut lid_layouts: HashMap<_, HashMap<_, _>> = HashMap::new();
+        let mut files = HashMap::new();
         for LayoutIn {
             name: conf_name,
             matches,
+            match_count,
             layout: setup,
+            fb,
+            dpi,
+            mm,
+            priority,
+            screen,
+            group,
+            lid,
+            file,
         } in layouts
         {
-            let mut mon_set = Vec::with_capacity(matches.len());
-            for m in matches.into_iter() {
+            if conf_name == SAFE_LAYOUT {
+                return Err(Error::ReservedName(conf_name));
+            }
+            // Every monitor config of a layout that matches a count is paired with a wildcard,
+            // except those named after a monitor it requires
+            if let Some(count) = match_count {
+                if setup.len() != count {
+                    return Err(Error::CountMismatch(conf_name, count, setup.len()));
+                }
+                for alias in matches.iter() {
+                    let required = matches.iter().filter(|&m| m == alias).count();
+                    let named = setup.iter().filter(|mon| mon.name == *alias).count();
+                    if named != required {
+                        return Err(Error::UnplacedRequirement(conf_name, alias.clone()));
+                    }
+                }
+            }
+            let wildcards = match_count.map_or(0, |count| count - matches.len());
+            let mut mon_set = vec![Monitor::wildcard(); wildcards];
+            for m in matches.iter() {
                 let mon_desc = mon_names
-                    .get(&m)
-                    .ok_or_else(|| Error::UnknownMonitor(conf_name.clone(), m))?;
+                    .get(m)
+                    .ok_or_else(|| Error::UnknownMonitor(conf_name.clone(), m.clone()))?;
                 mon_set.push(mon_desc.clone())
             }
             mon_set.sort();
             let mut fb_size = Mode { w: 0, h: 0 };
-            let mut next_setup = HashMap::with_capacity(setup.len());
+            let mut next_setup: HashMap<_, Vec<_>> = HashMap::with_capacity(setup.len());
+            let wildcard = Monitor::wildcard();
             for mon in setup.into_iter() {
-                let mon_desc = mon_names
-                    .get(&mon.name)
-                    .ok_or_else(|| Error::UnknownMonitor(conf_name.clone(), mon.name.clone()))?;
-                if mon.rot.is_some() {
-                    fb_size.w = max(fb_size.w, mon.position.x as u16 + mon.mode.h);
-                    fb_size.h = max(fb_size.h, mon.position.y as u16 + mon.mode.w);
-                } else {
-                    fb_size.w = max(fb_size.w, mon.position.x as u16 + mon.mode.w);
-                    fb_size.h = max(fb_size.h, mon.position.y as u16 + mon.mode.h);
+                let mon_desc = match match_count {
+                    Some(_) if !matches.contains(&mon.name) => &wildcard,
+                    _ => mon_names
+                        .get(&mon.name)
+                        .ok_or_else(|| Error::UnknownMonitor(conf_name.clone(), mon.name.clone()))?,
+                };
+                if mon.off {
+                    next_setup.entry(mon_desc.clone()).or_default().push(mon);
+                    continue;
+                }
+                let (w, h) = mon.span();
+                let right = (mon.position.x as u16).checked_add(w);
+                let bottom = (mon.position.y as u16).checked_add(h);
+                match (right, bottom) {
+                    (Some(right), Some(bottom)) => {
+                        fb_size.w = max(fb_size.w, right);
+                        fb_size.h = max(fb_size.h, bottom);
+                    }
+                    _ => return Err(Error::OffScreen(conf_name, mon.name)),
+                }
+                next_setup.entry(mon_desc.clone()).or_default().push(mon);
+            }
+            if let Some(fb) = fb {
+                if fb.union(&fb_size) != fb {
+                    return Err(Error::FbTooSmall(conf_name, fb, fb_size));
+                }
+                fb_size = fb;
+            }
+            // Layouts in different groups, or for different lid states, may match the same
+            // monitors
+            let out = match (group, lid) {
+                (Some(group), _) => groups.entry(group).or_default(),
+                (None, Some(lid)) => lid_layouts.entry(lid).or_default(),
+                (None, None) => &mut out,
+            };
+            // Within one file, a later layout replaces an earlier one that matches the same
+            // monitors. Files of a directory can't replace each other's layouts.
+            if let Some(existing) = out.get(&mon_set) {
+                if files.get(&existing.name) != Some(&file) {
+                    return Err(Error::DuplicateLayout(existing.name.clone(), conf_name));
                 }
-                next_setup.insert(mon_desc.clone(), mon);
             }
+            files.insert(conf_name.clone(), file);
             out.insert(
                 mon_set,
                 SingleConfig {
                     name: conf_name,
                     setup: next_setup,
                     fb_size,
+                    dpi,
+                    mm,
+                    priority,
+                    screen,
                 },
             );
         }
-        Ok(Config(out))
+        Ok(Config {
+            layouts: out,
+            groups,
+            lid_layouts,
+            monitors: mon_names,
+            mirror_all,
+            side_by_side,
+            settings: settings.unwrap_or_default(),
+            on_error_exec,
+        })
+    }
+}
+
+impl TryFrom<Vec<Node>> for Config {
+    type Error = Error;
+    fn try_from(document: Vec<Node>) -> Result<Self> {
+        Config::try_from(Declarations::try_from(document)?)
+    }
+}
+
+/// Build a node with a name as its only value
+fn named_node(kind: &str, name: &str) -> Node {
+    Node {
+        name: kind.to_string(),
+        values: vec![KdlValue::String(name.to_string())],
+        properties: HashMap::new(),
+        children: Vec::new(),
+    }
+}
+
+impl ModeLine {
+    fn to_node(&self) -> Node {
+        let mut values = vec![KdlValue::Float(self.clock)];
+        values.extend(
+            [
+                self.hdisplay,
+                self.hsync_start,
+                self.hsync_end,
+                self.htotal,
+                self.vdisplay,
+                self.vsync_start,
+                self.vsync_end,
+                self.vtotal,
+            ]
+            .iter()
+            .map(|&t| KdlValue::Int(t.into())),
+        );
+        for (flag_name, flag) in MODE_FLAGS.iter() {
+            if self.flags & u32::from(*flag) != 0 {
+                values.push(KdlValue::String(flag_name.to_string()));
+            }
+        }
+        Node {
+            name: "modeline".to_string(),
+            values,
+            properties: HashMap::new(),
+            children: Vec::new(),
+        }
+    }
+}
+
+impl MonConfig {
+    fn to_node(&self) -> Node {
+        let mut node = named_node("monitor", &self.name);
+        if self.off {
+            node.values.push(KdlValue::String("off".to_string()));
+            if let Some(output) = &self.output {
+                let output = KdlValue::String(output.clone());
+                node.properties.insert("output".to_string(), output);
+            }
+            return node;
+        }
+        let props = &mut node.properties;
+        props.insert("w".to_string(), KdlValue::Int(self.mode.w.into()));
+        props.insert("h".to_string(), KdlValue::Int(self.mode.h.into()));
+        props.insert("x".to_string(), KdlValue::Int(self.position.x.into()));
+        props.insert("y".to_string(), KdlValue::Int(self.position.y.into()));
+        if self.primary {
+            props.insert("primary".to_string(), KdlValue::Boolean(true));
+        }
+        if let Some(rot) = &self.rot {
+            let rot = match rot {
+                Rotation::Left => "left",
+                Rotation::Right => "right",
+            };
+            props.insert("rotate".to_string(), KdlValue::String(rot.to_string()));
+        }
+        if let Some(reflect) = self.reflect {
+            props.insert("reflect".to_string(), KdlValue::String(reflect.name().to_string()));
+        }
+        if let Some(output) = &self.output {
+            props.insert("output".to_string(), KdlValue::String(output.clone()));
+        }
+        if let Some(backlight) = self.backlight {
+            props.insert("backlight".to_string(), KdlValue::Int(backlight.into()));
+        }
+        if let Some(vrr) = self.vrr {
+            props.insert("vrr".to_string(), KdlValue::Boolean(vrr));
+        }
+        if let Some(mode) = self.scaling_mode {
+            let mode = KdlValue::String(mode.name().to_string());
+            props.insert("scaling-mode".to_string(), mode);
+        }
+        if let Some(prefer) = self.prefer_refresh {
+            props.insert("prefer-refresh".to_string(), KdlValue::Boolean(prefer));
+        }
+        if let Some(crtc) = self.crtc {
+            props.insert("crtc".to_string(), KdlValue::Int(crtc.into()));
+        }
+        if let Some(device) = &self.touch_device {
+            props.insert("touch-device".to_string(), KdlValue::String(device.clone()));
+        }
+        if let Some(icc) = &self.icc {
+            props.insert("icc".to_string(), KdlValue::String(icc.clone()));
+        }
+        if let Some(exec) = &self.exec {
+            props.insert("exec".to_string(), KdlValue::String(exec.clone()));
+        }
+        if let Some(line) = &self.modeline {
+            node.children.push(line.to_node());
+        }
+        if !self.properties.is_empty() {
+            let properties = self.properties.iter().map(|(prop, value)| {
+                let value = match value {
+                    PropertyValue::Int(i) => KdlValue::Int(*i),
+                    PropertyValue::String(s) => KdlValue::String(s.clone()),
+                };
+                (prop.clone(), value)
+            });
+            node.children.push(Node {
+                name: "properties".to_string(),
+                values: Vec::new(),
+                properties: properties.collect(),
+                children: Vec::new(),
+            });
+        }
+        if let Some(matrix) = &self.transform {
+            node.children.push(Node {
+                name: "transform".to_string(),
+                values: matrix.iter().map(|&v| KdlValue::Float(v)).collect(),
+                properties: HashMap::new(),
+                children: Vec::new(),
+            });
+        }
+        node
+    }
+}
+
+impl Monitor {
+    fn to_node(&self, alias: &str) -> Node {
+        let mut node = named_node("monitor", alias);
+        let fields = [
+            ("product", &self.product),
+            ("serial", &self.serial),
+            ("text", &self.text),
+            ("manufacturer", &self.manufacturer),
+            ("force-output", &self.force_output),
+            ("connector", &self.connector),
+        ];
+        for (field, value) in fields.iter() {
+            if let Some(value) = value {
+                let value = KdlValue::String(value.clone());
+                node.properties.insert(field.to_string(), value);
+            }
+        }
+        if self.any_serial {
+            node.properties.insert("any-serial".to_string(), KdlValue::Boolean(true));
+        }
+        if let Some(pattern) = &self.product_pattern {
+            let pattern = KdlValue::String(pattern.0.as_str().to_string());
+            node.properties.insert("product-pattern".to_string(), pattern);
+        }
+        if let Some(diagonal) = self.diagonal {
+            let diagonal = KdlValue::Int(diagonal.into());
+            node.properties.insert("diagonal".to_string(), diagonal);
+        }
+        node
     }
 }
 
 impl Config {
+    /// Write the configuration as a document, which parses back into an identical configuration.
+    /// Layouts are written with the size of their frame buffer, and match monitors by the first
+    /// of their aliases. Nodes are sorted by name, so that the output is stable.
+    pub fn to_kdl(&self) -> String {
+        let mut aliases: Vec<_> = self.monitors.iter().collect();
+        aliases.sort();
+        let mut nodes: Vec<Node> = aliases
+            .iter()
+            .map(|(alias, mon)| mon.to_node(alias))
+            .collect();
+        if let Some(mirror_all) = &self.mirror_all {
+            nodes.push(named_node("mirror-all", mirror_all));
+        }
+        if let Some(side_by_side) = &self.side_by_side {
+            nodes.push(named_node("side-by-side", side_by_side));
+        }
+        if let Some(command) = &self.on_error_exec {
+            nodes.push(named_node("on-error-exec", command));
+        }
+        if self.settings != Settings::default() {
+            let prefer_refresh = Node {
+                name: "prefer-refresh".to_string(),
+                values: vec![KdlValue::Boolean(self.settings.prefer_refresh)],
+                properties: HashMap::new(),
+                children: Vec::new(),
+            };
+            nodes.push(Node {
+                name: "settings".to_string(),
+                values: Vec::new(),
+                properties: HashMap::new(),
+                children: vec![prefer_refresh],
+            });
+        }
+        let mut layouts: Vec<_> = self.layouts.iter().map(|l| (None, None, l)).collect();
+        for (group, grouped) in self.groups.iter() {
+            layouts.extend(grouped.iter().map(|l| (Some(group), None, l)));
+        }
+        for (&lid, lid_layouts) in self.lid_layouts.iter() {
+            layouts.extend(lid_layouts.iter().map(|l| (None, Some(lid), l)));
+        }
+        layouts.sort_by(|(a_group, a_lid, (a_mons, a)), (b_group, b_lid, (b_mons, b))| {
+            a_group
+                .cmp(b_group)
+                .then(a_lid.cmp(b_lid))
+                .then(a.name.cmp(&b.name))
+                .then(a_mons.cmp(b_mons))
+        });
+        for (group, lid, (mons, layout)) in layouts {
+            let mut node = named_node("layout", &layout.name);
+            if let Some(group) = group {
+                node.properties.insert("group".to_string(), KdlValue::String(group.clone()));
+            }
+            if let Some(lid) = lid {
+                node.properties.insert("lid".to_string(), KdlValue::String(lid.name().to_string()));
+            }
+            let fb = KdlValue::String(layout.fb_size.to_string());
+            node.properties.insert("fb".to_string(), fb);
+            if let Some(dpi) = layout.dpi {
+                node.properties.insert("dpi".to_string(), KdlValue::Int(dpi.into()));
+            }
+            if layout.priority != 0 {
+                let priority = KdlValue::Int(layout.priority.into());
+                node.properties.insert("priority".to_string(), priority);
+            }
+            if let Some(mm) = &layout.mm {
+                node.properties.insert("mm".to_string(), KdlValue::String(mm.to_string()));
+            }
+            if let Some(screen) = layout.screen {
+                node.properties.insert("screen".to_string(), KdlValue::Int(screen.into()));
+            }
+            if mons.iter().any(|mon| mon.wildcard) {
+                let count = KdlValue::Int(mons.len() as i64);
+                node.properties.insert("match-count".to_string(), count);
+                let require: Vec<_> = mons
+                    .iter()
+                    .filter_map(|mon| aliases.iter().find(|(_, m)| *m == mon))
+                    .map(|(alias, _)| alias.as_str())
+                    .collect();
+                if !require.is_empty() {
+                    let require = KdlValue::String(require.join(","));
+                    node.properties.insert("require".to_string(), require);
+                }
+            } else {
+                let matches = mons
+                    .iter()
+                    .filter_map(|mon| aliases.iter().find(|(_, m)| *m == mon))
+                    .map(|(alias, _)| KdlValue::String(alias.to_string()))
+                    .collect();
+                node.children.push(Node {
+                    name: "matches".to_string(),
+                    values: matches,
+                    properties: HashMap::new(),
+                    children: Vec::new(),
+                });
+            }
+            let mut setup: Vec<_> = layout.setup.iter().collect();
+            setup.sort_by_key(|(mon, _)| *mon);
+            for (_, confs) in setup {
+                node.children.extend(confs.iter().map(MonConfig::to_node));
+            }
+            nodes.push(node);
+        }
+        nodes.iter().map(|n| format!("{}\n", n)).collect()
+    }
+
+    /// Find the layout that matches the connected monitors. Layouts that match with fewer fuzzy
+    /// monitors are preferred. On a match, the connected monitors are replaced by the monitors of
+    /// the layout they're paired with.
+    pub fn find_layout<K: Copy + Eq + Hash>(
+        &self,
+        connected: &HashMap<K, Monitor>,
+    ) -> Option<(&SingleConfig, HashMap<K, Monitor>)> {
+        best_layout(&self.layouts, connected)
+    }
+
+    /// Find the layout in `group` that matches the connected monitors, as `find_layout` does.
+    pub fn find_group_layout<K: Copy + Eq + Hash>(
+        &self,
+        group: &str,
+        connected: &HashMap<K, Monitor>,
+    ) -> Option<(&SingleConfig, HashMap<K, Monitor>)> {
+        best_layout(self.groups.get(group)?, connected)
+    }
+
+    /// Find the layout for the lid state `lid` that matches the connected monitors, as
+    /// `find_layout` does.
+    pub fn find_lid_layout<K: Copy + Eq + Hash>(
+        &self,
+        lid: Lid,
+        connected: &HashMap<K, Monitor>,
+    ) -> Option<(&SingleConfig, HashMap<K, Monitor>)> {
+        best_layout(self.lid_layouts.get(&lid)?, connected)
+    }
+
+    /// Find a layout, with the monitors it matches, by its name, whether or not it's in a group
+    /// or requires a lid state.
+    pub fn named_layout(&self, name: &str) -> Option<(&Vec<Monitor>, &SingleConfig)> {
+        self.layouts
+            .iter()
+            .chain(self.groups.values().flatten())
+            .chain(self.lid_layouts.values().flatten())
+            .find(|(_, layout)| layout.name == name)
+    }
+
+    /// The configuration of X screen `screen`: this one, without the layouts that are applied
+    /// to other screens.
+    pub fn for_screen(&self, screen: usize) -> Self {
+        let on_screen = |layout: &SingleConfig| layout.screen.is_none_or(|s| s as usize == screen);
+        let mut config = self.clone();
+        config.layouts.retain(|_, layout| on_screen(layout));
+        for layouts in config.groups.values_mut().chain(config.lid_layouts.values_mut()) {
+            layouts.retain(|_, layout| on_screen(layout));
+        }
+        config
+    }
+
+    /// The configuration used when none is given, which places every connected output side by
+    /// side, in its preferred mode.
+    pub fn built_in() -> Self {
+        // Unwrap below is safe, as the built in configuration is checked by a test
+        Config::from_reader(BUILT_IN.as_bytes()).unwrap()
+    }
+
+    /// Read the configuration from a path, which is either a file or a directory of files, or
+    /// "-" for stdin.
+    pub fn from_path(path: &str) -> Result<Self> {
+        if Path::new(path).is_dir() {
+            Config::from_dir(Path::new(path))
+        } else {
+            Config::from_fname(path)
+        }
+    }
+
+    /// Read and merge every ".kdl" file in a directory. A monitor alias may be declared in more
+    /// than one file, as long as every declaration is the same.
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        let mut paths = std::fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        paths.retain(|p| p.extension() == Some(OsStr::new("kdl")) && p.is_file());
+        paths.sort();
+        let mut merged = Declarations::default();
+        for (file, path) in paths.into_iter().enumerate() {
+            let text = std::fs::read_to_string(&path)?;
+            let Declarations {
+                layouts,
+                monitors,
+                mirror_all,
+                side_by_side,
+                settings,
+                on_error_exec,
+            } = Declarations::try_from(parse_kdl(&text)?)?;
+            if mirror_all.is_some() {
+                if merged.mirror_all.is_some() {
+                    return Err(Error::DuplicateSingleton("mirror-all"));
+                }
+                merged.mirror_all = mirror_all;
+            }
+            if side_by_side.is_some() {
+                if merged.side_by_side.is_some() {
+                    return Err(Error::DuplicateSingleton("side-by-side"));
+                }
+                merged.side_by_side = side_by_side;
+            }
+            if settings.is_some() {
+                if merged.settings.is_some() {
+                    return Err(Error::DuplicateSingleton("settings"));
+                }
+                merged.settings = settings;
+            }
+            if on_error_exec.is_some() {
+                if merged.on_error_exec.is_some() {
+                    return Err(Error::DuplicateSingleton("on-error-exec"));
+                }
+                merged.on_error_exec = on_error_exec;
+            }
+            for (name, mon) in monitors {
+                match merged.monitors.get(&name) {
+                    Some(existing) if existing != &mon => {
+                        return Err(Error::ConflictingMonitor(name, path.display().to_string()))
+                    }
+                    _ => {
+                        merged.monitors.insert(name, mon);
+                    }
+                }
+            }
+            merged.layouts.extend(layouts.into_iter().map(|layout| LayoutIn { file, ..layout }));
+        }
+        Config::try_from(merged)
+    }
+
+    /// Read the configuration from a file, or from stdin when `config_name` is "-".
     pub fn from_fname(config_name: &str) -> Result<Self> {
-        let mut file = std::fs::File::open(&config_name)?;
+        if config_name == "-" {
+            return Config::from_reader(std::io::stdin().lock());
+        }
+        Config::from_reader(std::fs::File::open(&config_name)?)
+    }
+
+    /// Read the configuration from all of `reader`.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self> {
         let mut text = String::new();
-        file.read_to_string(&mut text)?;
-        let document = parse_document(&text)?;
+        reader.read_to_string(&mut text)?;
+        let document = parse_kdl(&text)?;
         Config::try_from(document)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config> {
+        Config::try_from(parse_document(text)?)
+    }
+
+    #[test]
+    fn identical_monitors_keep_both_configs() {
+        let config = parse(
+            r#"
+            monitor "Left" product="Twin"
+            monitor "Right" product="Twin"
+            layout "Twins" {
+                matches "Left" "Right"
+                monitor "Left" w=1920 h=1080 x=0 y=0 output="DP-1"
+                monitor "Right" w=1920 h=1080 x=1920 y=0 output="DP-2"
+            }
+            "#,
+        )
+        .unwrap();
+        let twin = Monitor {
+            product: Some("Twin".to_string()),
+            ..Default::default()
+        };
+        let layout = &config.layouts[&vec![twin.clone(), twin.clone()]];
+        assert_eq!(layout.fb_size, Mode { w: 3840, h: 1080 });
+        let outputs: Vec<_> = layout.setup[&twin]
+            .iter()
+            .map(|m| m.output.as_deref())
+            .collect();
+        assert_eq!(outputs, vec![Some("DP-1"), Some("DP-2")]);
+    }
+
+    #[test]
+    fn modeline_sets_mode() {
+        let config = parse(
+            r#"
+            monitor "Wide" product="Wide"
+            layout "Wide" {
+                matches "Wide"
+                monitor "Wide" x=0 y=0 {
+                    modeline 185.58 2560 2608 2640 2720 1080 1083 1093 1137 "+hsync" "-vsync"
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let layout = config.layouts.values().next().unwrap();
+        let conf = &layout.setup.values().next().unwrap()[0];
+        assert_eq!(conf.mode, Mode { w: 2560, h: 1080 });
+        let line = conf.modeline.as_ref().unwrap();
+        assert_eq!(line.htotal, 2720);
+        assert_eq!(
+            line.flags,
+            u32::from(ModeFlag::HSYNC_POSITIVE) | u32::from(ModeFlag::VSYNC_NEGATIVE)
+        );
+
+        let mismatch = parse(
+            r#"
+            monitor "Wide" product="Wide"
+            layout "Wide" {
+                matches "Wide"
+                monitor "Wide" w=1920 h=1080 x=0 y=0 {
+                    modeline 185.58 2560 2608 2640 2720 1080 1083 1093 1137
+                }
+            }
+            "#,
+        );
+        assert!(matches!(mismatch, Err(Error::ModeLineMismatch(_))));
+    }
+
+    #[test]
+    fn serializes_to_json() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel" serial="1"
+            layout "Alone" lid="open" {
+                matches "Laptop"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0 rotate="left" {
+                    properties "Broadcast RGB"="Full"
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["monitors"]["Laptop"]["product"], "Panel");
+        let layouts = &json["lid_layouts"]["open"];
+        assert_eq!(layouts[0]["key"][0]["serial"], "1");
+        let setup = &layouts[0]["value"]["setup"];
+        assert_eq!(setup[0]["key"]["product"], "Panel");
+        let conf = &setup[0]["value"][0];
+        assert_eq!(conf["rot"], "left");
+        assert_eq!(conf["properties"]["Broadcast RGB"], "Full");
+    }
+
+    #[test]
+    fn timing_formulas_compute_a_modeline() {
+        let config = parse(
+            r#"
+            monitor "Wide" product="Wide"
+            monitor "Old" product="Old"
+            layout "Wide" {
+                matches "Wide" "Old"
+                monitor "Wide" x=0 y=0 {
+                    cvt w=1920 h=1080 r=60 reduced=true
+                }
+                monitor "Old" w=1024 h=768 x=1920 y=0 {
+                    gtf r=75
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let layout = config.layouts.values().next().unwrap();
+        let confs: Vec<_> = layout.setup.values().flatten().collect();
+        let wide = confs.iter().find(|c| c.name == "Wide").unwrap();
+        assert_eq!(wide.mode, Mode { w: 1920, h: 1080 });
+        assert_eq!(wide.modeline, Some(crate::timing::cvt(1920, 1080, 60.0, true)));
+        let old = confs.iter().find(|c| c.name == "Old").unwrap();
+        assert_eq!(old.modeline, Some(crate::timing::gtf(1024, 768, 75.0)));
+
+        let both = parse(
+            r#"
+            monitor "Wide" product="Wide"
+            layout "Wide" {
+                matches "Wide"
+                monitor "Wide" x=0 y=0 {
+                    modeline 185.58 2560 2608 2640 2720 1080 1083 1093 1137
+                    cvt w=2560 h=1080 r=75
+                }
+            }
+            "#,
+        );
+        assert!(matches!(both, Err(Error::FieldConflict(_, "modeline", "cvt"))));
+        let formulas = |first: &str, second: &str| {
+            parse(&format!(
+                r#"
+                monitor "Wide" product="Wide"
+                layout "Wide" {{
+                    matches "Wide"
+                    monitor "Wide" x=0 y=0 {{
+                        {} w=2560 h=1080
+                        {} w=2560 h=1080
+                    }}
+                }}
+                "#,
+                first, second
+            ))
+        };
+        let twice = formulas("gtf", "gtf");
+        assert!(matches!(twice, Err(Error::DuplicateSingleton("layout.monitor.gtf"))));
+        let mixed = formulas("cvt", "gtf");
+        assert!(matches!(mixed, Err(Error::FieldConflict(_, "cvt", "gtf"))));
+        let sizeless = parse(
+            r#"
+            monitor "Wide" product="Wide"
+            layout "Wide" {
+                matches "Wide"
+                monitor "Wide" x=0 y=0 {
+                    cvt r=75
+                }
+            }
+            "#,
+        );
+        assert!(matches!(sizeless, Err(Error::MissingField(_, "w"))));
+    }
+
+    #[test]
+    fn transforms_widen_the_frame_buffer() {
+        let config = parse(
+            r#"
+            monitor "Projector" product="Projector"
+            layout "Keystone" {
+                matches "Projector"
+                monitor "Projector" w=1920 h=1080 x=0 y=0 {
+                    transform 1 0.2 0 0 1 0 0 0 1
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let layout = config.layouts.values().next().unwrap();
+        assert_eq!(layout.fb_size, Mode { w: 2136, h: 1080 });
+
+        let singular = parse(
+            r#"
+            monitor "Projector" product="Projector"
+            layout "Keystone" {
+                matches "Projector"
+                monitor "Projector" w=1920 h=1080 x=0 y=0 {
+                    transform 1 2 0 2 4 0 0 0 1
+                }
+            }
+            "#,
+        );
+        assert!(matches!(singular, Err(Error::SingularTransform(_))));
+    }
+
+    #[test]
+    fn fuzzy_monitors_match_by_manufacturer_and_size() {
+        let config = parse(
+            r#"
+            monitor "dell24" manufacturer="DEL" diagonal=24
+            monitor "Exact" product="DELL U2415" serial="1"
+            layout "Any" {
+                matches "dell24" "dell24"
+                monitor "dell24" w=1920 h=1200 x=0 y=0 output="DP-1"
+                monitor "dell24" w=1920 h=1200 x=1920 y=0 output="DP-2"
+            }
+            layout "Known" {
+                matches "Exact" "dell24"
+                monitor "Exact" w=1920 h=1200 x=0 y=0
+                monitor "dell24" w=1920 h=1200 x=1920 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let dell = |serial: &str, diagonal| Monitor {
+            product: Some("DELL U2415".to_string()),
+            serial: Some(serial.to_string()),
+            manufacturer: Some("DEL".to_string()),
+            diagonal: Some(diagonal),
+            ..Default::default()
+        };
+        let connected: HashMap<_, _> = vec![(1, dell("2", 24)), (2, dell("3", 24))]
+            .into_iter()
+            .collect();
+        let (layout, paired) = config.find_layout(&connected).unwrap();
+        assert_eq!(layout.name, "Any");
+        assert_eq!(paired[&1], config.monitors["dell24"]);
+
+        // The layout with an exact match wins over the fully fuzzy one
+        let connected: HashMap<_, _> = vec![(1, dell("1", 24)), (2, dell("3", 24))]
+            .into_iter()
+            .collect();
+        let (layout, paired) = config.find_layout(&connected).unwrap();
+        assert_eq!(layout.name, "Known");
+        assert_eq!(paired[&1], config.monitors["Exact"]);
+        assert_eq!(paired[&2], config.monitors["dell24"]);
+
+        let connected: HashMap<_, _> = vec![(1, dell("2", 24)), (2, dell("3", 27))]
+            .into_iter()
+            .collect();
+        assert!(config.find_layout(&connected).is_none());
+    }
+
+    #[test]
+    fn the_most_specific_rule_wins_then_the_priority() {
+        let config = parse(
+            r#"
+            monitor "Exact" product="DELL U2415" serial="1"
+            monitor "Dell" manufacturer="DEL"
+            monitor "Big" diagonal=24
+            monitor "Port" connector="DP"
+            layout "Known" {
+                matches "Exact"
+                monitor "Exact" w=1920 h=1200 x=0 y=0
+            }
+            layout "ByMaker" {
+                matches "Dell"
+                monitor "Dell" w=1920 h=1200 x=0 y=0
+            }
+            layout "BySize" priority=1 {
+                matches "Big"
+                monitor "Big" w=1920 h=1200 x=0 y=0
+            }
+            layout "ByPort" priority=9 {
+                matches "Port"
+                monitor "Port" w=1920 h=1200 x=0 y=0
+            }
+            layout "ByCount" match-count=1 priority=9 {
+                monitor "Any" w=1920 h=1200 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let found = |manufacturer: &str, serial: &str, diagonal, connector: &str| {
+            let mon = Monitor {
+                product: Some("DELL U2415".to_string()),
+                serial: Some(serial.to_string()),
+                manufacturer: Some(manufacturer.to_string()),
+                diagonal: Some(diagonal),
+                connector: Some(connector.to_string()),
+                ..Default::default()
+            };
+            let connected: HashMap<_, _> = vec![(1, mon)].into_iter().collect();
+            let (layout, paired) = config.find_layout(&connected).unwrap();
+            (layout.name.as_str(), MatchRule::of(paired.values()))
+        };
+        assert_eq!(found("DEL", "1", 24, "DP"), ("Known", MatchRule::Exact));
+        assert_eq!(found("DEL", "2", 24, "DP"), ("BySize", MatchRule::Fuzzy));
+        assert_eq!(found("DEL", "2", 27, "DP"), ("ByMaker", MatchRule::Fuzzy));
+        assert_eq!(found("SAM", "2", 27, "DP"), ("ByPort", MatchRule::Connector));
+        assert_eq!(found("SAM", "2", 27, "HDMI"), ("ByCount", MatchRule::Count));
+        let config = parse(&config.to_kdl()).unwrap();
+        assert_eq!(config.named_layout("BySize").unwrap().1.priority, 1);
+    }
+
+    #[test]
+    fn layouts_may_be_scoped_to_a_screen() {
+        let config = parse(
+            r#"
+            monitor "Panel" product="Panel"
+            monitor "Tv" product="Tv"
+            layout "Desk" {
+                matches "Panel"
+                monitor "Panel" w=1920 h=1080 x=0 y=0
+            }
+            layout "Wall" screen=1 {
+                matches "Tv"
+                monitor "Tv" w=3840 h=2160 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let first = config.for_screen(0);
+        assert!(first.named_layout("Desk").is_some());
+        assert!(first.named_layout("Wall").is_none());
+        let second = config.for_screen(1);
+        assert!(second.named_layout("Desk").is_some());
+        assert_eq!(second.named_layout("Wall").unwrap().1.screen, Some(1));
+        let config = parse(&config.to_kdl()).unwrap();
+        assert_eq!(config.named_layout("Wall").unwrap().1.screen, Some(1));
+        assert!(parse(r#"layout "L" screen=256 { matches; }"#).is_err());
+    }
+
+    #[test]
+    fn any_serial_matches_the_serial_number() {
+        let config = parse(
+            r#"
+            monitor "Strict" product="DELL U2415" serial="1234"
+            monitor "Either" product="DELL U2415" serial="1234" any-serial=true
+            layout "Strict" {
+                matches "Strict"
+                monitor "Strict" w=1920 h=1200 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let dell = |serial: Option<&str>, serial_number| Monitor {
+            product: Some("DELL U2415".to_string()),
+            serial: serial.map(str::to_string),
+            serial_number,
+            ..Default::default()
+        };
+        let before = dell(Some("1234"), None);
+        let after = dell(None, Some(1234));
+        let (strict, either) = (&config.monitors["Strict"], &config.monitors["Either"]);
+        assert!(strict.matches(&before) && either.matches(&before));
+        assert!(!strict.matches(&after) && either.matches(&after));
+        assert!(!either.matches(&dell(Some("4321"), Some(4321 + 1))));
+    }
+
+    #[test]
+    fn product_patterns_match_every_revision() {
+        let config = parse(
+            r#"
+            monitor "U2720Q" product-pattern="^DELL U2720Q( .*)?$"
+            layout "Desk" {
+                matches "U2720Q"
+                monitor "U2720Q" w=3840 h=2160 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let dell = |product: &str| Monitor {
+            product: Some(product.to_string()),
+            ..Default::default()
+        };
+        let pattern = &config.monitors["U2720Q"];
+        assert!(pattern.matches(&dell("DELL U2720Q")));
+        assert!(pattern.matches(&dell("DELL U2720Q A")));
+        assert!(!pattern.matches(&dell("DELL U2720QM")));
+        assert!(!pattern.matches(&Monitor::default()));
+
+        let both = parse(r#"monitor "U2720Q" product="DELL" product-pattern="DELL""#);
+        assert!(matches!(both, Err(Error::FieldConflict(..))));
+        let bad = parse(r#"monitor "U2720Q" product-pattern="DELL (""#);
+        assert!(matches!(bad, Err(Error::BadPattern(..))));
+    }
+
+    #[test]
+    fn empty_matches_match_no_monitors() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            layout "Nothing" {
+                matches
+            }
+            layout "Alone" {
+                matches "Laptop"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let connected: HashMap<u32, Monitor> = HashMap::new();
+        let (layout, paired) = config.find_layout(&connected).unwrap();
+        assert_eq!(layout.name, "Nothing");
+        assert!(paired.is_empty());
+        assert_eq!(layout.fb_size, Mode { w: 0, h: 0 });
+    }
+
+    #[test]
+    fn text_tells_identical_monitors_apart() {
+        let config = parse(
+            r#"
+            monitor "Left" product="LG" text="MODEL-A"
+            monitor "Right" product="LG" text="MODEL-B"
+            layout "Pair" {
+                matches "Left" "Right"
+                monitor "Left" w=1920 h=1080 x=0 y=0
+                monitor "Right" w=1920 h=1080 x=1920 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let lg = |text: &str| Monitor {
+            product: Some("LG".to_string()),
+            text: Some(text.to_string()),
+            ..Default::default()
+        };
+        let connected: HashMap<_, _> = vec![(1, lg("MODEL-B")), (2, lg("MODEL-A"))]
+            .into_iter()
+            .collect();
+        let (_, paired) = config.find_layout(&connected).unwrap();
+        assert_eq!(paired[&1], config.monitors["Right"]);
+        assert_eq!(paired[&2], config.monitors["Left"]);
+        let connected: HashMap<_, _> = vec![(1, lg("MODEL-A")), (2, lg("MODEL-C"))]
+            .into_iter()
+            .collect();
+        assert!(config.find_layout(&connected).is_none());
+    }
+
+    #[test]
+    fn fb_overrides_computed_size() {
+        let layout = |fb: &str| {
+            parse(&format!(
+                r#"
+                monitor "Wide" product="Wide"
+                layout "Wide" fb="{}" {{
+                    matches "Wide"
+                    monitor "Wide" w=2560 h=1080 x=0 y=0
+                }}
+                "#,
+                fb
+            ))
+        };
+        let config = layout("3840x2160").unwrap();
+        let single = config.layouts.values().next().unwrap();
+        assert_eq!(single.fb_size, Mode { w: 3840, h: 2160 });
+        assert!(matches!(layout("1920x1080"), Err(Error::FbTooSmall(..))));
+        assert!(matches!(layout("huge"), Err(Error::FieldTypeMisMatch(..))));
+    }
+
+    #[test]
+    fn off_monitors_match_without_a_size() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            monitor "Home" product="Big"
+            layout "Docked" {
+                matches "Laptop" "Home"
+                monitor "Laptop" "off"
+                monitor "Home" w=2560 h=1440 x=0 y=0
+            }
+            layout "Closed" {
+                matches "Laptop"
+                monitor "Laptop" x="off"
+            }
+            "#,
+        )
+        .unwrap();
+        let laptop = &config.monitors["Laptop"];
+        let docked = &config.layouts[&vec![config.monitors["Home"].clone(), laptop.clone()]];
+        assert_eq!(docked.fb_size, Mode { w: 2560, h: 1440 });
+        assert!(docked.setup[laptop][0].off);
+        let closed = &config.layouts[&vec![laptop.clone()]];
+        assert!(closed.setup[laptop][0].off);
+        assert_eq!(closed.fb_size, Mode { w: 0, h: 0 });
+    }
+
+    #[test]
+    fn settings_apply_to_every_layout() {
+        let config = parse(
+            r#"
+            settings {
+                prefer-refresh true
+            }
+            monitor "Fast" product="Fast"
+            layout "Fast" {
+                matches "Fast"
+                monitor "Fast" w=1920 h=1080 x=0 y=0 prefer-refresh=false
+            }
+            "#,
+        )
+        .unwrap();
+        assert!(config.settings.prefer_refresh);
+        let layout = config.layouts.values().next().unwrap();
+        assert_eq!(layout.setup.values().next().unwrap()[0].prefer_refresh, Some(false));
+        assert!(matches!(
+            parse("settings\nsettings"),
+            Err(Error::DuplicateSingleton("settings"))
+        ));
+        assert!(matches!(
+            parse("settings {\n debounce 5\n}"),
+            Err(Error::Unexpected(_))
+        ));
+    }
+
+    #[test]
+    fn layouts_may_set_the_screen_mm() {
+        let layout = |mm: &str| {
+            parse(&format!(
+                "monitor \"A\" product=\"A\"\nlayout \"L\" mm=\"{}\" {{\n matches \"A\"\n \
+                 monitor \"A\" w=1920 h=1080 x=0 y=0\n}}",
+                mm
+            ))
+        };
+        let config = layout("600x340").unwrap();
+        let single = config.layouts.values().next().unwrap();
+        assert_eq!(single.mm, Some(Mode { w: 600, h: 340 }));
+        assert_eq!(parse(&config.to_kdl()).unwrap(), config);
+        assert!(matches!(layout("0x340"), Err(Error::FieldTypeMisMatch(..))));
+    }
+
+    #[test]
+    fn built_in_config_places_outputs_side_by_side() {
+        let config = Config::built_in();
+        assert_eq!(config.side_by_side.as_deref(), Some("side by side"));
+        assert!(config.layouts.is_empty() && config.mirror_all.is_none());
+        assert_eq!(parse(&config.to_kdl()).unwrap(), config);
+        assert!(matches!(
+            parse("side-by-side \"a\"\nside-by-side \"b\""),
+            Err(Error::DuplicateSingleton("side-by-side"))
+        ));
+    }
+
+    #[test]
+    fn on_error_exec_is_kept() {
+        let config = parse(r#"on-error-exec "notify-send \"$MONITOR_LAYOUT_ERROR\"""#).unwrap();
+        let command = config.on_error_exec.as_deref();
+        assert_eq!(command, Some(r#"notify-send "$MONITOR_LAYOUT_ERROR""#));
+        assert_eq!(parse(&config.to_kdl()).unwrap(), config);
+        assert!(matches!(
+            parse("on-error-exec \"a\"\non-error-exec \"b\""),
+            Err(Error::DuplicateSingleton("on-error-exec"))
+        ));
+    }
+
+    #[test]
+    fn monitors_past_the_largest_fb_are_rejected() {
+        let config = parse(
+            r#"
+            monitor "Wide" product="Wide"
+            layout "Wide" {
+                matches "Wide"
+                monitor "Wide" w=2560 h=1080 x=64000 y=0
+            }
+            "#,
+        );
+        assert!(matches!(config, Err(Error::OffScreen(..))));
+    }
+
+    #[test]
+    fn centered_monitors_shift_the_layout() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            monitor "Wide" product="Wide"
+            layout "Stacked" {
+                matches "Laptop" "Wide"
+                monitor "Laptop" w=1920 h=1200 hcenter-on="Wide" y=1440
+                monitor "Wide" w=3440 h=1440 hcenter-on="Elsewhere" y=0
+            }
+            "#,
+        );
+        assert!(matches!(config, Err(Error::BadCenter(..))));
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            monitor "Wide" product="Wide"
+            layout "Stacked" {
+                matches "Laptop" "Wide"
+                monitor "Wide" w=3440 h=1440 hcenter-on="Laptop" y=0
+                monitor "Laptop" w=1920 h=1200 x=0 y=1440
+            }
+            "#,
+        )
+        .unwrap();
+        let layout = config.layouts.values().next().unwrap();
+        let position = |product: &str| {
+            let (_, confs) = layout
+                .setup
+                .iter()
+                .find(|(m, _)| m.product.as_deref() == Some(product))
+                .unwrap();
+            let Position { x, y } = confs[0].position;
+            (x, y)
+        };
+        assert_eq!(position("Wide"), (0, 0));
+        assert_eq!(position("Panel"), (760, 1440));
+        assert_eq!(layout.fb_size, Mode { w: 3440, h: 2640 });
+    }
+
+    #[test]
+    fn detached_monitors_form_islands() {
+        let config = parse(
+            r#"
+            monitor "Left" product="Left"
+            monitor "Right" product="Right"
+            monitor "Corner" product="Corner"
+            monitor "Far" product="Far"
+            layout "Desk" {
+                matches "Left" "Right" "Corner" "Far"
+                monitor "Left" w=1920 h=1080 x=0 y=0
+                monitor "Right" w=1920 h=1080 x=1920 y=500
+                monitor "Corner" w=800 h=600 x=3840 y=1580
+                monitor "Far" w=800 h=600 x=6000 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let layout = config.layouts.values().next().unwrap();
+        assert_eq!(layout.islands(), vec![vec!["Left", "Right"], vec!["Corner"], vec!["Far"]]);
+        let config = parse(
+            r#"
+            monitor "Left" product="Left"
+            monitor "Right" product="Right"
+            layout "Desk" {
+                matches "Left" "Right"
+                monitor "Left" w=1920 h=1080 x=0 y=0
+                monitor "Right" w=1920 h=1080 x=1000 y=1080
+            }
+            "#,
+        )
+        .unwrap();
+        let layout = config.layouts.values().next().unwrap();
+        assert_eq!(layout.islands(), vec![vec!["Left", "Right"]]);
+    }
+
+    #[test]
+    fn centering_on_scaled_monitors_rounds_once() {
+        // Scaled by half, the projector is 500.5 pixels wide, so the monitors centered under it
+        // are 200.25 and 224.75 pixels from its left edge
+        let config = parse(
+            r#"
+            monitor "Projector" product="Projector"
+            monitor "Panel" product="Panel"
+            monitor "Small" product="Small"
+            layout "Column" {
+                matches "Projector" "Panel" "Small"
+                monitor "Projector" w=1001 h=1000 x=0 y=0 {
+                    transform 0.5 0 0 0 0.5 0 0 0 1
+                }
+                monitor "Panel" w=100 h=100 hcenter-on="Projector" y=500
+                monitor "Small" w=51 h=51 hcenter-on="Panel" y=600
+            }
+            "#,
+        )
+        .unwrap();
+        let layout = config.layouts.values().next().unwrap();
+        let x = |product: &str| {
+            let (_, confs) = layout
+                .setup
+                .iter()
+                .find(|(m, _)| m.product.as_deref() == Some(product))
+                .unwrap();
+            confs[0].position.x
+        };
+        assert_eq!((x("Projector"), x("Panel"), x("Small")), (0, 200, 225));
+    }
+
+    #[test]
+    fn grouped_layouts_match_the_same_monitors() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            layout "Alone" {
+                matches "Laptop"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+            }
+            layout "Presenting" group="work" {
+                matches "Laptop"
+                monitor "Laptop" w=1280 h=720 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let connected: HashMap<_, _> = vec![(1, config.monitors["Laptop"].clone())]
+            .into_iter()
+            .collect();
+        let (alone, _) = config.find_layout(&connected).unwrap();
+        assert_eq!(alone.name, "Alone");
+        let (presenting, _) = config.find_group_layout("work", &connected).unwrap();
+        assert_eq!(presenting.name, "Presenting");
+        assert!(config.find_group_layout("home", &connected).is_none());
+        assert!(config.named_layout("Presenting").is_some());
+    }
+
+    #[test]
+    fn lid_layouts_match_the_same_monitors() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            monitor "Dock" product="Dock"
+            layout "Docked" {
+                matches "Laptop" "Dock"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+                monitor "Dock" w=2560 h=1440 x=1920 y=0
+            }
+            layout "Clamshell" lid="closed" {
+                matches "Laptop" "Dock"
+                monitor "Laptop" "off"
+                monitor "Dock" w=2560 h=1440 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let connected: HashMap<_, _> = vec![
+            (1, config.monitors["Laptop"].clone()),
+            (2, config.monitors["Dock"].clone()),
+        ]
+        .into_iter()
+        .collect();
+        let (docked, _) = config.find_layout(&connected).unwrap();
+        assert_eq!(docked.name, "Docked");
+        let (clamshell, _) = config.find_lid_layout(Lid::Closed, &connected).unwrap();
+        assert_eq!(clamshell.name, "Clamshell");
+        assert!(config.find_lid_layout(Lid::Open, &connected).is_none());
+        assert_eq!(parse(&config.to_kdl()).unwrap(), config);
+    }
+
+    #[test]
+    fn counted_layouts_match_any_monitors() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            monitor "Home" product="Big"
+            layout "Docked" {
+                matches "Laptop" "Home"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+                monitor "Home" w=2560 h=1440 x=1920 y=0
+            }
+            layout "Any-Two" match-count=2 {
+                monitor "first" w=1920 h=1080 x=0 y=0
+                monitor "second" w=1920 h=1080 x=1920 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let other = Monitor {
+            product: Some("Projector".to_string()),
+            ..Default::default()
+        };
+        let laptop = config.monitors["Laptop"].clone();
+        let mut connected: HashMap<_, _> = vec![(1, laptop), (2, config.monitors["Home"].clone())]
+            .into_iter()
+            .collect();
+        assert_eq!(config.find_layout(&connected).unwrap().0.name, "Docked");
+        connected.insert(2, other.clone());
+        assert_eq!(config.find_layout(&connected).unwrap().0.name, "Any-Two");
+        connected.insert(3, other);
+        assert!(config.find_layout(&connected).is_none());
+        assert_eq!(parse(&config.to_kdl()).unwrap(), config);
+
+        let config = parse(
+            r#"
+            layout "Any-Two" match-count=2 {
+                monitor "first" w=1920 h=1080 x=0 y=0
+            }
+            "#,
+        );
+        assert!(matches!(config, Err(Error::CountMismatch(_, 2, 1))));
+    }
+
+    #[test]
+    fn counts_may_require_monitors() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            layout "Any-Two" match-count=2 {
+                monitor "first" w=1920 h=1080 x=0 y=0
+                monitor "second" w=1920 h=1080 x=1920 y=0
+            }
+            layout "Laptop-And-One" match-count=2 require="Laptop" {
+                monitor "Laptop" w=1920 h=1080 x=0 y=1080
+                monitor "other" w=1920 h=1080 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let other = Monitor {
+            product: Some("Projector".to_string()),
+            ..Default::default()
+        };
+        let laptop = config.monitors["Laptop"].clone();
+        let mut connected: HashMap<_, _> =
+            vec![(1, laptop.clone()), (2, other.clone())].into_iter().collect();
+        let (layout, paired) = config.find_layout(&connected).unwrap();
+        assert_eq!(layout.name, "Laptop-And-One");
+        assert_eq!(layout.setup[&paired[&1]][0].position, Position { x: 0, y: 1080 });
+        assert_eq!(layout.setup[&paired[&2]][0].name, "other");
+        connected.insert(1, other);
+        assert_eq!(config.find_layout(&connected).unwrap().0.name, "Any-Two");
+        assert_eq!(parse(&config.to_kdl()).unwrap(), config);
+
+        let unplaced = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            layout "Laptop-And-One" match-count=2 require="Laptop" {
+                monitor "first" w=1920 h=1080 x=0 y=0
+                monitor "second" w=1920 h=1080 x=1920 y=0
+            }
+            "#,
+        );
+        assert!(matches!(unplaced, Err(Error::UnplacedRequirement(_, _))));
+        let both = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            layout "Alone" require="Laptop" {
+                matches "Laptop"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+            }
+            "#,
+        );
+        assert!(matches!(both, Err(Error::LayoutConflict(_, "matches", "require"))));
+    }
+
+    #[test]
+    fn monitors_may_not_share_a_pin() {
+        let desk = |left: &str, right: &str| {
+            parse(&format!(
+                r#"
+                monitor "Left" product="Left"
+                monitor "Right" product="Right"
+                layout "Desk" {{
+                    matches "Left" "Right"
+                    monitor "Left" w=1920 h=1080 x=0 y=0 {}
+                    monitor "Right" w=1920 h=1080 x=1920 y=0 {}
+                }}
+                "#,
+                left, right
+            ))
+        };
+        let config = desk("crtc=0", "crtc=0");
+        assert!(matches!(config, Err(Error::DuplicateCrtc(_, _, _, 0))));
+        let config = desk(r#"output="DP-1""#, r#"output="DP-1""#);
+        assert!(matches!(config, Err(Error::DuplicateOutput(..))));
+        let config = desk("primary=true", "primary=true");
+        assert!(matches!(config, Err(Error::DuplicatePrimary(..))));
+        assert!(desk("primary=true", r#"output="DP-1""#).is_ok());
+
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            layout "Alone" {
+                matches "Laptop"
+                monitor "Laptop" "off"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+            }
+            "#,
+        );
+        assert!(matches!(config, Err(Error::OffAndLit(..))));
+    }
+
+    #[test]
+    fn later_layouts_in_a_file_replace_earlier_ones() {
+        let config = parse(
+            r#"
+            monitor "Laptop" product="Panel"
+            layout "Old" {
+                matches "Laptop"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+            }
+            layout "New" {
+                matches "Laptop"
+                monitor "Laptop" w=1280 h=720 x=0 y=0
+            }
+            "#,
+        )
+        .unwrap();
+        let names: Vec<_> = config.layouts.values().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, vec!["New"]);
+    }
+
+    #[test]
+    fn directory_configs_merge() {
+        let dir = std::env::temp_dir().join(format!("monitor-layout-conf-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let write = |name: &str, text: &str| std::fs::write(dir.join(name), text).unwrap();
+        write("monitors.kdl", r#"monitor "Laptop" product="Panel""#);
+        write(
+            "home.kdl",
+            r#"
+            monitor "Home" product="Big"
+            layout "Docked" {
+                matches "Laptop" "Home"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+                monitor "Home" w=2560 h=1440 x=1920 y=0
+            }
+            "#,
+        );
+        write("notes.txt", "not a config");
+        let config = Config::from_path(dir.to_str().unwrap()).unwrap();
+        assert_eq!(config.monitors.len(), 2);
+        assert_eq!(config.layouts.len(), 1);
+
+        write(
+            "undocked.kdl",
+            r#"
+            layout "Undocked" {
+                matches "Laptop" "Home"
+                monitor "Laptop" w=1920 h=1080 x=0 y=0
+            }
+            "#,
+        );
+        assert!(matches!(
+            Config::from_dir(&dir),
+            Err(Error::DuplicateLayout(..))
+        ));
+        std::fs::remove_file(dir.join("undocked.kdl")).unwrap();
+
+        write("other.kdl", r#"monitor "Home" product="Small""#);
+        assert!(matches!(
+            Config::from_dir(&dir),
+            Err(Error::ConflictingMonitor(..))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
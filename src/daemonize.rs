@@ -0,0 +1,151 @@
+//! Detaching the daemon from the terminal that started it, and recording its PID.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// Turn the result of a libc call into an `io::Result`.
+fn cvt(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+/// A process that has been detached from its terminal, but whose original parent is still
+/// waiting to hear that it started.
+pub struct Detached {
+    ready: File,
+}
+
+/// Fork twice, so that the process is no longer a session leader and can't acquire a
+/// controlling terminal. The original process exits once the returned `Detached` is marked
+/// ready, with a failure status when the daemon exits before then.
+pub fn daemonize() -> io::Result<Detached> {
+    let mut fds = [0; 2];
+    cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (mut waiting, ready) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    if cvt(unsafe { libc::fork() })? != 0 {
+        drop(ready);
+        let mut byte = [0];
+        let started = matches!(waiting.read(&mut byte), Ok(1));
+        std::process::exit(if started { 0 } else { 1 });
+    }
+    drop(waiting);
+    cvt(unsafe { libc::setsid() })?;
+    if cvt(unsafe { libc::fork() })? != 0 {
+        unsafe { libc::_exit(0) };
+    }
+    Ok(Detached { ready })
+}
+
+impl Detached {
+    /// Point stdin, stdout and stderr at /dev/null, and let the original process exit.
+    /// Errors until now are still printed on the terminal.
+    pub fn ready(mut self) -> io::Result<()> {
+        let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+        for fd in 0..=2 {
+            cvt(unsafe { libc::dup2(null.as_raw_fd(), fd) })?;
+        }
+        self.ready.write_all(&[1])
+    }
+}
+
+/// A file containing the PID of this process, which is removed when dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the PID to a temporary file that's renamed to `path`, so readers never see a
+    /// partial write.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, format!("{}\n", std::process::id()))?;
+        fs::rename(&tmp, path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+/// The PID recorded in the file at `path`, when that process is still running. A missing file,
+/// or one naming a process that has exited, is None.
+pub fn running_pid(path: &Path) -> io::Result<Option<libc::pid_t>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let pid = match text.trim().parse::<libc::pid_t>() {
+        Ok(pid) if pid > 0 && pid as u32 != std::process::id() => pid,
+        _ => return Ok(None),
+    };
+    Ok(Some(pid).filter(|&pid| is_running(pid)))
+}
+
+/// Check that `pid` names a process, even one owned by another user.
+fn is_running(pid: libc::pid_t) -> bool {
+    cvt(unsafe { libc::kill(pid, 0) }).is_ok()
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Ask the process `pid` to exit with SIGTERM, and wait up to `timeout` for it to. Returns
+/// whether it exited.
+pub fn stop(pid: libc::pid_t, timeout: Duration) -> io::Result<bool> {
+    match cvt(unsafe { libc::kill(pid, libc::SIGTERM) }) {
+        Err(e) if e.raw_os_error() == Some(libc::ESRCH) => return Ok(true),
+        res => res?,
+    };
+    let deadline = Instant::now() + timeout;
+    while is_running(pid) {
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        sleep(Duration::from_millis(50));
+    }
+    Ok(true)
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pid_file_is_removed_when_dropped() {
+        let path = std::env::temp_dir().join(format!("monitor-layout-{}.pid", std::process::id()));
+        let pid_file = PidFile::create(&path).unwrap();
+        let pid = fs::read_to_string(&path).unwrap();
+        assert_eq!(pid.trim().parse::<u32>().unwrap(), std::process::id());
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn running_processes_are_stopped() {
+        let path = std::env::temp_dir().join(format!("monitor-layout-{}.run", std::process::id()));
+        assert_eq!(running_pid(&path).unwrap(), None);
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let pid = child.id() as libc::pid_t;
+        fs::write(&path, format!("{}\n", pid)).unwrap();
+        assert_eq!(running_pid(&path).unwrap(), Some(pid));
+        // The child is reaped as soon as it exits, so that it isn't left as a zombie
+        let waiter = std::thread::spawn(move || child.wait().unwrap());
+        assert!(stop(pid, Duration::from_secs(5)).unwrap());
+        assert!(!waiter.join().unwrap().success());
+        assert_eq!(running_pid(&path).unwrap(), None);
+        fs::write(&path, "not a pid\n").unwrap();
+        assert_eq!(running_pid(&path).unwrap(), None);
+        fs::remove_file(&path).unwrap();
+    }
+}
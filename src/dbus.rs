@@ -0,0 +1,151 @@
+//! A D-Bus service that lets desktop applets reapply layouts and see which one is applied.
+use std::io::{self, Read, Write};
+use std::os::unix::{
+    io::{AsRawFd, RawFd},
+    net::UnixStream,
+};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use tracing::debug;
+use zbus::{blocking::Connection, fdo, interface};
+
+/// The well known name of the service on the session bus
+pub const NAME: &str = "org.monitorlayout.Daemon";
+const PATH: &str = "/org/monitorlayout/Daemon";
+
+/// A request from a D-Bus client, to be handled by the X event loop.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    Reapply,
+    ApplyNamed(String),
+}
+
+/// The object served at PATH. Its methods run on the connection's executor thread, so they
+/// only queue requests and wake the event loop.
+struct Daemon {
+    layouts: Vec<String>,
+    current: String,
+    requests: Sender<Request>,
+    wake: UnixStream,
+}
+
+impl Daemon {
+    fn send(&self, request: Request) -> fdo::Result<()> {
+        self.requests
+            .send(request)
+            .map_err(|_| fdo::Error::Failed("The daemon is shutting down".to_string()))?;
+        (&self.wake)
+            .write_all(&[0])
+            .map_err(|e| fdo::Error::IOError(e.to_string()))
+    }
+}
+
+#[interface(name = "org.monitorlayout.Daemon")]
+impl Daemon {
+    /// Match the connected monitors against the layouts again, and apply the matching one.
+    fn reapply(&self) -> fdo::Result<()> {
+        self.send(Request::Reapply)
+    }
+
+    /// Apply the layout called `name`, when it matches the connected monitors.
+    fn apply_named(&self, name: String) -> fdo::Result<()> {
+        if !self.layouts.contains(&name) {
+            return Err(fdo::Error::InvalidArgs(format!("No layout named {}", name)));
+        }
+        self.send(Request::ApplyNamed(name))
+    }
+
+    /// The name of the last layout applied, or an empty string before the first.
+    #[zbus(property)]
+    fn current_layout(&self) -> String {
+        self.current.clone()
+    }
+}
+
+/// A connection to the session bus that owns NAME, and the requests its clients have made.
+pub struct DbusService {
+    conn: Connection,
+    requests: Receiver<Request>,
+    woken: UnixStream,
+}
+
+impl DbusService {
+    /// Serve the daemon on the session bus. `layouts` are the names that `ApplyNamed` accepts.
+    pub fn start(layouts: Vec<String>) -> zbus::Result<Self> {
+        let (woken, wake) = UnixStream::pair()?;
+        woken.set_nonblocking(true)?;
+        let (send, requests) = channel();
+        let daemon = Daemon {
+            layouts,
+            current: String::new(),
+            requests: send,
+            wake,
+        };
+        let conn = zbus::blocking::connection::Builder::session()?
+            .name(NAME)?
+            .serve_at(PATH, daemon)?
+            .build()?;
+        Ok(Self {
+            conn,
+            requests,
+            woken,
+        })
+    }
+
+    /// Take every request made since the last call.
+    pub fn requests(&mut self) -> io::Result<Vec<Request>> {
+        let mut buf = [0; 64];
+        loop {
+            match self.woken.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let requests: Vec<_> = self.requests.try_iter().collect();
+        debug!("D-Bus requests: {:?}", requests);
+        Ok(requests)
+    }
+
+    /// Update the `CurrentLayout` property, notifying clients that watch it.
+    pub fn set_current_layout(&self, name: &str) -> zbus::Result<()> {
+        let iface = self.conn.object_server().interface::<_, Daemon>(PATH)?;
+        let mut daemon = iface.get_mut();
+        if daemon.current == name {
+            return Ok(());
+        }
+        daemon.current = name.to_string();
+        async_io::block_on(daemon.current_layout_changed(iface.signal_context()))
+    }
+}
+
+impl AsRawFd for DbusService {
+    fn as_raw_fd(&self) -> RawFd {
+        self.woken.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_requests_for_known_layouts() {
+        let (mut woken, wake) = UnixStream::pair().unwrap();
+        let (send, requests) = channel();
+        let daemon = Daemon {
+            layouts: vec!["Docked".to_string()],
+            current: String::new(),
+            requests: send,
+            wake,
+        };
+        daemon.reapply().unwrap();
+        daemon.apply_named("Docked".to_string()).unwrap();
+        assert!(daemon.apply_named("Undocked".to_string()).is_err());
+        let mut buf = [0; 4];
+        assert_eq!(woken.read(&mut buf).unwrap(), 2);
+        let queued: Vec<_> = requests.try_iter().collect();
+        assert_eq!(queued, vec![Request::Reapply, Request::ApplyNamed("Docked".to_string())]);
+    }
+}
@@ -0,0 +1,105 @@
+//! Read the product identification from the DisplayID extension blocks of an EDID, which some
+//! monitors use in place of the product name and serial number descriptors of the base block.
+
+/// The size of an EDID block, including the base block
+const BLOCK_LEN: usize = 128;
+
+/// The tag of an EDID extension block that holds a DisplayID section
+const DISPLAYID_EXTENSION: u8 = 0x70;
+
+/// The tags of the product identification data block, in DisplayID 1.x and 2.x
+const PRODUCT_ID_V1: u8 = 0x00;
+const PRODUCT_ID_V2: u8 = 0x20;
+
+/// The product identification of a DisplayID section. Fields the monitor leaves blank are None.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProductId {
+    /// The product name string, without padding
+    pub product: Option<String>,
+    /// The serial number, which is unused when 0
+    pub serial: Option<u32>,
+}
+
+/// Parse the payload of a product identification data block: a manufacturer or OUI, a product
+/// code, a serial number, a week and year, and the length of the product name that follows.
+fn parse_product_id(payload: &[u8]) -> Option<ProductId> {
+    let fixed = payload.get(..12)?;
+    let serial = u32::from_le_bytes([fixed[5], fixed[6], fixed[7], fixed[8]]);
+    let name_len = usize::from(fixed[11]);
+    let name = payload.get(12..12 + name_len).unwrap_or(&payload[12..]);
+    let product = String::from_utf8_lossy(name)
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string();
+    Some(ProductId {
+        product: Some(product).filter(|p| !p.is_empty()),
+        serial: Some(serial).filter(|&n| n != 0),
+    })
+}
+
+/// Find the product identification data block within the DisplayID section of an extension
+/// block. The section starts after the extension tag with a version, the length of its data
+/// blocks, a product type and an extension count.
+fn section_product_id(block: &[u8]) -> Option<ProductId> {
+    let len = usize::from(*block.get(2)?);
+    let mut blocks = block.get(5..5 + len)?;
+    while let [tag, _revision, len, rest @ ..] = blocks {
+        let len = usize::from(*len);
+        let payload = rest.get(..len)?;
+        if *tag == PRODUCT_ID_V1 || *tag == PRODUCT_ID_V2 {
+            return parse_product_id(payload);
+        }
+        blocks = &rest[len..];
+    }
+    None
+}
+
+/// Read the product identification from the first DisplayID extension block of `edid` that has
+/// one. Returns None when there is no such block.
+pub fn product_id(edid: &[u8]) -> Option<ProductId> {
+    edid.chunks_exact(BLOCK_LEN)
+        .skip(1)
+        .filter(|block| block[0] == DISPLAYID_EXTENSION)
+        .find_map(section_product_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an EDID with a blank base block and one DisplayID extension block holding the
+    /// `data` blocks.
+    fn edid_with_displayid(data: &[u8]) -> Vec<u8> {
+        let mut edid = vec![0; BLOCK_LEN * 2];
+        let ext = &mut edid[BLOCK_LEN..];
+        ext[..5].copy_from_slice(&[DISPLAYID_EXTENSION, 0x20, data.len() as u8, 0x03, 0]);
+        ext[5..5 + data.len()].copy_from_slice(data);
+        edid
+    }
+
+    #[test]
+    fn reads_the_product_identification_block() {
+        // A tiled display topology block precedes the product identification
+        let mut data = vec![0x28, 0, 2, 0xaa, 0xbb];
+        data.extend_from_slice(&[PRODUCT_ID_V2, 0, 18, 0x00, 0x1c, 0xf8, 0x34, 0x12]);
+        data.extend_from_slice(&[0x15, 0xcd, 0x5b, 0x07, 12, 30, 6]);
+        data.extend_from_slice(b"Studio");
+        assert_eq!(
+            product_id(&edid_with_displayid(&data)),
+            Some(ProductId {
+                product: Some("Studio".to_string()),
+                serial: Some(123_456_789),
+            })
+        );
+        // Blank fields are left out
+        let blank = [PRODUCT_ID_V1, 0, 14, b'A', b'B', b'C', 1, 0, 0, 0, 0, 0, 1, 30, 2, 0, 0];
+        assert_eq!(
+            product_id(&edid_with_displayid(&blank)),
+            Some(ProductId {
+                product: None,
+                serial: None
+            })
+        );
+        assert_eq!(product_id(&edid_with_displayid(&[0x28, 0, 0])), None);
+        assert_eq!(product_id(&[0; BLOCK_LEN]), None);
+    }
+}
@@ -0,0 +1,108 @@
+//! A Unix domain socket that tells every connected client about layout changes.
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::{
+    fs::FileTypeExt,
+    io::{AsRawFd, RawFd},
+    net::{UnixListener, UnixStream},
+};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::{debug, info};
+
+/// A listening socket, and the clients connected to it.
+pub struct EventSocket {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<UnixStream>,
+}
+
+impl EventSocket {
+    /// Listen at `path`, replacing a socket left behind by an earlier daemon.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => fs::remove_file(path)?,
+            _ => (),
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    /// Accept every client that is waiting to connect.
+    pub fn accept(&mut self) -> io::Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((client, _)) => {
+                    client.set_nonblocking(true)?;
+                    debug!("Event socket client connected");
+                    self.clients.push(client);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send a line with the time, in seconds since the epoch, and the name of an applied layout
+    /// to every client. Clients that have disconnected, or that aren't keeping up, are dropped.
+    pub fn broadcast(&mut self, name: &str) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|t| t.as_secs())
+            .unwrap_or_default();
+        let line = format!("{} {}\n", time, name);
+        self.clients.retain(|mut client| match client.write_all(line.as_bytes()) {
+            Ok(()) => true,
+            Err(e) => {
+                info!("Dropping event socket client: {}", e);
+                false
+            }
+        });
+    }
+}
+
+impl AsRawFd for EventSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for EventSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn broadcasts_to_connected_clients() {
+        let path = std::env::temp_dir().join(format!("monitor-layout-{}.sock", std::process::id()));
+        let mut socket = EventSocket::bind(&path).unwrap();
+        let gone = UnixStream::connect(&path).unwrap();
+        let stays = UnixStream::connect(&path).unwrap();
+        socket.accept().unwrap();
+        drop(gone);
+        socket.broadcast("Docked");
+        socket.broadcast("Laptop Only");
+        assert_eq!(socket.clients.len(), 1);
+        let mut lines = BufReader::new(stays).lines();
+        for name in &["Docked", "Laptop Only"] {
+            let line = lines.next().unwrap().unwrap();
+            let (time, layout) = line.split_once(' ').unwrap();
+            assert!(time.parse::<u64>().is_ok());
+            assert_eq!(&layout, name);
+        }
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,166 @@
+//! Read the video card gamma table, or VCGT, of an ICC profile, which calibrates a monitor once
+//! it's loaded into the gamma ramps of the CRTC that drives it.
+
+/// The signature of the VCGT tag, and of its type
+const VCGT: &[u8] = b"vcgt";
+
+/// The offset of the tag count, which follows the profile's header
+const TAG_TABLE: usize = 128;
+
+/// The calibration curves of a profile, for red, green and blue, each mapping 0 to 1 onto 0 to 1
+#[derive(Debug, Clone, PartialEq)]
+pub enum Vcgt {
+    /// Curves sampled in equal steps
+    Table([Vec<f64>; 3]),
+    /// Curves of the form `min + (max - min) * x ^ gamma`, as (gamma, min, max)
+    Formula([(f64, f64, f64); 3]),
+}
+
+fn be_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]))
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    let b = bytes.get(at..at + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Read a signed 15.16 fixed point number.
+fn s15_fixed16(bytes: &[u8], at: usize) -> Option<f64> {
+    Some(f64::from(be_u32(bytes, at)? as i32) / 65536.0)
+}
+
+/// Parse the data of a table VCGT: the number of channels, the number of entries in each, and
+/// the size of an entry, 1 or 2 bytes, followed by the entries. A single channel is used for
+/// all three.
+fn parse_table(data: &[u8]) -> Option<Vcgt> {
+    let channels = usize::from(be_u16(data, 12)?);
+    let entries = usize::from(be_u16(data, 14)?);
+    let entry = |i: usize| match be_u16(data, 16)? {
+        1 => Some(f64::from(*data.get(18 + i)?) / 255.0),
+        2 => Some(f64::from(be_u16(data, 18 + 2 * i)?) / 65535.0),
+        _ => None,
+    };
+    let channel = |c: usize| -> Option<Vec<f64>> {
+        (0..entries).map(|i| entry(c * entries + i)).collect()
+    };
+    match channels {
+        1 => {
+            let curve = channel(0)?;
+            Some(Vcgt::Table([curve.clone(), curve.clone(), curve]))
+        }
+        3 => Some(Vcgt::Table([channel(0)?, channel(1)?, channel(2)?])),
+        _ => None,
+    }
+}
+
+/// Find the VCGT of an ICC profile. Returns None when the profile has none, or it can't be read.
+pub fn vcgt(profile: &[u8]) -> Option<Vcgt> {
+    // A count past the end of the profile is bounded by the entries the profile can hold
+    let count = be_u32(profile, TAG_TABLE)? as usize;
+    let count = count.min(profile.len().saturating_sub(TAG_TABLE + 4) / 12);
+    let tag = (0..count)
+        .map(|i| TAG_TABLE + 4 + 12 * i)
+        .find(|&entry| profile.get(entry..entry + 4) == Some(VCGT))?;
+    let offset = be_u32(profile, tag + 4)? as usize;
+    let size = be_u32(profile, tag + 8)? as usize;
+    let data = profile.get(offset..offset.checked_add(size)?)?;
+    if data.get(..4)? != VCGT {
+        return None;
+    }
+    match be_u32(data, 8)? {
+        0 => parse_table(data),
+        1 => {
+            let curve = |c: usize| {
+                let at = 12 + 12 * c;
+                let gamma = s15_fixed16(data, at)?;
+                Some((gamma, s15_fixed16(data, at + 4)?, s15_fixed16(data, at + 8)?))
+            };
+            Some(Vcgt::Formula([curve(0)?, curve(1)?, curve(2)?]))
+        }
+        _ => None,
+    }
+}
+
+/// Convert a value from 0 to 1 into an entry of a gamma ramp.
+fn ramp_entry(value: f64) -> u16 {
+    (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+}
+
+impl Vcgt {
+    /// Sample the curves into red, green and blue gamma ramps of `size` entries each, as a CRTC
+    /// takes them.
+    pub fn ramps(&self, size: usize) -> [Vec<u16>; 3] {
+        let step = |i: usize| if size > 1 { i as f64 / (size - 1) as f64 } else { 0.0 };
+        let ramp = |c: usize| -> Vec<u16> {
+            (0..size)
+                .map(|i| match self {
+                    Vcgt::Table(curves) => {
+                        let curve = &curves[c];
+                        let at = step(i) * (curve.len().max(1) - 1) as f64;
+                        let (below, above) = (at.floor() as usize, at.ceil() as usize);
+                        match (curve.get(below), curve.get(above)) {
+                            (Some(lo), Some(hi)) => ramp_entry(lo + (hi - lo) * at.fract()),
+                            _ => ramp_entry(step(i)),
+                        }
+                    }
+                    Vcgt::Formula(curves) => {
+                        let (gamma, min, max) = curves[c];
+                        ramp_entry(min + (max - min) * step(i).powf(gamma))
+                    }
+                })
+                .collect()
+        };
+        [ramp(0), ramp(1), ramp(2)]
+    }
+}
+
+/// Gamma ramps of `size` entries that leave colors as they are
+pub fn linear_ramps(size: usize) -> [Vec<u16>; 3] {
+    Vcgt::Formula([(1.0, 0.0, 1.0); 3]).ramps(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a profile with a blank header and a single VCGT tag holding `data`, which follows
+    /// the tag's type signature and reserved bytes.
+    fn profile_with_vcgt(data: &[u8]) -> Vec<u8> {
+        let mut profile = vec![0; TAG_TABLE];
+        let offset = TAG_TABLE + 4 + 12;
+        let size = 8 + data.len();
+        profile.extend_from_slice(&1u32.to_be_bytes());
+        profile.extend_from_slice(VCGT);
+        profile.extend_from_slice(&(offset as u32).to_be_bytes());
+        profile.extend_from_slice(&(size as u32).to_be_bytes());
+        profile.extend_from_slice(VCGT);
+        profile.extend_from_slice(&[0; 4]);
+        profile.extend_from_slice(data);
+        profile
+    }
+
+    #[test]
+    fn reads_table_and_formula_curves() {
+        // One channel of three 2 byte entries, used for red, green and blue
+        let mut table = vec![0, 0, 0, 0, 0, 1, 0, 3, 0, 2];
+        table.extend_from_slice(&[0, 0, 0x90, 0x00, 0xf0, 0x00]);
+        let found = vcgt(&profile_with_vcgt(&table)).unwrap();
+        let [red, green, blue] = found.ramps(5);
+        assert_eq!(red, vec![0, 18432, 36864, 49152, 61440]);
+        assert_eq!(green, red);
+        assert_eq!(blue, red);
+        // Gamma 2 from 0 to 1 on every channel
+        let mut formula = vec![0, 0, 0, 1];
+        for _ in 0..3 {
+            formula.extend_from_slice(&[0, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0]);
+        }
+        let found = vcgt(&profile_with_vcgt(&formula)).unwrap();
+        assert_eq!(found.ramps(3)[1], vec![0, 16384, 65535]);
+        assert_eq!(linear_ramps(3)[0], vec![0, 32768, 65535]);
+        assert_eq!(vcgt(&[0; TAG_TABLE + 4]), None);
+        let mut endless = profile_with_vcgt(&table);
+        endless[TAG_TABLE..TAG_TABLE + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(vcgt(&endless).is_some());
+    }
+}
@@ -0,0 +1,82 @@
+//! Reading configurations written in KDL v2, by converting them to the KDL v1 nodes that the
+//! configuration is read from.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use kdl::{KdlNode, KdlValue};
+use kdl2::{KdlDocument, KdlValue as Kdl2Value};
+
+use crate::config::{Error, Result};
+
+/// The line that marks a document as KDL v2, as the KDL specification recommends
+pub const VERSION_MARKER: &str = "/- kdl-version 2";
+
+/// Parse a KDL v2 document into KDL v1 nodes.
+pub fn parse_document(text: &str) -> Result<Vec<KdlNode>> {
+    let document: KdlDocument = text.parse()?;
+    convert_nodes(&document)
+}
+
+fn convert_nodes(document: &KdlDocument) -> Result<Vec<KdlNode>> {
+    document.nodes().iter().map(convert_node).collect()
+}
+
+/// Convert a node. Arguments become values and properties become properties, where the last of
+/// any repeated property wins, as in KDL v1. Type annotations are dropped.
+fn convert_node(node: &kdl2::KdlNode) -> Result<KdlNode> {
+    let mut values = Vec::new();
+    let mut properties = HashMap::new();
+    for entry in node.entries() {
+        let value = convert_value(entry.value())?;
+        match entry.name() {
+            Some(name) => {
+                properties.insert(name.value().to_string(), value);
+            }
+            None => values.push(value),
+        }
+    }
+    let children = match node.children() {
+        Some(children) => convert_nodes(children)?,
+        None => Vec::new(),
+    };
+    Ok(KdlNode {
+        name: node.name().value().to_string(),
+        values,
+        properties,
+        children,
+    })
+}
+
+fn convert_value(value: &Kdl2Value) -> Result<KdlValue> {
+    Ok(match value {
+        Kdl2Value::String(s) => KdlValue::String(s.clone()),
+        Kdl2Value::Integer(i) => KdlValue::Int(i64::try_from(*i).map_err(|_| Error::IntRange(*i))?),
+        Kdl2Value::Float(f) => KdlValue::Float(*f),
+        Kdl2Value::Bool(b) => KdlValue::Boolean(*b),
+        Kdl2Value::Null => KdlValue::Null,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_v2_syntax() {
+        let nodes = parse_document(
+            r#"/- kdl-version 2
+            monitor Laptop product="Panel"
+            layout Docked {
+                matches Laptop
+                monitor Laptop w=1920 h=1080 x=0 y=0 primary=#true
+            }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].values, vec![KdlValue::String("Laptop".to_string())]);
+        let monitor = &nodes[1].children[1];
+        assert_eq!(monitor.properties["w"], KdlValue::Int(1920));
+        assert_eq!(monitor.properties["primary"], KdlValue::Boolean(true));
+    }
+}
@@ -1,34 +1,225 @@
-use std::error::Error;
 use x11rb::{
     connection::Connection,
-    protocol::randr::{ConnectionExt as RandrExt, GetScreenResourcesCurrentReply, Output},
-    protocol::xproto::{Atom, ConnectionExt as XprotoExt, Window},
+    cookie::Cookie,
+    errors::{ConnectionError, ReplyError},
+    protocol::randr::{
+        self, Connection as RandrConnection, ConnectionExt as RandrExt, Crtc,
+        GetOutputPropertyReply, GetScreenResourcesCurrentReply, Output,
+    },
+    protocol::xproto::{Atom, AtomEnum, ConnectionExt as XprotoExt, Timestamp, Window},
+    CURRENT_TIME,
 };
 
+use std::fmt::Display;
+
 use edid::{parse, EDID};
 use nom::IResult;
 use miette::{IntoDiagnostic, Result};
+use thiserror::Error;
+use tracing::{debug, warn};
 
 pub mod app;
+pub mod backend;
+pub mod colorimetry;
 pub mod commands;
 pub mod config;
+pub mod daemonize;
+#[cfg(feature = "dbus")]
+pub mod dbus;
+pub mod displayid;
+pub mod event_socket;
+pub mod icc;
+#[cfg(feature = "kdl2")]
+mod kdl_v2;
+pub mod lid;
+pub mod selector;
+pub mod timing;
+pub mod xresources;
+
+use backend::Backend;
+use config::{Mode, Monitor, Position};
+
+/// An EDID, with the product identification of its DisplayID extension blocks, if any, and the
+/// color characteristics of its base block
+#[derive(Debug)]
+pub struct ParsedEdid {
+    pub edid: EDID,
+    pub display_id: Option<displayid::ProductId>,
+    pub colorimetry: Option<colorimetry::Colorimetry>,
+}
+
+/// A pending read of an EDID property
+type EdidCookie<'c, C> = Cookie<'c, C, GetOutputPropertyReply>;
+
+/// Send a request that reads none of an EDID property, as its reply gives the length of the
+/// property in bytes.
+fn probe_edid<C: Connection>(
+    conn: &C,
+    atom_edid: Atom,
+    output: Output,
+) -> Result<EdidCookie<'_, C>, ConnectionError> {
+    conn.randr_get_output_property(output, atom_edid, AtomEnum::INTEGER, 0, 0, false, true)
+}
+
+/// Send the request to read the first `len` bytes of an EDID from an output.
+fn request_edid<C: Connection>(
+    conn: &C,
+    atom_edid: Atom,
+    output: Output,
+    len: u32,
+) -> Result<EdidCookie<'_, C>, ConnectionError> {
+    // The length of a read is counted in 32 bit units
+    let longs = len.div_ceil(4);
+    conn.randr_get_output_property(output, atom_edid, AtomEnum::INTEGER, 0, longs, false, true)
+}
+
+/// Send the requests to read the whole EDID of each output, after reading their lengths. Each
+/// step's requests are all sent before any of its replies are awaited, so that reading many
+/// EDIDs costs about two round trips to the X server.
+fn request_edids<'c, C: Connection>(
+    conn: &'c C,
+    outputs: &[Output],
+    atom_edid: Atom,
+) -> Vec<(Output, Result<EdidCookie<'c, C>, ReplyError>)> {
+    let probes: Vec<_> = outputs
+        .iter()
+        .map(|&out| (out, probe_edid(conn, atom_edid, out)))
+        .collect();
+    probes
+        .into_iter()
+        .map(|(out, probe)| {
+            let cookie = probe.map_err(ReplyError::from).and_then(|c| c.reply()).and_then(|p| {
+                Ok(request_edid(conn, atom_edid, out, p.bytes_after)?)
+            });
+            (out, cookie)
+        })
+        .collect()
+}
+
+/// The size of an EDID block, the last byte of which is its checksum
+const EDID_BLOCK_LEN: usize = 128;
+
+/// Find the blocks of an EDID whose bytes don't sum to zero, modulo 256. A trailing partial
+/// block is always bad.
+pub fn bad_edid_blocks(edid: &[u8]) -> Vec<usize> {
+    edid.chunks(EDID_BLOCK_LEN)
+        .enumerate()
+        .filter(|(_, block)| {
+            let sum = block.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            block.len() != EDID_BLOCK_LEN || sum != 0
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Parse the bytes of an EDID read from `output`, with any DisplayID extension blocks, warning
+/// about blocks with bad checksums.
+pub(crate) fn parse_edid_bytes(output: impl Display, data: &[u8]) -> Option<ParsedEdid> {
+    for block in bad_edid_blocks(data) {
+        warn!("EDID block {} of output {} checksum invalid", block, output);
+    }
+    match parse(data) {
+        IResult::Done(_, edid) => Some(ParsedEdid {
+            edid,
+            display_id: displayid::product_id(data),
+            colorimetry: colorimetry::colorimetry(data),
+        }),
+        _ => None,
+    }
+}
 
-use config::Monitor;
+/// Wait for the reply to a request sent by `request_edid` for `output`, and parse it. When the
+/// output has no EDID property, or it doesn't parse, the output's other properties with EDID
+/// in their names are tried instead.
+fn parse_edid_reply<C: Connection>(
+    conn: &C,
+    atom_edid: Atom,
+    output: Output,
+    cookie: EdidCookie<'_, C>,
+) -> Result<Option<ParsedEdid>, ReplyError> {
+    let props = cookie.reply()?;
+    if let Some(edid) = parse_edid_bytes(output, &props.data) {
+        return Ok(Some(edid));
+    }
+    for data in other_edid_properties(conn, atom_edid, output)? {
+        if let Some(edid) = parse_edid_bytes(output, &data) {
+            return Ok(Some(edid));
+        }
+    }
+    Ok(None)
+}
+
+/// Read every property of an output, other than `atom_edid`, whose name contains "EDID", as
+/// some drivers name the property differently, such as "EDID_DATA".
+fn other_edid_properties<C: Connection>(
+    conn: &C,
+    atom_edid: Atom,
+    output: Output,
+) -> Result<Vec<Vec<u8>>, ReplyError> {
+    let atoms = conn.randr_list_output_properties(output)?.reply()?.atoms;
+    let mut found = Vec::new();
+    for atom in atoms.into_iter().filter(|&atom| atom != atom_edid) {
+        let name = conn.get_atom_name(atom)?.reply()?.name;
+        if String::from_utf8_lossy(&name).contains("EDID") {
+            debug!(
+                "Reading EDID of output {} from property {}",
+                output,
+                String::from_utf8_lossy(&name)
+            );
+            let len = probe_edid(conn, atom, output)?.reply()?.bytes_after;
+            let data = request_edid(conn, atom, output, len)?.reply()?.data;
+            if !data.is_empty() {
+                found.push(data);
+            }
+        }
+    }
+    Ok(found)
+}
 
 /// Read an EDID from an output.
 pub fn get_edid<C: Connection>(
     conn: &C,
     atom_edid: Atom,
     output: Output,
-) -> Result<Option<EDID>, Box<dyn Error>> {
-    let cookie = conn.randr_get_output_property(output, atom_edid, 19u32, 0, 256, false, true)?;
-    let props = cookie.reply()?;
-    match parse(&props.data) {
-        IResult::Done(_, edid) => Ok(Some(edid)),
-        _ => Ok(None),
+) -> Result<Option<ParsedEdid>, ReplyError> {
+    let len = probe_edid(conn, atom_edid, output)?.reply()?.bytes_after;
+    parse_edid_reply(conn, atom_edid, output, request_edid(conn, atom_edid, output, len)?)
+}
+
+/// The name of an output, such as "DP-1", for messages, or its id when the name can't be read.
+fn output_label<C: Connection>(conn: &C, output: Output) -> String {
+    match get_output_name(conn, output, CURRENT_TIME) {
+        Ok(name) => name,
+        Err(_) => format!("output {}", output),
     }
 }
 
+/// Construct an iterator that represents a mapping from Xorg output ids to the unparsed bytes of
+/// their EDIDs. Outputs without an EDID have no bytes.
+pub fn get_raw_edids<'o, C: Connection>(
+    conn: &'o C,
+    outputs: &'o [Output],
+    atom_edid: Atom,
+) -> impl Iterator<Item = (Output, Vec<u8>)> + 'o {
+    let cookies = request_edids(conn, outputs, atom_edid);
+    cookies.into_iter().filter_map(move |(out, cookie)| {
+        let data = cookie.and_then(|c| c.reply()).and_then(|props| {
+            if !props.data.is_empty() {
+                return Ok(props.data);
+            }
+            let others = other_edid_properties(conn, atom_edid, out)?;
+            Ok(others.into_iter().next().unwrap_or_default())
+        });
+        match data {
+            Ok(data) => Some((out, data)),
+            Err(e) => {
+                warn!("EDID read failed for {}: {}", output_label(conn, out), e);
+                None
+            }
+        }
+    })
+}
+
 /// A convienience function to complete a RandR getScreenResourcesCurrent request.
 pub fn get_outputs<C: Connection>(
     conn: &C,
@@ -42,23 +233,143 @@ pub fn get_outputs<C: Connection>(
     )
 }
 
-/// Construct an iterator that represents a mapping from Xorg output ids to monitor descriptions.
-/// The monitor descriptions are generated from the EDID of the display.
-pub fn get_monitors<'o, C: Connection>(
+/// Find the outputs with a monitor connected, out of all of the outputs in `res`. The output info
+/// requests are all sent before any reply is awaited.
+pub fn get_connected_outputs<C: Connection>(
+    conn: &C,
+    res: &GetScreenResourcesCurrentReply,
+) -> Result<Vec<Output>> {
+    let cookies = res
+        .outputs
+        .iter()
+        .map(|&out| Ok((out, conn.randr_get_output_info(out, res.config_timestamp)?)))
+        .collect::<Result<Vec<_>, ConnectionError>>()
+        .into_diagnostic()?;
+    let mut connected = Vec::with_capacity(cookies.len());
+    for (out, cookie) in cookies {
+        if cookie.reply().into_diagnostic()?.connection == RandrConnection::CONNECTED {
+            connected.push(out);
+        }
+    }
+    Ok(connected)
+}
+
+/// The outputs with a monitor connected, as of a configuration of the X server. A notification
+/// that leaves these unchanged, such as for a property of an output, can't change which layout
+/// matches, so the EDIDs need not be read again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectedOutputs {
+    config_timestamp: Timestamp,
+    outputs: Vec<Output>,
+}
+
+impl ConnectedOutputs {
+    /// `connected` is the result of `get_connected_outputs` for the resources `res`.
+    pub fn new(res: &GetScreenResourcesCurrentReply, connected: &[Output]) -> Self {
+        Self {
+            config_timestamp: res.config_timestamp,
+            outputs: connected.to_vec(),
+        }
+    }
+}
+
+/// Read the name of an output, such as "DP-1".
+pub fn get_output_name<C: Connection>(conn: &C, out: Output, ts: Timestamp) -> Result<String> {
+    String::from_utf8(
+        conn.randr_get_output_info(out, ts)
+            .into_diagnostic()?
+            .reply()
+            .into_diagnostic()?
+            .name,
+    )
+    .into_diagnostic()
+}
+
+/// Construct an iterator that represents a mapping from Xorg output ids to parsed EDIDs.
+/// Outputs without an EDID are skipped.
+///
+/// The requests for every output are sent before any reply is awaited, so that reading many
+/// EDIDs costs about two round trips to the X server.
+pub fn get_edids<'o, C: Connection>(
     conn: &'o C,
     outputs: &'o Vec<Output>,
     atom_edid: Atom,
-) -> impl Iterator<Item = (Output, Monitor)> + 'o {
-    outputs
-        .iter()
-        .filter_map(move |out| match get_edid(conn, atom_edid, *out) {
-            Ok(Some(m)) => Some((*out, Monitor::from(m))),
+) -> impl Iterator<Item = (Output, ParsedEdid)> + 'o {
+    let cookies = request_edids(conn, outputs, atom_edid);
+    cookies.into_iter().filter_map(move |(out, cookie)| {
+        match cookie.and_then(|c| parse_edid_reply(conn, atom_edid, out, c)) {
+            Ok(Some(edid)) => Some((out, edid)),
             Ok(None) => None,
             Err(e) => {
-                eprintln!("Error reading EDID for Output {}: {}", out, e);
+                warn!("EDID read failed for {}: {}", output_label(conn, out), e);
                 None
             }
-        })
+        }
+    })
+}
+
+/// Construct an iterator that represents a mapping from Xorg output ids to monitor descriptions.
+/// The monitor descriptions are generated from the EDID of the display.
+pub fn get_monitors<'o, C: Connection>(
+    conn: &'o C,
+    outputs: &'o Vec<Output>,
+    atom_edid: Atom,
+) -> impl Iterator<Item = (Output, Monitor)> + 'o {
+    get_edids(conn, outputs, atom_edid).map(|(out, edid)| (out, Monitor::from(edid)))
+}
+
+/// The current configuration of an output that is showing a picture.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputState {
+    pub output: Output,
+    /// The name of the output, such as "DP-1"
+    pub name: String,
+    pub crtc: Crtc,
+    /// The monitor connected to the output, or None when its EDID can't be read
+    pub monitor: Option<Monitor>,
+    /// The size of the mode, before rotation
+    pub mode: Mode,
+    pub position: Position,
+    /// The CRTC's rotation field: one rotation bit, and a bit for each axis it's reflected along
+    pub rotation: u16,
+    pub primary: bool,
+}
+
+/// Read the configuration of every output that is showing a picture, in the order of the
+/// screen's outputs.
+pub fn current_layout<B: Backend>(conn: &B, root: Window) -> Result<Vec<OutputState>> {
+    let res = conn.screen_resources_current(root)?;
+    let timestamp = res.config_timestamp;
+    let primary = conn.output_primary(root)?;
+    let mut states = Vec::new();
+    for &output in res.outputs.iter() {
+        let info = conn.output_info(output, timestamp)?;
+        if info.crtc == 0 {
+            continue;
+        }
+        let crtc = conn.crtc_info(info.crtc, timestamp)?;
+        let mode = match res.modes.iter().find(|mi| mi.id == crtc.mode) {
+            Some(mi) => Mode {
+                w: mi.width,
+                h: mi.height,
+            },
+            None => continue,
+        };
+        states.push(OutputState {
+            output,
+            name: String::from_utf8_lossy(&info.name).into_owned(),
+            crtc: info.crtc,
+            monitor: conn.monitor(output)?,
+            mode,
+            position: Position {
+                x: crtc.x,
+                y: crtc.y,
+            },
+            rotation: crtc.rotation,
+            primary: output == primary,
+        });
+    }
+    Ok(states)
 }
 
 /// Get the atom that allows reading an EDID from an output
@@ -71,3 +382,110 @@ pub fn edid_atom<C: Connection>(conn: &C) -> Result<Atom> {
             .atom
     )
 }
+
+/// The oldest RandR version with every request this program makes, such as
+/// GetScreenResourcesCurrent and SetPanning
+pub const MIN_RANDR_VERSION: (u32, u32) = (1, 3);
+
+/// The RandR version from which the server describes tiled monitors with the TILE property
+pub const TILE_RANDR_VERSION: (u32, u32) = (1, 5);
+
+/// The X server's RandR extension can't drive monitors the way this program does.
+#[derive(Error, Debug)]
+pub enum RandrError {
+    #[error("The X server does not support the RandR extension")]
+    Missing,
+    #[error("The X server supports RandR {0}.{1}, but at least {2}.{3} is required")]
+    TooOld(u32, u32, u32, u32),
+}
+
+/// Check that a RandR version is at least `MIN_RANDR_VERSION`.
+fn require_randr(version: (u32, u32)) -> Result<(u32, u32), RandrError> {
+    if version < MIN_RANDR_VERSION {
+        let (major, minor) = MIN_RANDR_VERSION;
+        return Err(RandrError::TooOld(version.0, version.1, major, minor));
+    }
+    Ok(version)
+}
+
+/// Ask the X server for its RandR version, failing when it's older than `MIN_RANDR_VERSION`, so
+/// that an old server is reported up front rather than by the first request it doesn't know.
+pub fn randr_version<C: Connection>(conn: &C) -> Result<(u32, u32)> {
+    if conn
+        .extension_information(randr::X11_EXTENSION_NAME)
+        .into_diagnostic()?
+        .is_none()
+    {
+        return Err(RandrError::Missing).into_diagnostic();
+    }
+    let reply = conn
+        .randr_query_version(TILE_RANDR_VERSION.0, TILE_RANDR_VERSION.1)
+        .into_diagnostic()?
+        .reply()
+        .into_diagnostic()?;
+    let version = (reply.major_version, reply.minor_version);
+    debug!("The X server supports RandR {}.{}", version.0, version.1);
+    require_randr(version).into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+
+    #[test]
+    fn reads_the_state_of_lit_outputs() {
+        let laptop = Monitor {
+            product: Some("Panel".to_string()),
+            ..Default::default()
+        };
+        let mock = MockBackend::default()
+            .mode(1, 1920, 1080)
+            .output(10, "eDP-1", 100, &[100, 101], &[1])
+            .output(11, "DP-1", 0, &[100, 101], &[1])
+            .crtc(100, 1, 0, 0, &[10])
+            .crtc(101, 0, 0, 0, &[])
+            .monitor(10, laptop.clone())
+            .size(1920, 1080);
+        let mock = MockBackend {
+            primary: 10,
+            ..mock
+        };
+        assert_eq!(
+            current_layout(&mock, 1).unwrap(),
+            vec![OutputState {
+                output: 10,
+                name: "eDP-1".to_string(),
+                crtc: 100,
+                monitor: Some(laptop),
+                mode: Mode { w: 1920, h: 1080 },
+                position: Position { x: 0, y: 0 },
+                rotation: 1,
+                primary: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn requires_randr_1_3() {
+        assert_eq!(require_randr((1, 5)).unwrap(), (1, 5));
+        assert_eq!(require_randr((1, 3)).unwrap(), (1, 3));
+        let old = require_randr((1, 2)).unwrap_err();
+        assert_eq!(
+            old.to_string(),
+            "The X server supports RandR 1.2, but at least 1.3 is required"
+        );
+    }
+
+    #[test]
+    fn finds_blocks_with_bad_checksums() {
+        let mut edid = vec![0u8; 3 * EDID_BLOCK_LEN];
+        edid[0] = 0x12;
+        edid[EDID_BLOCK_LEN - 1] = 0xee;
+        assert!(bad_edid_blocks(&edid).is_empty());
+        edid[EDID_BLOCK_LEN + 5] = 1;
+        assert_eq!(bad_edid_blocks(&edid), vec![1]);
+        edid.push(0);
+        assert_eq!(bad_edid_blocks(&edid), vec![1, 3]);
+    }
+}
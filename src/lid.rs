@@ -0,0 +1,47 @@
+//! Reading the state of a laptop's lid from ACPI.
+use std::fs;
+use std::path::Path;
+
+use tracing::debug;
+
+use crate::config::Lid;
+
+/// The directory holding a directory for each lid ACPI reports
+const LID_DIR: &str = "/proc/acpi/button/lid";
+
+/// Parse the contents of a lid's state file, such as "state:      open".
+fn parse_state(text: &str) -> Option<Lid> {
+    match text.strip_prefix("state:")?.trim() {
+        "open" => Some(Lid::Open),
+        "closed" => Some(Lid::Closed),
+        _ => None,
+    }
+}
+
+/// The state of the laptop's lid, or None when there is no lid, or its state can't be read.
+/// When ACPI reports more than one lid, the first that can be read is used.
+pub fn lid_state() -> Option<Lid> {
+    let mut lids: Vec<_> = fs::read_dir(LID_DIR)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .collect();
+    lids.sort();
+    let state = lids
+        .iter()
+        .filter_map(|lid| fs::read_to_string(Path::new(lid).join("state")).ok())
+        .find_map(|text| parse_state(&text));
+    debug!("The lid is {:?}", state);
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lid_states() {
+        assert_eq!(parse_state("state:      open\n"), Some(Lid::Open));
+        assert_eq!(parse_state("state:      closed\n"), Some(Lid::Closed));
+        assert_eq!(parse_state("state:      unknown\n"), None);
+    }
+}
@@ -16,16 +16,36 @@ fn main() -> Result<()> {
         2 => Level::DEBUG,
         _ => Level::TRACE,
     };
-    FmtSubscriber::builder()
-        .with_max_level(level)
-        .without_time()
-        .with_writer(std::io::stderr)
-        .try_init()
-        .unwrap();
+    let builder = FmtSubscriber::builder()
+        .with_writer(std::io::stderr);
+    if args.value_of("log-format") == Some("json") {
+        // The daemon's status lines are info events in JSON mode, so they must always be shown
+        builder
+            .json()
+            .with_max_level(std::cmp::max(level, Level::INFO))
+            .try_init()
+            .unwrap();
+    } else {
+        builder.with_max_level(level).without_time().try_init().unwrap();
+    }
     match args.subcommand() {
         ("daemon", Some(args)) => monitor_layout::commands::daemon(args),
-        ("check", Some(args)) => monitor_layout::commands::check(args).map(|_| ()),
+        ("check", Some(args)) => monitor_layout::commands::check_config(args),
+        ("dump", Some(args)) => monitor_layout::commands::dump(args),
         ("print-edids", Some(args)) => monitor_layout::commands::print_edids(args),
+        ("dump-edid", Some(args)) => monitor_layout::commands::dump_edid(args),
+        ("doctor", Some(_)) => monitor_layout::commands::doctor(),
+        ("modes", Some(args)) => monitor_layout::commands::modes(args),
+        ("apply", Some(args)) => monitor_layout::commands::apply(args),
+        ("apply-group", Some(args)) => monitor_layout::commands::apply_group(args),
+        ("render", Some(args)) => monitor_layout::commands::render(args),
+        ("match", Some(args)) => monitor_layout::commands::match_layout(args),
+        ("import", Some(args)) => monitor_layout::commands::import(args),
+        ("diff", Some(args)) => monitor_layout::commands::diff(args).map(|differs| {
+            if differs {
+                std::process::exit(1)
+            }
+        }),
         _ => {
             app::args().print_help().into_diagnostic()?;
             println!("");
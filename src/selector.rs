@@ -0,0 +1,154 @@
+//! A user provided script that picks the layout to apply, in place of matching.
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+
+use crate::config::Monitor;
+
+/// How long the script has to pick a layout before it's killed, and the layouts are matched as
+/// usual
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the script is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Describe the connected monitors, each paired with the name of its output, for the script.
+fn input(monitors: &[(String, &Monitor)]) -> Value {
+    let monitors: Vec<_> = monitors
+        .iter()
+        .map(|(output, mon)| {
+            json!({
+                "output": output,
+                "product": mon.product,
+                "serial": mon.serial,
+                "text": mon.text,
+                "manufacturer": mon.manufacturer,
+                "diagonal": mon.diagonal,
+            })
+        })
+        .collect();
+    json!({ "monitors": monitors })
+}
+
+/// Run the script at `path` with a JSON description of the connected monitors on its stdin, and
+/// return the first line it prints. A script that prints nothing, fails, or takes longer than a
+/// few seconds, picks no layout.
+pub fn select(path: &Path, monitors: &[(String, &Monitor)]) -> io::Result<Option<String>> {
+    select_within(path, monitors, TIMEOUT)
+}
+
+fn select_within(
+    path: &Path,
+    monitors: &[(String, &Monitor)],
+    timeout: Duration,
+) -> io::Result<Option<String>> {
+    let mut child = Command::new(path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    // The input is written from another thread, as a script that doesn't read all of it would
+    // otherwise block the write once the pipe is full
+    let writer = child.stdin.take().map(|mut stdin| {
+        let input = input(monitors).to_string();
+        thread::spawn(move || stdin.write_all(input.as_bytes()))
+    });
+    let status = match wait_until(&mut child, Instant::now() + timeout) {
+        Ok(Some(status)) => status,
+        Ok(None) => {
+            warn!("Layout selector {} took too long, so it was killed", path.display());
+            kill(&mut child);
+            return Ok(None);
+        }
+        Err(e) => {
+            kill(&mut child);
+            return Err(e);
+        }
+    };
+    // A script that doesn't read its input closes the pipe early, which is fine
+    match writer.map(|writer| writer.join()) {
+        Some(Ok(Err(e))) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+        _ => (),
+    }
+    if !status.success() {
+        warn!("Layout selector {} exited with {}", path.display(), status);
+        return Ok(None);
+    }
+    let mut stdout = Vec::new();
+    if let Some(mut out) = child.stdout.take() {
+        out.read_to_end(&mut stdout)?;
+    }
+    let stdout = String::from_utf8_lossy(&stdout);
+    let name = stdout.lines().next().map(str::trim).unwrap_or_default();
+    debug!("Layout selector {} picked {:?}", path.display(), name);
+    Ok(Some(name.to_string()).filter(|name| !name.is_empty()))
+}
+
+/// Wait for a child to exit, returning None when it's still running at `deadline`.
+fn wait_until(child: &mut Child, deadline: Instant) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Kill a child, and wait for it so that it isn't left a zombie.
+fn kill(child: &mut Child) {
+    if let Err(e) = child.kill().and_then(|()| child.wait().map(drop)) {
+        warn!("Could not stop layout selector: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn prints_the_layout_chosen_by_the_script() {
+        let dir = std::env::temp_dir().join(format!("monitor-layout-sel-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("select");
+        std::fs::write(&script, "#!/bin/sh\ngrep -q '\"output\":\"DP-1\"' && echo Docked\n")
+            .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let dock = Monitor {
+            serial: Some("1234".to_string()),
+            ..Default::default()
+        };
+        let picked = select(&script, &[("DP-1".to_string(), &dock)]).unwrap();
+        assert_eq!(picked.as_deref(), Some("Docked"));
+        let picked = select(&script, &[("HDMI-1".to_string(), &dock)]).unwrap();
+        assert_eq!(picked, None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scripts_that_take_too_long_pick_nothing() {
+        let dir = std::env::temp_dir().join(format!("monitor-layout-slow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("select");
+        std::fs::write(&script, "#!/bin/sh\nexec sleep 60\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Enough monitors to fill the pipe, which the script never reads
+        let dock = Monitor {
+            text: Some("x".repeat(1024)),
+            ..Default::default()
+        };
+        let monitors: Vec<_> = (0..100).map(|i| (format!("DP-{}", i), &dock)).collect();
+        let started = Instant::now();
+        let picked = select_within(&script, &monitors, Duration::from_millis(200)).unwrap();
+        assert_eq!(picked, None);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+//! Compute the timings of a mode from its size and refresh rate, with the VESA Coordinated Video
+//! Timings (CVT) and Generalized Timing Formula (GTF), as the cvt and gtf utilities do.
+use x11rb::protocol::randr::ModeFlag;
+
+use crate::config::ModeLine;
+
+/// The granularity of horizontal timings, in pixels
+const H_GRANULARITY: u16 = 8;
+
+/// The lines between the end of the picture and vertical sync
+const V_FRONT_PORCH: u16 = 3;
+
+/// The fewest lines of vertical back porch of a CVT mode
+const CVT_MIN_V_BACK_PORCH: u16 = 6;
+
+/// The shortest vertical sync and back porch of a CVT or GTF mode, in microseconds
+const MIN_VSYNC_BACK_PORCH: f64 = 550.0;
+
+/// The shortest vertical blanking of a reduced blanking CVT mode, in microseconds
+const CVT_RB_MIN_V_BLANK: f64 = 460.0;
+
+/// The horizontal blanking and sync of a reduced blanking CVT mode, in pixels
+const CVT_RB_H_BLANK: u16 = 160;
+const CVT_RB_H_SYNC: u16 = 32;
+
+/// The blanking formula's offset and gradient, C' and M', shared by CVT and GTF
+const C_PRIME: f64 = 30.0;
+const M_PRIME: f64 = 300.0;
+
+/// The width of horizontal sync, as a percentage of the total line
+const H_SYNC_PERCENT: u32 = 8;
+
+/// The pixel clock of a CVT mode is a multiple of this many kHz
+const CVT_CLOCK_STEP: u32 = 250;
+
+/// The lines of vertical sync of a CVT mode, which identify its aspect ratio.
+fn cvt_vsync(w: u16, h: u16) -> u16 {
+    let (w, h) = (u32::from(w), u32::from(h));
+    if h.is_multiple_of(3) && h * 4 / 3 == w {
+        4
+    } else if h * 16 / 9 == w {
+        5
+    } else if h * 16 / 10 == w {
+        6
+    } else if (h.is_multiple_of(4) && h * 5 / 4 == w) || h * 15 / 9 == w {
+        7
+    } else {
+        10
+    }
+}
+
+/// Compute a CVT mode of `w` by `h` pixels, refreshed `refresh` times a second. The width is
+/// rounded down to a multiple of 8 pixels. Reduced blanking shortens the blanking intervals, and
+/// so the pixel clock, which suits digital displays.
+pub fn cvt(w: u16, h: u16, refresh: f64, reduced: bool) -> ModeLine {
+    let hdisplay = w - w % H_GRANULARITY;
+    let vsync = cvt_vsync(hdisplay, h);
+    let (htotal, hsync_start, hsync_end, vtotal, clock, flags) = if reduced {
+        // The line period, in microseconds, and the lines of vertical blanking
+        let hperiod = (1_000_000.0 / refresh - CVT_RB_MIN_V_BLANK) / f64::from(h);
+        let vblank = ((CVT_RB_MIN_V_BLANK / hperiod) as u16 + 1)
+            .max(V_FRONT_PORCH + vsync + CVT_MIN_V_BACK_PORCH);
+        let htotal = hdisplay + CVT_RB_H_BLANK;
+        let hsync_end = hdisplay + CVT_RB_H_BLANK / 2;
+        let vtotal = h + vblank;
+        let clock = (refresh * f64::from(vtotal) * f64::from(htotal) / 1000.0) as u32;
+        let flags = u32::from(ModeFlag::HSYNC_POSITIVE) | u32::from(ModeFlag::VSYNC_NEGATIVE);
+        (htotal, hsync_end - CVT_RB_H_SYNC, hsync_end, vtotal, clock, flags)
+    } else {
+        let lines = f64::from(h + V_FRONT_PORCH);
+        let hperiod = (1_000_000.0 / refresh - MIN_VSYNC_BACK_PORCH) / lines;
+        let vsync_back_porch = ((MIN_VSYNC_BACK_PORCH / hperiod) as u16 + 1)
+            .max(vsync + CVT_MIN_V_BACK_PORCH);
+        let blank_percent = (C_PRIME - M_PRIME * hperiod / 1000.0).max(20.0);
+        let hblank = (f64::from(hdisplay) * blank_percent / (100.0 - blank_percent)) as u16;
+        let hblank = hblank - hblank % (2 * H_GRANULARITY);
+        let htotal = hdisplay + hblank;
+        let hsync_end = hdisplay + hblank / 2;
+        let hsync_start = hsync_end - (u32::from(htotal) * H_SYNC_PERCENT / 100) as u16;
+        let hsync_start = hsync_start + H_GRANULARITY - hsync_start % H_GRANULARITY;
+        let vtotal = h + vsync_back_porch + V_FRONT_PORCH;
+        let clock = (f64::from(htotal) * 1000.0 / hperiod) as u32;
+        let flags = u32::from(ModeFlag::HSYNC_NEGATIVE) | u32::from(ModeFlag::VSYNC_POSITIVE);
+        (htotal, hsync_start, hsync_end, vtotal, clock, flags)
+    };
+    ModeLine {
+        clock: f64::from(clock - clock % CVT_CLOCK_STEP) / 1000.0,
+        hdisplay,
+        hsync_start,
+        hsync_end,
+        htotal,
+        vdisplay: h,
+        vsync_start: h + V_FRONT_PORCH,
+        vsync_end: h + V_FRONT_PORCH + vsync,
+        vtotal,
+        flags,
+    }
+}
+
+/// Compute a GTF mode of `w` by `h` pixels, refreshed `refresh` times a second. The width is
+/// rounded to the nearest multiple of 8 pixels.
+pub fn gtf(w: u16, h: u16, refresh: f64) -> ModeLine {
+    // GTF has a single line of front porch, and 3 lines of vertical sync
+    let (front_porch, vsync) = (1, 3);
+    let granularity = f64::from(H_GRANULARITY);
+    let hdisplay = ((f64::from(w) / granularity).round() * granularity) as u16;
+    let estimate = (1.0 / refresh - MIN_VSYNC_BACK_PORCH / 1_000_000.0)
+        / f64::from(h + front_porch)
+        * 1_000_000.0;
+    let vsync_back_porch = (MIN_VSYNC_BACK_PORCH / estimate).round() as u16;
+    let vtotal = h + vsync_back_porch + front_porch;
+    // The line period that gives the refresh rate with these lines
+    let hperiod = 1_000_000.0 / (f64::from(vtotal) * refresh);
+    let duty_cycle = C_PRIME - M_PRIME * hperiod / 1000.0;
+    let hblank = (f64::from(hdisplay) * duty_cycle / (100.0 - duty_cycle) / (2.0 * granularity))
+        .round()
+        * 2.0
+        * granularity;
+    let htotal = hdisplay + hblank as u16;
+    let hsync = f64::from(H_SYNC_PERCENT * u32::from(htotal)) / 100.0;
+    let hsync = (hsync / granularity).round() * granularity;
+    let hsync_start = hdisplay + (hblank / 2.0 - hsync) as u16;
+    ModeLine {
+        clock: (f64::from(htotal) / hperiod * 1000.0).round() / 1000.0,
+        hdisplay,
+        hsync_start,
+        hsync_end: hsync_start + hsync as u16,
+        htotal,
+        vdisplay: h,
+        vsync_start: h + front_porch,
+        vsync_end: h + front_porch + vsync,
+        vtotal,
+        flags: u32::from(ModeFlag::HSYNC_NEGATIVE) | u32::from(ModeFlag::VSYNC_POSITIVE),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timings(line: &ModeLine) -> [u16; 8] {
+        [
+            line.hdisplay,
+            line.hsync_start,
+            line.hsync_end,
+            line.htotal,
+            line.vdisplay,
+            line.vsync_start,
+            line.vsync_end,
+            line.vtotal,
+        ]
+    }
+
+    #[test]
+    fn matches_the_cvt_and_gtf_utilities() {
+        let line = cvt(1920, 1080, 60.0, false);
+        assert_eq!(line.clock, 173.0);
+        assert_eq!(timings(&line), [1920, 2048, 2248, 2576, 1080, 1083, 1088, 1120]);
+        assert_eq!(
+            line.flags,
+            u32::from(ModeFlag::HSYNC_NEGATIVE) | u32::from(ModeFlag::VSYNC_POSITIVE)
+        );
+        let line = cvt(1920, 1080, 60.0, true);
+        assert_eq!(line.clock, 138.5);
+        assert_eq!(timings(&line), [1920, 1968, 2000, 2080, 1080, 1083, 1088, 1111]);
+        let line = cvt(1366, 768, 60.0, false);
+        assert_eq!(line.clock, 84.75);
+        assert_eq!(timings(&line), [1360, 1432, 1568, 1776, 768, 771, 781, 798]);
+        let line = gtf(1920, 1080, 60.0);
+        assert_eq!(line.clock, 172.798);
+        assert_eq!(timings(&line), [1920, 2040, 2248, 2576, 1080, 1081, 1084, 1118]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Reading and updating the X resource database, stored in the RESOURCE_MANAGER property of the
+//! root window.
+use miette::{IntoDiagnostic, Result};
+use x11rb::{
+    connection::Connection,
+    protocol::xproto::{AtomEnum, ConnectionExt as XprotoExt, PropMode, Window},
+    wrapper::ConnectionExt as WrapperExt,
+};
+
+/// Set `key` to `value` in a resource database, keeping every other resource.
+pub fn merge_resource(database: &str, key: &str, value: &str) -> String {
+    let mut found = false;
+    let mut merged = String::with_capacity(database.len() + key.len() + value.len() + 3);
+    for line in database.lines() {
+        let is_key = match line.split_once(':') {
+            Some((name, _)) => name.trim() == key,
+            None => false,
+        };
+        if is_key {
+            if !found {
+                merged.push_str(&format!("{}:\t{}\n", key, value));
+                found = true;
+            }
+        } else {
+            merged.push_str(line);
+            merged.push('\n');
+        }
+    }
+    if !found {
+        merged.push_str(&format!("{}:\t{}\n", key, value));
+    }
+    merged
+}
+
+/// Set a single resource in the X resource database of the screen with root window `root`.
+pub fn set_resource<C: Connection>(conn: &C, root: Window, key: &str, value: &str) -> Result<()> {
+    let current = conn
+        .get_property(
+            false,
+            root,
+            AtomEnum::RESOURCE_MANAGER,
+            AtomEnum::STRING,
+            0,
+            u32::MAX,
+        )
+        .into_diagnostic()?
+        .reply()
+        .into_diagnostic()?;
+    let database = merge_resource(&String::from_utf8_lossy(&current.value), key, value);
+    conn.change_property8(
+        PropMode::REPLACE,
+        root,
+        AtomEnum::RESOURCE_MANAGER,
+        AtomEnum::STRING,
+        database.as_bytes(),
+    )
+    .into_diagnostic()?
+    .check()
+    .into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_keeps_other_resources() {
+        let database = "Xcursor.size:\t24\nXft.dpi:\t96\nXft.antialias:\t1\n";
+        assert_eq!(
+            merge_resource(database, "Xft.dpi", "144"),
+            "Xcursor.size:\t24\nXft.dpi:\t144\nXft.antialias:\t1\n"
+        );
+        assert_eq!(merge_resource("", "Xft.dpi", "144"), "Xft.dpi:\t144\n");
+        assert_eq!(
+            merge_resource("Xcursor.size: 24", "Xft.dpi", "96"),
+            "Xcursor.size: 24\nXft.dpi:\t96\n"
+        );
+    }
+}
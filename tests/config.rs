@@ -0,0 +1,321 @@
+//! Property tests for the configuration parser
+use kdl::parse_document;
+use monitor_layout::config::{
+    Config, Mode, ModeLine, MonConfig, Monitor, Position, PropertyValue, Reflection, Rotation,
+    ScalingMode, Settings, SingleConfig,
+};
+use proptest::{collection, option, prelude::*};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+};
+
+fn monitor() -> impl Strategy<Value = Monitor> {
+    (
+        (
+            option::of("[ -~]{0,12}"),
+            option::of("[ -~]{0,12}"),
+            option::of("[A-Z]{3}"),
+            option::of(1u8..=255),
+            option::of("(DP|HDMI|eDP)-[0-9]"),
+            option::of("DP|HDMI|eDP|VGA"),
+        ),
+        (any::<bool>(), option::of("[ -~]{0,12}"), option::of("[A-Z]{1,8}")),
+    )
+        .prop_map(|fields| {
+            let (product, serial, manufacturer, diagonal, force_output, connector) = fields.0;
+            let (any_serial, text, pattern) = fields.1;
+            Monitor {
+                // A monitor matches either its product or a pattern
+                product_pattern: pattern
+                    .filter(|_| product.is_none())
+                    .map(|pattern| pattern.parse().unwrap()),
+                product,
+                // Only a monitor with a serial may match it against the serial number
+                any_serial: any_serial && serial.is_some(),
+                serial,
+                serial_number: None,
+                text,
+                manufacturer,
+                diagonal,
+                force_output,
+                connector,
+                wildcard: false,
+            }
+        })
+}
+
+fn modeline(w: u16, h: u16) -> impl Strategy<Value = ModeLine> {
+    (1u32..1_000_000, any::<[u16; 6]>(), 0u32..512).prop_map(move |(khz, timings, flags)| {
+        let [hsync_start, hsync_end, htotal, vsync_start, vsync_end, vtotal] = timings;
+        ModeLine {
+            clock: f64::from(khz) / 1000.0,
+            hdisplay: w,
+            hsync_start,
+            hsync_end,
+            htotal,
+            vdisplay: h,
+            vsync_start,
+            vsync_end,
+            vtotal,
+            flags,
+        }
+    })
+}
+
+/// A monitor config without its name, which is picked from the aliases
+fn mon_config() -> impl Strategy<Value = MonConfig> {
+    prop_oneof![
+        9 => lit_mon_config(),
+        1 => option::of("(DP|HDMI|eDP)-[0-9]").prop_map(|output| MonConfig {
+            name: String::new(),
+            mode: Mode { w: 0, h: 0 },
+            position: Position { x: 0, y: 0 },
+            primary: false,
+            rot: None,
+            reflect: None,
+            output,
+            modeline: None,
+            transform: None,
+            backlight: None,
+            vrr: None,
+            scaling_mode: None,
+            prefer_refresh: None,
+            crtc: None,
+            touch_device: None,
+            properties: BTreeMap::new(),
+            icc: None,
+            exec: None,
+            off: true,
+        }),
+    ]
+}
+
+fn lit_mon_config() -> impl Strategy<Value = MonConfig> {
+    let scaling_mode = prop_oneof![
+        Just(ScalingMode::Full),
+        Just(ScalingMode::Center),
+        Just(ScalingMode::Aspect),
+    ];
+    let reflect = prop_oneof![Just(Reflection::X), Just(Reflection::Y), Just(Reflection::XY)];
+    let value = prop_oneof![
+        any::<i64>().prop_map(PropertyValue::Int),
+        "[ -~]{0,12}".prop_map(PropertyValue::String),
+    ];
+    let properties = (
+        option::of("(DP|HDMI|eDP)-[0-9]"),
+        option::of(0u8..=100),
+        option::of(any::<bool>()),
+        option::of(scaling_mode),
+        option::of(any::<bool>()),
+        option::of(reflect),
+        option::of("[ -~]{0,20}"),
+        option::of("[ -~]{1,20}"),
+        option::of("[ -~]{1,20}"),
+        collection::btree_map("[ -~]{1,12}", value, 0..3),
+    );
+    (1u16..4000, 1u16..4000, properties)
+        .prop_flat_map(|(w, h, properties)| {
+            (
+                0i16..4000,
+                0i16..4000,
+                any::<bool>(),
+                option::of(any::<bool>()),
+                option::of(modeline(w, h)),
+            )
+                .prop_map(move |(x, y, primary, left, modeline)| {
+                    let (
+                        output,
+                        backlight,
+                        vrr,
+                        scaling_mode,
+                        prefer_refresh,
+                        reflect,
+                        exec,
+                        touch_device,
+                        icc,
+                        output_properties,
+                    ) = properties.clone();
+                    let rot = left.map(|left| if left { Rotation::Left } else { Rotation::Right });
+                    MonConfig {
+                        name: String::new(),
+                        mode: Mode { w, h },
+                        position: Position { x, y },
+                        primary,
+                        rot,
+                        reflect,
+                        output,
+                        modeline,
+                        transform: None,
+                        backlight,
+                        vrr,
+                        scaling_mode,
+                        prefer_refresh,
+                        crtc: None,
+                        touch_device,
+                        properties: output_properties,
+                        icc,
+                        exec,
+                        off: false,
+                    }
+                })
+        })
+}
+
+/// A layout, with monitors given by their index into the sorted aliases
+#[derive(Debug)]
+struct LayoutSpec {
+    name: String,
+    monitors: Vec<(usize, MonConfig)>,
+    fb_extra: (u16, u16),
+    dpi: Option<u32>,
+    group: Option<String>,
+}
+
+fn layout(aliases: usize) -> impl Strategy<Value = LayoutSpec> {
+    (
+        "[ -~]{1,12}".prop_filter("the safe layout's name is reserved", |name| name != "safe"),
+        collection::vec((0..aliases, mon_config()), 1..4),
+        (0u16..100, 0u16..100),
+        option::of(1u32..400),
+        option::of("work|home"),
+    )
+        .prop_map(|(name, mut monitors, fb_extra, dpi, group)| {
+            // A layout may only have one primary monitor, and one monitor on each output
+            let mut primary = false;
+            let mut outputs = Vec::new();
+            for (_, conf) in monitors.iter_mut() {
+                conf.primary &= !primary;
+                primary |= conf.primary;
+                if let Some(output) = conf.output.take() {
+                    if !outputs.contains(&output) {
+                        outputs.push(output.clone());
+                        conf.output = Some(output);
+                    }
+                }
+            }
+            LayoutSpec {
+                name,
+                monitors,
+                fb_extra,
+                dpi,
+                group,
+            }
+        })
+}
+
+fn build(
+    monitors: HashMap<String, Monitor>,
+    specs: Vec<LayoutSpec>,
+    mirror_all: Option<String>,
+    settings: Settings,
+) -> Config {
+    let mut aliases: Vec<_> = monitors.iter().collect();
+    aliases.sort();
+    let mut layouts = HashMap::new();
+    let mut groups: HashMap<String, HashMap<_, _>> = HashMap::new();
+    for spec in specs {
+        let mut mon_set = Vec::new();
+        let mut setup: HashMap<Monitor, Vec<MonConfig>> = HashMap::new();
+        let mut fb_size = Mode { w: 0, h: 0 };
+        for (i, mut conf) in spec.monitors {
+            let (alias, mon) = aliases[i];
+            conf.name = alias.clone();
+            mon_set.push(mon.clone());
+            setup.entry(mon.clone()).or_default().push(conf);
+            let conf = setup[mon].last().unwrap();
+            if conf.off {
+                continue;
+            }
+            let (w, h) = match conf.rot {
+                Some(_) => (conf.mode.h, conf.mode.w),
+                None => (conf.mode.w, conf.mode.h),
+            };
+            fb_size = fb_size.union(&Mode {
+                w: conf.position.x as u16 + w,
+                h: conf.position.y as u16 + h,
+            });
+        }
+        mon_set.sort();
+        fb_size.w += spec.fb_extra.0;
+        fb_size.h += spec.fb_extra.1;
+        let layouts = match spec.group {
+            Some(group) => groups.entry(group).or_default(),
+            None => &mut layouts,
+        };
+        layouts.entry(mon_set).or_insert(SingleConfig {
+            name: spec.name,
+            fb_size,
+            setup,
+            dpi: spec.dpi,
+            mm: None,
+            priority: 0,
+            screen: None,
+        });
+    }
+    Config {
+        layouts,
+        groups,
+        lid_layouts: HashMap::new(),
+        monitors,
+        mirror_all,
+        side_by_side: None,
+        settings,
+        on_error_exec: None,
+    }
+}
+
+fn config() -> impl Strategy<Value = Config> {
+    collection::hash_map("[ -~]{1,10}", monitor(), 1..5).prop_flat_map(|monitors| {
+        let aliases = monitors.len();
+        (
+            Just(monitors),
+            collection::vec(layout(aliases), 0..4),
+            option::of("[ -~]{1,12}"),
+            any::<bool>(),
+        )
+            .prop_map(|(monitors, specs, mirror_all, prefer_refresh)| {
+                build(monitors, specs, mirror_all, Settings { prefer_refresh })
+            })
+    })
+}
+
+/// Words that make up a config, so that the parser sees documents that are almost valid
+fn config_word() -> impl Strategy<Value = String> {
+    prop_oneof![
+        prop::sample::select(vec![
+            "monitor", "layout", "matches", "modeline", "mirror-all", "{", "}", "\n", ";",
+            "\"+hsync\"", "\"left\"", "true", "null",
+        ])
+        .prop_map(str::to_string),
+        "\"[a-z]{0,4}\"",
+        "-?[0-9]{1,6}(\\.[0-9]{1,3})?",
+        "(w|h|x|y|fb|dpi|group|rotate|primary|backlight|diagonal|product|output)=",
+        "\"[0-9]{1,5}x[0-9]{1,5}\"",
+    ]
+}
+
+proptest! {
+    #[test]
+    fn round_trips_through_kdl(config in config()) {
+        let text = config.to_kdl();
+        let document = parse_document(&text).unwrap();
+        let parsed = Config::try_from(document).unwrap();
+        prop_assert_eq!(parsed, config, "{}", text);
+    }
+
+    #[test]
+    fn arbitrary_text_does_not_panic(text in "\\PC*") {
+        if let Ok(document) = parse_document(&text) {
+            let _ = Config::try_from(document);
+        }
+    }
+
+    #[test]
+    fn arbitrary_documents_do_not_panic(words in collection::vec(config_word(), 0..40)) {
+        if let Ok(document) = parse_document(words.join(" ")) {
+            let _ = Config::try_from(document);
+        }
+    }
+}